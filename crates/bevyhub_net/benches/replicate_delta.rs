@@ -0,0 +1,73 @@
+//! Compares full payload and delta change messages for a
+//! transform-like component on many entities.
+//! ```sh
+//! cargo bench -p bevyhub_net --bench replicate_delta
+//! ```
+#![feature(test)]
+extern crate test;
+use bevy::prelude::*;
+use bevyhub_net::prelude::*;
+use serde::Deserialize;
+use serde::Serialize;
+use test::Bencher;
+
+const NUM_ENTITIES: usize = 500;
+
+#[derive(Debug, Clone, Component, Serialize, Deserialize)]
+struct MyTransform {
+	translation: [f32; 3],
+	rotation: [f32; 4],
+	scale: [f32; 3],
+}
+
+fn setup(delta: bool) -> App {
+	let mut app = App::new();
+	app.add_plugins(ReplicatePlugin);
+	if delta {
+		app.replicate_delta::<MyTransform>();
+	} else {
+		app.replicate::<MyTransform>();
+	}
+	for _ in 0..NUM_ENTITIES {
+		app.world_mut().spawn((Replicate::default(), MyTransform {
+			translation: [0.; 3],
+			rotation: [0., 0., 0., 1.],
+			scale: [1.; 3],
+		}));
+	}
+	app.update();
+	app.world_mut().resource_mut::<MessageOutgoing>().clear();
+	app
+}
+
+/// Move every entity and encode the resulting messages.
+fn step(app: &mut App) -> Vec<u8> {
+	for mut transform in app
+		.world_mut()
+		.query::<&mut MyTransform>()
+		.iter_mut(app.world_mut())
+	{
+		transform.translation[0] += 1.;
+	}
+	app.update();
+	let messages = app
+		.world_mut()
+		.resource_mut::<MessageOutgoing>()
+		.drain(..)
+		.collect();
+	Message::vec_into_bytes(&messages).unwrap()
+}
+
+#[bench]
+fn full_payload(b: &mut Bencher) {
+	let mut app = setup(false);
+	b.bytes = step(&mut app).len() as u64;
+	b.iter(|| step(&mut app));
+}
+
+#[bench]
+fn delta_payload(b: &mut Bencher) {
+	let mut app = setup(true);
+	b.bytes = step(&mut app).len() as u64;
+	b.iter(|| step(&mut app));
+}
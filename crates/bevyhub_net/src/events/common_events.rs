@@ -6,7 +6,8 @@ use serde::Serialize;
 /**
  * This adds common replication events to the app.
  * It should be added before any other replications are registered in order
- * to preserve the registration ids
+ * to preserve the registration ids, unless peers exchange registrations
+ * with the [`ReplicateHandshakePlugin`].
  *
 */
 pub struct CommonEventsPlugin;
@@ -0,0 +1,46 @@
+use crate::prelude::*;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Delivery guarantees of a [`Channel`].
+#[derive(
+	Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub enum ChannelKind {
+	/// Every message arrives in the order it was sent.
+	#[default]
+	Reliable,
+	/// Messages may be dropped or arrive out of order.
+	Unreliable,
+	/// Messages may be dropped, and any arriving after a newer
+	/// message on the same channel are discarded.
+	Sequenced,
+}
+
+impl ChannelKind {
+	pub fn is_reliable(&self) -> bool { matches!(self, Self::Reliable) }
+}
+
+/// A channel that a [`Transport`] sends messages on, the default
+/// is [`Channel::RELIABLE`].
+/// Transports without channel support send everything reliably.
+#[derive(
+	Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub struct Channel {
+	pub id: ChannelId,
+	pub kind: ChannelKind,
+}
+
+impl Channel {
+	/// The default channel, used for everything but changes.
+	pub const RELIABLE: Self = Self::new(0, ChannelKind::Reliable);
+	/// Unreliable unordered changes.
+	pub const UNRELIABLE: Self = Self::new(1, ChannelKind::Unreliable);
+	/// Unreliable latest-wins changes.
+	pub const SEQUENCED: Self = Self::new(2, ChannelKind::Sequenced);
+
+	pub const fn new(id: ChannelId, kind: ChannelKind) -> Self {
+		Self { id, kind }
+	}
+}
@@ -1,4 +1,7 @@
 pub type UserId = u32;
 pub type ClientId = u32;
+/// The sender of messages that were not stamped by the relay server,
+/// ie over a direct connection. The server assigns ids from 1.
+pub const DIRECT_CLIENT_ID: ClientId = 0;
 pub type LobbyId = u32;
 pub type ChannelId = u32;
@@ -0,0 +1,673 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::time::Duration;
+
+/// Maximum size of a decompressed batch, so that a small
+/// compressed batch cannot exhaust the memory of the receiver.
+pub const MAX_BATCH_SIZE: usize = 16 * 1024 * 1024;
+
+/// Compression of batches encoded by a [`CompactCodec`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Compression {
+	#[default]
+	None,
+	/// Fast compression, requires the `lz4` feature.
+	#[cfg(feature = "lz4")]
+	Lz4,
+	/// Smaller output, requires the `zstd` feature.
+	#[cfg(feature = "zstd")]
+	Zstd { level: i32 },
+}
+
+impl Compression {
+	fn flag(&self) -> u8 {
+		match self {
+			Self::None => 0,
+			#[cfg(feature = "lz4")]
+			Self::Lz4 => 1,
+			#[cfg(feature = "zstd")]
+			Self::Zstd { .. } => 2,
+		}
+	}
+
+	fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+		match self {
+			Self::None => Ok(bytes.to_vec()),
+			#[cfg(feature = "lz4")]
+			Self::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+			#[cfg(feature = "zstd")]
+			Self::Zstd { level } => Ok(zstd::bulk::compress(bytes, *level)?),
+		}
+	}
+
+	fn decompress(flag: u8, bytes: &[u8]) -> Result<Vec<u8>> {
+		match flag {
+			0 => Ok(bytes.to_vec()),
+			#[cfg(feature = "lz4")]
+			1 => {
+				// check the prepended size before allocating,
+				// lz4 cannot compress by more than 255 times
+				let Some(size) = bytes.get(..4) else {
+					anyhow::bail!("missing lz4 size");
+				};
+				let size = u32::from_le_bytes(size.try_into()?) as usize;
+				if size > bytes.len() * 255 || size > MAX_BATCH_SIZE {
+					anyhow::bail!("invalid lz4 size {size}");
+				}
+				Ok(lz4_flex::decompress(&bytes[4..], size)?)
+			}
+			#[cfg(feature = "zstd")]
+			2 => {
+				use std::io::Read;
+				let mut decoded = Vec::new();
+				zstd::stream::Decoder::new(bytes)?
+					.take(MAX_BATCH_SIZE as u64 + 1)
+					.read_to_end(&mut decoded)?;
+				if decoded.len() > MAX_BATCH_SIZE {
+					anyhow::bail!("batch exceeds {MAX_BATCH_SIZE} bytes");
+				}
+				Ok(decoded)
+			}
+			flag => anyhow::bail!(
+				"unsupported compression flag {flag}, is the `lz4` or `zstd` feature disabled?"
+			),
+		}
+	}
+}
+
+/**
+A compact binary encoding for batches of messages, an alternative to
+[`Message::vec_into_bytes`] for bandwidth constrained transports.
+
+- Registration and client ids are encoded as varints
+- Entities are stored once per batch in a table, and referenced by index
+  followed by their [`EntityOrigin`]
+- Payloads are length prefixed, only the binary payload is sent
+- Batches of at least [`Self::compress_threshold`] bytes are compressed
+
+Messages without a compact encoding are stored with bincode.
+**/
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CompactCodec {
+	pub compression: Compression,
+	/// Minimum uncompressed size of a batch to compress.
+	pub compress_threshold: usize,
+}
+
+impl Default for CompactCodec {
+	fn default() -> Self {
+		Self {
+			compression: Compression::None,
+			compress_threshold: 512,
+		}
+	}
+}
+
+const SPAWN: u8 = 0;
+const DESPAWN: u8 = 1;
+const ADD: u8 = 2;
+const CHANGE: u8 = 3;
+const REMOVE: u8 = 4;
+const INSERT_RESOURCE: u8 = 5;
+const CHANGE_RESOURCE: u8 = 6;
+const REMOVE_RESOURCE: u8 = 7;
+const SEND_EVENT: u8 = 8;
+const SEND_OBSERVER: u8 = 9;
+const CHANGE_DELTA: u8 = 10;
+const SENDER: u8 = 11;
+const RECIPIENT: u8 = 12;
+const TIMESTAMP: u8 = 13;
+/// Any other message, encoded with bincode.
+const OTHER: u8 = 255;
+
+impl CompactCodec {
+	pub fn new(compression: Compression) -> Self {
+		Self {
+			compression,
+			..default()
+		}
+	}
+
+	pub fn with_compress_threshold(
+		mut self,
+		compress_threshold: usize,
+	) -> Self {
+		self.compress_threshold = compress_threshold;
+		self
+	}
+
+	pub fn encode(&self, messages: &[Message]) -> Result<Vec<u8>> {
+		let mut entities = EntityTable::default();
+		let mut body = Vec::new();
+		write_varint(&mut body, messages.len() as u64);
+		for message in messages {
+			write_message(&mut body, &mut entities, message)?;
+		}
+		let mut bytes = Vec::with_capacity(body.len() + 16);
+		let mut table = Vec::new();
+		write_varint(&mut table, entities.entities.len() as u64);
+		for entity in entities.entities.iter() {
+			write_varint(&mut table, entity.index() as u64);
+			write_varint(&mut table, entity.generation() as u64);
+		}
+		table.extend(body);
+
+		if self.compression != Compression::None
+			&& table.len() >= self.compress_threshold
+		{
+			bytes.push(self.compression.flag());
+			bytes.extend(self.compression.compress(&table)?);
+		} else {
+			bytes.push(Compression::None.flag());
+			bytes.extend(table);
+		}
+		Ok(bytes)
+	}
+
+	/// Decode a batch encoded with any [`Compression`] supported by
+	/// the enabled features.
+	pub fn decode(bytes: &[u8]) -> Result<Vec<Message>> {
+		let Some((flag, bytes)) = bytes.split_first() else {
+			anyhow::bail!("empty batch");
+		};
+		let bytes = Compression::decompress(*flag, bytes)?;
+		let mut reader = Reader { bytes: &bytes };
+		let num_entities = reader.len()?;
+		let entities = (0..num_entities)
+			.map(|_| {
+				let index = reader.u32()? as u64;
+				let generation = reader.u32()? as u64;
+				Entity::try_from_bits(generation << 32 | index)
+					.map_err(|e| anyhow::anyhow!("{e}"))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let num_messages = reader.len()?;
+		let messages = (0..num_messages)
+			.map(|_| read_message(&mut reader, &entities))
+			.collect::<Result<Vec<_>>>()?;
+		if !reader.bytes.is_empty() {
+			anyhow::bail!("{} trailing bytes in batch", reader.bytes.len());
+		}
+		Ok(messages)
+	}
+}
+
+/// Entities of a batch in the order they first appear.
+#[derive(Default)]
+struct EntityTable {
+	entities: Vec<Entity>,
+	indices: HashMap<Entity, u64>,
+}
+
+impl EntityTable {
+	fn index(&mut self, entity: Entity) -> u64 {
+		*self.indices.entry(entity).or_insert_with(|| {
+			self.entities.push(entity);
+			self.entities.len() as u64 - 1
+		})
+	}
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+	while value >= 0x80 {
+		bytes.push(value as u8 | 0x80);
+		value >>= 7;
+	}
+	bytes.push(value as u8);
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+	write_varint(bytes, value.len() as u64);
+	bytes.extend_from_slice(value);
+}
+
+fn write_origin(bytes: &mut Vec<u8>, origin: &EntityOrigin) {
+	match origin {
+		EntityOrigin::Sender => write_varint(bytes, 0),
+		EntityOrigin::Receiver => write_varint(bytes, 1),
+		EntityOrigin::Client(client_id) => {
+			write_varint(bytes, *client_id as u64 + 2)
+		}
+	}
+}
+
+fn write_payload(bytes: &mut Vec<u8>, payload: &MessagePayload) -> Result<()> {
+	let MessagePayload::Bytes(payload) = payload.into_bytes()? else {
+		unreachable!("into_bytes returns bytes");
+	};
+	write_bytes(bytes, &payload);
+	Ok(())
+}
+
+fn write_message(
+	bytes: &mut Vec<u8>,
+	entities: &mut EntityTable,
+	message: &Message,
+) -> Result<()> {
+	let mut header = |bytes: &mut Vec<u8>,
+	                  tag: u8,
+	                  reg_id: Option<&RegistrationId>,
+	                  entity: Option<&Entity>| {
+		bytes.push(tag);
+		if let Some(reg_id) = reg_id {
+			write_varint(bytes, reg_id.inner() as u64);
+		}
+		if let Some(entity) = entity {
+			write_varint(bytes, entities.index(*entity));
+		}
+	};
+	match message {
+		Message::Spawn { entity } => header(bytes, SPAWN, None, Some(entity)),
+		Message::Despawn { entity, origin } => {
+			header(bytes, DESPAWN, None, Some(entity));
+			write_origin(bytes, origin);
+		}
+		Message::Add {
+			reg_id,
+			entity,
+			origin,
+			payload,
+		} => {
+			header(bytes, ADD, Some(reg_id), Some(entity));
+			write_origin(bytes, origin);
+			write_payload(bytes, payload)?;
+		}
+		Message::Change {
+			reg_id,
+			entity,
+			origin,
+			payload,
+		} => {
+			header(bytes, CHANGE, Some(reg_id), Some(entity));
+			write_origin(bytes, origin);
+			write_payload(bytes, payload)?;
+		}
+		Message::Remove {
+			reg_id,
+			entity,
+			origin,
+		} => {
+			header(bytes, REMOVE, Some(reg_id), Some(entity));
+			write_origin(bytes, origin);
+		}
+		Message::InsertResource { reg_id, payload } => {
+			header(bytes, INSERT_RESOURCE, Some(reg_id), None);
+			write_payload(bytes, payload)?;
+		}
+		Message::ChangeResource { reg_id, payload } => {
+			header(bytes, CHANGE_RESOURCE, Some(reg_id), None);
+			write_payload(bytes, payload)?;
+		}
+		Message::RemoveResource { reg_id } => {
+			header(bytes, REMOVE_RESOURCE, Some(reg_id), None)
+		}
+		Message::SendEvent { reg_id, payload } => {
+			header(bytes, SEND_EVENT, Some(reg_id), None);
+			write_payload(bytes, payload)?;
+		}
+		Message::SendObserver { reg_id, payload } => {
+			header(bytes, SEND_OBSERVER, Some(reg_id), None);
+			write_payload(bytes, payload)?;
+		}
+		Message::ChangeDelta {
+			reg_id,
+			entity,
+			origin,
+			delta,
+			..
+		} => {
+			header(bytes, CHANGE_DELTA, Some(reg_id), Some(entity));
+			write_origin(bytes, origin);
+			bytes.extend(delta.baseline.to_le_bytes());
+			write_varint(bytes, delta.runs.len() as u64);
+			for (offset, run) in delta.runs.iter() {
+				write_varint(bytes, *offset as u64);
+				write_bytes(bytes, run);
+			}
+		}
+		Message::Sender { client_id } => {
+			bytes.push(SENDER);
+			write_varint(bytes, *client_id as u64);
+		}
+		Message::Recipient { client_id } => {
+			bytes.push(RECIPIENT);
+			write_varint(bytes, *client_id as u64);
+		}
+		Message::Timestamp { elapsed } => {
+			bytes.push(TIMESTAMP);
+			write_varint(bytes, elapsed.as_secs());
+			write_varint(bytes, elapsed.subsec_nanos() as u64);
+		}
+		other => {
+			bytes.push(OTHER);
+			write_bytes(
+				bytes,
+				&bincode::serialize(&other.with_bytes_payload()?)?,
+			);
+		}
+	}
+	Ok(())
+}
+
+struct Reader<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn u8(&mut self) -> Result<u8> {
+		let Some((value, bytes)) = self.bytes.split_first() else {
+			anyhow::bail!("unexpected end of batch");
+		};
+		self.bytes = bytes;
+		Ok(*value)
+	}
+
+	fn varint(&mut self) -> Result<u64> {
+		let mut value = 0u64;
+		for shift in (0..64).step_by(7) {
+			let byte = self.u8()?;
+			value |= ((byte & 0x7f) as u64) << shift;
+			if byte & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+		anyhow::bail!("varint is too long")
+	}
+
+	/// A varint length, checked against the remaining bytes
+	/// so a corrupt batch cannot allocate too much.
+	fn len(&mut self) -> Result<usize> {
+		let len = self.varint()? as usize;
+		if len > self.bytes.len() {
+			anyhow::bail!("length {len} exceeds the remaining bytes");
+		}
+		Ok(len)
+	}
+
+	fn bytes(&mut self) -> Result<&'a [u8]> {
+		let len = self.len()?;
+		let (value, bytes) = self.bytes.split_at(len);
+		self.bytes = bytes;
+		Ok(value)
+	}
+
+	fn u64_le(&mut self) -> Result<u64> {
+		let Some((value, bytes)) = self.bytes.split_first_chunk::<8>() else {
+			anyhow::bail!("unexpected end of batch");
+		};
+		self.bytes = bytes;
+		Ok(u64::from_le_bytes(*value))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		Ok(u32::try_from(self.varint()?)?)
+	}
+
+	fn reg_id(&mut self) -> Result<RegistrationId> {
+		Ok(RegistrationId::new_with(usize::try_from(self.varint()?)?))
+	}
+
+	fn entity(&mut self, entities: &[Entity]) -> Result<Entity> {
+		let index = self.varint()? as usize;
+		entities.get(index).copied().ok_or_else(|| {
+			anyhow::anyhow!("entity index {index} is not in the entity table")
+		})
+	}
+
+	fn origin(&mut self) -> Result<EntityOrigin> {
+		match self.varint()? {
+			0 => Ok(EntityOrigin::Sender),
+			1 => Ok(EntityOrigin::Receiver),
+			value => Ok(EntityOrigin::Client(u32::try_from(value - 2)?)),
+		}
+	}
+
+	fn payload(&mut self) -> Result<MessagePayload> {
+		Ok(MessagePayload::Bytes(self.bytes()?.to_vec()))
+	}
+}
+
+fn read_message(reader: &mut Reader, entities: &[Entity]) -> Result<Message> {
+	let message = match reader.u8()? {
+		SPAWN => Message::Spawn {
+			entity: reader.entity(entities)?,
+		},
+		DESPAWN => Message::Despawn {
+			entity: reader.entity(entities)?,
+			origin: reader.origin()?,
+		},
+		ADD => Message::Add {
+			reg_id: reader.reg_id()?,
+			entity: reader.entity(entities)?,
+			origin: reader.origin()?,
+			payload: reader.payload()?,
+		},
+		CHANGE => Message::Change {
+			reg_id: reader.reg_id()?,
+			entity: reader.entity(entities)?,
+			origin: reader.origin()?,
+			payload: reader.payload()?,
+		},
+		REMOVE => Message::Remove {
+			reg_id: reader.reg_id()?,
+			entity: reader.entity(entities)?,
+			origin: reader.origin()?,
+		},
+		INSERT_RESOURCE => Message::InsertResource {
+			reg_id: reader.reg_id()?,
+			payload: reader.payload()?,
+		},
+		CHANGE_RESOURCE => Message::ChangeResource {
+			reg_id: reader.reg_id()?,
+			payload: reader.payload()?,
+		},
+		REMOVE_RESOURCE => Message::RemoveResource {
+			reg_id: reader.reg_id()?,
+		},
+		SEND_EVENT => Message::SendEvent {
+			reg_id: reader.reg_id()?,
+			payload: reader.payload()?,
+		},
+		SEND_OBSERVER => Message::SendObserver {
+			reg_id: reader.reg_id()?,
+			payload: reader.payload()?,
+		},
+		CHANGE_DELTA => {
+			let reg_id = reader.reg_id()?;
+			let entity = reader.entity(entities)?;
+			let origin = reader.origin()?;
+			let baseline = reader.u64_le()?;
+			let num_runs = reader.len()?;
+			let runs = (0..num_runs)
+				.map(|_| Ok((reader.u32()?, reader.bytes()?.to_vec())))
+				.collect::<Result<Vec<_>>>()?;
+			Message::ChangeDelta {
+				reg_id,
+				entity,
+				origin,
+				delta: ByteDelta { baseline, runs },
+				payload: None,
+			}
+		}
+		SENDER => Message::Sender {
+			client_id: reader.u32()?,
+		},
+		RECIPIENT => Message::Recipient {
+			client_id: reader.u32()?,
+		},
+		TIMESTAMP => Message::Timestamp {
+			elapsed: Duration::new(reader.varint()?, reader.u32()?),
+		},
+		OTHER => bincode::deserialize(reader.bytes()?)?,
+		tag => anyhow::bail!("unknown message tag {tag}"),
+	};
+	Ok(message)
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use proptest::prelude::*;
+	use sweet::prelude::*;
+
+	fn entity() -> impl Strategy<Value = Entity> {
+		// a few entities so the table is shared between messages
+		(0u32..8, 1u32..3).prop_map(|(index, generation)| {
+			Entity::from_bits((generation as u64) << 32 | index as u64)
+		})
+	}
+
+	fn origin() -> impl Strategy<Value = EntityOrigin> {
+		prop_oneof![
+			Just(EntityOrigin::Sender),
+			Just(EntityOrigin::Receiver),
+			any::<u32>().prop_map(EntityOrigin::Client),
+		]
+	}
+
+	fn reg_id() -> impl Strategy<Value = RegistrationId> {
+		prop_oneof![0usize..200, any::<usize>()]
+			.prop_map(RegistrationId::new_with)
+	}
+
+	fn payload() -> impl Strategy<Value = MessagePayload> {
+		proptest::collection::vec(any::<u8>(), 0..64)
+			.prop_map(MessagePayload::Bytes)
+	}
+
+	fn message() -> impl Strategy<Value = Message> {
+		prop_oneof![
+			entity().prop_map(|entity| Message::Spawn { entity }),
+			(entity(), origin()).prop_map(|(entity, origin)| {
+				Message::Despawn { entity, origin }
+			}),
+			(reg_id(), entity(), origin(), payload()).prop_map(
+				|(reg_id, entity, origin, payload)| Message::Add {
+					reg_id,
+					entity,
+					origin,
+					payload
+				}
+			),
+			(reg_id(), entity(), origin(), payload()).prop_map(
+				|(reg_id, entity, origin, payload)| Message::Change {
+					reg_id,
+					entity,
+					origin,
+					payload
+				}
+			),
+			(reg_id(), entity(), origin()).prop_map(
+				|(reg_id, entity, origin)| Message::Remove {
+					reg_id,
+					entity,
+					origin
+				}
+			),
+			(reg_id(), payload()).prop_map(|(reg_id, payload)| {
+				Message::ChangeResource { reg_id, payload }
+			}),
+			reg_id().prop_map(|reg_id| Message::RemoveResource { reg_id }),
+			(reg_id(), payload()).prop_map(|(reg_id, payload)| {
+				Message::SendEvent { reg_id, payload }
+			}),
+			(
+				reg_id(),
+				entity(),
+				origin(),
+				any::<u64>(),
+				proptest::collection::vec(
+					(
+						any::<u32>(),
+						proptest::collection::vec(any::<u8>(), 0..8)
+					),
+					0..4
+				)
+			)
+				.prop_map(|(reg_id, entity, origin, baseline, runs)| {
+					Message::ChangeDelta {
+						reg_id,
+						entity,
+						origin,
+						delta: ByteDelta { baseline, runs },
+						payload: None,
+					}
+				}),
+			any::<u32>().prop_map(|client_id| Message::Sender { client_id }),
+			any::<u32>().prop_map(|client_id| Message::Recipient { client_id }),
+			(any::<u64>(), 0u32..1_000_000_000).prop_map(|(secs, nanos)| {
+				Message::Timestamp {
+					elapsed: std::time::Duration::new(secs, nanos),
+				}
+			}),
+			(entity(), proptest::option::of(any::<u32>())).prop_map(
+				|(entity, client_id)| Message::OwnerChanged {
+					entity,
+					client_id
+				}
+			),
+		]
+	}
+
+	fn codecs() -> Vec<CompactCodec> {
+		#[allow(unused_mut)]
+		let mut codecs = vec![CompactCodec::default()];
+		#[cfg(feature = "lz4")]
+		codecs.push(
+			CompactCodec::new(Compression::Lz4).with_compress_threshold(0),
+		);
+		#[cfg(feature = "zstd")]
+		codecs.push(
+			CompactCodec::new(Compression::Zstd { level: 3 })
+				.with_compress_threshold(0),
+		);
+		codecs
+	}
+
+	proptest! {
+		#[test]
+		fn round_trip(messages in proptest::collection::vec(message(), 0..32)) {
+			for codec in codecs() {
+				let bytes = codec.encode(&messages).unwrap();
+				prop_assert_eq!(&CompactCodec::decode(&bytes).unwrap(), &messages);
+			}
+		}
+
+		#[test]
+		fn corrupt(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+			// never panics
+			let _ = CompactCodec::decode(&bytes);
+		}
+	}
+
+	#[test]
+	fn smaller() -> Result<()> {
+		let messages = (0..100)
+			.map(|i| {
+				Ok(Message::Change {
+					reg_id: RegistrationId::new_with(3),
+					entity: Entity::from_raw(i % 10),
+					origin: EntityOrigin::Sender,
+					payload: MessagePayload::new(i as f32)?.into_bytes()?,
+				})
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let bincode = Message::vec_into_bytes(&messages)?.len();
+		let compact = CompactCodec::default().encode(&messages)?.len();
+		expect(compact * 3 < bincode).to_be_true();
+		Ok(())
+	}
+	#[test]
+	#[cfg(feature = "zstd")]
+	fn max_batch_size() -> Result<()> {
+		let mut bytes = vec![Compression::Zstd { level: 3 }.flag()];
+		bytes.extend(zstd::bulk::compress(&vec![0; MAX_BATCH_SIZE + 1], 3)?);
+		expect(CompactCodec::decode(&bytes))
+			.to_be_err_str(&format!("batch exceeds {MAX_BATCH_SIZE} bytes"));
+		Ok(())
+	}
+}
@@ -1,9 +1,18 @@
+use crate::prelude::ByteDelta;
+use crate::prelude::ClientId;
+use crate::prelude::CompactCodec;
+use crate::prelude::DeltaValue;
+use crate::prelude::EntityOrigin;
 use crate::prelude::RegistrationId;
+use crate::prelude::RtcSignal;
 use anyhow::Result;
 use bevy::prelude::*;
+use bincode::Options;
 use serde::de::DeserializeOwned;
+use serde::de::DeserializeSeed;
 use serde::Deserialize;
 use serde::Serialize;
+use std::time::Duration;
 
 #[derive(Debug, Default, Clone, PartialEq, Deref, DerefMut, Resource)]
 pub struct MessageIncoming(pub Vec<Message>);
@@ -20,20 +29,24 @@ pub enum Message {
 	},
 	Despawn {
 		entity: Entity,
+		origin: EntityOrigin,
 	},
 	Add {
 		reg_id: RegistrationId,
 		entity: Entity,
+		origin: EntityOrigin,
 		payload: MessagePayload,
 	},
 	Change {
 		reg_id: RegistrationId,
 		entity: Entity,
+		origin: EntityOrigin,
 		payload: MessagePayload,
 	},
 	Remove {
 		reg_id: RegistrationId,
 		entity: Entity,
+		origin: EntityOrigin,
 	},
 	InsertResource {
 		reg_id: RegistrationId,
@@ -54,6 +67,79 @@ pub enum Message {
 		reg_id: RegistrationId,
 		payload: MessagePayload,
 	},
+	/// A [`Message::Change`] sent as a diff against the previous value,
+	/// see [`App::replicate_delta`].
+	ChangeDelta {
+		reg_id: RegistrationId,
+		entity: Entity,
+		origin: EntityOrigin,
+		delta: ByteDelta,
+		/// The full value, only used when converting to json.
+		#[serde(skip)]
+		payload: Option<DeltaValue>,
+	},
+	/// All following messages in the batch were sent by this client.
+	/// This is usually prepended by the server relaying the messages.
+	Sender {
+		client_id: ClientId,
+	},
+	/// The sender's `type_name -> RegistrationId` table, used to remap
+	/// ids per peer, see [`ReplicateHandshakePlugin`].
+	Registrations {
+		types: Vec<(String, RegistrationId)>,
+	},
+	/// The sender's protocol version and schema hashes,
+	/// see [`ReplicateProtocol`].
+	Protocol {
+		version: u32,
+		schema: Vec<(String, u64)>,
+	},
+	/// A peer connected, usually sent by the relay server.
+	PeerConnected {
+		client_id: ClientId,
+	},
+	/// A peer disconnected, usually sent by the relay server.
+	PeerDisconnected {
+		client_id: ClientId,
+	},
+	/// All following messages in the batch are only for this client,
+	/// see [`Transport::send_to`]. Used by in-process relays like the
+	/// [`ReplicationTestHarness`], the relay server reads a
+	/// [`RelayHeader::Recipient`] instead.
+	Recipient {
+		client_id: ClientId,
+	},
+	/// Request authority over an entity spawned by the receiver,
+	/// see [`ReplicateAuthorityPlugin`].
+	AuthorityRequest {
+		entity: Entity,
+		origin: EntityOrigin,
+	},
+	/// The [`Owner`] of an entity spawned by the sender changed,
+	/// `None` meaning the sender has authority again.
+	OwnerChanged {
+		entity: Entity,
+		client_id: Option<ClientId>,
+	},
+	/// The receiver was given [`Authority`] over an entity spawned by the sender.
+	AuthorityGranted {
+		entity: Entity,
+	},
+	/// WebRTC signaling relayed by the server to a single peer,
+	/// see [`RtcTransport`].
+	Signal {
+		signal: RtcSignal,
+	},
+	/// The id the relay server assigned to the receiver,
+	/// see [`RelayHeader::Welcome`].
+	Welcome {
+		client_id: ClientId,
+	},
+	/// The [`Time::elapsed`] of the sender when the batch was sent,
+	/// used to stamp the changes after it, see [`IncomingTimestamp`].
+	Timestamp {
+		elapsed: Duration,
+	},
 }
 
 impl Message {
@@ -79,6 +165,34 @@ impl Message {
 		Ok(bytes)
 	}
 
+	/// Self describing binary messages for js consumers, structs are
+	/// encoded as maps and payloads as [`MessagePayload::MsgPack`].
+	#[cfg(feature = "msgpack")]
+	pub fn vec_into_msgpack(items: &[Message]) -> Result<Vec<u8>> {
+		let items = items
+			.iter()
+			.map(|m| m.with_msgpack_payload())
+			.collect::<Result<Vec<_>>>()?;
+		Ok(rmp_serde::to_vec_named(&items)?)
+	}
+
+	#[cfg(feature = "msgpack")]
+	pub fn vec_from_msgpack(bytes: &[u8]) -> Result<Vec<Message>> {
+		Ok(rmp_serde::from_slice(bytes)?)
+	}
+
+	/// Encode with a [`CompactCodec`], decode with [`Message::vec_from_compact`].
+	pub fn vec_into_compact(
+		items: &[Message],
+		codec: &CompactCodec,
+	) -> Result<Vec<u8>> {
+		codec.encode(items)
+	}
+
+	pub fn vec_from_compact(bytes: &[u8]) -> Result<Vec<Message>> {
+		CompactCodec::decode(bytes)
+	}
+
 	#[cfg(feature = "serde_json")]
 	pub fn vec_from_json(json: &str) -> serde_json::Result<Vec<Message>> {
 		serde_json::from_str::<Vec<Message>>(json)
@@ -95,6 +209,60 @@ impl Message {
 		Ok(json)
 	}
 
+	pub fn reg_id_mut(&mut self) -> Option<&mut RegistrationId> {
+		match self {
+			Self::Add { reg_id, .. }
+			| Self::Change { reg_id, .. }
+			| Self::ChangeDelta { reg_id, .. }
+			| Self::Remove { reg_id, .. }
+			| Self::InsertResource { reg_id, .. }
+			| Self::ChangeResource { reg_id, .. }
+			| Self::RemoveResource { reg_id }
+			| Self::SendEvent { reg_id, .. }
+			| Self::SendObserver { reg_id, .. } => Some(reg_id),
+			Self::Spawn { .. }
+			| Self::Despawn { .. }
+			| Self::Sender { .. }
+			| Self::Registrations { .. }
+			| Self::Protocol { .. }
+			| Self::PeerConnected { .. }
+			| Self::PeerDisconnected { .. }
+			| Self::Recipient { .. }
+			| Self::AuthorityRequest { .. }
+			| Self::OwnerChanged { .. }
+			| Self::AuthorityGranted { .. }
+			| Self::Signal { .. }
+			| Self::Welcome { .. }
+			| Self::Timestamp { .. } => None,
+		}
+	}
+
+	/// The entity this message is about, if any.
+	pub fn entity(&self) -> Option<Entity> {
+		match self {
+			Self::Spawn { entity }
+			| Self::Despawn { entity, .. }
+			| Self::Add { entity, .. }
+			| Self::Change { entity, .. }
+			| Self::ChangeDelta { entity, .. }
+			| Self::Remove { entity, .. } => Some(*entity),
+			_ => None,
+		}
+	}
+
+	/// The app that spawned the entity of [`Self::entity`].
+	pub fn origin(&self) -> Option<EntityOrigin> {
+		match self {
+			Self::Spawn { .. } => Some(EntityOrigin::Sender),
+			Self::Despawn { origin, .. }
+			| Self::Add { origin, .. }
+			| Self::Change { origin, .. }
+			| Self::ChangeDelta { origin, .. }
+			| Self::Remove { origin, .. } => Some(*origin),
+			_ => None,
+		}
+	}
+
 	fn with_payload(
 		&self,
 		func: impl FnOnce(&MessagePayload) -> Result<MessagePayload>,
@@ -102,19 +270,23 @@ impl Message {
 		match self {
 			Self::Add {
 				entity,
+				origin,
 				reg_id,
 				payload,
 			} => Ok(Self::Add {
 				entity: *entity,
+				origin: *origin,
 				reg_id: *reg_id,
 				payload: func(payload)?,
 			}),
 			Self::Change {
 				entity,
+				origin,
 				reg_id,
 				payload,
 			} => Ok(Self::Change {
 				entity: *entity,
+				origin: *origin,
 				reg_id: *reg_id,
 				payload: func(payload)?,
 			}),
@@ -139,15 +311,166 @@ impl Message {
 	}
 
 	pub fn with_bytes_payload(&self) -> Result<Self> {
-		self.with_payload(|payload| payload.into_bytes())
+		match self {
+			Self::ChangeDelta {
+				reg_id,
+				entity,
+				origin,
+				delta,
+				..
+			} => Ok(Self::ChangeDelta {
+				reg_id: *reg_id,
+				entity: *entity,
+				origin: *origin,
+				delta: delta.clone(),
+				payload: None,
+			}),
+			other => other.with_payload(|payload| payload.into_bytes()),
+		}
+	}
+	/// The number of bytes of the message when sent by binary transports,
+	/// used by [`TransportBudget`] and [`TransportStats`].
+	pub fn num_bytes(&self) -> usize {
+		self.with_bytes_payload()
+			.and_then(|message| Ok(bincode::serialized_size(&message)?))
+			.or_else(|_| bincode::serialized_size(self))
+			.unwrap_or_default() as usize
+	}
+	/// Like [`Self::with_json_payload`], with [`MessagePayload::MsgPack`].
+	#[cfg(feature = "msgpack")]
+	pub fn with_msgpack_payload(&self) -> Result<Self> {
+		match self {
+			Self::ChangeDelta {
+				reg_id,
+				entity,
+				origin,
+				payload: Some(payload),
+				..
+			} => Ok(Self::Change {
+				reg_id: *reg_id,
+				entity: *entity,
+				origin: *origin,
+				payload: payload.into_json()?.into_msgpack()?,
+			}),
+			Self::ChangeDelta { .. } => anyhow::bail!(
+				"delta message has no payload, cannot be converted to msgpack"
+			),
+			other => other.with_payload(|payload| payload.into_msgpack()),
+		}
 	}
 	pub fn with_json_payload(&self) -> Result<Self> {
-		self.with_payload(|payload| payload.into_json())
+		match self {
+			// json consumers cannot apply byte deltas
+			Self::ChangeDelta {
+				reg_id,
+				entity,
+				origin,
+				payload: Some(payload),
+				..
+			} => Ok(Self::Change {
+				reg_id: *reg_id,
+				entity: *entity,
+				origin: *origin,
+				payload: payload.into_json()?,
+			}),
+			Self::ChangeDelta { .. } => anyhow::bail!(
+				"delta message has no payload, cannot be converted to json"
+			),
+			other => other.with_payload(|payload| payload.into_json()),
+		}
 	}
 }
 
 
 
+/// The encoding of batches of messages sent as bytes.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum WireFormat {
+	/// See [`Message::vec_into_bytes`].
+	#[default]
+	Bincode,
+	/// Utf-8 json, see [`Message::vec_into_json`].
+	#[cfg(feature = "serde_json")]
+	Json,
+	/// See [`Message::vec_into_compact`].
+	Compact(CompactCodec),
+	/// See [`Message::vec_into_msgpack`].
+	#[cfg(feature = "msgpack")]
+	MsgPack,
+}
+
+impl WireFormat {
+	/// The [`Self::tag`] of [`Self::Json`], available without the feature.
+	pub const JSON_TAG: u8 = 1;
+
+	/// Identifies the format of a batch in a [`RelayFrame`].
+	pub fn tag(&self) -> u8 {
+		match self {
+			Self::Bincode => 0,
+			#[cfg(feature = "serde_json")]
+			Self::Json => Self::JSON_TAG,
+			Self::Compact(_) => 2,
+			#[cfg(feature = "msgpack")]
+			Self::MsgPack => 3,
+		}
+	}
+
+	/// The format of a [`Self::tag`], compact batches are decoded
+	/// the same regardless of the [`CompactCodec`].
+	pub fn from_tag(tag: u8) -> Result<Self> {
+		match tag {
+			0 => Ok(Self::Bincode),
+			#[cfg(feature = "serde_json")]
+			Self::JSON_TAG => Ok(Self::Json),
+			2 => Ok(Self::Compact(CompactCodec::default())),
+			#[cfg(feature = "msgpack")]
+			3 => Ok(Self::MsgPack),
+			tag => anyhow::bail!(
+				"unsupported wire format {tag}, is its feature disabled?"
+			),
+		}
+	}
+
+	pub fn encode(&self, messages: &Vec<Message>) -> Result<Vec<u8>> {
+		match self {
+			Self::Bincode => Message::vec_into_bytes(messages),
+			#[cfg(feature = "serde_json")]
+			Self::Json => Ok(Message::vec_into_json(messages)?.into_bytes()),
+			Self::Compact(codec) => Message::vec_into_compact(messages, codec),
+			#[cfg(feature = "msgpack")]
+			Self::MsgPack => Message::vec_into_msgpack(messages),
+		}
+	}
+
+	pub fn decode(&self, bytes: &[u8]) -> Result<Vec<Message>> {
+		match self {
+			Self::Bincode => Ok(Message::vec_from_bytes(bytes)?),
+			#[cfg(feature = "serde_json")]
+			Self::Json => {
+				Ok(Message::vec_from_json(std::str::from_utf8(bytes)?)?)
+			}
+			Self::Compact(_) => Message::vec_from_compact(bytes),
+			#[cfg(feature = "msgpack")]
+			Self::MsgPack => Message::vec_from_msgpack(bytes),
+		}
+	}
+
+	/// Whether batches are utf-8 text, sent by websockets as text frames.
+	pub fn is_text(&self) -> bool {
+		#[cfg(feature = "serde_json")]
+		if matches!(self, Self::Json) {
+			return true;
+		}
+		false
+	}
+
+	/// The format of binary batches received by a transport with this format,
+	/// text formats receive binary batches as [`Self::Bincode`].
+	pub fn binary(&self) -> Self {
+		if self.is_text() { Self::Bincode } else { *self }
+	}
+}
+
 /// A serializable container for message payloads.
 /// With the `serde_json` feature enabled, both binary and json representations are stored
 /// and filtered depending on whether [`Message::vec_into_json`] or [`Message::vec_into_bytes`] is called.
@@ -156,6 +479,11 @@ pub enum MessagePayload {
 	Bytes(Vec<u8>),
 	Json(String),
 	Dual(Vec<u8>, String),
+	/// A self describing binary payload converted from the json payload,
+	/// see [`MessagePayload::into_msgpack`].
+	MsgPack(
+		#[cfg_attr(feature = "msgpack", serde(with = "serde_bytes"))] Vec<u8>,
+	),
 }
 
 impl MessagePayload {
@@ -179,6 +507,11 @@ impl MessagePayload {
 				)
 			}
 			Self::Dual(bytes, _) => Ok(Self::Bytes(bytes.clone())),
+			Self::MsgPack(_) => {
+				anyhow::bail!(
+					"message payload is msgpack, cannot be converted to bytes"
+				)
+			}
 		}
 	}
 	pub fn into_json(&self) -> Result<Self> {
@@ -188,6 +521,29 @@ impl MessagePayload {
 			),
 			Self::Json(json) => Ok(Self::Json(json.clone())),
 			Self::Dual(_, json) => Ok(Self::Json(json.clone())),
+			Self::MsgPack(_bytes) => {
+				#[cfg(feature = "msgpack")]
+				return Ok(Self::Json(serde_json::to_string(
+					&rmp_serde::from_slice::<serde_json::Value>(_bytes)?,
+				)?));
+				#[cfg(not(feature = "msgpack"))]
+				anyhow::bail!("message payload is msgpack but `msgpack` feature is not enabled")
+			}
+		}
+	}
+	/// Convert the json payload to msgpack, so that it has the same
+	/// structure as the json payload.
+	#[cfg(feature = "msgpack")]
+	pub fn into_msgpack(&self) -> Result<Self> {
+		match self {
+			Self::Bytes(_) => anyhow::bail!(
+				"message payload is bytes, cannot be converted to msgpack"
+			),
+			Self::Json(json) | Self::Dual(_, json) => {
+				let value = serde_json::from_str::<serde_json::Value>(json)?;
+				Ok(Self::MsgPack(rmp_serde::to_vec(&value)?))
+			}
+			Self::MsgPack(bytes) => Ok(Self::MsgPack(bytes.clone())),
 		}
 	}
 
@@ -205,6 +561,46 @@ impl MessagePayload {
 				anyhow::bail!("message payload is json but `serde_json` feature is not enabled")
 			}
 			Self::Dual(bytes, _) => Ok(bincode::deserialize(bytes)?),
+			Self::MsgPack(_bytes) => {
+				#[cfg(feature = "msgpack")]
+				return Ok(serde_json::from_value(rmp_serde::from_slice::<
+					serde_json::Value,
+				>(_bytes)?)?);
+				#[cfg(not(feature = "msgpack"))]
+				anyhow::bail!("message payload is msgpack but `msgpack` feature is not enabled")
+			}
+		}
+	}
+	/// Like [`Self::deserialize`] but with a [`DeserializeSeed`],
+	/// ie a [`TypedReflectDeserializer`](bevy::reflect::serde::TypedReflectDeserializer).
+	pub fn deserialize_seed<'de, T: DeserializeSeed<'de>>(
+		&'de self,
+		seed: T,
+	) -> Result<T::Value> {
+		match self {
+			Self::Bytes(bytes) | Self::Dual(bytes, _) => {
+				// the same options as [`bincode::deserialize`]
+				Ok(bincode::DefaultOptions::new()
+					.with_fixint_encoding()
+					.allow_trailing_bytes()
+					.deserialize_seed(seed, bytes)?)
+			}
+			Self::Json(json) => {
+				#[cfg(feature = "serde_json")]
+				return Ok(seed.deserialize(
+					&mut serde_json::Deserializer::from_str(json),
+				)?);
+				#[cfg(not(feature = "serde_json"))]
+				anyhow::bail!("message payload is json but `serde_json` feature is not enabled")
+			}
+			Self::MsgPack(_bytes) => {
+				#[cfg(feature = "msgpack")]
+				return Ok(seed.deserialize(rmp_serde::from_slice::<
+					serde_json::Value,
+				>(_bytes)?)?);
+				#[cfg(not(feature = "msgpack"))]
+				anyhow::bail!("message payload is msgpack but `msgpack` feature is not enabled")
+			}
 		}
 	}
 }
@@ -243,4 +639,44 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	#[cfg(feature = "msgpack")]
+	fn msgpack() -> Result<()> {
+		use bevy::prelude::*;
+		use serde::Deserialize;
+		use serde::Serialize;
+
+		#[derive(Debug, PartialEq, Serialize, Deserialize)]
+		enum MyEvent {
+			Jump { height: f32 },
+			Crouch,
+		}
+
+		let payload = MessagePayload::new(MyEvent::Jump { height: 2. })?;
+		let msgpack = payload.into_msgpack()?;
+		expect(msgpack.deserialize::<MyEvent>()?)
+			.to_be(MyEvent::Jump { height: 2. });
+		expect(msgpack.into_json()?).to_be(payload.into_json()?);
+
+		let messages = vec![
+			Message::Spawn {
+				entity: Entity::from_raw(3),
+			},
+			Message::SendEvent {
+				reg_id: RegistrationId::new_with(0),
+				payload: MessagePayload::new(MyEvent::Crouch)?,
+			},
+		];
+		let bytes = WireFormat::MsgPack.encode(&messages)?;
+		expect(bytes.len() < Message::vec_into_json(&messages)?.len())
+			.to_be_true();
+		let received = WireFormat::MsgPack.decode(&bytes)?;
+		expect(&received[0]).to_be(&messages[0]);
+		let Message::SendEvent { payload, .. } = &received[1] else {
+			panic!("expected event");
+		};
+		expect(payload.deserialize::<MyEvent>()?).to_be(MyEvent::Crouch);
+		Ok(())
+	}
 }
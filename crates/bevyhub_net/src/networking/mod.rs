@@ -1,3 +1,9 @@
+pub mod channel;
+#[allow(unused_imports)]
+pub use self::channel::*;
+pub mod compact_codec;
+#[allow(unused_imports)]
+pub use self::compact_codec::*;
 pub mod client_meta;
 #[allow(unused_imports)]
 pub use self::client_meta::*;
@@ -7,9 +13,27 @@ pub use self::default_transport_plugin::*;
 pub mod message;
 #[allow(unused_imports)]
 pub use self::message::*;
+pub mod peer;
+#[allow(unused_imports)]
+pub use self::peer::*;
+pub mod relay_frame;
+#[allow(unused_imports)]
+pub use self::relay_frame::*;
+pub mod rtc_transport;
+#[allow(unused_imports)]
+pub use self::rtc_transport::*;
+pub mod simulated_transport;
+#[allow(unused_imports)]
+pub use self::simulated_transport::*;
 pub mod transport;
 #[allow(unused_imports)]
 pub use self::transport::*;
+pub mod transport_budget;
+#[allow(unused_imports)]
+pub use self::transport_budget::*;
 pub mod transport_plugin;
 #[allow(unused_imports)]
 pub use self::transport_plugin::*;
+pub mod udp_protocol;
+#[allow(unused_imports)]
+pub use self::udp_protocol::*;
@@ -0,0 +1,50 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// A peer connected to or disconnected from the transport,
+/// usually from a [`Message::PeerConnected`] sent by the relay server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Event)]
+pub enum PeerEvent {
+	Connected(ClientId),
+	Disconnected(ClientId),
+}
+
+/// Messages for a single peer, sent after [`MessageOutgoing`]
+/// with [`Transport::send_to`].
+#[derive(Debug, Default, Clone, PartialEq, Deref, DerefMut, Resource)]
+pub struct PeerOutgoing(pub HashMap<ClientId, Vec<Message>>);
+
+impl PeerOutgoing {
+	pub fn push(&mut self, client_id: ClientId, message: Message) {
+		self.0.entry(client_id).or_default().push(message);
+	}
+}
+
+/// The id the relay server assigned to this app,
+/// from the [`Message::Welcome`] sent when connecting.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deref, Resource)]
+pub struct LocalClientId(pub Option<ClientId>);
+
+/// Sends a [`PeerEvent`] for each [`Message::PeerConnected`]
+/// and [`Message::PeerDisconnected`], and sets the [`LocalClientId`].
+pub fn handle_peer_messages(
+	incoming: Res<MessageIncoming>,
+	mut local: ResMut<LocalClientId>,
+	mut events: EventWriter<PeerEvent>,
+) {
+	for message in incoming.iter() {
+		match message {
+			Message::Welcome { client_id } => {
+				local.0 = Some(*client_id);
+			}
+			Message::PeerConnected { client_id } => {
+				events.send(PeerEvent::Connected(*client_id));
+			}
+			Message::PeerDisconnected { client_id } => {
+				events.send(PeerEvent::Disconnected(*client_id));
+			}
+			_ => {}
+		}
+	}
+}
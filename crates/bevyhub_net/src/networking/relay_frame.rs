@@ -0,0 +1,255 @@
+use crate::prelude::*;
+use anyhow::Result;
+
+/// Who a batch sent to or by the relay server is for or from, written in
+/// front of the batch so that the server never decodes it, see [`RelayFrame`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RelayHeader {
+	/// Sent by a client, a batch for every other client.
+	Broadcast,
+	/// Sent by a client, a batch only for this client,
+	/// see [`Transport::send_to`].
+	Recipient(ClientId),
+	/// Sent by the server, a batch relayed from this client.
+	Sender(ClientId),
+	/// Sent by the server, the id it assigned to the receiver.
+	/// This is the first frame after connecting.
+	Welcome(ClientId),
+	/// Sent by the server, see [`Message::PeerConnected`].
+	PeerConnected(ClientId),
+	/// Sent by the server, see [`Message::PeerDisconnected`].
+	PeerDisconnected(ClientId),
+}
+
+impl RelayHeader {
+	fn tag(&self) -> (u8, ClientId) {
+		match self {
+			Self::Broadcast => (b'b', 0),
+			Self::Recipient(client_id) => (b'r', *client_id),
+			Self::Sender(client_id) => (b's', *client_id),
+			Self::Welcome(client_id) => (b'w', *client_id),
+			Self::PeerConnected(client_id) => (b'c', *client_id),
+			Self::PeerDisconnected(client_id) => (b'd', *client_id),
+		}
+	}
+
+	fn from_tag(tag: u8, client_id: ClientId) -> Result<Self> {
+		match tag {
+			b'b' => Ok(Self::Broadcast),
+			b'r' => Ok(Self::Recipient(client_id)),
+			b's' => Ok(Self::Sender(client_id)),
+			b'w' => Ok(Self::Welcome(client_id)),
+			b'c' => Ok(Self::PeerConnected(client_id)),
+			b'd' => Ok(Self::PeerDisconnected(client_id)),
+			tag => anyhow::bail!("unknown relay header {tag}"),
+		}
+	}
+
+	/// The message a header received from the server is read as.
+	fn into_message(self) -> Result<Message> {
+		match self {
+			Self::Sender(client_id) => Ok(Message::Sender { client_id }),
+			Self::Welcome(client_id) => Ok(Message::Welcome { client_id }),
+			Self::PeerConnected(client_id) => {
+				Ok(Message::PeerConnected { client_id })
+			}
+			Self::PeerDisconnected(client_id) => {
+				Ok(Message::PeerDisconnected { client_id })
+			}
+			Self::Broadcast | Self::Recipient(_) => {
+				anyhow::bail!("{self:?} is only sent to the relay server")
+			}
+		}
+	}
+}
+
+/// Length of the header of a [`RelayFrame::Binary`].
+pub const RELAY_HEADER_LEN: usize = 6;
+
+/**
+A batch of messages with a [`RelayHeader`], as sent to and by the relay
+server over a websocket or UDP.
+
+Binary frames start with the header tag, the little endian [`ClientId`]
+and the [`WireFormat::tag`] of the batch. Text frames, used by
+[`WireFormat::Json`], start with a line of the tag and the decimal
+[`ClientId`], ie `s3`. The server only reads and replaces the header,
+so each client can send with any format and receives the batches of
+others in the format they were sent with.
+**/
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayFrame {
+	Binary(Vec<u8>),
+	Text(String),
+}
+
+impl RelayFrame {
+	/// Encode a batch, as a text frame for text formats.
+	pub fn encode(
+		header: RelayHeader,
+		format: WireFormat,
+		messages: &Vec<Message>,
+	) -> Result<Self> {
+		let batch = format.encode(messages)?;
+		if format.is_text() {
+			let (tag, client_id) = header.tag();
+			let batch = String::from_utf8(batch)?;
+			Ok(Self::Text(format!("{}{client_id}\n{batch}", tag as char)))
+		} else {
+			Ok(Self::binary(header, format.tag(), &batch))
+		}
+	}
+
+	/// A frame without a batch, ie a [`RelayHeader::Welcome`].
+	pub fn header_only(header: RelayHeader) -> Self {
+		Self::binary(header, WireFormat::Bincode.tag(), &[])
+	}
+
+	fn binary(header: RelayHeader, format_tag: u8, batch: &[u8]) -> Self {
+		Self::Binary(binary_bytes(header, format_tag, batch))
+	}
+
+	pub fn header(&self) -> Result<RelayHeader> {
+		match self {
+			Self::Binary(bytes) => {
+				let Some(header) = bytes.get(..RELAY_HEADER_LEN) else {
+					anyhow::bail!("relay frame is too short");
+				};
+				let client_id =
+					ClientId::from_le_bytes(header[1..5].try_into()?);
+				RelayHeader::from_tag(header[0], client_id)
+			}
+			Self::Text(text) => Ok(split_text(text)?.0),
+		}
+	}
+
+	/// The same batch with another header, used by the relay server
+	/// to replace the [`RelayHeader::Broadcast`] of a client with
+	/// its [`RelayHeader::Sender`].
+	pub fn with_header(&self, header: RelayHeader) -> Result<Self> {
+		match self {
+			Self::Binary(bytes) => {
+				self.header()?;
+				Ok(Self::binary(
+					header,
+					bytes[RELAY_HEADER_LEN - 1],
+					&bytes[RELAY_HEADER_LEN..],
+				))
+			}
+			Self::Text(text) => {
+				let (_, batch) = split_text(text)?;
+				let (tag, client_id) = header.tag();
+				Ok(Self::Text(format!("{}{client_id}\n{batch}", tag as char)))
+			}
+		}
+	}
+
+	/// The frame as bytes, for transports without text frames like UDP.
+	pub fn into_bytes(self) -> Result<Vec<u8>> {
+		match self {
+			Self::Binary(bytes) => Ok(bytes),
+			Self::Text(text) => {
+				let (header, batch) = split_text(&text)?;
+				Ok(binary_bytes(header, WireFormat::JSON_TAG, batch.as_bytes()))
+			}
+		}
+	}
+
+	/// Decode the batch with the format it was sent with. The header
+	/// is read as the first message, ie a [`Message::Sender`].
+	pub fn decode(&self) -> Result<Vec<Message>> {
+		let (header, format_tag, batch) = match self {
+			Self::Binary(bytes) => (
+				self.header()?,
+				bytes[RELAY_HEADER_LEN - 1],
+				&bytes[RELAY_HEADER_LEN..],
+			),
+			Self::Text(text) => {
+				let (header, batch) = split_text(text)?;
+				(header, WireFormat::JSON_TAG, batch.as_bytes())
+			}
+		};
+		let mut messages = vec![header.into_message()?];
+		if !batch.is_empty() {
+			messages.extend(WireFormat::from_tag(format_tag)?.decode(batch)?);
+		}
+		Ok(messages)
+	}
+}
+
+fn binary_bytes(header: RelayHeader, format_tag: u8, batch: &[u8]) -> Vec<u8> {
+	let (tag, client_id) = header.tag();
+	let mut bytes = Vec::with_capacity(RELAY_HEADER_LEN + batch.len());
+	bytes.push(tag);
+	bytes.extend(client_id.to_le_bytes());
+	bytes.push(format_tag);
+	bytes.extend_from_slice(batch);
+	bytes
+}
+
+/// Split a text frame into its header line and batch.
+fn split_text(text: &str) -> Result<(RelayHeader, &str)> {
+	let (header, batch) = text.split_once('\n').unwrap_or((text, ""));
+	let Some(tag) = header.bytes().next() else {
+		anyhow::bail!("relay frame is empty");
+	};
+	let client_id = header.get(1..).unwrap_or_default().parse::<ClientId>()?;
+	Ok((RelayHeader::from_tag(tag, client_id)?, batch))
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use sweet::prelude::*;
+
+	#[test]
+	fn works() -> Result<()> {
+		let messages = vec![Message::Spawn {
+			entity: Entity::from_raw(7),
+		}];
+		let frame =
+			RelayFrame::encode(RelayHeader::Broadcast, default(), &messages)?;
+		expect(frame.header()?).to_be(RelayHeader::Broadcast);
+		expect(frame.decode())
+			.to_be_err_str("Broadcast is only sent to the relay server");
+
+		let relayed = frame.with_header(RelayHeader::Sender(3))?;
+		expect(relayed.header()?).to_be(RelayHeader::Sender(3));
+		expect(relayed.decode()?)
+			.to_be(vec![Message::Sender { client_id: 3 }, messages[0].clone()]);
+
+		let welcome = RelayFrame::header_only(RelayHeader::Welcome(2));
+		expect(welcome.decode()?)
+			.to_be(vec![Message::Welcome { client_id: 2 }]);
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "serde_json")]
+	fn text() -> Result<()> {
+		let messages = vec![Message::Spawn {
+			entity: Entity::from_raw(7),
+		}];
+		let frame = RelayFrame::encode(
+			RelayHeader::Recipient(4),
+			WireFormat::Json,
+			&messages,
+		)?;
+		let RelayFrame::Text(text) = &frame else {
+			panic!("expected text");
+		};
+		expect(text.starts_with("r4\n[")).to_be_true();
+		expect(frame.header()?).to_be(RelayHeader::Recipient(4));
+
+		let relayed = frame.with_header(RelayHeader::Sender(1))?;
+		let expected =
+			vec![Message::Sender { client_id: 1 }, messages[0].clone()];
+		expect(relayed.decode()?).to_be(expected.clone());
+		// text frames are sent over udp with the json format tag
+		let bytes = relayed.into_bytes()?;
+		expect(RelayFrame::Binary(bytes).decode()?).to_be(expected);
+		Ok(())
+	}
+}
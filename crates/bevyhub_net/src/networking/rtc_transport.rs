@@ -0,0 +1,546 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::utils::HashMap;
+use bevy::utils::HashSet;
+use flume::Receiver;
+use flume::Sender;
+use forky::prelude::ResultTEExt;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Label of the reliable ordered data channel.
+pub const RTC_RELIABLE_LABEL: &str = "bevyhub_reliable";
+/// Label of the unreliable unordered data channel.
+pub const RTC_UNRELIABLE_LABEL: &str = "bevyhub_unreliable";
+/// Maximum batches held for a peer until its [`RtcSignal::ChannelOpen`]
+/// arrives, further batches are dropped.
+pub const MAX_HELD_BATCHES: usize = 1024;
+
+/// WebRTC signaling between two peers, relayed by the signaling
+/// server as a [`Message::Signal`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RtcSignal {
+	/// The session description of the peer creating the data channels.
+	Offer {
+		sdp: String,
+	},
+	Answer {
+		sdp: String,
+	},
+	IceCandidate {
+		candidate: String,
+		sdp_mid: Option<String>,
+		sdp_m_line_index: Option<u16>,
+	},
+	/// Relayed after the last message sent through the signaling transport
+	/// once the data channel is open. Batches from the data channel are held
+	/// until it arrives so reliable messages stay in order.
+	ChannelOpen,
+}
+
+/// Signals for a peer, sent by an [`RtcPeer`] to the [`RtcTransport`].
+pub type RtcSignalSender = Sender<(ClientId, RtcSignal)>;
+/// Batches received by an [`RtcPeer`] on either data channel.
+pub type RtcDataSender = Sender<(ClientId, Vec<u8>)>;
+
+/// Creates peer connections for an [`RtcTransport`],
+/// ie with `web-sys` or the `webrtc` crate.
+pub trait RtcBackend {
+	type Peer: RtcPeer;
+	/// Start connecting to a peer. The offering peer creates the data
+	/// channels and sends an [`RtcSignal::Offer`] through `signals`.
+	fn connect(
+		&mut self,
+		client_id: ClientId,
+		offer: bool,
+		signals: RtcSignalSender,
+		data: RtcDataSender,
+	) -> Result<Self::Peer>;
+}
+
+/// A connection to a single peer, closed when dropped.
+pub trait RtcPeer {
+	/// Handle a signal sent by the remote peer.
+	fn signal(&mut self, signal: RtcSignal) -> Result<()>;
+	/// Whether the reliable data channel is open.
+	fn is_open(&self) -> bool;
+	/// Send a batch on the reliable or unreliable data channel.
+	fn send(&mut self, reliable: bool, bytes: Vec<u8>) -> Result<()>;
+}
+
+/**
+A peer-to-peer [`Transport`] over WebRTC data channels, using another
+transport connected to the relay server for signaling only.
+
+When a peer connects the existing peers send it an offer, so every peer
+is connected to every other. Messages on a reliable [`Channel`] are sent on
+an ordered reliable data channel, and on other channels, ie changes registered
+with [`Channel::UNRELIABLE`], on an unordered channel without retransmits.
+Batches on a [`ChannelKind::Sequenced`] channel arriving after a newer one
+are discarded.
+
+Until the data channel to every lobby member is open, broadcasts are also
+relayed by the signaling transport, and relayed copies from peers with an open
+data channel are dropped. Changes registered without a channel are sent on
+[`Channel::SEQUENCED`], see [`Transport::change_channel`].
+**/
+pub struct RtcTransport<S, B: RtcBackend> {
+	signaling: S,
+	backend: B,
+	peers: HashMap<ClientId, B::Peer>,
+	/// Peers in the lobby, whether or not a connection was created.
+	members: HashSet<ClientId>,
+	/// Peers that were sent a [`RtcSignal::ChannelOpen`].
+	switched: HashSet<ClientId>,
+	/// Batches from the data channel of peers that have not
+	/// sent a [`RtcSignal::ChannelOpen`] yet.
+	held: HashMap<ClientId, Vec<Vec<u8>>>,
+	/// Peers that sent a [`RtcSignal::ChannelOpen`].
+	opened: HashSet<ClientId>,
+	next_sequence: HashMap<ChannelId, u64>,
+	last_sequence: HashMap<(ClientId, ChannelId), u64>,
+	signal_send: RtcSignalSender,
+	signal_recv: Receiver<(ClientId, RtcSignal)>,
+	data_send: RtcDataSender,
+	data_recv: Receiver<(ClientId, Vec<u8>)>,
+}
+
+impl<S: Transport, B: RtcBackend> RtcTransport<S, B> {
+	pub fn new(signaling: S, backend: B) -> Self {
+		let (signal_send, signal_recv) = flume::unbounded();
+		let (data_send, data_recv) = flume::unbounded();
+		Self {
+			signaling,
+			backend,
+			peers: HashMap::default(),
+			members: HashSet::default(),
+			switched: HashSet::default(),
+			held: HashMap::default(),
+			opened: HashSet::default(),
+			next_sequence: HashMap::default(),
+			last_sequence: HashMap::default(),
+			signal_send,
+			signal_recv,
+			data_send,
+			data_recv,
+		}
+	}
+
+	/// Whether the data channel to a peer is open.
+	pub fn is_open(&self, client_id: ClientId) -> bool {
+		self.peers
+			.get(&client_id)
+			.is_some_and(|peer| peer.is_open())
+	}
+
+	pub fn signaling(&self) -> &S {
+		&self.signaling
+	}
+
+	fn peer(
+		&mut self,
+		client_id: ClientId,
+		offer: bool,
+	) -> Result<&mut B::Peer> {
+		if !self.peers.contains_key(&client_id) {
+			let peer = self.backend.connect(
+				client_id,
+				offer,
+				self.signal_send.clone(),
+				self.data_send.clone(),
+			)?;
+			self.peers.insert(client_id, peer);
+		}
+		Ok(self.peers.get_mut(&client_id).unwrap())
+	}
+
+	/// The open peer, relaying a [`RtcSignal::ChannelOpen`]
+	/// before the first batch sent on its data channel.
+	fn open_peer(
+		&mut self,
+		client_id: ClientId,
+	) -> Result<Option<&mut B::Peer>> {
+		if !self.is_open(client_id) {
+			return Ok(None);
+		}
+		if self.switched.insert(client_id) {
+			self.signaling.send_to(client_id, &[Message::Signal {
+				signal: RtcSignal::ChannelOpen,
+			}])?;
+		}
+		Ok(self.peers.get_mut(&client_id))
+	}
+
+	/// Handle signaling messages, returning all others.
+	fn recv_signaling(&mut self) -> Result<Vec<Message>> {
+		let mut messages = Vec::new();
+		let mut sender = DIRECT_CLIENT_ID;
+		for message in self.signaling.recv()? {
+			match message {
+				Message::Signal {
+					signal: RtcSignal::ChannelOpen,
+				} => {
+					self.opened.insert(sender);
+					let held = self.held.remove(&sender).unwrap_or_default();
+					for bytes in held {
+						self.push_data(&mut messages, sender, &bytes);
+					}
+				}
+				Message::Signal { signal } => {
+					self.peer(sender, false)
+						.and_then(|peer| peer.signal(signal))
+						.ok_or(|e| log::error!("signal from {sender}: {e}"));
+				}
+				Message::Sender { client_id } => {
+					sender = client_id;
+					self.members.insert(client_id);
+					messages.push(message);
+				}
+				Message::PeerConnected { client_id } => {
+					self.members.insert(client_id);
+					self.peer(client_id, true).ok_or(|e| log::error!("{e}"));
+					messages.push(message);
+				}
+				Message::PeerDisconnected { client_id } => {
+					self.members.remove(&client_id);
+					self.peers.remove(&client_id);
+					self.switched.remove(&client_id);
+					self.held.remove(&client_id);
+					self.opened.remove(&client_id);
+					self.last_sequence.retain(|(id, _), _| *id != client_id);
+					messages.push(message);
+				}
+				Message::Welcome { .. } => {
+					messages.push(message);
+				}
+				// also received on the data channel
+				_ if self.opened.contains(&sender) => {}
+				message => {
+					messages.push(message);
+				}
+			}
+		}
+		Ok(messages)
+	}
+
+	fn push_data(
+		&mut self,
+		messages: &mut Vec<Message>,
+		client_id: ClientId,
+		bytes: &[u8],
+	) {
+		let Some((channel, sequence, bytes)) = unframe_batch(bytes)
+			.ok_or(|e| log::error!("data from {client_id}: {e}"))
+		else {
+			return;
+		};
+		if sequence != 0 {
+			let last =
+				self.last_sequence.entry((client_id, channel)).or_default();
+			if sequence <= *last {
+				return;
+			}
+			*last = sequence;
+		}
+		if let Some(batch) = Message::vec_from_bytes(bytes)
+			.ok_or(|e| log::error!("data from {client_id}: {e}"))
+		{
+			messages.push(Message::Sender { client_id });
+			messages.extend(batch);
+		}
+	}
+
+	fn send_signals(&mut self) -> Result<()> {
+		for (client_id, signal) in self.signal_recv.try_iter() {
+			self.signaling
+				.send_to(client_id, &[Message::Signal { signal }])?;
+		}
+		Ok(())
+	}
+}
+
+impl<S: Transport, B: RtcBackend> Transport for RtcTransport<S, B> {
+	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
+		self.send_channel(Channel::RELIABLE, messages)
+	}
+
+	fn send_channel(
+		&mut self,
+		channel: Channel,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		let sequence = if channel.kind == ChannelKind::Sequenced {
+			let next = self.next_sequence.entry(channel.id).or_default();
+			*next += 1;
+			*next
+		} else {
+			0
+		};
+		let bytes = Message::vec_into_bytes(messages)?;
+		let bytes = frame_batch(channel.id, sequence, bytes);
+		let reliable = channel.kind.is_reliable();
+		// members we have not heard from yet are reached by the relay
+		let mut relay = self.members.is_empty();
+		for client_id in self.members.clone() {
+			match self.open_peer(client_id)? {
+				Some(peer) => peer.send(reliable, bytes.clone())?,
+				None => relay = true,
+			}
+		}
+		if relay {
+			self.signaling.send(messages)?;
+		}
+		Ok(())
+	}
+
+	fn send_to(
+		&mut self,
+		client_id: ClientId,
+		messages: &[Message],
+	) -> Result<()> {
+		match self.open_peer(client_id)? {
+			Some(peer) => peer.send(
+				true,
+				frame_batch(
+					Channel::RELIABLE.id,
+					0,
+					Message::vec_into_bytes(&messages.to_vec())?,
+				),
+			),
+			None => self.signaling.send_to(client_id, messages),
+		}
+	}
+
+	fn change_channel(&self) -> Channel { Channel::SEQUENCED }
+
+	fn recv(&mut self) -> Result<Vec<Message>> {
+		let mut messages = self.recv_signaling()?;
+		self.send_signals()?;
+		let data = self.data_recv.try_iter().collect::<Vec<_>>();
+		for (client_id, bytes) in data {
+			if self.opened.contains(&client_id) {
+				self.push_data(&mut messages, client_id, &bytes);
+				continue;
+			}
+			let held = self.held.entry(client_id).or_default();
+			if held.len() < MAX_HELD_BATCHES {
+				held.push(bytes);
+			} else {
+				log::warn!(
+					"client {client_id} has not sent a channel open signal, dropping batch"
+				);
+			}
+		}
+		Ok(messages)
+	}
+}
+
+/// Prefix a batch with its [`ChannelId`] and sequence number,
+/// which is zero unless the channel is [`ChannelKind::Sequenced`].
+fn frame_batch(channel: ChannelId, sequence: u64, bytes: Vec<u8>) -> Vec<u8> {
+	let mut framed = Vec::with_capacity(12 + bytes.len());
+	framed.extend(channel.to_le_bytes());
+	framed.extend(sequence.to_le_bytes());
+	framed.extend(bytes);
+	framed
+}
+
+fn unframe_batch(bytes: &[u8]) -> Result<(ChannelId, u64, &[u8])> {
+	if bytes.len() < 12 {
+		anyhow::bail!("batch of {} bytes has no header", bytes.len());
+	}
+	let channel = ChannelId::from_le_bytes(bytes[0..4].try_into()?);
+	let sequence = u64::from_le_bytes(bytes[4..12].try_into()?);
+	Ok((channel, sequence, &bytes[12..]))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use bevy::prelude::*;
+	use sweet::prelude::*;
+
+	#[derive(Default)]
+	struct MockRelay {
+		incoming: Vec<Message>,
+		/// Batches sent to a single peer or broadcast.
+		sent: Vec<(Option<ClientId>, Vec<Message>)>,
+	}
+
+	impl Transport for MockRelay {
+		fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
+			self.sent.push((None, messages.clone()));
+			Ok(())
+		}
+		fn send_to(
+			&mut self,
+			client_id: ClientId,
+			messages: &[Message],
+		) -> Result<()> {
+			self.sent.push((Some(client_id), messages.to_vec()));
+			Ok(())
+		}
+		fn recv(&mut self) -> Result<Vec<Message>> {
+			Ok(std::mem::take(&mut self.incoming))
+		}
+	}
+
+	#[derive(Default)]
+	struct MockPeer {
+		open: bool,
+		sent: Vec<(bool, Vec<u8>)>,
+	}
+
+	impl RtcPeer for MockPeer {
+		fn signal(&mut self, _signal: RtcSignal) -> Result<()> { Ok(()) }
+		fn is_open(&self) -> bool { self.open }
+		fn send(&mut self, reliable: bool, bytes: Vec<u8>) -> Result<()> {
+			self.sent.push((reliable, bytes));
+			Ok(())
+		}
+	}
+
+	struct MockBackend;
+
+	impl RtcBackend for MockBackend {
+		type Peer = MockPeer;
+		fn connect(
+			&mut self,
+			_client_id: ClientId,
+			_offer: bool,
+			_signals: RtcSignalSender,
+			_data: RtcDataSender,
+		) -> Result<MockPeer> {
+			Ok(MockPeer::default())
+		}
+	}
+
+	fn spawn(index: u32) -> Vec<Message> {
+		vec![Message::Spawn {
+			entity: Entity::from_raw(index),
+		}]
+	}
+
+	fn framed(sequence: u64, messages: &Vec<Message>) -> Result<Vec<u8>> {
+		let channel = if sequence == 0 {
+			Channel::RELIABLE
+		} else {
+			Channel::SEQUENCED
+		};
+		Ok(frame_batch(
+			channel.id,
+			sequence,
+			Message::vec_into_bytes(messages)?,
+		))
+	}
+
+	fn channel_open() -> Message {
+		Message::Signal {
+			signal: RtcSignal::ChannelOpen,
+		}
+	}
+
+	#[test]
+	fn relays_to_members() -> Result<()> {
+		let mut transport =
+			RtcTransport::new(MockRelay::default(), MockBackend);
+		expect(transport.change_channel()).to_be(Channel::SEQUENCED);
+
+		transport.signaling.incoming = vec![
+			Message::PeerConnected { client_id: 2 },
+			Message::PeerConnected { client_id: 3 },
+		];
+		transport.recv()?;
+		transport.peers.get_mut(&2).unwrap().open = true;
+		transport.send_channel(Channel::UNRELIABLE, &spawn(0))?;
+
+		let peer = transport.peers.get(&2).unwrap();
+		let bytes = frame_batch(
+			Channel::UNRELIABLE.id,
+			0,
+			Message::vec_into_bytes(&spawn(0))?,
+		);
+		expect(&peer.sent).to_be(&vec![(false, bytes)]);
+		// the channel open marker is relayed before the data channel is
+		// used, and peer 3 is still relayed to
+		expect(&transport.signaling.sent).to_be(&vec![
+			(Some(2), vec![channel_open()]),
+			(None, spawn(0)),
+		]);
+
+		transport.peers.get_mut(&3).unwrap().open = true;
+		transport.signaling.sent.clear();
+		transport.send(&spawn(1))?;
+		expect(&transport.signaling.sent)
+			.to_be(&vec![(Some(3), vec![channel_open()])]);
+		Ok(())
+	}
+
+	#[test]
+	fn holds_until_channel_open() -> Result<()> {
+		let mut transport =
+			RtcTransport::new(MockRelay::default(), MockBackend);
+		transport.data_send.send((2, framed(0, &spawn(1))?))?;
+		transport.signaling.incoming =
+			vec![Message::Sender { client_id: 2 }, spawn(0)[0].clone()];
+		let sender = Message::Sender { client_id: 2 };
+		expect(transport.recv()?)
+			.to_be(vec![sender.clone(), spawn(0)[0].clone()]);
+
+		// relayed copies of batches on the data channel are dropped
+		transport.signaling.incoming =
+			vec![sender.clone(), channel_open(), spawn(1)[0].clone()];
+		expect(transport.recv()?)
+			.to_be(vec![sender.clone(), sender.clone(), spawn(1)[0].clone()]);
+
+		transport.data_send.send((2, framed(0, &spawn(2))?))?;
+		expect(transport.recv()?).to_be(vec![sender, spawn(2)[0].clone()]);
+		Ok(())
+	}
+
+	#[test]
+	fn held_limit() -> Result<()> {
+		let mut transport =
+			RtcTransport::new(MockRelay::default(), MockBackend);
+		for _ in 0..MAX_HELD_BATCHES + 1 {
+			transport.data_send.send((2, framed(0, &spawn(0))?))?;
+		}
+		transport.recv()?;
+		expect(transport.held[&2].len()).to_be(MAX_HELD_BATCHES);
+		Ok(())
+	}
+
+	#[test]
+	fn sequenced() -> Result<()> {
+		let mut transport =
+			RtcTransport::new(MockRelay::default(), MockBackend);
+		transport.signaling.incoming =
+			vec![Message::Sender { client_id: 2 }, channel_open()];
+		transport.recv()?;
+
+		transport.data_send.send((2, framed(2, &spawn(2))?))?;
+		transport.data_send.send((2, framed(1, &spawn(1))?))?;
+		// reliable batches are not sequenced
+		transport.data_send.send((2, framed(0, &spawn(0))?))?;
+		let sender = Message::Sender { client_id: 2 };
+		expect(transport.recv()?).to_be(vec![
+			sender.clone(),
+			spawn(2)[0].clone(),
+			sender,
+			spawn(0)[0].clone(),
+		]);
+
+		// the sequence is per channel
+		transport.peers.insert(2, MockPeer {
+			open: true,
+			..default()
+		});
+		transport.members.insert(2);
+		transport.send_channel(Channel::SEQUENCED, &spawn(3))?;
+		transport.send_channel(Channel::SEQUENCED, &spawn(4))?;
+		let sent = &transport.peers[&2].sent;
+		expect(&sent[0]).to_be(&(false, framed(1, &spawn(3))?));
+		expect(&sent[1]).to_be(&(false, framed(2, &spawn(4))?));
+		Ok(())
+	}
+}
@@ -0,0 +1,341 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::utils::HashMap;
+use bevy::utils::Instant;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use std::time::Duration;
+
+/// Network conditions applied by a [`SimulatedTransport`].
+/// Loss, duplication and reordering only apply to unreliable channels.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct NetworkConditions {
+	/// Time before a packet is passed to the inner transport.
+	pub latency: Duration,
+	/// Maximum random amount added to or subtracted from the latency.
+	pub jitter: Duration,
+	/// Chance from 0 to 1 that an unreliable packet is dropped.
+	pub packet_loss: f64,
+	/// Chance from 0 to 1 that an unreliable packet is sent twice.
+	pub duplication: f64,
+	/// Chance from 0 to 1 that an unreliable packet is delayed by an
+	/// extra `latency + jitter`, so it arrives after later packets.
+	pub reorder: f64,
+}
+
+impl NetworkConditions {
+	pub fn with_latency(mut self, latency: Duration) -> Self {
+		self.latency = latency;
+		self
+	}
+	pub fn with_jitter(mut self, jitter: Duration) -> Self {
+		self.jitter = jitter;
+		self
+	}
+	pub fn with_packet_loss(mut self, packet_loss: f64) -> Self {
+		self.packet_loss = packet_loss.clamp(0., 1.);
+		self
+	}
+	pub fn with_duplication(mut self, duplication: f64) -> Self {
+		self.duplication = duplication.clamp(0., 1.);
+		self
+	}
+	pub fn with_reorder(mut self, reorder: f64) -> Self {
+		self.reorder = reorder.clamp(0., 1.);
+		self
+	}
+}
+
+enum Clock {
+	Real(Instant),
+	Manual(Duration),
+}
+
+impl Clock {
+	fn now(&self) -> Duration {
+		match self {
+			Clock::Real(start) => start.elapsed(),
+			Clock::Manual(elapsed) => *elapsed,
+		}
+	}
+}
+
+struct InFlight {
+	deliver_at: Duration,
+	channel: Channel,
+	/// Index in the order packets were sent on the channel,
+	/// used to discard stale sequenced packets.
+	index: u64,
+	messages: Vec<Message>,
+}
+
+/// Wraps a [`Transport`], delaying outgoing packets and simulating
+/// loss, duplication and reordering with a seeded rng.
+/// Reliable packets are delayed but always arrive in order.
+///
+/// Packets are passed to the inner transport on the next call to
+/// [`Transport::send`] or [`Transport::recv`] after they are due.
+pub struct SimulatedTransport<T: Transport> {
+	pub inner: T,
+	pub conditions: NetworkConditions,
+	rng: StdRng,
+	clock: Clock,
+	in_flight: Vec<InFlight>,
+	next_index: HashMap<ChannelId, u64>,
+	last_index: HashMap<ChannelId, u64>,
+	last_reliable: Duration,
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+	pub fn new(inner: T, conditions: NetworkConditions) -> Self {
+		Self {
+			inner,
+			conditions,
+			rng: StdRng::seed_from_u64(0),
+			clock: Clock::Real(Instant::now()),
+			in_flight: Vec::new(),
+			next_index: HashMap::default(),
+			last_index: HashMap::default(),
+			last_reliable: Duration::ZERO,
+		}
+	}
+
+	pub fn with_seed(mut self, seed: u64) -> Self {
+		self.rng = StdRng::seed_from_u64(seed);
+		self
+	}
+
+	/// Only advance time with [`SimulatedTransport::advance`],
+	/// for deterministic tests.
+	pub fn with_manual_clock(mut self) -> Self {
+		self.clock = Clock::Manual(self.clock.now());
+		self
+	}
+
+	/// Advance a manual clock, ignored for a real clock.
+	pub fn advance(&mut self, delta: Duration) {
+		if let Clock::Manual(elapsed) = &mut self.clock {
+			*elapsed += delta;
+		}
+	}
+
+	/// Number of packets not yet passed to the inner transport.
+	pub fn num_in_flight(&self) -> usize { self.in_flight.len() }
+
+	fn delay(&mut self) -> Duration {
+		let jitter = self.conditions.jitter.as_secs_f64();
+		let jitter = if jitter > 0. {
+			self.rng.gen_range(-jitter..=jitter)
+		} else {
+			0.
+		};
+		let latency = self.conditions.latency.as_secs_f64() + jitter;
+		Duration::from_secs_f64(latency.max(0.))
+	}
+
+	fn unreliable_delay(&mut self) -> Duration {
+		let delay = self.delay();
+		if self.rng.gen_bool(self.conditions.reorder) {
+			let extra = self.conditions.latency + self.conditions.jitter;
+			delay + extra.max(Duration::from_millis(1))
+		} else {
+			delay
+		}
+	}
+
+	/// Pass due packets to the inner transport.
+	pub fn flush(&mut self) -> Result<()> {
+		let now = self.clock.now();
+		// stable sort keeps reliable packets in order
+		self.in_flight.sort_by_key(|packet| packet.deliver_at);
+		let num_due = self
+			.in_flight
+			.iter()
+			.take_while(|packet| packet.deliver_at <= now)
+			.count();
+		for packet in self.in_flight.drain(..num_due).collect::<Vec<_>>() {
+			if packet.channel.kind == ChannelKind::Sequenced {
+				let last = self.last_index.get(&packet.channel.id);
+				if last.is_some_and(|last| packet.index <= *last) {
+					continue;
+				}
+				self.last_index.insert(packet.channel.id, packet.index);
+			}
+			self.inner.send_channel(packet.channel, &packet.messages)?;
+		}
+		Ok(())
+	}
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
+		self.send_channel(Channel::RELIABLE, messages)
+	}
+
+	fn send_channel(
+		&mut self,
+		channel: Channel,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		let index = self.next_index.entry(channel.id).or_default();
+		let packet = InFlight {
+			deliver_at: Duration::ZERO,
+			channel,
+			index: *index,
+			messages: messages.clone(),
+		};
+		*index += 1;
+		let now = self.clock.now();
+
+		if channel.kind.is_reliable() {
+			let deliver_at = (now + self.delay()).max(self.last_reliable);
+			self.last_reliable = deliver_at;
+			self.in_flight.push(InFlight {
+				deliver_at,
+				..packet
+			});
+		} else if self.rng.gen_bool(self.conditions.packet_loss) {
+			// dropped
+		} else {
+			if self.rng.gen_bool(self.conditions.duplication) {
+				let deliver_at = now + self.unreliable_delay();
+				self.in_flight.push(InFlight {
+					deliver_at,
+					channel,
+					index: packet.index,
+					messages: packet.messages.clone(),
+				});
+			}
+			let deliver_at = now + self.unreliable_delay();
+			self.in_flight.push(InFlight {
+				deliver_at,
+				..packet
+			});
+		}
+		self.flush()
+	}
+
+	fn change_channel(&self) -> Channel { self.inner.change_channel() }
+
+	fn recv(&mut self) -> Result<Vec<Message>> {
+		self.flush()?;
+		self.inner.recv()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use std::time::Duration;
+	use sweet::prelude::*;
+
+	fn spawn(index: u32) -> Vec<Message> {
+		vec![Message::Spawn {
+			entity: Entity::from_raw(index),
+		}]
+	}
+
+	fn indices(messages: &[Message]) -> Vec<u32> {
+		messages
+			.iter()
+			.map(|message| match message {
+				Message::Spawn { entity } => entity.index(),
+				_ => unreachable!(),
+			})
+			.collect()
+	}
+
+	fn pair(
+		conditions: NetworkConditions,
+	) -> (SimulatedTransport<ChannelsTransport>, ChannelsTransport) {
+		let (a, b) = ChannelsTransport::pair();
+		let a = SimulatedTransport::new(a, conditions)
+			.with_seed(1)
+			.with_manual_clock();
+		(a, b)
+	}
+
+	#[test]
+	fn latency() -> Result<()> {
+		let (mut a, mut b) = pair(
+			NetworkConditions::default()
+				.with_latency(Duration::from_millis(100))
+				.with_jitter(Duration::from_millis(50)),
+		);
+		for i in 0..10 {
+			a.send(&spawn(i))?;
+			a.send_channel(Channel::UNRELIABLE, &spawn(i))?;
+		}
+		expect(b.recv()?).to_be(vec![]);
+		expect(a.num_in_flight()).to_be(20);
+		a.advance(Duration::from_millis(150));
+		a.flush()?;
+		expect(a.num_in_flight()).to_be(0);
+		let received = b.recv()?;
+		expect(received.len()).to_be(20);
+		Ok(())
+	}
+
+	#[test]
+	fn reliable_order() -> Result<()> {
+		let (mut a, mut b) = pair(
+			NetworkConditions::default()
+				.with_latency(Duration::from_millis(100))
+				.with_jitter(Duration::from_millis(100))
+				.with_packet_loss(0.5)
+				.with_duplication(0.5)
+				.with_reorder(0.5),
+		);
+		for i in 0..100 {
+			a.send(&spawn(i))?;
+			a.advance(Duration::from_millis(10));
+		}
+		a.advance(Duration::from_secs(1));
+		a.flush()?;
+		expect(indices(&b.recv()?)).to_be((0..100).collect::<Vec<_>>());
+		Ok(())
+	}
+
+	#[test]
+	fn unreliable() -> Result<()> {
+		let conditions = NetworkConditions::default()
+			.with_jitter(Duration::from_millis(100))
+			.with_packet_loss(0.2)
+			.with_duplication(0.2)
+			.with_reorder(0.2);
+
+		let run = |channel: Channel| -> Result<Vec<u32>> {
+			let (mut a, mut b) = pair(conditions.clone());
+			for i in 0..100 {
+				a.send_channel(channel, &spawn(i))?;
+				a.advance(Duration::from_millis(10));
+			}
+			a.advance(Duration::from_secs(1));
+			a.flush()?;
+			Ok(indices(&b.recv()?))
+		};
+
+		let unreliable = run(Channel::UNRELIABLE)?;
+		expect(unreliable.is_sorted()).to_be_false();
+		let mut deduped = unreliable.clone();
+		deduped.sort();
+		deduped.dedup();
+		// some were dropped and some duplicated
+		expect(deduped.len()).to_be_less_than(100);
+		expect(deduped.len()).to_be_less_than(unreliable.len());
+
+		let sequenced = run(Channel::SEQUENCED)?;
+		expect(sequenced.len()).to_be_less_than(100);
+		expect(sequenced.is_sorted()).to_be_true();
+		let mut deduped = sequenced.clone();
+		deduped.dedup();
+		expect(deduped).to_be(sequenced);
+
+		// same seed, same result
+		expect(run(Channel::UNRELIABLE)?).to_be(unreliable);
+		Ok(())
+	}
+}
@@ -1,29 +1,101 @@
-use anyhow::Result;
 use crate::prelude::*;
+use anyhow::Result;
+use bevy::prelude::Event;
+use bevy::utils::HashMap;
 use flume::Receiver;
 use flume::Sender;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
 
 pub trait Transport {
 	fn send(&mut self, messages: &Vec<Message>) -> Result<()>;
 	fn recv(&mut self) -> Result<Vec<Message>>;
+	/// Send messages with the delivery guarantees of the channel,
+	/// by default every channel is sent reliably with [`Transport::send`].
+	fn send_channel(
+		&mut self,
+		channel: Channel,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		let _ = channel;
+		self.send(messages)
+	}
+	/// Send messages to a single peer. By default they are prefixed with
+	/// a [`Message::Recipient`] for an in-process relay to route,
+	/// clients of the relay server send a [`RelayHeader::Recipient`].
+	fn send_to(
+		&mut self,
+		client_id: ClientId,
+		messages: &[Message],
+	) -> Result<()> {
+		let mut batch = Vec::with_capacity(messages.len() + 1);
+		batch.push(Message::Recipient { client_id });
+		batch.extend(messages.iter().cloned());
+		self.send(&batch)
+	}
+	/// The channel of changes registered without [`App::replicate_channel`].
+	fn change_channel(&self) -> Channel { Channel::RELIABLE }
+}
+
+/// Connection state changes of a transport that connects to a remote
+/// peer, ie the `NativeWsClient`.
+#[derive(Debug, Clone, PartialEq, Eq, Event)]
+pub enum ConnectionEvent {
+	/// A connection attempt has started.
+	Connecting,
+	/// The transport is connected and sending messages.
+	Connected,
+	/// The connection was lost or the attempt failed,
+	/// messages are buffered until the next connection.
+	Disconnected { reason: String },
+}
+
+/// A batch of messages sent on a channel, with a per channel sequence number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelPacket {
+	pub channel: Channel,
+	pub sequence: u64,
+	pub messages: Vec<Message>,
 }
 
+/// An in-process transport, useful for testing.
+/// Packets on unreliable channels can be dropped or reordered
+/// using a seeded rng, reliable packets always arrive in order.
 pub struct ChannelsTransport {
-	pub send: Sender<Vec<Message>>,
-	pub recv: Receiver<Vec<Message>>,
+	pub send: Sender<ChannelPacket>,
+	pub recv: Receiver<ChannelPacket>,
+	/// Chance from 0 to 1 that an unreliable packet is dropped.
+	pub packet_loss: f64,
+	/// Chance from 0 to 1 that an unreliable packet is held back
+	/// until after the next packet is sent.
+	pub reorder: f64,
+	rng: StdRng,
+	held: Option<ChannelPacket>,
+	next_sequence: HashMap<ChannelId, u64>,
+	last_sequence: HashMap<ChannelId, u64>,
 }
 
 impl ChannelsTransport {
 	pub fn loopback() -> Self {
 		let (send, recv) = flume::unbounded();
-		Self { send, recv }
+		Self::new(send, recv)
 	}
 
 	pub fn new(
-		send: Sender<Vec<Message>>,
-		recv: Receiver<Vec<Message>>,
+		send: Sender<ChannelPacket>,
+		recv: Receiver<ChannelPacket>,
 	) -> Self {
-		Self { send, recv }
+		Self {
+			send,
+			recv,
+			packet_loss: 0.,
+			reorder: 0.,
+			rng: StdRng::seed_from_u64(0),
+			held: None,
+			next_sequence: HashMap::default(),
+			last_sequence: HashMap::default(),
+		}
 	}
 
 	pub fn pair() -> (Self, Self) {
@@ -31,20 +103,74 @@ impl ChannelsTransport {
 		let (send2, recv2) = flume::unbounded();
 		(Self::new(send1, recv2), Self::new(send2, recv1))
 	}
+
+	pub fn with_packet_loss(mut self, packet_loss: f64) -> Self {
+		self.packet_loss = packet_loss.clamp(0., 1.);
+		self
+	}
+
+	pub fn with_reorder(mut self, reorder: f64) -> Self {
+		self.reorder = reorder.clamp(0., 1.);
+		self
+	}
+
+	pub fn with_seed(mut self, seed: u64) -> Self {
+		self.rng = StdRng::seed_from_u64(seed);
+		self
+	}
 }
 
 impl Transport for ChannelsTransport {
 	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
-		self.send.send(messages.clone())?;
+		self.send_channel(Channel::RELIABLE, messages)
+	}
+
+	fn send_channel(
+		&mut self,
+		channel: Channel,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		let sequence = self.next_sequence.entry(channel.id).or_default();
+		let packet = ChannelPacket {
+			channel,
+			sequence: *sequence,
+			messages: messages.clone(),
+		};
+		*sequence += 1;
+
+		if channel.kind.is_reliable() {
+			self.send.send(packet)?;
+		} else if self.rng.gen_bool(self.packet_loss) {
+			// dropped
+		} else if self.held.is_none() && self.rng.gen_bool(self.reorder) {
+			self.held = Some(packet);
+			return Ok(());
+		} else {
+			self.send.send(packet)?;
+		}
+		if let Some(held) = self.held.take() {
+			self.send.send(held)?;
+		}
 		Ok(())
 	}
 
 	fn recv(&mut self) -> Result<Vec<Message>> {
-		self.recv.try_recv_all_flat()
+		let mut messages = Vec::new();
+		for packet in self.recv.try_recv_all()? {
+			if packet.channel.kind == ChannelKind::Sequenced {
+				let last = self.last_sequence.get(&packet.channel.id);
+				if last.is_some_and(|last| packet.sequence <= *last) {
+					continue;
+				}
+				self.last_sequence
+					.insert(packet.channel.id, packet.sequence);
+			}
+			messages.extend(packet.messages);
+		}
+		Ok(messages)
 	}
 }
 
-
 #[cfg(test)]
 #[cfg(not(target_arch = "wasm32"))]
 mod test {
@@ -67,4 +193,66 @@ mod test {
 
 		Ok(())
 	}
+
+	fn spawn(index: u32) -> Vec<Message> {
+		vec![Message::Spawn {
+			entity: Entity::from_raw(index),
+		}]
+	}
+
+	#[test]
+	fn packet_loss() -> Result<()> {
+		let (a, mut b) = ChannelsTransport::pair();
+		let mut a = a.with_packet_loss(0.5).with_seed(1);
+		for i in 0..100 {
+			a.send_channel(Channel::UNRELIABLE, &spawn(i))?;
+			a.send_channel(Channel::RELIABLE, &spawn(i))?;
+		}
+		let received = b.recv()?;
+		expect(received.len()).to_be_greater_than(100);
+		expect(received.len()).to_be_less_than(200);
+
+		// reliable messages are all received in order
+		let mut a = a.with_packet_loss(1.);
+		for i in 0..100 {
+			a.send_channel(Channel::UNRELIABLE, &spawn(i))?;
+			a.send(&spawn(i))?;
+		}
+		expect(b.recv()?).to_be((0..100).flat_map(spawn).collect::<Vec<_>>());
+		Ok(())
+	}
+
+	#[test]
+	fn sequenced() -> Result<()> {
+		let (a, mut b) = ChannelsTransport::pair();
+		let mut a = a.with_reorder(0.5).with_seed(1);
+		for i in 0..100 {
+			a.send_channel(Channel::UNRELIABLE, &spawn(i))?;
+		}
+		// release any held packet
+		a.send(&vec![])?;
+		let received = b.recv()?;
+		expect(received.len()).to_be(100);
+		expect(received)
+			.not()
+			.to_be((0..100).flat_map(spawn).collect::<Vec<_>>());
+
+		let (a, mut b) = ChannelsTransport::pair();
+		let mut a = a.with_reorder(0.5).with_seed(1);
+		for i in 0..100 {
+			a.send_channel(Channel::SEQUENCED, &spawn(i))?;
+		}
+		let received = b.recv()?;
+		expect(received.len()).to_be_less_than(100);
+		// older messages are discarded
+		let indices = received
+			.iter()
+			.map(|message| match message {
+				Message::Spawn { entity } => entity.index(),
+				_ => unreachable!(),
+			})
+			.collect::<Vec<_>>();
+		expect(indices.is_sorted()).to_be_true();
+		Ok(())
+	}
 }
@@ -0,0 +1,463 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy::utils::HashSet;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// Totals for every transport, updated by [`transport_incoming`]
+/// and [`transport_outgoing`]. Byte counts are estimated with
+/// [`Message::num_bytes`].
+#[derive(Debug, Default, Clone, PartialEq, Resource)]
+pub struct TransportStats {
+	pub messages_sent: u64,
+	pub messages_received: u64,
+	pub bytes_sent: u64,
+	/// Bytes sent in the last tick of a transport.
+	pub bytes_sent_last_tick: usize,
+	/// Messages carried over to the next tick by a [`TransportBudget`].
+	pub messages_deferred: usize,
+	/// Deferred changes replaced by a newer change before being sent.
+	pub messages_superseded: u64,
+}
+
+/// Identifies a carried over change, so that a newer change
+/// to the same component or resource replaces it.
+type ChangeKey = (Option<(Entity, EntityOrigin)>, RegistrationId);
+
+/**
+Limits the bytes per second sent by [`transport_outgoing`] for the transport `T`,
+see [`App::transport_budget`].
+
+Messages over budget are carried over to the next tick:
+- Changes are sent in order of [`ReplicateRegistry::priority`], plus the
+  priority accumulated by every tick they were deferred, so that low priority
+  changes are eventually sent. A deferred change is replaced by a newer change
+  to the same component or resource, which also drops the deferred deltas
+  to it.
+- All other messages, including deltas which depend on the previous value, are
+  sent in order, before any change. A delta to a component with a deferred
+  change is held until that change is sent, so it is never applied to an
+  older value.
+
+Messages to single peers, ie snapshots, are always sent in full but count
+towards the budget of the next tick.
+**/
+#[derive(Resource)]
+pub struct TransportBudget<T> {
+	/// Bytes per second, or `None` to send everything.
+	pub bytes_per_second: Option<usize>,
+	/// Bytes that may be sent, limited to one second of budget.
+	allowance: f64,
+	deferred: Vec<Message>,
+	accumulated: HashMap<ChangeKey, f32>,
+	/// The [`Time::elapsed`] of the last tick.
+	last_tick: Option<Duration>,
+	phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for TransportBudget<T> {
+	fn default() -> Self {
+		Self::new(None)
+	}
+}
+
+impl<T> TransportBudget<T> {
+	pub fn new(bytes_per_second: Option<usize>) -> Self {
+		Self {
+			bytes_per_second,
+			allowance: 0.,
+			deferred: Vec::new(),
+			accumulated: HashMap::default(),
+			last_tick: None,
+			phantom: PhantomData,
+		}
+	}
+
+	/// Messages carried over to the next tick.
+	pub fn deferred(&self) -> &Vec<Message> {
+		&self.deferred
+	}
+
+	/// Time since the last call, the first call starts
+	/// with a full second of allowance.
+	fn elapsed(&mut self, now: Duration) -> Duration {
+		let elapsed = self
+			.last_tick
+			.map(|last| now.saturating_sub(last))
+			.unwrap_or(Duration::from_secs(1));
+		self.last_tick = Some(now);
+		elapsed
+	}
+
+	/// Count bytes sent outside of the budget against it.
+	pub fn spend(&mut self, bytes: usize) {
+		if self.bytes_per_second.is_some() {
+			self.allowance -= bytes as f64;
+		}
+	}
+
+	/// Add `outgoing` to the deferred messages and take those that fit in
+	/// the budget for `elapsed`. At least one message is taken if there is
+	/// any allowance, so messages larger than the budget are still sent.
+	pub fn take(
+		&mut self,
+		registrations: &ReplicateRegistry,
+		stats: &mut TransportStats,
+		elapsed: Duration,
+		outgoing: impl IntoIterator<Item = Message>,
+	) -> Vec<Message> {
+		let Some(bytes_per_second) = self.bytes_per_second else {
+			self.deferred.extend(outgoing);
+			return std::mem::take(&mut self.deferred);
+		};
+		for message in outgoing {
+			self.defer(stats, message);
+		}
+		self.allowance = (self.allowance
+			+ bytes_per_second as f64 * elapsed.as_secs_f64())
+		.min(bytes_per_second as f64);
+
+		let mut sent = vec![false; self.deferred.len()];
+		let mut any_sent = false;
+		let mut try_send = |allowance: &mut f64, message: &Message| {
+			let bytes = message.num_bytes() as f64;
+			if *allowance > 0. && (bytes <= *allowance || !any_sent) {
+				*allowance -= bytes;
+				any_sent = true;
+				true
+			} else {
+				false
+			}
+		};
+
+		// deltas to a deferred change wait for it
+		let pending_changes = self
+			.deferred
+			.iter()
+			.filter_map(change_key)
+			.collect::<HashSet<_>>();
+		let mut held = Vec::new();
+
+		// ordered messages, stopping at the first that does not fit
+		let mut ordered_sent = true;
+		for (index, message) in self.deferred.iter().enumerate() {
+			let pending = delta_key(message)
+				.is_some_and(|key| pending_changes.contains(&key));
+			if pending {
+				held.push(index);
+			} else if change_key(message).is_none() {
+				if !try_send(&mut self.allowance, message) {
+					ordered_sent = false;
+					break;
+				}
+				sent[index] = true;
+			}
+		}
+
+		// changes by priority, once every ordered message is sent
+		let mut changes = self
+			.deferred
+			.iter()
+			.enumerate()
+			.filter_map(|(index, message)| {
+				let key = change_key(message)?;
+				let priority = registrations.priority(key.1);
+				let accumulated =
+					self.accumulated.get(&key).copied().unwrap_or_default();
+				Some((index, key, priority, priority + accumulated))
+			})
+			.collect::<Vec<_>>();
+		changes.sort_by(|a, b| b.3.total_cmp(&a.3));
+		for (index, key, priority, _) in changes {
+			if ordered_sent
+				&& try_send(&mut self.allowance, &self.deferred[index])
+			{
+				sent[index] = true;
+				self.accumulated.remove(&key);
+			} else {
+				*self.accumulated.entry(key).or_default() += priority;
+			}
+		}
+
+		// held deltas in order, once their change is sent
+		let mut blocked = self
+			.deferred
+			.iter()
+			.enumerate()
+			.filter(|(index, _)| !sent[*index])
+			.filter_map(|(_, message)| change_key(message))
+			.collect::<HashSet<_>>();
+		for index in held {
+			let Some(key) = delta_key(&self.deferred[index]) else {
+				continue;
+			};
+			if !blocked.contains(&key)
+				&& try_send(&mut self.allowance, &self.deferred[index])
+			{
+				sent[index] = true;
+			} else {
+				blocked.insert(key);
+			}
+		}
+
+		let mut sent = sent.into_iter();
+		let (messages, deferred) = std::mem::take(&mut self.deferred)
+			.into_iter()
+			.partition(|_| sent.next().unwrap_or_default());
+		self.deferred = deferred;
+		messages
+	}
+
+	/// Carry over a message, replacing a deferred change to the
+	/// same component or resource. Deferred changes and deltas are
+	/// dropped if the component, resource or entity is removed.
+	fn defer(&mut self, stats: &mut TransportStats, message: Message) {
+		if let Some(key) = change_key(&message) {
+			// the change is newer than any deferred delta
+			self.deferred
+				.retain(|prev| delta_key(prev) != Some(key));
+			if let Some(prev) = self
+				.deferred
+				.iter_mut()
+				.find(|prev| change_key(prev) == Some(key))
+			{
+				*prev = message;
+				stats.messages_superseded += 1;
+				return;
+			}
+		}
+		let removed = |key: &ChangeKey| match &message {
+			Message::Remove {
+				reg_id,
+				entity,
+				origin,
+			} => *key == (Some((*entity, *origin)), *reg_id),
+			Message::Despawn { entity, origin } => {
+				key.0 == Some((*entity, *origin))
+			}
+			Message::RemoveResource { reg_id } => *key == (None, *reg_id),
+			_ => false,
+		};
+		self.deferred.retain(|prev| {
+			!change_key(prev)
+				.or_else(|| delta_key(prev))
+				.is_some_and(|key| removed(&key))
+		});
+		self.accumulated.retain(|key, _| !removed(key));
+		self.deferred.push(message);
+	}
+}
+
+/// Changes that can be sent in any order, and replaced by newer changes.
+fn change_key(message: &Message) -> Option<ChangeKey> {
+	match message {
+		Message::Change {
+			reg_id,
+			entity,
+			origin,
+			..
+		} => Some((Some((*entity, *origin)), *reg_id)),
+		Message::ChangeResource { reg_id, .. } => Some((None, *reg_id)),
+		_ => None,
+	}
+}
+
+/// Deltas to a component, which must be applied in order
+/// after any change to it.
+fn delta_key(message: &Message) -> Option<ChangeKey> {
+	match message {
+		Message::ChangeDelta {
+			reg_id,
+			entity,
+			origin,
+			..
+		} => Some((Some((*entity, *origin)), *reg_id)),
+		_ => None,
+	}
+}
+
+/// Take the messages that fit in the budget of `T`,
+/// or all messages if the transport has no [`TransportBudget`].
+/// `now` is the [`Time::elapsed`] of the app.
+pub(crate) fn take_outgoing<T>(
+	budget: Option<&mut TransportBudget<T>>,
+	registrations: &ReplicateRegistry,
+	stats: &mut TransportStats,
+	now: Duration,
+	outgoing: &mut MessageOutgoing,
+) -> Vec<Message> {
+	let Some(budget) = budget else {
+		return outgoing.drain(..).collect();
+	};
+	let elapsed = budget.elapsed(now);
+	let messages =
+		budget.take(registrations, stats, elapsed, outgoing.drain(..));
+	stats.messages_deferred = budget.deferred.len();
+	messages
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use std::time::Duration;
+	use sweet::prelude::*;
+
+	#[derive(Component, Serialize, Deserialize)]
+	struct High;
+	#[derive(Component, Serialize, Deserialize)]
+	struct Low;
+
+	fn change(reg_id: usize, entity: u32, value: u8) -> Message {
+		Message::Change {
+			reg_id: RegistrationId::new_with(reg_id),
+			entity: Entity::from_raw(entity),
+			origin: EntityOrigin::Sender,
+			payload: MessagePayload::Bytes(vec![value; 16]),
+		}
+	}
+
+	fn setup() -> (ReplicateRegistry, TransportStats, TransportBudget<()>) {
+		let mut registrations = ReplicateRegistry::default();
+		registrations.register_component::<High>(ReplicateDirection::Both);
+		registrations.register_component::<Low>(ReplicateDirection::Both);
+		registrations.set_priority::<High>(4.);
+		// two changes per tick
+		let bytes_per_second = change(0, 0, 0).num_bytes() * 2 * 10;
+		(
+			registrations,
+			default(),
+			TransportBudget::new(Some(bytes_per_second)),
+		)
+	}
+
+	const TICK: Duration = Duration::from_millis(100);
+
+	#[test]
+	fn unlimited() -> Result<()> {
+		let (registrations, mut stats, _) = setup();
+		let mut budget = TransportBudget::<()>::default();
+		let messages = (0..100).map(|i| change(0, i, 0));
+		expect(
+			budget
+				.take(&registrations, &mut stats, TICK, messages)
+				.len(),
+		)
+		.to_be(100);
+		Ok(())
+	}
+
+	#[test]
+	fn priority() -> Result<()> {
+		let (registrations, mut stats, mut budget) = setup();
+		let high = (0..2).map(|i| change(0, i, 0));
+		let low = (10..12).map(|i| change(1, i, 0));
+		let messages = low.chain(high).collect::<Vec<_>>();
+		let sent = budget.take(&registrations, &mut stats, TICK, messages);
+		expect(sent.len()).to_be(2);
+		expect(sent.iter().all(|m| m.entity().unwrap().index() < 10))
+			.to_be_true();
+		expect(budget.deferred().len()).to_be(2);
+
+		// a newer change replaces the deferred one
+		let messages = [change(1, 10, 1)];
+		budget.take(&registrations, &mut stats, Duration::ZERO, messages);
+		expect(budget.deferred().len()).to_be(2);
+		expect(stats.messages_superseded).to_be(1);
+
+		// low priority changes accumulate until they are sent
+		let mut sent_low = Vec::new();
+		for _ in 0..4 {
+			let high = (0..2).map(|i| change(0, i, 0));
+			let sent = budget.take(&registrations, &mut stats, TICK, high);
+			sent_low.extend(
+				sent.into_iter()
+					.filter(|m| m.entity().unwrap().index() >= 10),
+			);
+		}
+		expect(sent_low.len()).to_be(2);
+		expect(sent_low.contains(&change(1, 10, 1))).to_be_true();
+		Ok(())
+	}
+
+	#[test]
+	fn ordered() -> Result<()> {
+		let (registrations, mut stats, mut budget) = setup();
+		let spawns = (0..4).map(|i| Message::Spawn {
+			entity: Entity::from_raw(i),
+		});
+		let messages = [change(0, 0, 0)]
+			.into_iter()
+			.chain(spawns)
+			.collect::<Vec<_>>();
+		// everything fits and is sent in the original order
+		let sent =
+			budget.take(&registrations, &mut stats, TICK, messages.clone());
+		expect(sent).to_be(messages);
+
+		// large messages are sent one per tick
+		let event = |i| Message::SendEvent {
+			reg_id: RegistrationId::new_with(0),
+			payload: MessagePayload::Bytes(vec![i; 100]),
+		};
+		let sent =
+			budget.take(&registrations, &mut stats, TICK, (0..3).map(event));
+		expect(sent).to_be(vec![event(0)]);
+		// changes wait for ordered messages
+		let sent =
+			budget.take(&registrations, &mut stats, TICK, [change(0, 0, 0)]);
+		expect(sent).to_be(vec![event(1)]);
+		let sent = budget.take(&registrations, &mut stats, TICK, []);
+		expect(sent).to_be(vec![event(2)]);
+		let sent = budget.take(&registrations, &mut stats, TICK, []);
+		expect(sent).to_be(vec![change(0, 0, 0)]);
+		Ok(())
+	}
+
+	fn delta(entity: u32, value: u8) -> Message {
+		let mut next = [0; 16];
+		next[0] = value;
+		Message::ChangeDelta {
+			reg_id: RegistrationId::new_with(1),
+			entity: Entity::from_raw(entity),
+			origin: EntityOrigin::Sender,
+			delta: ByteDelta::new(&[0; 16], &next).unwrap(),
+			payload: None,
+		}
+	}
+
+	#[test]
+	fn deltas() -> Result<()> {
+		let (registrations, mut stats, mut budget) = setup();
+		// the low priority change is deferred
+		let high = (0..2).map(|i| change(0, i, 0));
+		let messages = [change(1, 10, 0)].into_iter().chain(high);
+		let sent = budget.take(&registrations, &mut stats, TICK, messages);
+		expect(sent.len()).to_be(2);
+
+		// the delta waits for the change
+		let high = (0..2).map(|i| change(0, i, 0));
+		let messages = [delta(10, 1)].into_iter().chain(high);
+		let sent = budget.take(&registrations, &mut stats, TICK, messages);
+		expect(sent.iter().all(|m| m.entity().unwrap().index() < 10))
+			.to_be_true();
+		expect(budget.deferred())
+			.to_be(&vec![change(1, 10, 0), delta(10, 1)]);
+		let sent = budget.take(&registrations, &mut stats, TICK * 2, []);
+		expect(sent).to_be(vec![change(1, 10, 0), delta(10, 1)]);
+
+		// a newer change drops the deferred deltas
+		let (registrations, mut stats, mut budget) = setup();
+		let high = (0..2).map(|i| change(0, i, 0));
+		let messages = [change(1, 10, 2)].into_iter().chain(high);
+		budget.take(&registrations, &mut stats, TICK, messages);
+		let messages = [delta(10, 3), change(1, 10, 4)];
+		budget.take(&registrations, &mut stats, Duration::ZERO, messages);
+		expect(budget.deferred()).to_be(&vec![change(1, 10, 4)]);
+		Ok(())
+	}
+}
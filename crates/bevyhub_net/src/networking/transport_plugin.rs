@@ -8,7 +8,9 @@ pub const DEFAULT_TRANSPORT_INTERVAL: Duration = Duration::from_millis(100);
 
 #[extend::ext(name=AppExtTransport)]
 pub impl App {
-	/// Adds the [`transport_incoming`] and [`transport_outgoing`] systems for a given transport type, and inserts it as a [`NonSend`]
+	/// Adds the [`transport_incoming`] and [`transport_outgoing`] systems for a given transport type, and inserts it as a [`NonSend`].
+	/// Outgoing messages are sent in runs with the same [`ReplicateRegistry::channel_or`] with [`Transport::send_channel`].
+	/// The transport has an unlimited [`TransportBudget`], see [`App::transport_budget`].
 	fn add_transport<T: 'static + Transport>(
 		&mut self,
 		transport: T,
@@ -20,26 +22,65 @@ pub impl App {
 		transport: T,
 		interval: Duration,
 	) -> &mut Self {
-		self.insert_non_send_resource(transport).add_systems(
-			Update,
-			(
-				transport_incoming::<T>
-					.run_if(on_timer(interval))
-					.before(MessageIncomingSet),
-				transport_outgoing::<T>
-					.run_if(on_timer(interval))
-					.after(MessageOutgoingSet),
-			),
-		);
+		self.insert_non_send_resource(transport)
+			.add_transport_systems::<T>(interval)
+	}
+	/// Adds the transport systems without inserting the transport, for
+	/// transports that are inserted later, ie once connected.
+	/// Outgoing messages are queued until the transport is inserted.
+	fn add_transport_systems<T: 'static + Transport>(
+		&mut self,
+		interval: Duration,
+	) -> &mut Self {
+		self.init_resource::<TransportBudget<T>>()
+			.init_resource::<TransportStats>()
+			.add_systems(
+				Update,
+				(
+					transport_incoming::<T>
+						.run_if(transport_exists::<T>)
+						.run_if(on_timer(interval))
+						.before(MessageIncomingSet),
+					transport_outgoing::<T>
+						.run_if(transport_exists::<T>)
+						.run_if(on_timer(interval))
+						.after(MessageOutgoingSet)
+						.after(PeerOutgoingSet),
+				),
+			);
 		self
 	}
+	/// Limit the bytes per second sent by a transport added with
+	/// [`App::add_transport`], see [`TransportBudget`].
+	fn transport_budget<T: 'static + Transport>(
+		&mut self,
+		bytes_per_second: usize,
+	) -> &mut Self {
+		self.world_mut()
+			.resource_mut::<TransportBudget<T>>()
+			.bytes_per_second = Some(bytes_per_second);
+		self
+	}
+}
+
+/// Run condition for systems of a transport that may not be inserted yet.
+pub fn transport_exists<T: 'static>(transport: Option<NonSend<T>>) -> bool {
+	transport.is_some()
 }
 
 pub(crate) fn transport_incoming<T: Transport>(
 	mut events: ResMut<MessageIncoming>,
+	mut stats: ResMut<TransportStats>,
 	mut transport: NonSendMut<T>,
 ) {
 	if let Some(messages) = transport.recv().ok_or(|e| log::error!("foo {e}")) {
+		stats.messages_received += messages.len() as u64;
+		if !matches!(messages.first(), None | Some(Message::Sender { .. })) {
+			// dont inherit the sender of a previous transport
+			events.push(Message::Sender {
+				client_id: DIRECT_CLIENT_ID,
+			});
+		}
 		for message in messages {
 			// log::info!("<<< MESSAGE: {:?}", message);
 			events.push(message);
@@ -47,16 +88,71 @@ pub(crate) fn transport_incoming<T: Transport>(
 	}
 }
 
+/// Sends [`MessageOutgoing`] in runs of messages on the same channel,
+/// and then [`PeerOutgoing`].
+/// If the app has a [`Time`], each batch starts with a
+/// [`Message::Timestamp`].
 pub(crate) fn transport_outgoing<T: Transport>(
+	registrations: Res<ReplicateRegistry>,
+	time: Option<Res<Time>>,
 	mut outgoing: ResMut<MessageOutgoing>,
+	mut peer_outgoing: ResMut<PeerOutgoing>,
+	mut stats: ResMut<TransportStats>,
+	mut budget: Option<ResMut<TransportBudget<T>>>,
 	mut transport: NonSendMut<T>,
 ) {
-	if outgoing.is_empty() {
-		return;
+	let messages = take_outgoing(
+		budget.as_deref_mut(),
+		&registrations,
+		&mut stats,
+		time.as_ref().map(|time| time.elapsed()).unwrap_or_default(),
+		&mut outgoing,
+	);
+	let mut bytes = 0;
+	let mut channels: Vec<(Channel, Vec<Message>)> = Vec::new();
+	let change_channel = transport.change_channel();
+	for message in messages {
+		bytes += message.num_bytes();
+		let channel = registrations.channel_or(&message, change_channel);
+		// consecutive runs so messages are sent in order
+		match channels.last_mut() {
+			Some((last, messages)) if *last == channel => {
+				messages.push(message);
+			}
+			_ => channels.push((channel, vec![message])),
+		}
 	}
-
-	let messages = outgoing.drain(..).collect();
-	transport.send(&messages).ok_or(|e| log::error!("{e}"));
+	let timestamp = time.map(|time| Message::Timestamp {
+		elapsed: time.elapsed(),
+	});
+	let stamp = |messages: &mut Vec<Message>| {
+		if let Some(timestamp) = &timestamp {
+			messages.insert(0, timestamp.clone());
+		}
+	};
+	for (channel, messages) in channels.iter_mut() {
+		stamp(messages);
+		stats.messages_sent += messages.len() as u64;
+		transport
+			.send_channel(*channel, messages)
+			.ok_or(|e| log::error!("{e}"));
+	}
+	// sent after the broadcast so a snapshot is never older
+	// than the messages before it
+	for (client_id, mut messages) in peer_outgoing.drain() {
+		stamp(&mut messages);
+		let peer_bytes = messages.iter().map(Message::num_bytes).sum();
+		if let Some(budget) = budget.as_mut() {
+			budget.spend(peer_bytes);
+		}
+		bytes += peer_bytes;
+		stats.messages_sent += messages.len() as u64;
+		transport
+			.send_to(client_id, &messages)
+			.ok_or(|e| log::error!("{e}"));
+	}
+	stats.bytes_sent += bytes as u64;
+	stats.bytes_sent_last_tick = bytes;
 	// {
 	// 	#[cfg(target_arch = "wasm32")]
 	// 	wasm_bindgen_futures::spawn_local(async move {
@@ -97,3 +193,91 @@ pub(crate) fn transport_outgoing<T: Transport>(
 // 		self
 // 	}
 // }
+
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::ecs::system::RunSystemOnce;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct MyComponent(u32);
+
+	#[test]
+	fn channels() -> Result<()> {
+		let (a, mut b) = ChannelsTransport::pair();
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin)
+			.replicate::<MyComponent>()
+			.replicate_channel::<MyComponent>(Channel::UNRELIABLE)
+			.insert_non_send_resource(a.with_packet_loss(1.));
+
+		let entity = app
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)))
+			.id();
+		app.update();
+		app.world_mut()
+			.run_system_once(transport_outgoing::<ChannelsTransport>)?;
+		expect(b.recv()?.len()).to_be(2);
+
+		app.world_mut().get_mut::<MyComponent>(entity).unwrap().0 = 8;
+		app.update();
+		expect(&app.world().resource::<MessageOutgoing>()[0]).to_be(
+			&Message::Change {
+				reg_id: RegistrationId::new_with(0),
+				entity,
+				origin: EntityOrigin::Sender,
+				payload: MessagePayload::new(MyComponent(8))?,
+			},
+		);
+		app.world_mut()
+			.run_system_once(transport_outgoing::<ChannelsTransport>)?;
+		// the change was dropped
+		expect(b.recv()?).to_be(vec![]);
+		Ok(())
+	}
+
+	#[test]
+	fn channel_order() -> Result<()> {
+		let (a, mut b) = ChannelsTransport::pair();
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin)
+			.replicate::<MyComponent>()
+			.replicate_channel::<MyComponent>(Channel::UNRELIABLE)
+			.insert_non_send_resource(a);
+		let entity = Entity::from_raw(7);
+		let reg_id = RegistrationId::new_with(0);
+		let origin = EntityOrigin::Sender;
+		let messages = vec![
+			Message::Add {
+				reg_id,
+				entity,
+				origin,
+				payload: MessagePayload::new(MyComponent(7))?,
+			},
+			Message::Change {
+				reg_id,
+				entity,
+				origin,
+				payload: MessagePayload::new(MyComponent(8))?,
+			},
+			Message::Remove {
+				reg_id,
+				entity,
+				origin,
+			},
+		];
+		app.world_mut().resource_mut::<MessageOutgoing>().0 = messages.clone();
+		app.world_mut()
+			.run_system_once(transport_outgoing::<ChannelsTransport>)?;
+		// the remove is not sent before the change
+		expect(b.recv()?).to_be(messages);
+		Ok(())
+	}
+}
@@ -0,0 +1,602 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Identifies bevyhub datagrams, others are ignored.
+pub const UDP_PROTOCOL_ID: u32 = 0x6268_7562;
+/// Largest fragment of a batch, small enough that a datagram
+/// fits in a typical MTU.
+pub const UDP_FRAGMENT_SIZE: usize = 1024;
+/// Size of the buffer datagrams are received into.
+pub const UDP_MAX_DATAGRAM: usize = 2048;
+/// How often endpoints should call [`UdpConnection::poll_transmit`].
+pub const UDP_TICK: Duration = Duration::from_millis(10);
+
+/// A datagram of the UDP protocol, encoded with bincode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpPacket {
+	pub protocol_id: u32,
+	/// The connection token assigned by the server, zero until accepted.
+	pub token: u64,
+	/// Starts at 1, zero for handshake packets which are not acked.
+	pub sequence: u32,
+	/// The latest sequence received from the remote.
+	pub ack: u32,
+	/// Bit `n` is set if `ack - n - 1` was also received.
+	pub ack_bits: u32,
+	pub body: UdpBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UdpBody {
+	/// Sent by a client until accepted, the nonce is echoed back.
+	Connect {
+		nonce: u64,
+	},
+	/// Sent by the server with the token for the connection.
+	Accept {
+		nonce: u64,
+	},
+	/// Carries only acks, also sent as a keepalive.
+	Ack,
+	Fragment(UdpFragment),
+	Disconnect,
+}
+
+/// A part of a batch of messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpFragment {
+	pub channel: Channel,
+	/// Reliable batches are numbered separately from the others.
+	pub batch: u32,
+	pub index: u16,
+	pub count: u16,
+	pub bytes: Vec<u8>,
+}
+
+impl UdpPacket {
+	/// A packet outside of the connection sequence.
+	pub fn handshake(token: u64, body: UdpBody) -> Self {
+		Self {
+			protocol_id: UDP_PROTOCOL_ID,
+			token,
+			sequence: 0,
+			ack: 0,
+			ack_bits: 0,
+			body,
+		}
+	}
+
+	pub fn to_bytes(&self) -> Result<Vec<u8>> {
+		Ok(bincode::serialize(self)?)
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+		let packet: Self = bincode::deserialize(bytes)?;
+		if packet.protocol_id != UDP_PROTOCOL_ID {
+			anyhow::bail!("unknown protocol id: {}", packet.protocol_id);
+		}
+		Ok(packet)
+	}
+}
+
+/// Timings of a [`UdpConnection`].
+#[derive(Debug, Clone)]
+pub struct UdpSettings {
+	/// Resend reliable fragments that have not been acked in this time.
+	pub resend_after: Duration,
+	/// Send an [`UdpBody::Ack`] if nothing else was sent in this time.
+	pub keepalive: Duration,
+	/// Close the connection if nothing is received in this time.
+	pub timeout: Duration,
+	/// Incomplete unreliable batches older than this many batches
+	/// are discarded.
+	pub max_pending_batches: u32,
+	/// Reliable fragments this many batches ahead of the next batch to
+	/// deliver are dropped without an ack, so are resent later.
+	pub max_reliable_window: u32,
+}
+
+impl Default for UdpSettings {
+	fn default() -> Self {
+		Self {
+			resend_after: Duration::from_millis(100),
+			keepalive: Duration::from_secs(1),
+			timeout: Duration::from_secs(5),
+			max_pending_batches: 32,
+			max_reliable_window: 256,
+		}
+	}
+}
+
+/**
+The state of one end of a UDP connection, without any IO so that
+it can be driven by a socket or directly in tests.
+
+Batches larger than [`UDP_FRAGMENT_SIZE`] are split into fragments, one
+per packet. Every packet carries acks for the last 33 packets received,
+fragments on a reliable [`Channel`] are resent until acked and delivered
+in order. Other fragments are sent once, and batches on a
+[`ChannelKind::Sequenced`] channel arriving after a newer one are discarded.
+**/
+pub struct UdpConnection {
+	pub token: u64,
+	pub settings: UdpSettings,
+	next_sequence: u32,
+	next_reliable: u32,
+	next_unreliable: u32,
+	outgoing: VecDeque<UdpFragment>,
+	/// Reliable fragments by the sequence of the packet they were sent in.
+	unacked: BTreeMap<u32, (Instant, UdpFragment)>,
+	remote_sequence: u32,
+	remote_bits: u32,
+	ack_pending: bool,
+	/// The next reliable batch to deliver.
+	deliver_reliable: u32,
+	reliable: BTreeMap<u32, Reassembly>,
+	unreliable: BTreeMap<u32, Reassembly>,
+	last_sequenced: HashMap<ChannelId, u32>,
+	last_sent: Instant,
+	last_received: Instant,
+	disconnected: bool,
+}
+
+struct Reassembly {
+	channel: Channel,
+	fragments: Vec<Option<Vec<u8>>>,
+	remaining: usize,
+}
+
+impl Reassembly {
+	fn new(fragment: &UdpFragment) -> Self {
+		Self {
+			channel: fragment.channel,
+			fragments: vec![None; fragment.count as usize],
+			remaining: fragment.count as usize,
+		}
+	}
+
+	fn insert(&mut self, fragment: UdpFragment) {
+		if let Some(slot @ None) =
+			self.fragments.get_mut(fragment.index as usize)
+		{
+			*slot = Some(fragment.bytes);
+			self.remaining -= 1;
+		}
+	}
+
+	fn into_batch(self) -> (Channel, Vec<u8>) {
+		(
+			self.channel,
+			self.fragments.into_iter().flatten().flatten().collect(),
+		)
+	}
+}
+
+impl UdpConnection {
+	pub fn new(token: u64, settings: UdpSettings, now: Instant) -> Self {
+		Self {
+			token,
+			settings,
+			next_sequence: 1,
+			next_reliable: 0,
+			next_unreliable: 0,
+			outgoing: VecDeque::new(),
+			unacked: BTreeMap::new(),
+			remote_sequence: 0,
+			remote_bits: 0,
+			ack_pending: false,
+			deliver_reliable: 0,
+			reliable: BTreeMap::new(),
+			unreliable: BTreeMap::new(),
+			last_sequenced: HashMap::default(),
+			last_sent: now,
+			last_received: now,
+			disconnected: false,
+		}
+	}
+
+	/// Queue a batch, sent by the next [`Self::poll_transmit`].
+	pub fn send(&mut self, channel: Channel, bytes: &[u8]) -> Result<()> {
+		let count = bytes.len().div_ceil(UDP_FRAGMENT_SIZE).max(1);
+		let Ok(count) = u16::try_from(count) else {
+			anyhow::bail!("batch too large to fragment: {} bytes", bytes.len());
+		};
+		let next = if channel.kind.is_reliable() {
+			&mut self.next_reliable
+		} else {
+			&mut self.next_unreliable
+		};
+		let batch = *next;
+		*next += 1;
+		let chunks = bytes.chunks(UDP_FRAGMENT_SIZE);
+		// an empty batch is still sent as a single fragment
+		let chunks = chunks.chain((bytes.is_empty()).then_some(&[][..]));
+		for (index, chunk) in chunks.enumerate() {
+			self.outgoing.push_back(UdpFragment {
+				channel,
+				batch,
+				index: index as u16,
+				count,
+				bytes: chunk.to_vec(),
+			});
+		}
+		Ok(())
+	}
+
+	/// Encode queued fragments, reliable fragments due for a resend,
+	/// and an ack if nothing else is sent but one is due.
+	pub fn poll_transmit(&mut self, now: Instant) -> Result<Vec<Vec<u8>>> {
+		let resend = self
+			.unacked
+			.iter()
+			.filter(|(_, (sent, _))| now - *sent >= self.settings.resend_after)
+			.map(|(sequence, _)| *sequence)
+			.collect::<Vec<_>>();
+		for sequence in resend.into_iter().rev() {
+			let (_, fragment) = self.unacked.remove(&sequence).unwrap();
+			self.outgoing.push_front(fragment);
+		}
+
+		let mut datagrams = Vec::new();
+		while let Some(fragment) = self.outgoing.pop_front() {
+			let sequence = self.next_sequence;
+			let reliable = fragment.channel.kind.is_reliable();
+			let packet = self.packet(UdpBody::Fragment(fragment));
+			datagrams.push(packet.to_bytes()?);
+			if reliable {
+				let UdpBody::Fragment(fragment) = packet.body else {
+					unreachable!()
+				};
+				self.unacked.insert(sequence, (now, fragment));
+			}
+		}
+		if datagrams.is_empty()
+			&& (self.ack_pending
+				|| now - self.last_sent >= self.settings.keepalive)
+		{
+			datagrams.push(self.packet(UdpBody::Ack).to_bytes()?);
+		}
+		if !datagrams.is_empty() {
+			self.last_sent = now;
+		}
+		Ok(datagrams)
+	}
+
+	/// Encode a [`UdpBody::Disconnect`], the remote closes the connection
+	/// without waiting for the timeout.
+	pub fn disconnect(&mut self) -> Result<Vec<u8>> {
+		self.disconnected = true;
+		self.packet(UdpBody::Disconnect).to_bytes()
+	}
+
+	/// Handle a packet with this connection's token, returning batches
+	/// that are complete and ready to be delivered.
+	pub fn receive(
+		&mut self,
+		now: Instant,
+		packet: UdpPacket,
+	) -> Vec<(Channel, Vec<u8>)> {
+		if packet.sequence == 0
+			|| !Self::is_valid(&packet.body)
+			|| !self.in_window(&packet.body)
+			|| !self.record_sequence(packet.sequence)
+		{
+			return Vec::new();
+		}
+		self.last_received = now;
+		self.handle_acks(packet.ack, packet.ack_bits);
+		match packet.body {
+			UdpBody::Fragment(fragment) => {
+				self.ack_pending = true;
+				self.receive_fragment(fragment)
+			}
+			UdpBody::Disconnect => {
+				self.disconnected = true;
+				Vec::new()
+			}
+			_ => Vec::new(),
+		}
+	}
+
+	/// Whether either end disconnected or the connection timed out.
+	pub fn is_closed(&self, now: Instant) -> bool {
+		self.disconnected || now - self.last_received >= self.settings.timeout
+	}
+
+	/// Reliable fragments sent but not yet acked.
+	pub fn num_unacked(&self) -> usize { self.unacked.len() }
+
+	fn packet(&mut self, body: UdpBody) -> UdpPacket {
+		let sequence = self.next_sequence;
+		self.next_sequence += 1;
+		self.ack_pending = false;
+		UdpPacket {
+			protocol_id: UDP_PROTOCOL_ID,
+			token: self.token,
+			sequence,
+			ack: self.remote_sequence,
+			ack_bits: self.remote_bits,
+			body,
+		}
+	}
+
+	/// Whether a fragment has an index within its count, others would
+	/// deliver an empty batch or never complete.
+	fn is_valid(body: &UdpBody) -> bool {
+		match body {
+			UdpBody::Fragment(fragment) => fragment.index < fragment.count,
+			_ => true,
+		}
+	}
+
+	/// Whether a reliable fragment is close enough to the next batch
+	/// to deliver to be held until its batch is complete.
+	fn in_window(&self, body: &UdpBody) -> bool {
+		match body {
+			UdpBody::Fragment(fragment)
+				if fragment.channel.kind.is_reliable() =>
+			{
+				fragment.batch.saturating_sub(self.deliver_reliable)
+					< self.settings.max_reliable_window
+			}
+			_ => true,
+		}
+	}
+
+	/// Record a received sequence, returning false for duplicates
+	/// and packets too old to ack.
+	fn record_sequence(&mut self, sequence: u32) -> bool {
+		if sequence > self.remote_sequence {
+			let shift = sequence - self.remote_sequence;
+			if self.remote_sequence != 0 {
+				self.remote_bits =
+					self.remote_bits.checked_shl(shift).unwrap_or(0)
+						| 1u32.checked_shl(shift - 1).unwrap_or(0);
+			}
+			self.remote_sequence = sequence;
+			return true;
+		}
+		let bit = match self.remote_sequence - sequence {
+			0 => return false,
+			diff @ 1..=32 => 1 << (diff - 1),
+			_ => return false,
+		};
+		let received = self.remote_bits & bit != 0;
+		self.remote_bits |= bit;
+		!received
+	}
+
+	fn handle_acks(&mut self, ack: u32, ack_bits: u32) {
+		if ack == 0 {
+			return;
+		}
+		self.unacked.remove(&ack);
+		for n in 0..32 {
+			if ack_bits & (1 << n) != 0 && ack > n + 1 {
+				self.unacked.remove(&(ack - n - 1));
+			}
+		}
+	}
+
+	fn receive_fragment(
+		&mut self,
+		fragment: UdpFragment,
+	) -> Vec<(Channel, Vec<u8>)> {
+		let batch = fragment.batch;
+		if fragment.channel.kind.is_reliable() {
+			if batch < self.deliver_reliable {
+				// resent after the ack was lost
+				return Vec::new();
+			}
+			self.reliable
+				.entry(batch)
+				.or_insert_with(|| Reassembly::new(&fragment))
+				.insert(fragment);
+			let mut batches = Vec::new();
+			while let Some(entry) = self.reliable.first_entry() {
+				if *entry.key() != self.deliver_reliable
+					|| entry.get().remaining > 0
+				{
+					break;
+				}
+				batches.push(entry.remove().into_batch());
+				self.deliver_reliable += 1;
+			}
+			return batches;
+		}
+
+		let reassembly = self
+			.unreliable
+			.entry(batch)
+			.or_insert_with(|| Reassembly::new(&fragment));
+		reassembly.insert(fragment);
+		let complete = reassembly.remaining == 0;
+		let oldest = batch.saturating_sub(self.settings.max_pending_batches);
+		self.unreliable.retain(|pending, _| *pending >= oldest);
+		if !complete {
+			return Vec::new();
+		}
+		let (channel, bytes) =
+			self.unreliable.remove(&batch).unwrap().into_batch();
+		if channel.kind == ChannelKind::Sequenced {
+			let last = self.last_sequenced.get(&channel.id);
+			if last.is_some_and(|last| batch <= *last) {
+				return Vec::new();
+			}
+			self.last_sequenced.insert(channel.id, batch);
+		}
+		vec![(channel, bytes)]
+	}
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use std::time::Duration;
+	use std::time::Instant;
+	use sweet::prelude::*;
+
+	fn pair(now: Instant) -> (UdpConnection, UdpConnection) {
+		(
+			UdpConnection::new(1, UdpSettings::default(), now),
+			UdpConnection::new(1, UdpSettings::default(), now),
+		)
+	}
+
+	fn deliver(
+		datagrams: Vec<Vec<u8>>,
+		to: &mut UdpConnection,
+		now: Instant,
+	) -> Result<Vec<(Channel, Vec<u8>)>> {
+		let mut batches = Vec::new();
+		for datagram in datagrams {
+			batches.extend(to.receive(now, UdpPacket::from_bytes(&datagram)?));
+		}
+		Ok(batches)
+	}
+
+	#[test]
+	fn fragments() -> Result<()> {
+		let now = Instant::now();
+		let (mut a, mut b) = pair(now);
+		let bytes = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+		a.send(Channel::UNRELIABLE, &bytes)?;
+		a.send(Channel::RELIABLE, &[])?;
+		let datagrams = a.poll_transmit(now)?;
+		expect(datagrams.len()).to_be(6);
+		let mut batches = deliver(datagrams, &mut b, now)?;
+		expect(batches.len()).to_be(2);
+		expect(batches.remove(0)).to_be((Channel::UNRELIABLE, bytes));
+		expect(batches.remove(0)).to_be((Channel::RELIABLE, vec![]));
+		Ok(())
+	}
+
+	#[test]
+	fn resends_reliable() -> Result<()> {
+		let mut now = Instant::now();
+		let (mut a, mut b) = pair(now);
+		a.send(Channel::RELIABLE, &[0])?;
+		a.send(Channel::UNRELIABLE, &[1])?;
+		a.send(Channel::RELIABLE, &[2])?;
+		// the first reliable batch is lost
+		let mut datagrams = a.poll_transmit(now)?;
+		datagrams.remove(0);
+		expect(deliver(datagrams, &mut b, now)?)
+			.to_be(vec![(Channel::UNRELIABLE, vec![1])]);
+		expect(a.num_unacked()).to_be(2);
+
+		// acks arrive before the resend is due
+		deliver(b.poll_transmit(now)?, &mut a, now)?;
+		expect(a.num_unacked()).to_be(1);
+
+		now += Duration::from_millis(100);
+		let batches = deliver(a.poll_transmit(now)?, &mut b, now)?;
+		expect(batches).to_be(vec![
+			(Channel::RELIABLE, vec![0]),
+			(Channel::RELIABLE, vec![2]),
+		]);
+		deliver(b.poll_transmit(now)?, &mut a, now)?;
+		expect(a.num_unacked()).to_be(0);
+		Ok(())
+	}
+
+	#[test]
+	fn reliable_window() -> Result<()> {
+		let mut now = Instant::now();
+		let (mut a, _) = pair(now);
+		let mut b = UdpConnection::new(
+			1,
+			UdpSettings {
+				max_reliable_window: 2,
+				..UdpSettings::default()
+			},
+			now,
+		);
+		for i in 0..3 {
+			a.send(Channel::RELIABLE, &[i])?;
+		}
+		// the first batch is lost, the third is outside the window
+		let mut datagrams = a.poll_transmit(now)?;
+		datagrams.remove(0);
+		expect(deliver(datagrams, &mut b, now)?).to_be(vec![]);
+		deliver(b.poll_transmit(now)?, &mut a, now)?;
+		expect(a.num_unacked()).to_be(2);
+
+		now += Duration::from_millis(100);
+		let batches = deliver(a.poll_transmit(now)?, &mut b, now)?;
+		expect(batches).to_be(vec![
+			(Channel::RELIABLE, vec![0]),
+			(Channel::RELIABLE, vec![1]),
+			(Channel::RELIABLE, vec![2]),
+		]);
+		Ok(())
+	}
+
+	#[test]
+	fn invalid_fragments() -> Result<()> {
+		let now = Instant::now();
+		let (_, mut b) = pair(now);
+		// an empty count, and an index outside of the count
+		let fragments = [(1, 0, 0), (2, 1, 1)];
+		for (sequence, index, count) in fragments {
+			let packet = UdpPacket {
+				sequence,
+				..UdpPacket::handshake(
+					1,
+					UdpBody::Fragment(UdpFragment {
+						channel: Channel::RELIABLE,
+						batch: 0,
+						index,
+						count,
+						bytes: vec![0],
+					}),
+				)
+			};
+			expect(b.receive(now, packet)).to_be(vec![]);
+		}
+		// neither was acked
+		let datagrams = b.poll_transmit(now)?;
+		expect(datagrams.is_empty()).to_be_true();
+		Ok(())
+	}
+
+	#[test]
+	fn sequenced_and_duplicates() -> Result<()> {
+		let now = Instant::now();
+		let (mut a, mut b) = pair(now);
+		a.send(Channel::SEQUENCED, &[0])?;
+		a.send(Channel::SEQUENCED, &[1])?;
+		let mut datagrams = a.poll_transmit(now)?;
+		datagrams.reverse();
+		datagrams.push(datagrams[0].clone());
+		expect(deliver(datagrams, &mut b, now)?)
+			.to_be(vec![(Channel::SEQUENCED, vec![1])]);
+		Ok(())
+	}
+
+	#[test]
+	fn timeout() -> Result<()> {
+		let now = Instant::now();
+		let (mut a, mut b) = pair(now);
+		let later = now + Duration::from_secs(1);
+		// keepalives prevent a timeout
+		deliver(a.poll_transmit(later)?, &mut b, later)?;
+		expect(b.is_closed(now + Duration::from_secs(5))).to_be_false();
+		expect(b.is_closed(now + Duration::from_secs(6))).to_be_true();
+
+		let datagram = a.disconnect()?;
+		deliver(vec![datagram], &mut b, later)?;
+		expect(a.is_closed(later)).to_be_true();
+		expect(b.is_closed(later)).to_be_true();
+		Ok(())
+	}
+}
@@ -3,49 +3,216 @@ use bevy::prelude::*;
 use forky::prelude::ResultTEExt;
 
 
+/// Entities spawned by this app that peers can send changes to.
+type OwnEntities<'w, 's> =
+	Query<'w, 's, (), (With<Replicate>, Without<RemoteEntity>)>;
+
+/// Resolve the entity of an incoming message to a local entity.
+/// This is either an entity spawned by a peer, or one of our own
+/// [`Replicate`] entities that a peer is sending back.
+fn local_entity(
+	registrations: &ReplicateRegistry,
+	own: &OwnEntities,
+	local_client: LocalClientId,
+	sender: ClientId,
+	entity: Entity,
+	origin: EntityOrigin,
+) -> Option<Entity> {
+	registrations
+		.entities
+		.incoming(sender, local_client, entity, origin, |entity| {
+			own.contains(entity)
+		})
+}
+
+fn entity_fns(
+	registrations: &ReplicateRegistry,
+	own: &OwnEntities,
+	owners: &Query<(Option<&Owner>, Has<Authority>)>,
+	local_client: LocalClientId,
+	sender: ClientId,
+	(entity, origin): (&Entity, &EntityOrigin),
+	reg_id: RegistrationId,
+) -> Option<(Entity, ComponentFns)> {
+	let entity = local_entity(
+		registrations,
+		own,
+		local_client,
+		sender,
+		*entity,
+		*origin,
+	)?;
+	if let Ok((owner, authority)) = owners.get(entity) {
+		if !accepts_changes(owner, authority, sender) {
+			log::warn!(
+				"rejected change to {entity} from client {sender}, which is not the owner"
+			);
+			return None;
+		}
+	}
+	let fns = registrations.incoming_component_fns.get(&reg_id)?;
+	Some((entity, *fns))
+}
+
 pub fn handle_incoming_commands(
 	mut commands: Commands,
 	mut registrations: ResMut<ReplicateRegistry>,
 	incoming: Res<MessageIncoming>,
+	local_client: Res<LocalClientId>,
+	own: OwnEntities,
+	owners: Query<(Option<&Owner>, Has<Authority>)>,
 ) {
+	let local_client = *local_client;
+	// entities are namespaced by the client that spawned them
+	let mut sender = DIRECT_CLIENT_ID;
+	let mut stamped = false;
+
 	for msg in incoming.iter() {
 		match msg {
+			Message::Sender { client_id } => {
+				sender = *client_id;
+				if stamped {
+					commands.insert_resource(IncomingTimestamp::default());
+					stamped = false;
+				}
+			}
+			Message::Timestamp { elapsed } => {
+				// inserted in order with the changes after it
+				commands.insert_resource(IncomingTimestamp(Some(*elapsed)));
+				stamped = true;
+			}
+			Message::Registrations { .. } => {
+				// handled by [`remap_incoming`]
+			}
+			Message::Protocol { .. } => {
+				// handled by [`check_protocol`]
+			}
+			Message::PeerConnected { .. }
+			| Message::PeerDisconnected { .. }
+			| Message::Welcome { .. } => {
+				// handled by [`handle_peer_messages`]
+			}
+			Message::Recipient { .. } => {
+				// used by the relay server
+			}
+			Message::Signal { .. } => {
+				// handled by [`RtcTransport`]
+			}
+			Message::AuthorityRequest { .. }
+			| Message::OwnerChanged { .. }
+			| Message::AuthorityGranted { .. } => {
+				// handled by [`handle_authority_messages`]
+			}
 			Message::Spawn { entity } => {
-				let local = commands.spawn_empty().id();
-				registrations.entities.insert(*entity, local);
+				let remote = RemoteEntity::new(sender, *entity);
+				// may already be spawned, ie by a snapshot
+				if registrations.entities.local(remote).is_none() {
+					let local = commands.spawn(remote).id();
+					registrations.entities.insert(remote, local);
+				}
 			}
-			Message::Despawn { entity } => {
-				commands.entity(*entity).despawn();
+			Message::Despawn { entity, origin } => {
+				if let Some(local) = local_entity(
+					&registrations,
+					&own,
+					local_client,
+					sender,
+					*entity,
+					*origin,
+				) {
+					// the map is cleaned up by the [`RemoteEntity`] hook
+					registrations
+						.removed
+						.retain(|(entity, _)| *entity != local);
+					if let Some(mut local) = commands.get_entity(local) {
+						local.despawn();
+					}
+				}
 			}
 			Message::Add {
 				entity,
+				origin,
 				reg_id,
 				payload,
 			} => {
-				if let Some((entity, fns)) =
-					registrations.entity_fns(*entity, *reg_id)
-				{
-					(fns.insert)(&mut commands.entity(entity), &payload)
+				if let Some((entity, fns)) = entity_fns(
+					&registrations,
+					&own,
+					&owners,
+					local_client,
+					sender,
+					(entity, origin),
+					*reg_id,
+				) {
+					registrations.removed.remove(&(entity, *reg_id));
+					let mut entity = commands.entity(entity);
+					(fns.insert)(&mut entity, *reg_id, sender, payload)
 						.ok_or(|e| log::error!("{e}"));
 				}
 			}
 			Message::Change {
 				entity,
+				origin,
 				reg_id,
 				payload,
 			} => {
-				if let Some((entity, fns)) =
-					registrations.entity_fns(*entity, *reg_id)
-				{
-					(fns.change)(&mut commands.entity(entity), payload)
+				if let Some((entity, fns)) = entity_fns(
+					&registrations,
+					&own,
+					&owners,
+					local_client,
+					sender,
+					(entity, origin),
+					*reg_id,
+				) {
+					if registrations.removed.contains(&(entity, *reg_id)) {
+						// sent before the remove
+						continue;
+					}
+					let mut entity = commands.entity(entity);
+					(fns.change)(&mut entity, *reg_id, sender, payload)
 						.ok_or(|e| log::error!("{e}"));
 				}
 			}
-			Message::Remove { entity, reg_id } => {
-				if let Some((entity, fns)) =
-					registrations.entity_fns(*entity, *reg_id)
-				{
-					(fns.remove)(&mut commands.entity(entity));
+			Message::ChangeDelta {
+				entity,
+				origin,
+				reg_id,
+				delta,
+				..
+			} => {
+				if let Some((entity, fns)) = entity_fns(
+					&registrations,
+					&own,
+					&owners,
+					local_client,
+					sender,
+					(entity, origin),
+					*reg_id,
+				) {
+					if registrations.removed.contains(&(entity, *reg_id)) {
+						continue;
+					}
+					let mut entity = commands.entity(entity);
+					(fns.apply_delta)(&mut entity, *reg_id, delta);
+				}
+			}
+			Message::Remove {
+				entity,
+				origin,
+				reg_id,
+			} => {
+				if let Some((entity, fns)) = entity_fns(
+					&registrations,
+					&own,
+					&owners,
+					local_client,
+					sender,
+					(entity, origin),
+					*reg_id,
+				) {
+					(fns.remove)(&mut commands.entity(entity), *reg_id);
+					registrations.removed.insert((entity, *reg_id));
 				}
 			}
 			Message::InsertResource { reg_id, payload } => {
@@ -87,22 +254,26 @@ pub fn handle_incoming_commands(
 			}
 		}
 	}
+	if stamped {
+		commands.insert_resource(IncomingTimestamp::default());
+	}
 }
 
 pub fn handle_incoming_world(world: &mut World) {
 	let registrations = world.resource::<ReplicateRegistry>();
+	let mut sender = DIRECT_CLIENT_ID;
 	let events = world
 		.resource::<MessageIncoming>()
 		.iter()
 		.filter_map(|msg| match msg {
-			Message::SendEvent { reg_id, payload } => {
-				if let Some(fns) = registrations.incoming_event_fns.get(reg_id)
-				{
-					Some((fns.clone(), payload.clone()))
-				} else {
-					None
-				}
+			Message::Sender { client_id } => {
+				sender = *client_id;
+				None
 			}
+			Message::SendEvent { reg_id, payload } => registrations
+				.incoming_event_fns
+				.get(reg_id)
+				.map(|fns| (*fns, sender, payload.clone())),
 			_ => {
 				// all other messages are handled by `handle_incoming_commands`
 				None
@@ -110,7 +281,7 @@ pub fn handle_incoming_world(world: &mut World) {
 		})
 		.collect::<Vec<_>>();
 
-	for (fns, payload) in events {
-		(fns.send)(world, &payload).ok_or(|e| log::error!("{e}"));
+	for (fns, sender, payload) in events {
+		(fns.send)(world, sender, &payload).ok_or(|e| log::error!("{e}"));
 	}
 }
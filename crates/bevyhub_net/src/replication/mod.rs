@@ -1,9 +1,15 @@
 pub mod incoming;
 #[allow(unused_imports)]
 pub use self::incoming::*;
+pub mod replicate_authority;
+#[allow(unused_imports)]
+pub use self::replicate_authority::*;
 pub mod replicate_component;
 #[allow(unused_imports)]
 pub use self::replicate_component::*;
+pub mod replicate_delta;
+#[allow(unused_imports)]
+pub use self::replicate_delta::*;
 pub mod replicate_direction;
 #[allow(unused_imports)]
 pub use self::replicate_direction::*;
@@ -13,21 +19,54 @@ pub use self::replicate_entity::*;
 pub mod replicate_event;
 #[allow(unused_imports)]
 pub use self::replicate_event::*;
+pub mod replicate_handshake;
+#[allow(unused_imports)]
+pub use self::replicate_handshake::*;
+pub mod replicate_hierarchy;
+#[allow(unused_imports)]
+pub use self::replicate_hierarchy::*;
+pub mod replicate_interpolation;
+#[allow(unused_imports)]
+pub use self::replicate_interpolation::*;
+pub mod replicate_map_entities;
+#[allow(unused_imports)]
+pub use self::replicate_map_entities::*;
 pub mod replicate_observer;
 #[allow(unused_imports)]
 pub use self::replicate_observer::*;
 pub mod replicate_plugin;
 #[allow(unused_imports)]
 pub use self::replicate_plugin::*;
+pub mod replicate_prediction;
+#[allow(unused_imports)]
+pub use self::replicate_prediction::*;
+pub mod replicate_protocol;
+#[allow(unused_imports)]
+pub use self::replicate_protocol::*;
+pub mod replicate_reflect;
+#[allow(unused_imports)]
+pub use self::replicate_reflect::*;
 pub mod replicate_registry;
 #[allow(unused_imports)]
 pub use self::replicate_registry::*;
 pub mod replicate_registry_exporter;
 #[allow(unused_imports)]
 pub use self::replicate_registry_exporter::*;
+pub mod replicate_relevancy;
+#[allow(unused_imports)]
+pub use self::replicate_relevancy::*;
 pub mod replicate_resource;
 #[allow(unused_imports)]
 pub use self::replicate_resource::*;
+pub mod replicate_snapshot;
+#[allow(unused_imports)]
+pub use self::replicate_snapshot::*;
 pub mod replicate_type;
 #[allow(unused_imports)]
 pub use self::replicate_type::*;
+pub mod replication_config;
+#[allow(unused_imports)]
+pub use self::replication_config::*;
+pub mod replication_test_harness;
+#[allow(unused_imports)]
+pub use self::replication_test_harness::*;
@@ -0,0 +1,456 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+
+/// The client with authority over an entity, only changes from the owner
+/// are accepted and only the owner sends changes.
+/// Entities without an owner behave as before, accepting changes from any
+/// peer according to their [`ReplicateDirection`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Component)]
+pub struct Owner(pub ClientId);
+
+/// This app has authority over an entity with an [`Owner`],
+/// added when the owner is this app.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Component)]
+pub struct Authority;
+
+/// Filter for entities whose changes this app should send.
+pub type HasAuthority = Or<(Without<Owner>, With<Authority>)>;
+
+/// Whether changes from `sender` to an entity should be applied.
+pub fn accepts_changes(
+	owner: Option<&Owner>,
+	authority: bool,
+	sender: ClientId,
+) -> bool {
+	match owner {
+		_ if authority => false,
+		Some(owner) => owner.0 == sender,
+		None => true,
+	}
+}
+
+/// How an app responds to an [`AuthorityRequested`] for an entity it spawned.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Resource)]
+pub enum AuthorityPolicy {
+	/// Always transfer authority to the requesting client.
+	#[default]
+	Always,
+	/// Only transfer authority if the entity has no [`Owner`].
+	IfUnowned,
+	/// Never transfer automatically, requests can be handled by
+	/// reading [`AuthorityRequested`] and sending a [`TransferAuthority`].
+	Manual,
+}
+
+/// Send to request authority over a remote entity.
+#[derive(Debug, Clone, PartialEq, Event)]
+pub struct RequestAuthority {
+	pub entity: Entity,
+}
+
+/// A client requested authority over an entity spawned by this app.
+#[derive(Debug, Clone, PartialEq, Event)]
+pub struct AuthorityRequested {
+	pub entity: Entity,
+	pub client_id: ClientId,
+}
+
+/// Send to change the [`Owner`] of an entity spawned by this app,
+/// `None` returns authority to this app.
+#[derive(Debug, Clone, PartialEq, Event)]
+pub struct TransferAuthority {
+	pub entity: Entity,
+	pub client_id: Option<ClientId>,
+}
+
+/**
+Runtime transfer of authority over replicated entities.
+
+The handshake is as follows:
+1. A client sends a [`RequestAuthority`], which is sent to the app that spawned the entity as a [`Message::AuthorityRequest`]
+2. The app that spawned the entity sends an [`AuthorityRequested`], and if allowed by the [`AuthorityPolicy`] a [`TransferAuthority`]
+3. The spawner adds an [`Owner`] and sends a [`Message::OwnerChanged`] to every peer, and a [`Message::AuthorityGranted`] to the new owner
+4. The new owner adds [`Authority`] and [`Replicate`], so its changes are sent
+
+Peers that connect later receive the [`Owner`] in the snapshot of the spawner.
+
+Components should be registered with [`ReplicateDirection::Both`] so that
+either app can send changes.
+**/
+pub struct ReplicateAuthorityPlugin;
+
+impl Plugin for ReplicateAuthorityPlugin {
+	fn build(&self, app: &mut App) {
+		app.init_resource::<AuthorityPolicy>()
+			.add_event::<RequestAuthority>()
+			.add_event::<AuthorityRequested>()
+			.add_event::<TransferAuthority>()
+			.add_systems(
+				Update,
+				(
+					(handle_authority_messages, grant_requests)
+						.chain()
+						.after(handle_incoming_commands)
+						.in_set(MessageIncomingSet),
+					(request_authority, transfer_authority)
+						.in_set(MessageOutgoingSet),
+				),
+			);
+	}
+}
+
+/// Handles [`Message::AuthorityRequest`], [`Message::OwnerChanged`]
+/// and [`Message::AuthorityGranted`]. Ownership changes are only
+/// accepted from the app that spawned the entity.
+pub fn handle_authority_messages(
+	mut commands: Commands,
+	registrations: Res<ReplicateRegistry>,
+	incoming: Res<MessageIncoming>,
+	local_client: Res<LocalClientId>,
+	spawned: Query<(), (With<Replicate>, Without<RemoteEntity>)>,
+	mut requested: EventWriter<AuthorityRequested>,
+) {
+	let mut sender = DIRECT_CLIENT_ID;
+	// only the spawner sends ownership changes, about its own entities
+	let local = |sender: ClientId, entity: &Entity| {
+		registrations.entities.incoming(
+			sender,
+			*local_client,
+			*entity,
+			EntityOrigin::Sender,
+			|_| false,
+		)
+	};
+	for message in incoming.iter() {
+		match message {
+			Message::Sender { client_id } => {
+				sender = *client_id;
+			}
+			Message::AuthorityRequest { entity, origin } => {
+				if origin.spawner(sender, *local_client).is_none()
+					&& spawned.contains(*entity)
+				{
+					requested.send(AuthorityRequested {
+						entity: *entity,
+						client_id: sender,
+					});
+				} else {
+					log::warn!(
+						"client {sender} requested authority over {entity}, which was not spawned by this app"
+					);
+				}
+			}
+			Message::OwnerChanged { entity, client_id } => {
+				if let Some(mut entity) = local(sender, entity)
+					.and_then(|entity| commands.get_entity(entity))
+				{
+					entity.remove::<Authority>();
+					match client_id {
+						Some(client_id) => entity.insert(Owner(*client_id)),
+						None => entity.remove::<Owner>(),
+					};
+				}
+			}
+			Message::AuthorityGranted { entity } => {
+				if let Some(mut entity) = local(sender, entity)
+					.and_then(|entity| commands.get_entity(entity))
+				{
+					entity.insert((Authority, Replicate::default()));
+				}
+			}
+			_ => {}
+		}
+	}
+}
+
+fn grant_requests(
+	policy: Res<AuthorityPolicy>,
+	owners: Query<&Owner>,
+	mut requested: EventReader<AuthorityRequested>,
+	mut transfers: EventWriter<TransferAuthority>,
+) {
+	for request in requested.read() {
+		let grant = match *policy {
+			AuthorityPolicy::Always => true,
+			AuthorityPolicy::IfUnowned => !owners.contains(request.entity),
+			AuthorityPolicy::Manual => false,
+		};
+		if grant {
+			transfers.send(TransferAuthority {
+				entity: request.entity,
+				client_id: Some(request.client_id),
+			});
+		}
+	}
+}
+
+/// Sends each [`RequestAuthority`] to the app that spawned the entity,
+/// or to every peer if the spawner is not relayed.
+fn request_authority(
+	registrations: Res<ReplicateRegistry>,
+	mut requests: EventReader<RequestAuthority>,
+	mut outgoing: ResMut<MessageOutgoing>,
+	mut peer_outgoing: ResMut<PeerOutgoing>,
+) {
+	for request in requests.read() {
+		match registrations.entities.remote(request.entity) {
+			Some(remote) if remote.client_id != DIRECT_CLIENT_ID => {
+				peer_outgoing.push(
+					remote.client_id,
+					Message::AuthorityRequest {
+						entity: remote.entity,
+						origin: EntityOrigin::Receiver,
+					},
+				);
+			}
+			_ => {
+				let (entity, origin) =
+					registrations.entities.outgoing(request.entity);
+				outgoing.push(Message::AuthorityRequest { entity, origin });
+			}
+		}
+	}
+}
+
+/// Applies each [`TransferAuthority`] and notifies peers.
+pub fn transfer_authority(
+	mut commands: Commands,
+	mut transfers: EventReader<TransferAuthority>,
+	spawned: Query<(), (With<Replicate>, Without<RemoteEntity>)>,
+	mut outgoing: ResMut<MessageOutgoing>,
+	mut peer_outgoing: ResMut<PeerOutgoing>,
+) {
+	for TransferAuthority { entity, client_id } in transfers.read() {
+		if !spawned.contains(*entity) {
+			log::warn!(
+				"cannot transfer authority over {entity}, which was not spawned by this app"
+			);
+			continue;
+		}
+		let mut entity_commands = commands.entity(*entity);
+		entity_commands.remove::<Authority>();
+		match client_id {
+			Some(client_id) => {
+				entity_commands.insert(Owner(*client_id));
+			}
+			None => {
+				entity_commands.remove::<Owner>();
+			}
+		}
+		outgoing.push(Message::OwnerChanged {
+			entity: *entity,
+			client_id: *client_id,
+		});
+		if let Some(client_id) = client_id {
+			// sent after the broadcast so it is applied after the owner change
+			peer_outgoing.push(
+				*client_id,
+				Message::AuthorityGranted { entity: *entity },
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct MyComponent(u32);
+
+	/// Send outgoing messages of `from` to `to`, stamped with the sender.
+	fn send(from: &mut App, to: &mut App, sender: ClientId) {
+		let mut messages = vec![Message::Sender { client_id: sender }];
+		messages.extend(
+			from.world_mut().resource_mut::<MessageOutgoing>().drain(..),
+		);
+		for (_, peer_messages) in
+			from.world_mut().resource_mut::<PeerOutgoing>().drain()
+		{
+			messages.extend(peer_messages);
+		}
+		to.world_mut().resource_mut::<MessageIncoming>().0 = messages;
+		to.update();
+	}
+
+	fn value(app: &App, entity: Entity) -> u32 {
+		app.world().get::<MyComponent>(entity).unwrap().0
+	}
+
+	#[test]
+	fn transfer() -> Result<()> {
+		let mut host = App::new();
+		host.add_plugins((ReplicatePlugin, ReplicateAuthorityPlugin))
+			.replicate::<MyComponent>();
+		let mut client = App::new();
+		client
+			.add_plugins((ReplicatePlugin, ReplicateAuthorityPlugin))
+			.replicate::<MyComponent>();
+
+		let host_entity = host
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(0)))
+			.id();
+		host.update();
+		send(&mut host, &mut client, 0);
+		let entity = client
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, host_entity))
+			.unwrap();
+
+		client.world_mut().send_event(RequestAuthority { entity });
+		client.update();
+		send(&mut client, &mut host, 3);
+		expect(host.world().get::<Owner>(host_entity)).to_be(Some(&Owner(3)));
+		send(&mut host, &mut client, 0);
+		expect(client.world().get::<Owner>(entity)).to_be(Some(&Owner(3)));
+		expect(client.world().get::<Authority>(entity).is_some()).to_be_true();
+
+		// the owner's changes are accepted
+		client.world_mut().get_mut::<MyComponent>(entity).unwrap().0 = 7;
+		client.update();
+		send(&mut client, &mut host, 3);
+		expect(value(&host, host_entity)).to_be(7);
+
+		// changes from other clients are rejected
+		client.world_mut().get_mut::<MyComponent>(entity).unwrap().0 = 8;
+		client.update();
+		send(&mut client, &mut host, 4);
+		expect(value(&host, host_entity)).to_be(7);
+
+		// the host no longer sends changes
+		host.world_mut()
+			.get_mut::<MyComponent>(host_entity)
+			.unwrap()
+			.0 = 9;
+		host.update();
+		expect(host.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		// and takes authority back
+		host.world_mut().send_event(TransferAuthority {
+			entity: host_entity,
+			client_id: None,
+		});
+		host.update();
+		send(&mut host, &mut client, 0);
+		expect(client.world().get::<Owner>(entity)).to_be_none();
+		expect(client.world().get::<Authority>(entity)).to_be_none();
+		expect(host.world().get::<Owner>(host_entity)).to_be_none();
+		Ok(())
+	}
+
+	#[test]
+	fn policy() -> Result<()> {
+		let mut host = App::new();
+		host.add_plugins((ReplicatePlugin, ReplicateAuthorityPlugin))
+			.insert_resource(AuthorityPolicy::IfUnowned);
+		let entity = host.world_mut().spawn(Replicate::default()).id();
+		host.update();
+		host.world_mut().resource_mut::<MessageOutgoing>().clear();
+
+		let request = |client_id| {
+			vec![
+				Message::Sender { client_id },
+				Message::AuthorityRequest {
+					entity,
+					origin: EntityOrigin::Receiver,
+				},
+			]
+		};
+		host.world_mut().resource_mut::<MessageIncoming>().0 = request(1);
+		host.update();
+		host.world_mut().resource_mut::<MessageIncoming>().0 = request(2);
+		host.update();
+		expect(host.world().get::<Owner>(entity)).to_be(Some(&Owner(1)));
+		expect(host.world().resource::<MessageOutgoing>().len()).to_be(1);
+		Ok(())
+	}
+
+	#[test]
+	fn three_apps() -> Result<()> {
+		let mut harness = ReplicationTestHarness::new(3, |app| {
+			app.add_plugins((ReplicatePlugin, ReplicateAuthorityPlugin))
+				.replicate::<MyComponent>();
+		});
+		let host_entity = harness
+			.app_mut(1)
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(0)))
+			.id();
+		harness.step_n(2);
+		let on = |harness: &ReplicationTestHarness, client_id| {
+			harness.entity_on(client_id, 1, host_entity).unwrap()
+		};
+
+		let entity = on(&harness, 3);
+		harness
+			.app_mut(3)
+			.world_mut()
+			.send_event(RequestAuthority { entity });
+		harness.step_until(4, |harness| {
+			harness.client_ids().all(|client_id| {
+				harness
+					.app(client_id)
+					.world()
+					.get::<Owner>(on(harness, client_id))
+					== Some(&Owner(3))
+			})
+		})?;
+		expect(harness.app(3).world().get::<Authority>(entity).is_some())
+			.to_be_true();
+		expect(harness.app(2).world().get::<Authority>(on(&harness, 2)))
+			.to_be_none();
+
+		// the owner's changes reach the spawner and the other peer
+		harness
+			.app_mut(3)
+			.world_mut()
+			.get_mut::<MyComponent>(entity)
+			.unwrap()
+			.0 = 7;
+		harness.step_n(2);
+		harness.assert_on_all(&MyComponent(7))?;
+
+		// changes from a peer that is not the owner are rejected
+		let on_2 = on(&harness, 2);
+		let (change_entity, origin) = harness
+			.app(2)
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.outgoing(on_2);
+		let change = Message::Change {
+			reg_id: RegistrationId::new_with(0),
+			entity: change_entity,
+			origin,
+			payload: MessagePayload::new(MyComponent(8))?,
+		};
+		harness
+			.app_mut(2)
+			.world_mut()
+			.resource_mut::<MessageOutgoing>()
+			.push(change);
+		harness.step_n(2);
+		expect(value(harness.app(1), host_entity)).to_be(7);
+		expect(value(harness.app(3), entity)).to_be(7);
+
+		// peers that join later receive the owner
+		let mut app = App::new();
+		app.add_plugins((ReplicatePlugin, ReplicateAuthorityPlugin))
+			.replicate::<MyComponent>();
+		harness.add_app(app);
+		harness.step_n(2);
+		let on_4 = on(&harness, 4);
+		expect(harness.app(4).world().get::<Owner>(on_4))
+			.to_be(Some(&Owner(3)));
+		expect(value(harness.app(4), on_4)).to_be(7);
+		Ok(())
+	}
+}
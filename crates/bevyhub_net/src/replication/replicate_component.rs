@@ -1,53 +1,175 @@
 use crate::prelude::*;
 use anyhow::Result;
+use bevy::ecs::component::Tick;
 use bevy::ecs::system::EntityCommands;
 use bevy::prelude::*;
+use bevy::utils::HashMap;
 use forky::prelude::ResultTEExt;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::any::TypeId;
 
 /// Functions for handling reception of [`Component`] messages.
+/// Each is passed the [`RegistrationId`] of the message so that
+/// type-erased registrations like [`App::replicate_reflect`] can
+/// share a single implementation, and values are passed the sender
+/// to map their entity references, see [`App::replicate_map_entities`].
 #[derive(Copy, Clone)]
 pub struct ComponentFns {
-	// pub type_id: std::any::TypeId,
-	pub insert: fn(&mut EntityCommands, payload: &MessagePayload) -> Result<()>,
-	pub change: fn(&mut EntityCommands, payload: &MessagePayload) -> Result<()>,
-	pub remove: fn(&mut EntityCommands),
+	pub insert: fn(
+		&mut EntityCommands,
+		reg_id: RegistrationId,
+		sender: ClientId,
+		payload: &MessagePayload,
+	) -> Result<()>,
+	pub change: fn(
+		&mut EntityCommands,
+		reg_id: RegistrationId,
+		sender: ClientId,
+		payload: &MessagePayload,
+	) -> Result<()>,
+	/// Apply a [`ByteDelta`] to the current value of the component.
+	pub apply_delta:
+		fn(&mut EntityCommands, reg_id: RegistrationId, delta: &ByteDelta),
+	pub remove: fn(&mut EntityCommands, reg_id: RegistrationId),
 }
 
 impl ComponentFns {
-	pub fn new<T: Component + DeserializeOwned>() -> Self {
+	pub fn new<T: Component + Serialize + DeserializeOwned>() -> Self {
 		Self {
-			insert: |commands, payload| {
-				commands.insert(payload.deserialize::<T>()?);
+			// unreliable changes may arrive before the add,
+			// in which case the change is newer
+			insert: |commands, _, _, payload| {
+				commands.try_insert_if_new(payload.deserialize::<T>()?);
 				Ok(())
 			},
-			change: |commands, payload| {
-				commands.insert(payload.deserialize::<T>()?);
+			change: |commands, _, _, payload| {
+				let value = payload.deserialize::<T>()?;
+				queue_if_exists(commands, move |entity| {
+					set_component(entity, value)
+				});
 				Ok(())
 			},
-			remove: |commands| {
+			apply_delta: |commands, _, delta| {
+				let delta = delta.clone();
+				queue_if_exists(commands, move |entity| {
+					let Some(prev) = entity.get::<T>() else {
+						log::error!(
+							"received delta but component does not exist"
+						);
+						return;
+					};
+					let value = bincode::serialize(prev)
+						.map_err(anyhow::Error::from)
+						.and_then(|prev| delta.apply(&prev))
+						.and_then(|next| Ok(bincode::deserialize::<T>(&next)?));
+					if let Some(value) = value.ok_or(|e| log::error!("{e}")) {
+						set_component(entity, value);
+					}
+				});
+			},
+			remove: |commands, _| {
 				commands.remove::<T>();
 			},
 		}
 	}
 }
 
+/// Like [`EntityCommands::queue`] but does nothing if the entity
+/// was despawned earlier in the same batch.
+pub(crate) fn queue_if_exists(
+	commands: &mut EntityCommands,
+	func: impl 'static + Send + FnOnce(&mut EntityWorldMut),
+) {
+	let entity = commands.id();
+	commands.commands().queue(move |world: &mut World| {
+		if let Ok(mut entity) = world.get_entity_mut(entity) {
+			func(&mut entity);
+		}
+	});
+}
+
+/// Applies received values of `T` to the current value,
+/// see [`App::replicate_patch`].
+#[derive(Resource)]
+pub struct ComponentPatch<T>(pub fn(&mut T, T));
+
+/// Set a received value in place so that only `Changed<T>` is triggered,
+/// not `Added<T>` or the `OnAdd` and `OnInsert` hooks. The component is
+/// inserted if it does not exist yet, and patched if a [`ComponentPatch`]
+/// is registered.
+pub fn set_component<T: Component>(entity: &mut EntityWorldMut, value: T) {
+	let patch = entity
+		.world()
+		.get_resource::<ComponentPatch<T>>()
+		.map(|patch| patch.0);
+	let Some(mut current) = entity.get_mut::<T>() else {
+		// recorded first as the `OnAdd` observers run during the insert
+		let tick = entity.world().read_change_tick();
+		IncomingChanges::record(entity, TypeId::of::<T>(), tick);
+		entity.insert(value);
+		return;
+	};
+	match patch {
+		Some(patch) => patch(&mut current, value),
+		None => *current = value,
+	}
+	let tick = current.last_changed();
+	IncomingChanges::record(entity, TypeId::of::<T>(), tick);
+}
+
+/// The change ticks of components set by incoming messages, so that
+/// with [`ReplicateDirection::Both`] they are not sent back to the peers.
+/// Cleared after the [`MessageOutgoingSet`].
+#[derive(Debug, Default, Resource)]
+pub struct IncomingChanges(HashMap<(Entity, TypeId), Tick>);
+
+impl IncomingChanges {
+	/// Record a component of the entity as changed by an incoming message.
+	pub fn record(entity: &mut EntityWorldMut, type_id: TypeId, tick: Tick) {
+		let id = entity.id();
+		entity.world_scope(|world| {
+			if let Some(mut changes) = world.get_resource_mut::<Self>() {
+				changes.0.insert((id, type_id), tick);
+			}
+		});
+	}
+
+	/// Whether the last change to a component was an incoming message.
+	pub fn is_echo(&self, entity: Entity, type_id: TypeId, tick: Tick) -> bool {
+		self.0.get(&(entity, type_id)) == Some(&tick)
+	}
+
+	pub fn clear(&mut self) { self.0.clear(); }
+}
+
 fn outgoing_add<T: Component + Serialize>(
 	trigger: Trigger<OnAdd, T>,
 	registrations: Res<ReplicateRegistry>,
+	incoming: Res<IncomingChanges>,
 	mut outgoing: ResMut<MessageOutgoing>,
-	query: Query<&T, With<Replicate>>,
+	query: Query<Ref<T>, (With<Replicate>, HasAuthority)>,
 ) {
 	if let Ok(component) = query.get(trigger.entity()) {
-		let Some(payload) =
-			MessagePayload::new(component).ok_or(|e| log::error!("{e}"))
+		if incoming.is_echo(
+			trigger.entity(),
+			TypeId::of::<T>(),
+			component.last_changed(),
+		) {
+			return;
+		}
+		let Some(payload) = registrations
+			.outgoing_payload(component.into_inner())
+			.ok_or(|e| log::error!("{e}"))
 		else {
 			return;
 		};
+		let (entity, origin) =
+			registrations.entities.outgoing(trigger.entity());
 		outgoing.push(
 			Message::Add {
-				entity: trigger.entity(),
+				entity,
+				origin,
 				reg_id: registrations.registration_id::<T>(),
 				payload,
 			}
@@ -61,22 +183,34 @@ fn outgoing_add<T: Component + Serialize>(
 /// This is a system because currently no `OnChange` trigger exists
 fn outgoing_change<T: Component + Serialize>(
 	registrations: Res<ReplicateRegistry>,
+	incoming: Res<IncomingChanges>,
 	mut outgoing: ResMut<MessageOutgoing>,
-	query: Query<(Entity, Ref<T>), (Changed<T>, With<Replicate>)>,
+	query: Query<
+		(Entity, Ref<T>),
+		(Changed<T>, With<Replicate>, HasAuthority),
+	>,
 ) {
 	for (entity, component) in query.iter() {
-		if component.is_added() {
+		if component.is_added()
+			|| incoming.is_echo(
+				entity,
+				TypeId::of::<T>(),
+				component.last_changed(),
+			) {
 			continue;
 		}
-		let Some(payload) = MessagePayload::new(component.into_inner())
+		let Some(payload) = registrations
+			.outgoing_payload(component.into_inner())
 			.ok_or(|e| log::error!("{e}"))
 		else {
 			continue;
 		};
 
+		let (entity, origin) = registrations.entities.outgoing(entity);
 		outgoing.push(
 			Message::Change {
 				entity,
+				origin,
 				reg_id: registrations.registration_id::<T>(),
 				payload,
 			}
@@ -90,12 +224,15 @@ fn outgoing_remove<T: Component>(
 	trigger: Trigger<OnRemove, T>,
 	registrations: Res<ReplicateRegistry>,
 	mut outgoing: ResMut<MessageOutgoing>,
-	query: Query<(), With<Replicate>>,
+	query: Query<(), (With<Replicate>, HasAuthority)>,
 ) {
 	if query.contains(trigger.entity()) {
+		let (entity, origin) =
+			registrations.entities.outgoing(trigger.entity());
 		outgoing.push(
 			Message::Remove {
-				entity: trigger.entity(),
+				entity,
+				origin,
 				reg_id: registrations.registration_id::<T>(),
 			}
 			.into(),
@@ -105,6 +242,15 @@ fn outgoing_remove<T: Component>(
 
 pub fn register_component_outgoing<T: Component + Serialize>(app: &mut App) {
 	app.add_systems(Update, outgoing_change::<T>.in_set(MessageOutgoingSet));
+	register_component_outgoing_add_remove::<T>(app);
+	register_component_snapshot::<T>(app);
+}
+
+pub(crate) fn register_component_outgoing_add_remove<
+	T: Component + Serialize,
+>(
+	app: &mut App,
+) {
 	app.world_mut().add_observer(outgoing_add::<T>);
 	app.world_mut().add_observer(outgoing_remove::<T>);
 }
@@ -148,6 +294,7 @@ mod test {
 		expect(&msg_out[1]).to_be(
 			&Message::Add {
 				entity,
+				origin: EntityOrigin::Sender,
 				reg_id: RegistrationId::new_with(0),
 				payload: MessagePayload::new(&MyComponent(7))?,
 			}
@@ -156,12 +303,19 @@ mod test {
 		expect(&msg_out[2]).to_be(
 			&Message::Change {
 				entity,
+				origin: EntityOrigin::Sender,
 				reg_id: RegistrationId::new_with(0),
 				payload: MessagePayload::new(&MyComponent(8))?,
 			}
 			.into(),
 		);
-		expect(&msg_out[3]).to_be(&Message::Despawn { entity }.into());
+		expect(&msg_out[3]).to_be(
+			&Message::Despawn {
+				entity,
+				origin: EntityOrigin::Sender,
+			}
+			.into(),
+		);
 
 		Ok(())
 	}
@@ -225,4 +379,374 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	fn round_trip() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+
+		// different entity layouts
+		app2.world_mut().spawn_empty();
+		app2.world_mut().spawn(MyComponent(0));
+
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let entity2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, entity1))
+			.unwrap();
+		expect(app2.world().get::<MyComponent>(entity2))
+			.to_be(Some(&MyComponent(7)));
+
+		// CHANGE from app2
+		app2.world_mut()
+			.entity_mut(entity2)
+			.insert((Replicate::default(), MyComponent(8)));
+		app2.update();
+		Message::loopback(app2.world_mut(), app1.world_mut());
+		expect(&app1.world().resource::<MessageIncoming>()[0]).to_be(
+			&Message::Change {
+				entity: entity1,
+				origin: EntityOrigin::Receiver,
+				reg_id: RegistrationId::new_with(0),
+				payload: MessagePayload::new(MyComponent(8))?,
+			},
+		);
+		app1.update();
+		expect(app1.world().get::<MyComponent>(entity1))
+			.to_be(Some(&MyComponent(8)));
+
+		// REMOVE from app2
+		app2.world_mut().entity_mut(entity2).remove::<MyComponent>();
+		app2.update();
+		Message::loopback(app2.world_mut(), app1.world_mut());
+		app1.update();
+		expect(app1.world().get::<MyComponent>(entity1)).to_be_none();
+
+		Ok(())
+	}
+
+	#[test]
+	fn change_before_add() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		let entity = Entity::from_raw(5);
+		let reg_id = RegistrationId::new_with(0);
+		// an unreliable change overtook the add
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			Message::Spawn { entity },
+			Message::Change {
+				entity,
+				origin: EntityOrigin::Sender,
+				reg_id,
+				payload: MessagePayload::new(MyComponent(8))?,
+			},
+			Message::Add {
+				entity,
+				origin: EntityOrigin::Sender,
+				reg_id,
+				payload: MessagePayload::new(MyComponent(7))?,
+			},
+		];
+		app.update();
+		let local = app
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(1, entity))
+			.unwrap();
+		expect(app.world().get::<MyComponent>(local))
+			.to_be(Some(&MyComponent(8)));
+		Ok(())
+	}
+
+	#[derive(
+		Debug,
+		Clone,
+		PartialEq,
+		Component,
+		Reflect,
+		Serialize,
+		Deserialize,
+	)]
+	struct Health {
+		value: i32,
+		/// Only used locally.
+		#[reflect(ignore)]
+		#[serde(skip)]
+		flash: bool,
+	}
+
+	#[test]
+	fn patch() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin).replicate::<Health>();
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin)
+			.replicate::<Health>()
+			.replicate_patch_reflect::<Health>();
+
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), Health {
+				value: 7,
+				flash: false,
+			}))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		let entity2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, entity1))
+			.unwrap();
+		app2.world_mut().get_mut::<Health>(entity2).unwrap().flash = true;
+
+		app1.world_mut().get_mut::<Health>(entity1).unwrap().value = 8;
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(app2.world().get::<Health>(entity2)).to_be(Some(&Health {
+			value: 8,
+			flash: true,
+		}));
+		Ok(())
+	}
+
+	#[derive(Default, Resource)]
+	struct Inserts(usize);
+
+	#[test]
+	fn change_in_place() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin)
+			.replicate::<MyComponent>()
+			.init_resource::<Inserts>();
+		app2.world_mut().add_observer(
+			|_: Trigger<OnInsert, MyComponent>, mut inserts: ResMut<Inserts>| {
+				inserts.0 += 1;
+			},
+		);
+
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(app2.world().resource::<Inserts>().0).to_be(1);
+
+		app1.world_mut().entity_mut(entity1).insert(MyComponent(8));
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(
+			app2.world_mut()
+				.query::<&MyComponent>()
+				.iter(app2.world())
+				.next(),
+		)
+		.as_some()
+		.to_be(&MyComponent(8));
+		// the hooks do not run again
+		expect(app2.world().resource::<Inserts>().0).to_be(1);
+
+		Ok(())
+	}
+
+	#[test]
+	fn no_echo() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		let entity2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, entity1))
+			.unwrap();
+		app2.world_mut()
+			.entity_mut(entity2)
+			.insert(Replicate::default());
+		app2.update();
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		// both apps replicate the entity in both directions
+		app1.world_mut().entity_mut(entity1).insert(MyComponent(8));
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(app2.world().get::<MyComponent>(entity2))
+			.to_be(Some(&MyComponent(8)));
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		// local changes are still sent
+		app2.world_mut()
+			.get_mut::<MyComponent>(entity2)
+			.unwrap()
+			.0 = 9;
+		app2.update();
+		Message::loopback(app2.world_mut(), app1.world_mut());
+		app1.update();
+		expect(app1.world().get::<MyComponent>(entity1))
+			.to_be(Some(&MyComponent(9)));
+		expect(app1.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		// a change received before the add is not sent back as an add
+		app2.world_mut().entity_mut(entity2).remove::<MyComponent>();
+		app2.update();
+		app2.world_mut().resource_mut::<MessageOutgoing>().clear();
+		app2.world_mut().resource_mut::<MessageIncoming>().0 =
+			vec![Message::Change {
+				entity: entity1,
+				origin: EntityOrigin::Sender,
+				reg_id: RegistrationId::new_with(0),
+				payload: MessagePayload::new(MyComponent(10))?,
+			}];
+		app2.update();
+		expect(app2.world().get::<MyComponent>(entity2))
+			.to_be(Some(&MyComponent(10)));
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn multiple_clients() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+
+		// two clients with the same entity id
+		let entity = Entity::from_raw(5);
+		let reg_id = RegistrationId::new_with(0);
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			Message::Spawn { entity },
+			Message::Add {
+				entity,
+				origin: EntityOrigin::Sender,
+				reg_id,
+				payload: MessagePayload::new(MyComponent(1))?,
+			},
+			Message::Sender { client_id: 2 },
+			Message::Spawn { entity },
+			Message::Add {
+				entity,
+				origin: EntityOrigin::Sender,
+				reg_id,
+				payload: MessagePayload::new(MyComponent(2))?,
+			},
+		];
+		app.update();
+
+		let registry = app.world().resource::<ReplicateRegistry>();
+		expect(registry.entities.len()).to_be(2);
+		let local1 = registry.entities.local(RemoteEntity::new(1, entity));
+		let local2 = registry.entities.local(RemoteEntity::new(2, entity));
+		expect(local1).not().to_be(local2);
+		expect(app.world().get::<MyComponent>(local1.unwrap()))
+			.to_be(Some(&MyComponent(1)));
+		expect(app.world().get::<MyComponent>(local2.unwrap()))
+			.to_be(Some(&MyComponent(2)));
+
+		// despawning one leaves the other
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			Message::Despawn {
+				entity,
+				origin: EntityOrigin::Sender,
+			},
+		];
+		app.update();
+		expect(app.world().get_entity(local1.unwrap()).is_err()).to_be_true();
+		expect(app.world().get::<MyComponent>(local2.unwrap()))
+			.to_be(Some(&MyComponent(2)));
+		expect(app.world().resource::<ReplicateRegistry>().entities.len())
+			.to_be(1);
+
+		Ok(())
+	}
+
+	#[test]
+	fn same_ids() -> Result<()> {
+		let mut harness = ReplicationTestHarness::new(2, |app| {
+			app.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		});
+		let spawn = |harness: &mut ReplicationTestHarness, client_id, value| {
+			harness
+				.app_mut(client_id)
+				.world_mut()
+				.spawn((Replicate::default(), MyComponent(value)))
+				.id()
+		};
+		// both apps spawn the same entity id
+		let entity = spawn(&mut harness, 1, 1);
+		expect(spawn(&mut harness, 2, 2)).to_be(entity);
+		harness.step_n(2);
+		harness.assert_count_on_all::<MyComponent>(2)?;
+
+		let set = |harness: &mut ReplicationTestHarness,
+		           client_id,
+		           entity,
+		           value| {
+			harness
+				.app_mut(client_id)
+				.world_mut()
+				.get_mut::<MyComponent>(entity)
+				.unwrap()
+				.0 = value;
+		};
+		let get = |harness: &ReplicationTestHarness, client_id, entity| {
+			harness
+				.app(client_id)
+				.world()
+				.get::<MyComponent>(entity)
+				.cloned()
+		};
+		// the second app changes its copy of the first app's entity
+		let mirror = harness.entity_on(2, 1, entity).unwrap();
+		harness
+			.app_mut(2)
+			.world_mut()
+			.entity_mut(mirror)
+			.insert(Replicate::default());
+		set(&mut harness, 2, mirror, 3);
+		harness.step_n(2);
+		expect(get(&harness, 1, entity)).to_be(Some(MyComponent(3)));
+		expect(get(&harness, 2, entity)).to_be(Some(MyComponent(2)));
+		let on_1 = harness.entity_on(1, 2, entity).unwrap();
+		expect(get(&harness, 1, on_1)).to_be(Some(MyComponent(2)));
+
+		// and the first app changes its own entity
+		set(&mut harness, 1, entity, 4);
+		harness.step_n(2);
+		expect(get(&harness, 2, mirror)).to_be(Some(MyComponent(4)));
+		expect(get(&harness, 2, entity)).to_be(Some(MyComponent(2)));
+		Ok(())
+	}
 }
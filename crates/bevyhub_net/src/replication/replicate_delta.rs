@@ -0,0 +1,470 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use forky::prelude::ResultTEExt;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::TypeId;
+
+/// Runs of unchanged bytes shorter than this are merged into the
+/// surrounding changes, roughly the serialized size of a run header.
+const MIN_GAP: usize = 12;
+
+/// Deltas sent for an entity before a full [`Message::Change`] is sent
+/// instead, so that receivers which dropped a delta catch up.
+pub const DELTA_FULL_CHANGE_INTERVAL: u32 = 30;
+
+/// A byte level diff between two serialized values of equal length.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ByteDelta {
+	/// Hash of the value the diff was made against,
+	/// a delta is only applied to the same value.
+	pub baseline: u64,
+	/// Offset and replacement bytes for each changed run.
+	pub runs: Vec<(u32, Vec<u8>)>,
+}
+
+impl ByteDelta {
+	/// Diff two buffers, returns `None` if their lengths differ.
+	pub fn new(prev: &[u8], next: &[u8]) -> Option<Self> {
+		if prev.len() != next.len() {
+			return None;
+		}
+		let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+		let mut run_end = 0;
+		for (index, (a, b)) in prev.iter().zip(next.iter()).enumerate() {
+			if a == b {
+				continue;
+			}
+			match runs.last_mut() {
+				Some((_, bytes)) if index - run_end < MIN_GAP => {
+					bytes.extend_from_slice(&next[run_end..=index]);
+				}
+				_ => runs.push((index as u32, vec![*b])),
+			}
+			run_end = index + 1;
+		}
+		Some(Self {
+			baseline: Self::hash(prev),
+			runs,
+		})
+	}
+
+	/// A hash of a serialized value that is stable across platforms.
+	pub fn hash(bytes: &[u8]) -> u64 {
+		// FNV-1a
+		bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+			(hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+		})
+	}
+
+	pub fn is_empty(&self) -> bool { self.runs.is_empty() }
+
+	/// Apply the diff to a copy of the previous buffer.
+	/// # Errors
+	/// If the previous buffer is not the baseline, ie a delta was dropped
+	/// or the value changed locally, or a run is out of bounds.
+	pub fn apply(&self, prev: &[u8]) -> Result<Vec<u8>> {
+		if Self::hash(prev) != self.baseline {
+			anyhow::bail!(
+				"delta baseline does not match, waiting for a full change"
+			);
+		}
+		let mut next = prev.to_vec();
+		for (offset, bytes) in self.runs.iter() {
+			let start = *offset as usize;
+			let end = start + bytes.len();
+			if end > next.len() {
+				anyhow::bail!(
+					"delta run {start}..{end} is out of bounds for length {}",
+					next.len()
+				);
+			}
+			next[start..end].copy_from_slice(bytes);
+		}
+		Ok(next)
+	}
+}
+
+/// The full value of a [`Message::ChangeDelta`], only converted
+/// when the batch is encoded for json or msgpack consumers.
+#[derive(Clone)]
+pub struct DeltaValue {
+	bytes: Vec<u8>,
+	into_json: fn(&[u8]) -> Result<MessagePayload>,
+}
+
+impl DeltaValue {
+	pub fn new<T: Serialize + DeserializeOwned>(bytes: Vec<u8>) -> Self {
+		Self {
+			bytes,
+			into_json: |bytes| {
+				let value = bincode::deserialize::<T>(bytes)?;
+				MessagePayload::new(value)?.into_json()
+			},
+		}
+	}
+
+	/// The value as a [`MessagePayload::Json`].
+	pub fn into_json(&self) -> Result<MessagePayload> {
+		(self.into_json)(&self.bytes)
+	}
+}
+
+impl std::fmt::Debug for DeltaValue {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("DeltaValue")
+			.field("bytes", &self.bytes)
+			.finish()
+	}
+}
+
+impl PartialEq for DeltaValue {
+	fn eq(&self, other: &Self) -> bool { self.bytes == other.bytes }
+}
+
+/// The last value sent for an entity, and the number of deltas
+/// sent since the last full change.
+struct SentValue {
+	bytes: Vec<u8>,
+	deltas: u32,
+}
+
+/// Sends a [`Message::ChangeDelta`] against the last sent value,
+/// or a full [`Message::Change`] if the delta would be no smaller.
+/// Both are sent reliably so the last sent value is the one receivers
+/// apply the delta to, see [`ReplicateRegistry::delta_types`].
+/// Receivers drop deltas against another value than their own, ie
+/// after a local change, until the next full change which is sent
+/// at least every [`DELTA_FULL_CHANGE_INTERVAL`] deltas.
+fn outgoing_change_delta<T: Component + Serialize + DeserializeOwned>(
+	registrations: Res<ReplicateRegistry>,
+	incoming: Res<IncomingChanges>,
+	mut outgoing: ResMut<MessageOutgoing>,
+	mut sent: Local<HashMap<Entity, SentValue>>,
+	query: Query<(Entity, Ref<T>), (Changed<T>, With<Replicate>)>,
+	authority: Query<(), HasAuthority>,
+	mut removed: RemovedComponents<T>,
+) {
+	for entity in removed.read() {
+		sent.remove(&entity);
+	}
+	for (entity, component) in query.iter() {
+		let Some(bytes) =
+			bincode::serialize(&*component).ok_or(|e| log::error!("{e}"))
+		else {
+			continue;
+		};
+		let echo = incoming.is_echo(
+			entity,
+			TypeId::of::<T>(),
+			component.last_changed(),
+		);
+		if component.is_added() || echo || !authority.contains(entity) {
+			// the add message is the first baseline, and changes
+			// received from peers are what they already have
+			sent.insert(entity, SentValue { bytes, deltas: 0 });
+			continue;
+		}
+		let reg_id = registrations.registration_id::<T>();
+		let (remote, origin) = registrations.entities.outgoing(entity);
+
+		let prev = sent.get(&entity);
+		let mut deltas =
+			prev.map(|prev| prev.deltas + 1).unwrap_or_default();
+		let delta = prev
+			.filter(|_| deltas <= DELTA_FULL_CHANGE_INTERVAL)
+			.and_then(|prev| ByteDelta::new(&prev.bytes, &bytes))
+			.filter(|delta| {
+				bincode::serialized_size(delta)
+					.map(|size| (size as usize) < bytes.len())
+					.unwrap_or(false)
+			});
+
+		let message = if let Some(delta) = delta {
+			if delta.is_empty() {
+				continue;
+			}
+			Message::ChangeDelta {
+				entity: remote,
+				origin,
+				reg_id,
+				delta,
+				payload: Some(DeltaValue::new::<T>(bytes.clone())),
+			}
+		} else {
+			let Some(payload) = registrations
+				.outgoing_payload(component.into_inner())
+				.ok_or(|e| log::error!("{e}"))
+			else {
+				continue;
+			};
+			deltas = 0;
+			Message::Change {
+				entity: remote,
+				origin,
+				reg_id,
+				payload,
+			}
+		};
+		sent.insert(entity, SentValue { bytes, deltas });
+		outgoing.push(message);
+	}
+}
+
+/// Like [`register_component_outgoing`] but changes are sent as
+/// [`Message::ChangeDelta`] where possible.
+pub fn register_component_outgoing_delta<
+	T: Component + Serialize + DeserializeOwned,
+>(
+	app: &mut App,
+) {
+	app.add_systems(
+		Update,
+		outgoing_change_delta::<T>.in_set(MessageOutgoingSet),
+	);
+	register_component_outgoing_add_remove::<T>(app);
+	register_component_snapshot::<T>(app);
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, Component, Serialize, Deserialize, PartialEq)]
+	pub struct MyTransform {
+		translation: [f32; 3],
+		rotation: [f32; 4],
+		scale: [f32; 3],
+	}
+
+	impl Default for MyTransform {
+		fn default() -> Self {
+			Self {
+				translation: [0.; 3],
+				rotation: [0., 0., 0., 1.],
+				scale: [1.; 3],
+			}
+		}
+	}
+
+	#[test]
+	fn byte_delta() -> Result<()> {
+		let prev = (0..20).collect::<Vec<u8>>();
+		let mut next = prev.clone();
+		next[1] = 100;
+		next[3] = 101;
+		next[19] = 102;
+
+		let delta = ByteDelta::new(&prev, &next).unwrap();
+		expect(&delta.runs)
+			.to_be(&vec![(1, vec![100, 2, 101]), (19, vec![102])]);
+		expect(delta.apply(&prev)?).to_be(next.clone());
+		expect(ByteDelta::new(&prev, &prev).unwrap().is_empty()).to_be_true();
+		expect(ByteDelta::new(&prev, &[0])).to_be_none();
+		expect(delta.apply(&[0])).to_be_err();
+		// another value of the same length
+		expect(delta.apply(&next)).to_be_err_str(
+			"delta baseline does not match, waiting for a full change",
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn incoming() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin)
+			.replicate_delta::<MyTransform>();
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin)
+			.replicate_delta::<MyTransform>();
+
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), MyTransform::default()))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		app1.world_mut()
+			.get_mut::<MyTransform>(entity1)
+			.unwrap()
+			.translation[0] = 7.;
+		app1.update();
+
+		let msg_out = app1.world().resource::<MessageOutgoing>();
+		expect(msg_out.len()).to_be(1);
+		let Message::ChangeDelta { delta, .. } = &msg_out[0] else {
+			panic!("expected delta");
+		};
+		expect(delta.runs.len()).to_be(1);
+
+		// converts to bytes and back
+		let bytes = Message::vec_into_bytes(msg_out)?;
+		app1.world_mut().resource_mut::<MessageOutgoing>().0 =
+			Message::vec_from_bytes(&bytes)?;
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let transform = app2
+			.world_mut()
+			.query::<&MyTransform>()
+			.single(app2.world())
+			.clone();
+		expect(transform).to_be(MyTransform {
+			translation: [7., 0., 0.],
+			..default()
+		});
+
+		Ok(())
+	}
+
+	#[test]
+	fn dropped_delta() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin)
+			.replicate_delta::<MyTransform>();
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin)
+			.replicate_delta::<MyTransform>();
+
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), MyTransform::default()))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let set = |app1: &mut App, value: f32| {
+			app1.world_mut()
+				.get_mut::<MyTransform>(entity1)
+				.unwrap()
+				.translation[0] = value;
+			app1.update();
+		};
+		let get = |app2: &mut App| {
+			app2.world_mut()
+				.query::<&MyTransform>()
+				.single(app2.world())
+				.translation[0]
+		};
+		set(&mut app1, 1.);
+		app1.world_mut().resource_mut::<MessageOutgoing>().clear();
+		set(&mut app1, 2.);
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		// the delta against the dropped value is not applied
+		expect(get(&mut app2)).to_be(0.);
+
+		let mut num_full = 0;
+		for value in 0..DELTA_FULL_CHANGE_INTERVAL {
+			set(&mut app1, value as f32 + 3.);
+			num_full += app1
+				.world()
+				.resource::<MessageOutgoing>()
+				.iter()
+				.filter(|msg| matches!(msg, Message::Change { .. }))
+				.count();
+			Message::loopback(app1.world_mut(), app2.world_mut());
+			app2.update();
+		}
+		expect(num_full).to_be(1);
+		expect(get(&mut app2)).to_be(DELTA_FULL_CHANGE_INTERVAL as f32 + 2.);
+		Ok(())
+	}
+
+	#[test]
+	fn smaller_than_full() -> Result<()> {
+		fn encoded_len(delta: bool) -> Result<usize> {
+			let mut app = App::new();
+			app.add_plugins(ReplicatePlugin);
+			if delta {
+				app.replicate_delta::<MyTransform>();
+			} else {
+				app.replicate::<MyTransform>();
+			}
+			for _ in 0..100 {
+				app.world_mut()
+					.spawn((Replicate::default(), MyTransform::default()));
+			}
+			app.update();
+			app.world_mut().resource_mut::<MessageOutgoing>().clear();
+			for mut transform in app
+				.world_mut()
+				.query::<&mut MyTransform>()
+				.iter_mut(app.world_mut())
+			{
+				transform.translation[0] += 1.;
+			}
+			app.update();
+			let msg_out = app.world().resource::<MessageOutgoing>();
+			expect(msg_out.len()).to_be(100);
+			Ok(Message::vec_into_bytes(msg_out)?.len())
+		}
+		expect(encoded_len(false)?).to_be_greater_than(encoded_len(true)?);
+		Ok(())
+	}
+
+	#[test]
+	fn reliable() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin)
+			.replicate_delta::<MyTransform>()
+			.replicate_channel::<MyTransform>(Channel::UNRELIABLE);
+		let change = Message::Change {
+			entity: Entity::PLACEHOLDER,
+			origin: EntityOrigin::Sender,
+			reg_id: RegistrationId::new_with(0),
+			payload: MessagePayload::new(MyTransform::default())?,
+		};
+		let registry = app.world().resource::<ReplicateRegistry>();
+		expect(registry.channel(&change)).to_be(Channel::RELIABLE);
+		expect(registry.channel_or(&change, Channel::SEQUENCED))
+			.to_be(Channel::RELIABLE);
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "serde_json")]
+	fn json() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin)
+			.replicate_delta::<MyTransform>();
+		let entity = app
+			.world_mut()
+			.spawn((Replicate::default(), MyTransform::default()))
+			.id();
+		app.update();
+		app.world_mut().resource_mut::<MessageOutgoing>().clear();
+		app.world_mut()
+			.get_mut::<MyTransform>(entity)
+			.unwrap()
+			.translation[0] = 7.;
+		app.update();
+
+		let msg_out = app.world().resource::<MessageOutgoing>();
+		let messages =
+			Message::vec_from_json(&Message::vec_into_json(msg_out)?)?;
+		// json consumers receive the full value
+		expect(&messages[0]).to_be(&Message::Change {
+			entity,
+			origin: EntityOrigin::Sender,
+			reg_id: RegistrationId::new_with(0),
+			payload: MessagePayload::new(MyTransform {
+				translation: [7., 0., 0.],
+				..default()
+			})?
+			.into_json()?,
+		});
+		Ok(())
+	}
+}
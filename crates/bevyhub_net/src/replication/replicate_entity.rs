@@ -1,13 +1,188 @@
 use crate::prelude::*;
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::world::DeferredWorld;
 use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use serde::Serialize;
 
 
 pub struct ReplicateEntityPlugin;
 
+/// Added to entities spawned by an incoming [`Message::Spawn`],
+/// storing the client that sent it and the id of the entity in that client.
+/// Removing this component, usually by despawning, also removes
+/// the entity from the [`EntityMap`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Component)]
+#[component(on_remove = on_remove_remote_entity)]
+pub struct RemoteEntity {
+	pub client_id: ClientId,
+	pub entity: Entity,
+}
+
+impl RemoteEntity {
+	pub fn new(client_id: ClientId, entity: Entity) -> Self {
+		Self { client_id, entity }
+	}
+}
+
+/// Which app spawned the entity of a message, so that entities with the
+/// same id in different apps are never confused.
+#[derive(
+	Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub enum EntityOrigin {
+	/// Spawned by the sender of the message.
+	#[default]
+	Sender,
+	/// Spawned by the receiver, used by peers connected without a relay.
+	Receiver,
+	/// Spawned by this client of the relay, which may be the receiver.
+	Client(ClientId),
+}
+
+impl EntityOrigin {
+	/// The client that spawned the entity of a message received from
+	/// `sender`, or `None` if it was spawned by this app.
+	pub fn spawner(
+		&self,
+		sender: ClientId,
+		local: LocalClientId,
+	) -> Option<ClientId> {
+		match self {
+			Self::Sender => Some(sender),
+			Self::Receiver => None,
+			Self::Client(client_id) if Some(*client_id) == *local => None,
+			Self::Client(client_id) => Some(*client_id),
+		}
+	}
+}
+
+fn on_remove_remote_entity(
+	mut world: DeferredWorld,
+	entity: Entity,
+	_: ComponentId,
+) {
+	// deferred so that outgoing observers can still translate the entity
+	world.commands().queue(move |world: &mut World| {
+		if let Some(mut registry) =
+			world.get_resource_mut::<ReplicateRegistry>()
+		{
+			registry.entities.remove_local(entity);
+			registry.removed.retain(|(removed, _)| *removed != entity);
+		}
+	});
+}
+
+/// Bidirectional map of remote to local entity ids.
+/// Remote entities are namespaced by the client that spawned them.
+#[derive(Debug, Default, Clone)]
+pub struct EntityMap {
+	remote_to_local: HashMap<RemoteEntity, Entity>,
+	local_to_remote: HashMap<Entity, RemoteEntity>,
+}
+
+impl EntityMap {
+	pub fn insert(&mut self, remote: RemoteEntity, local: Entity) {
+		if let Some(prev) = self.remote_to_local.insert(remote, local) {
+			if prev != local {
+				self.local_to_remote.remove(&prev);
+			}
+		}
+		if let Some(prev) = self.local_to_remote.insert(local, remote) {
+			if prev != remote {
+				self.remote_to_local.remove(&prev);
+			}
+		}
+	}
+
+	/// Get the local entity for a remote entity.
+	pub fn local(&self, remote: RemoteEntity) -> Option<Entity> {
+		self.remote_to_local.get(&remote).copied()
+	}
+
+	/// Get the remote entity for a local entity.
+	pub fn remote(&self, local: Entity) -> Option<RemoteEntity> {
+		self.local_to_remote.get(&local).copied()
+	}
+
+	/// The id and origin an outgoing message should use for this entity,
+	/// ie the remote id if it was spawned by a peer, otherwise the local id.
+	pub fn outgoing(&self, local: Entity) -> (Entity, EntityOrigin) {
+		match self.remote(local) {
+			Some(remote) if remote.client_id == DIRECT_CLIENT_ID => {
+				(remote.entity, EntityOrigin::Receiver)
+			}
+			Some(remote) => {
+				(remote.entity, EntityOrigin::Client(remote.client_id))
+			}
+			None => (local, EntityOrigin::Sender),
+		}
+	}
+
+	/// The local entity of an incoming message's entity. Entities
+	/// spawned by this app are only resolved if `is_own` returns true,
+	/// ie they are still replicated.
+	pub fn incoming(
+		&self,
+		sender: ClientId,
+		local: LocalClientId,
+		entity: Entity,
+		origin: EntityOrigin,
+		is_own: impl FnOnce(Entity) -> bool,
+	) -> Option<Entity> {
+		match origin.spawner(sender, local) {
+			Some(client_id) => self.local(RemoteEntity::new(client_id, entity)),
+			None => is_own(entity).then_some(entity),
+		}
+	}
+
+	/// Remove a mapping by its remote entity, returning the local entity.
+	pub fn remove_remote(&mut self, remote: RemoteEntity) -> Option<Entity> {
+		let local = self.remote_to_local.remove(&remote)?;
+		self.local_to_remote.remove(&local);
+		Some(local)
+	}
+
+	/// Remove a mapping by its local entity, returning the remote entity.
+	pub fn remove_local(&mut self, local: Entity) -> Option<RemoteEntity> {
+		let remote = self.local_to_remote.remove(&local)?;
+		self.remote_to_local.remove(&remote);
+		Some(remote)
+	}
+
+	pub fn len(&self) -> usize { self.remote_to_local.len() }
+	pub fn is_empty(&self) -> bool { self.remote_to_local.is_empty() }
+}
+
+/// Despawn the entities spawned by peers that disconnected,
+/// the [`EntityMap`] is cleaned up by the [`RemoteEntity`] hook.
+pub fn despawn_disconnected(
+	mut commands: Commands,
+	mut events: EventReader<PeerEvent>,
+	remote: Query<(Entity, &RemoteEntity)>,
+) {
+	for event in events.read() {
+		let PeerEvent::Disconnected(client_id) = event else {
+			continue;
+		};
+		for (entity, remote) in remote.iter() {
+			if remote.client_id == *client_id {
+				commands.entity(entity).despawn();
+			}
+		}
+	}
+}
+
 pub fn outgoing_spawn(
 	trigger: Trigger<OnAdd, Replicate>,
+	registrations: Res<ReplicateRegistry>,
 	mut outgoing: ResMut<MessageOutgoing>,
 ) {
+	if registrations.entities.remote(trigger.entity()).is_some() {
+		// the peer already has this entity
+		return;
+	}
 	outgoing.push(
 		Message::Spawn {
 			entity: trigger.entity(),
@@ -18,14 +193,11 @@ pub fn outgoing_spawn(
 
 pub fn outgoing_despawn(
 	trigger: Trigger<OnRemove, Replicate>,
+	registrations: Res<ReplicateRegistry>,
 	mut outgoing: ResMut<MessageOutgoing>,
 ) {
-	outgoing.push(
-		Message::Despawn {
-			entity: trigger.entity(),
-		}
-		.into(),
-	);
+	let (entity, origin) = registrations.entities.outgoing(trigger.entity());
+	outgoing.push(Message::Despawn { entity, origin }.into());
 }
 
 // pub fn handle_entity_outgoing(
@@ -66,7 +238,13 @@ mod test {
 		let events = app.world_mut().resource_mut::<MessageOutgoing>();
 		expect(events.len()).to_be(2);
 		expect(&events[0]).to_be(&Message::Spawn { entity }.into());
-		expect(&events[1]).to_be(&Message::Despawn { entity }.into());
+		expect(&events[1]).to_be(
+			&Message::Despawn {
+				entity,
+				origin: EntityOrigin::Sender,
+			}
+			.into(),
+		);
 
 		Ok(())
 	}
@@ -99,4 +277,178 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	fn despawn() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin);
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin);
+
+		// different entity layouts
+		let dummy1 = app2.world_mut().spawn_empty().id();
+		let dummy2 = app2.world_mut().spawn_empty().id();
+		let entity1 = app1.world_mut().spawn(Replicate::default()).id();
+
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let entity2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, entity1))
+			.unwrap();
+		expect(entity2).not().to_be(entity1);
+		expect(app2.world().get::<RemoteEntity>(entity2))
+			.to_be(Some(&RemoteEntity::new(0, entity1)));
+		expect(
+			app2.world()
+				.resource::<ReplicateRegistry>()
+				.entities
+				.remote(entity2),
+		)
+		.to_be(Some(RemoteEntity::new(0, entity1)));
+
+		app1.world_mut().despawn(entity1);
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		expect(app2.world().get_entity(entity2).is_err()).to_be_true();
+		expect(app2.world().get_entity(dummy1).is_ok()).to_be_true();
+		expect(app2.world().get_entity(dummy2).is_ok()).to_be_true();
+		expect(app2.world().resource::<ReplicateRegistry>().entities.len())
+			.to_be(0);
+
+		Ok(())
+	}
+
+	#[test]
+	fn despawn_local() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin);
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin);
+
+		app2.world_mut().spawn_empty();
+		let entity1 = app1.world_mut().spawn(Replicate::default()).id();
+
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let entity2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, entity1))
+			.unwrap();
+		// replicate back to app1
+		app2.world_mut()
+			.entity_mut(entity2)
+			.insert(Replicate::default());
+		app2.update();
+		// no spawn because app1 already has it
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		app2.world_mut().despawn(entity2);
+		app2.update();
+		expect(app2.world().resource::<ReplicateRegistry>().entities.len())
+			.to_be(0);
+
+		Message::loopback(app2.world_mut(), app1.world_mut());
+		expect(&app1.world().resource::<MessageIncoming>()[0])
+			.to_be(&Message::Despawn {
+				entity: entity1,
+				origin: EntityOrigin::Receiver,
+			});
+		app1.update();
+		expect(app1.world().get_entity(entity1).is_err()).to_be_true();
+
+		Ok(())
+	}
+
+	#[test]
+	fn entity_map() -> Result<()> {
+		let mut map = EntityMap::default();
+		let remote = RemoteEntity::new(0, Entity::from_raw(1));
+		let local = Entity::from_raw(2);
+		map.insert(remote, local);
+		expect(map.local(remote)).to_be(Some(local));
+		expect(map.remote(local)).to_be(Some(remote));
+		expect(map.outgoing(local))
+			.to_be((remote.entity, EntityOrigin::Receiver));
+		expect(map.outgoing(remote.entity))
+			.to_be((remote.entity, EntityOrigin::Sender));
+
+		let local2 = Entity::from_raw(3);
+		map.insert(remote, local2);
+		expect(map.remote(local)).to_be_none();
+		expect(map.len()).to_be(1);
+
+		expect(map.remove_remote(remote)).to_be(Some(local2));
+		expect(map.is_empty()).to_be_true();
+		Ok(())
+	}
+
+	#[test]
+	fn origin() -> Result<()> {
+		let mut map = EntityMap::default();
+		let entity = Entity::from_raw(1);
+		let local = Entity::from_raw(2);
+		map.insert(RemoteEntity::new(2, entity), local);
+		expect(map.outgoing(local)).to_be((entity, EntityOrigin::Client(2)));
+
+		let this_app = LocalClientId(Some(3));
+		let origin = EntityOrigin::Client(2);
+		expect(origin.spawner(1, this_app)).to_be(Some(2));
+		expect(map.incoming(1, this_app, entity, origin, |_| true))
+			.to_be(Some(local));
+		// the same id spawned by this app
+		let origin = EntityOrigin::Client(3);
+		expect(origin.spawner(1, this_app)).to_be_none();
+		expect(map.incoming(1, this_app, entity, origin, |_| true))
+			.to_be(Some(entity));
+		expect(map.incoming(1, this_app, entity, origin, |_| false))
+			.to_be_none();
+		// the same id spawned by the sender
+		expect(map.incoming(1, this_app, entity, EntityOrigin::Sender, |_| {
+			true
+		}))
+		.to_be_none();
+		Ok(())
+	}
+	#[test]
+	fn peer_disconnected() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin);
+		let own = app.world_mut().spawn(Replicate::default()).id();
+		let entity = Entity::from_raw(7);
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			Message::Spawn { entity },
+			Message::Sender { client_id: 2 },
+			Message::Spawn { entity },
+		];
+		app.update();
+		let remote = |app: &App, client_id| {
+			app.world()
+				.resource::<ReplicateRegistry>()
+				.entities
+				.local(RemoteEntity::new(client_id, entity))
+		};
+		let mirror1 = remote(&app, 1).unwrap();
+		expect(remote(&app, 2).is_some()).to_be_true();
+
+		app.world_mut().resource_mut::<MessageIncoming>().0 =
+			vec![Message::PeerDisconnected { client_id: 1 }];
+		app.update();
+		expect(app.world().get_entity(mirror1).is_err()).to_be_true();
+		expect(remote(&app, 1)).to_be_none();
+		expect(remote(&app, 2).is_some()).to_be_true();
+		expect(app.world().get_entity(own).is_ok()).to_be_true();
+		Ok(())
+	}
 }
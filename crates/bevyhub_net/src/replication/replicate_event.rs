@@ -8,13 +8,17 @@ use serde::Serialize;
 /// Functions for handling reception of [`Event`] messages.
 #[derive(Copy, Clone)]
 pub struct EventFns {
-	pub send: fn(&mut World, payload: &MessagePayload) -> Result<()>,
+	pub send: fn(
+		&mut World,
+		sender: ClientId,
+		payload: &MessagePayload,
+	) -> Result<()>,
 }
 
 impl EventFns {
 	pub fn new<T: Event + DeserializeOwned>() -> Self {
 		Self {
-			send: |world, payload| {
+			send: |world, _, payload| {
 				world.send_event(payload.deserialize::<T>()?);
 				Ok(())
 			},
@@ -0,0 +1,329 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// The registrations of a peer, received in [`Message::Registrations`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerRegistrations {
+	/// Map of the peer's registration ids to the local ids of the same type.
+	pub to_local: HashMap<RegistrationId, RegistrationId>,
+	/// The type names registered by the peer.
+	pub type_names: HashMap<RegistrationId, String>,
+}
+
+/// Maximum number of messages held per peer while waiting for its
+/// [`Message::Protocol`] or [`Message::Registrations`].
+pub const MAX_PENDING_MESSAGES: usize = 1024;
+
+/// Messages from peers that have not sent their [`Message::Registrations`]
+/// yet. Their registration ids cannot be mapped to local ids, so the
+/// messages are held until the registrations arrive.
+#[derive(Debug, Default, Clone, Deref, DerefMut, Resource)]
+pub struct PendingRegistrations(pub HashMap<ClientId, Vec<Message>>);
+
+/// Sends this app's [`Message::Protocol`] and [`Message::Registrations`]
+/// on startup.
+/// - Peers with an incompatible protocol are rejected, see [`check_protocol`].
+/// - Both are sent again when types are registered after startup,
+///   see [`update_protocol`].
+/// - Registration ids are mapped by type name, instead of requiring both
+///   apps to register types in the same order, see [`remap_incoming`].
+/// - Messages from peers are held until their registrations arrive,
+///   see [`PendingRegistrations`].
+///
+/// Peers reply with their own protocol and registrations the first time they
+/// receive them from a client.
+pub struct ReplicateHandshakePlugin;
+
+impl Plugin for ReplicateHandshakePlugin {
+	fn build(&self, app: &mut App) {
+		app.add_event::<ProtocolEvent>()
+			.init_resource::<PeerProtocols>()
+			.init_resource::<PendingRegistrations>()
+			.add_systems(Startup, (insert_protocol, send_handshake).chain())
+			.add_systems(
+				Update,
+				(update_protocol, check_protocol)
+					.chain()
+					.in_set(MessageIncomingSet)
+					.before(remap_incoming),
+			);
+	}
+}
+
+fn send_handshake(
+	protocol: Res<ReplicateProtocol>,
+	registrations: Res<ReplicateRegistry>,
+	mut outgoing: ResMut<MessageOutgoing>,
+) {
+	outgoing.push(protocol.message());
+	outgoing.push(registrations.registrations_message());
+}
+
+/// Stores received [`Message::Registrations`] and maps the registration ids
+/// of incoming messages to local ids.
+/// Messages of a type not registered by this app are logged and discarded.
+/// If the [`PendingRegistrations`] resource exists, messages from peers
+/// that have not sent their registrations are held until they do.
+pub fn remap_incoming(
+	mut registrations: ResMut<ReplicateRegistry>,
+	mut pending: Option<ResMut<PendingRegistrations>>,
+	mut incoming: ResMut<MessageIncoming>,
+	mut outgoing: ResMut<MessageOutgoing>,
+	mut peer_outgoing: ResMut<PeerOutgoing>,
+) {
+	let mut sender = DIRECT_CLIENT_ID;
+	let mut messages = Vec::with_capacity(incoming.len());
+	for message in incoming.drain(..) {
+		match &message {
+			Message::Sender { client_id } => {
+				sender = *client_id;
+			}
+			Message::PeerDisconnected { client_id } => {
+				// the relay may assign the id to another peer
+				registrations.peers.remove(client_id);
+				if let Some(pending) = pending.as_mut() {
+					pending.remove(client_id);
+				}
+			}
+			Message::Registrations { types } => {
+				let is_new = !registrations.peers.contains_key(&sender);
+				let missing = registrations.insert_peer(sender, types);
+				if !missing.is_empty() {
+					log::warn!(
+						"client {sender} registered types that are not registered in this app, messages of these types will be ignored:\n{}",
+						missing.join("\n")
+					);
+				}
+				if is_new {
+					// other peers already have them
+					let reply = registrations.registrations_message();
+					if sender == DIRECT_CLIENT_ID {
+						outgoing.push(reply);
+					} else {
+						peer_outgoing.push(sender, reply);
+					}
+				}
+				messages.push(message);
+				let held = pending
+					.as_mut()
+					.and_then(|pending| pending.remove(&sender))
+					.unwrap_or_default();
+				for message in held {
+					match registrations.remap(sender, message) {
+						Ok(message) => messages.push(message),
+						Err(err) => log::error!("{err}"),
+					}
+				}
+				continue;
+			}
+			_ => {}
+		}
+		let awaits = awaits_handshake(&message)
+			&& !registrations.peers.contains_key(&sender);
+		if let (true, Some(pending)) = (awaits, pending.as_mut()) {
+			let held = pending.entry(sender).or_default();
+			if held.len() < MAX_PENDING_MESSAGES {
+				held.push(message);
+			} else {
+				log::warn!(
+					"client {sender} has not sent its registrations, dropping message"
+				);
+			}
+			continue;
+		}
+		match registrations.remap(sender, message) {
+			Ok(message) => messages.push(message),
+			Err(err) => log::error!("{err}"),
+		}
+	}
+	incoming.0 = messages;
+}
+
+/// Messages that are not part of the handshake, signaling or sent by the
+/// relay server, which are held until the peer completes the handshake.
+pub(crate) fn awaits_handshake(message: &Message) -> bool {
+	!matches!(
+		message,
+		Message::Sender { .. }
+			| Message::Recipient { .. }
+			| Message::Signal { .. }
+			| Message::Welcome { .. }
+			| Message::PeerConnected { .. }
+			| Message::PeerDisconnected { .. }
+			| Message::Protocol { .. }
+			| Message::Registrations { .. }
+	)
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct MyComponent(u32);
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct MyOtherComponent(String);
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct NotRegistered(u64);
+
+	#[test]
+	fn remaps_ids() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins((ReplicatePlugin, ReplicateHandshakePlugin))
+			.replicate::<NotRegistered>()
+			.replicate::<MyComponent>()
+			.replicate::<MyOtherComponent>();
+		let mut app2 = App::new();
+		app2.add_plugins((ReplicatePlugin, ReplicateHandshakePlugin))
+			.replicate::<MyOtherComponent>()
+			.replicate::<MyComponent>();
+
+		fn exchange(app1: &mut App, app2: &mut App) {
+			let msg_out = std::mem::take(
+				&mut app1.world_mut().resource_mut::<MessageOutgoing>().0,
+			);
+			Message::loopback(app2.world_mut(), app1.world_mut());
+			app2.world_mut().resource_mut::<MessageIncoming>().0 = msg_out;
+			app1.update();
+			app2.update();
+		}
+		app1.update();
+		app2.update();
+		// receiving the handshake from a new peer sends a reply
+		exchange(&mut app1, &mut app2);
+		expect(app1.world().resource::<MessageOutgoing>().len()).to_be(2);
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(2);
+		// both already know each other so dont reply again
+		exchange(&mut app1, &mut app2);
+		expect(app1.world().resource::<MessageOutgoing>().len()).to_be(0);
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(0);
+		expect(app2.world().resource::<ReplicateRegistry>().peers.len())
+			.to_be(1);
+
+		app1.world_mut().spawn((
+			Replicate::default(),
+			MyComponent(7),
+			MyOtherComponent("foo".into()),
+			NotRegistered(9),
+		));
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let (component, other) = app2
+			.world_mut()
+			.query::<(&MyComponent, &MyOtherComponent)>()
+			.single(app2.world());
+		expect(component).to_be(&MyComponent(7));
+		expect(other).to_be(&MyOtherComponent("foo".into()));
+		expect(
+			app2.world_mut()
+				.query::<&NotRegistered>()
+				.iter(app2.world())
+				.count(),
+		)
+		.to_be(0);
+		Ok(())
+	}
+
+	#[test]
+	fn holds_until_registrations() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins((ReplicatePlugin, ReplicateHandshakePlugin))
+			.replicate::<MyComponent>();
+		app.update();
+		app.world_mut().resource_mut::<MessageOutgoing>().clear();
+		let protocol = app.world().resource::<ReplicateProtocol>().message();
+
+		let entity = Entity::from_raw(5);
+		let add = Message::Add {
+			reg_id: RegistrationId::new_with(3),
+			entity,
+			origin: EntityOrigin::Sender,
+			payload: MessagePayload::new(MyComponent(7))?,
+		};
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 2 },
+			protocol,
+			Message::Spawn { entity },
+			add,
+		];
+		app.update();
+		expect(app.world().resource::<PendingRegistrations>()[&2].len())
+			.to_be(2);
+		expect(
+			app.world_mut()
+				.query::<&RemoteEntity>()
+				.iter(app.world())
+				.count(),
+		)
+		.to_be(0);
+		app.world_mut().resource_mut::<MessageOutgoing>().clear();
+
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 2 },
+			Message::Registrations {
+				types: vec![(
+					std::any::type_name::<MyComponent>().to_string(),
+					RegistrationId::new_with(3),
+				)],
+			},
+		];
+		app.update();
+		expect(app.world().resource::<PendingRegistrations>().len()).to_be(0);
+		// the reply is sent only to the new peer
+		let registrations = app
+			.world()
+			.resource::<ReplicateRegistry>()
+			.registrations_message();
+		expect(app.world().resource::<PeerOutgoing>()[&2].last())
+			.to_be(Some(&registrations));
+		expect(app.world().resource::<MessageOutgoing>().len()).to_be(0);
+		expect(
+			app.world_mut()
+				.query::<&MyComponent>()
+				.single(app.world()),
+		)
+		.to_be(&MyComponent(7));
+		Ok(())
+	}
+
+	#[test]
+	fn unknown_type() -> Result<()> {
+		let mut registry = ReplicateRegistry::default();
+		registry.register_component::<MyComponent>(ReplicateDirection::Both);
+		let missing = registry.insert_peer(3, &[
+			(
+				std::any::type_name::<NotRegistered>().to_string(),
+				RegistrationId::new_with(0),
+			),
+			(
+				std::any::type_name::<MyComponent>().to_string(),
+				RegistrationId::new_with(1),
+			),
+		]);
+		expect(missing.len()).to_be(1);
+
+		let remove = |id: usize| Message::Remove {
+			reg_id: RegistrationId::new_with(id),
+			entity: Entity::PLACEHOLDER,
+			origin: EntityOrigin::Sender,
+		};
+		expect(registry.remap(3, remove(1))?).to_be(remove(0));
+		expect(registry.remap(3, remove(0))).to_be_err_str(&format!(
+			"client 3 sent type {} which is not registered in this app",
+			std::any::type_name::<NotRegistered>()
+		));
+		expect(registry.remap(3, remove(2)))
+			.to_be_err_str("client 3 sent unknown registration id 2");
+		// no registrations from this client
+		expect(registry.remap(4, remove(2))?).to_be(remove(2));
+		Ok(())
+	}
+}
@@ -0,0 +1,158 @@
+use crate::prelude::*;
+use bevy::ecs::entity::EntityMapper;
+use bevy::ecs::entity::MapEntities;
+use bevy::prelude::*;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// The replicated form of [`Parent`], which cannot be replicated directly
+/// as [`Children`] of the parent must be kept in sync.
+/// This is added to [`Replicate`] entities with a [`Parent`] and the
+/// receiver rebuilds the hierarchy with [`BuildChildren::add_child`].
+#[derive(
+	Debug, Copy, Clone, PartialEq, Eq, Component, Serialize, Deserialize,
+)]
+pub struct ReplicateParent(pub Entity);
+
+impl MapEntities for ReplicateParent {
+	fn map_entities<M: EntityMapper>(&mut self, mapper: &mut M) {
+		self.0 = mapper.map_entity(self.0);
+	}
+}
+
+/// Register hierarchy replication, see [`App::replicate_hierarchy`].
+pub(crate) fn register_hierarchy(app: &mut App) {
+	app.replicate::<ReplicateParent>()
+		.replicate_map_entities::<ReplicateParent>();
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<ReplicateParent>();
+	if let Some(fns) = registry.incoming_component_fns.get_mut(&id) {
+		// the parent is set once the referenced entity is known
+		fns.insert = |commands, _, sender, payload| {
+			receive_mapped::<ReplicateParent>(
+				commands,
+				sender,
+				payload,
+				false,
+				apply_parent,
+			)
+		};
+		fns.change = |commands, _, sender, payload| {
+			receive_mapped::<ReplicateParent>(
+				commands,
+				sender,
+				payload,
+				true,
+				apply_parent,
+			)
+		};
+		fns.remove = |commands, _| {
+			let entity = commands.id();
+			commands.commands().queue(move |world: &mut World| {
+				if let Ok(mut entity) = world.get_entity_mut(entity) {
+					entity.remove::<ReplicateParent>().remove_parent();
+				}
+			});
+		};
+	}
+	app.add_systems(
+		Update,
+		sync_parent
+			.after(MessageIncomingSet)
+			.before(MessageOutgoingSet),
+	);
+}
+
+/// Update the hierarchy of an entity with a received [`ReplicateParent`].
+fn apply_parent(entity: &mut EntityWorldMut) {
+	let Some(parent) = entity.get::<ReplicateParent>().map(|p| p.0) else {
+		return;
+	};
+	if entity.get::<Parent>().map(|p| p.get()) == Some(parent) {
+		return;
+	}
+	let id = entity.id();
+	entity.world_scope(|world| {
+		if let Ok(mut parent) = world.get_entity_mut(parent) {
+			parent.add_child(id);
+		} else {
+			log::warn!("cannot set parent of {id}, {parent} does not exist");
+		}
+	});
+}
+
+type ParentChanged = (Or<(Changed<Parent>, Added<Replicate>)>, With<Replicate>);
+
+/// Keep the [`ReplicateParent`] of [`Replicate`] entities in sync with [`Parent`].
+fn sync_parent(
+	mut commands: Commands,
+	mut changed: Query<
+		(Entity, &Parent, Option<&mut ReplicateParent>),
+		ParentChanged,
+	>,
+	orphans: Query<(), (With<ReplicateParent>, Without<Parent>)>,
+	mut removed: RemovedComponents<Parent>,
+) {
+	for (entity, parent, replicate_parent) in changed.iter_mut() {
+		let next = ReplicateParent(parent.get());
+		if let Some(mut replicate_parent) = replicate_parent {
+			replicate_parent.set_if_neq(next);
+		} else {
+			commands.entity(entity).insert(next);
+		}
+	}
+	for entity in removed.read() {
+		if orphans.contains(entity) {
+			commands.entity(entity).remove::<ReplicateParent>();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use sweet::prelude::*;
+
+	fn app() -> App {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin).replicate_hierarchy();
+		app
+	}
+
+	#[test]
+	fn works() -> Result<()> {
+		let mut app1 = app();
+		let mut app2 = app();
+		// different entity layouts
+		app2.world_mut().spawn_empty();
+
+		let parent1 = app1.world_mut().spawn(Replicate::default()).id();
+		let child1 = app1
+			.world_mut()
+			.spawn(Replicate::default())
+			.set_parent(parent1)
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let entities = &app2.world().resource::<ReplicateRegistry>().entities;
+		let parent2 = entities.local(RemoteEntity::new(0, parent1)).unwrap();
+		let child2 = entities.local(RemoteEntity::new(0, child1)).unwrap();
+		expect(parent2).not().to_be(parent1);
+		expect(app2.world().get::<Parent>(child2).map(|p| p.get()))
+			.to_be(Some(parent2));
+		expect(app2.world().get::<Children>(parent2).map(|c| c.to_vec()))
+			.to_be(Some(vec![child2]));
+
+		app1.world_mut().entity_mut(child1).remove_parent();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(app2.world().get::<Parent>(child2)).to_be_none();
+		expect(app2.world().get::<Children>(parent2)).to_be_none();
+		Ok(())
+	}
+}
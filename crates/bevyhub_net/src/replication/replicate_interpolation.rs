@@ -0,0 +1,374 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use forky::prelude::ResultTEExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Components that can be blended between two received values.
+pub trait Interpolate {
+	/// Blend from `self` to `other`, where `t` is from 0 to 1.
+	fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		self + (other - self) * t
+	}
+}
+
+impl Interpolate for Transform {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		Transform {
+			translation: self.translation.lerp(other.translation, t),
+			rotation: self.rotation.slerp(other.rotation, t),
+			scale: self.scale.lerp(other.scale, t),
+		}
+	}
+}
+
+/// How far behind the latest received value interpolated components
+/// are rendered. Larger delays are smoother when messages are late,
+/// the default is two [`DEFAULT_TRANSPORT_INTERVAL`].
+#[derive(Debug, Copy, Clone, PartialEq, Deref, DerefMut, Resource)]
+pub struct InterpolationDelay(pub Duration);
+
+impl Default for InterpolationDelay {
+	fn default() -> Self { Self(DEFAULT_TRANSPORT_INTERVAL * 2) }
+}
+
+/// The [`Message::Timestamp`] of the batch that is being applied,
+/// `None` if the sender does not send timestamps.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Deref, Resource)]
+pub struct IncomingTimestamp(pub Option<Duration>);
+
+/// How long the clock of an [`InterpolationBuffer`] is kept
+/// before a sample with a higher latency may replace it.
+const CLOCK_WINDOW: Duration = Duration::from_secs(1);
+
+/// Received values of an interpolated component, stamped with the
+/// [`Message::Timestamp`] they were sent at, or the [`Time::elapsed`]
+/// they arrived at if the sender does not send timestamps.
+#[derive(Debug, Clone, Component)]
+pub struct InterpolationBuffer<T> {
+	samples: VecDeque<(Duration, T)>,
+	/// The local and sender time of the sample with the lowest latency,
+	/// used to convert local time to the time of the sender.
+	clock: (Duration, Duration),
+}
+
+impl<T> InterpolationBuffer<T> {
+	pub fn new(received: Duration, sent: Duration, value: T) -> Self {
+		Self {
+			samples: VecDeque::from([(sent, value)]),
+			clock: (received, sent),
+		}
+	}
+
+	/// Add a value that arrived at `received` and was sent at `sent`.
+	/// Values sent before the latest value are discarded.
+	pub fn push(&mut self, received: Duration, sent: Duration, value: T) {
+		let (clock_received, clock_sent) = self.clock;
+		// the lowest latency has the highest `sent - received`
+		if sent + clock_received >= clock_sent + received
+			|| received >= clock_received + CLOCK_WINDOW
+		{
+			self.clock = (received, sent);
+		}
+		if self.samples.back().is_some_and(|(latest, _)| sent < *latest) {
+			return;
+		}
+		self.samples.push_back((sent, value));
+	}
+
+	/// Convert a local [`Time::elapsed`] to the time of the sender.
+	pub fn sender_time(&self, local: Duration) -> Duration {
+		let (received, sent) = self.clock;
+		(local + sent).saturating_sub(received)
+	}
+
+	/// The most recently received value.
+	pub fn latest(&self) -> Option<&T> {
+		self.samples.back().map(|(_, value)| value)
+	}
+
+	pub fn len(&self) -> usize { self.samples.len() }
+	pub fn is_empty(&self) -> bool { self.samples.is_empty() }
+}
+
+impl<T: Clone + Interpolate> InterpolationBuffer<T> {
+	/// The value at the sender `time`, discarding samples that
+	/// are no longer needed.
+	/// Values before the first sample or after the last are held,
+	/// not extrapolated.
+	pub fn sample(&mut self, time: Duration) -> Option<T> {
+		while self.samples.get(1).is_some_and(|(next, _)| *next <= time) {
+			self.samples.pop_front();
+		}
+		match (self.samples.front(), self.samples.get(1)) {
+			(Some((from_time, from)), Some((to_time, to)))
+				if time > *from_time =>
+			{
+				let t = (time - *from_time).as_secs_f32()
+					/ (*to_time - *from_time).as_secs_f32();
+				Some(from.interpolate(to, t.clamp(0., 1.)))
+			}
+			(Some((_, from)), _) => Some(from.clone()),
+			_ => None,
+		}
+	}
+}
+
+/// Register interpolation of `T`, see [`App::interpolate`].
+pub(crate) fn register_interpolation<
+	T: Component + Clone + PartialEq + Interpolate + Serialize + DeserializeOwned,
+>(
+	app: &mut App,
+) {
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<T>();
+	let Some(fns) = registry.incoming_component_fns.get_mut(&id) else {
+		log::error!(
+			"{} must be registered as incoming before it can be interpolated",
+			std::any::type_name::<T>()
+		);
+		return;
+	};
+	fns.insert = |commands, _, _, payload| {
+		let value = payload.deserialize::<T>()?;
+		queue_if_exists(commands, move |entity| {
+			// a change may have arrived before the add
+			if !entity.contains::<T>() {
+				push_sample(entity, value);
+			}
+		});
+		Ok(())
+	};
+	fns.change = |commands, _, _, payload| {
+		let value = payload.deserialize::<T>()?;
+		queue_if_exists(commands, move |entity| push_sample(entity, value));
+		Ok(())
+	};
+	fns.apply_delta = |commands, _, delta| {
+		let delta = delta.clone();
+		queue_if_exists(commands, move |entity| {
+			// the delta is against the last received value
+			let prev = entity
+				.get::<InterpolationBuffer<T>>()
+				.and_then(|buffer| buffer.latest())
+				.or_else(|| entity.get::<T>());
+			let Some(prev) = prev else {
+				log::error!("received delta but component does not exist");
+				return;
+			};
+			let value = bincode::serialize(prev)
+				.map_err(anyhow::Error::from)
+				.and_then(|prev| delta.apply(&prev))
+				.and_then(|next| Ok(bincode::deserialize::<T>(&next)?));
+			if let Some(value) = value.ok_or(|e| log::error!("{e}")) {
+				push_sample(entity, value);
+			}
+		});
+	};
+
+	app.init_resource::<InterpolationDelay>().add_systems(
+		Update,
+		interpolate::<T>
+			.after(MessageIncomingSet)
+			.before(MessageOutgoingSet),
+	);
+}
+
+/// Add a received value to the buffer, the component itself is only
+/// inserted directly if this is the first value.
+fn push_sample<T: Component + Clone>(entity: &mut EntityWorldMut, value: T) {
+	let world = entity.world();
+	let now = world
+		.get_resource::<Time>()
+		.map(|time| time.elapsed())
+		.unwrap_or_default();
+	let sent = world
+		.get_resource::<IncomingTimestamp>()
+		.and_then(|timestamp| timestamp.0)
+		.unwrap_or(now);
+	if let Some(mut buffer) = entity.get_mut::<InterpolationBuffer<T>>() {
+		buffer.push(now, sent, value);
+	} else if let Some(prev) = entity.get::<T>().cloned() {
+		let mut buffer = InterpolationBuffer::new(now, sent, prev);
+		buffer.push(now, sent, value);
+		entity.insert(buffer);
+	} else {
+		let buffer = InterpolationBuffer::new(now, sent, value.clone());
+		entity.insert((buffer, value));
+	}
+}
+
+/// Set each interpolated component to its value at the sender time of
+/// [`Time::elapsed`] minus the [`InterpolationDelay`].
+fn interpolate<T: Component + Clone + PartialEq + Interpolate>(
+	time: Res<Time>,
+	delay: Res<InterpolationDelay>,
+	mut query: Query<(&mut T, &mut InterpolationBuffer<T>)>,
+) {
+	for (mut value, mut buffer) in query.iter_mut() {
+		let render_time =
+			buffer.sender_time(time.elapsed()).saturating_sub(**delay);
+		if let Some(next) = buffer.sample(render_time) {
+			value.set_if_neq(next);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use std::time::Duration;
+	use sweet::prelude::*;
+
+	#[test]
+	fn buffer() {
+		let millis = Duration::from_millis;
+		let mut buffer = InterpolationBuffer::new(millis(0), millis(0), 0.);
+		buffer.push(millis(100), millis(100), 10.);
+		buffer.push(millis(200), millis(200), 30.);
+		expect(buffer.sample(Duration::ZERO)).to_be(Some(0.));
+		expect(buffer.sample(Duration::from_millis(50))).to_be(Some(5.));
+		expect(buffer.sample(Duration::from_millis(150))).to_be(Some(20.));
+		// the first sample is no longer needed
+		expect(buffer.len()).to_be(2);
+		expect(buffer.sample(Duration::from_millis(300))).to_be(Some(30.));
+		expect(buffer.len()).to_be(1);
+	}
+
+	#[test]
+	fn clock() {
+		let millis = Duration::from_millis;
+		// both values arrive at once, but were sent 50ms apart
+		let mut buffer = InterpolationBuffer::new(millis(0), millis(500), 0.);
+		buffer.push(millis(200), millis(550), 10.);
+		buffer.push(millis(200), millis(600), 20.);
+		// values sent before the latest are discarded
+		buffer.push(millis(210), millis(520), 99.);
+		expect(buffer.len()).to_be(3);
+		// the first sample had the lowest latency
+		expect(buffer.sender_time(millis(200))).to_be(millis(700));
+		expect(buffer.sample(millis(575))).to_be(Some(15.));
+		// an old clock is replaced by the next sample
+		buffer.push(millis(1100), millis(1300), 30.);
+		expect(buffer.sender_time(millis(1100))).to_be(millis(1300));
+	}
+
+	#[test]
+	fn timestamps() -> Result<()> {
+		let (a, b) = ChannelsTransport::pair();
+		let mut server = App::new();
+		server
+			.add_plugins(ReplicatePlugin)
+			.init_resource::<Time>()
+			.replicate_with::<Transform>(ReplicateDirection::Outgoing)
+			.add_transport_with_duration(a, Duration::ZERO);
+		let mut client = App::new();
+		client
+			.add_plugins(ReplicatePlugin)
+			.init_resource::<Time>()
+			.insert_resource(InterpolationDelay(Duration::from_millis(100)))
+			.replicate_with::<Transform>(ReplicateDirection::Incoming)
+			.interpolate::<Transform>()
+			.add_transport_with_duration(b, Duration::ZERO);
+
+		let entity = server
+			.world_mut()
+			.spawn((Replicate::default(), Transform::default()))
+			.id();
+		server.update();
+		client.update();
+		// two changes sent 50ms apart arrive in the same update
+		for x in [10., 20.] {
+			server
+				.world_mut()
+				.resource_mut::<Time>()
+				.advance_by(Duration::from_millis(50));
+			server
+				.world_mut()
+				.get_mut::<Transform>(entity)
+				.unwrap()
+				.translation
+				.x = x;
+			server.update();
+		}
+		let mut step = |millis: u64| -> f32 {
+			client
+				.world_mut()
+				.resource_mut::<Time>()
+				.advance_by(Duration::from_millis(millis));
+			client.update();
+			client
+				.world_mut()
+				.query::<&Transform>()
+				.single(client.world())
+				.translation
+				.x
+		};
+		expect(step(100)).to_be(0.);
+		expect(step(50)).to_be(10.);
+		expect(step(25)).to_be(15.);
+		expect(step(25)).to_be(20.);
+		Ok(())
+	}
+
+	#[test]
+	fn transform() -> Result<()> {
+		let mut server = App::new();
+		server
+			.add_plugins(ReplicatePlugin)
+			.replicate_with::<Transform>(ReplicateDirection::Outgoing);
+		let mut client = App::new();
+		client
+			.add_plugins(ReplicatePlugin)
+			.init_resource::<Time>()
+			.insert_resource(InterpolationDelay(Duration::from_millis(100)))
+			.replicate_with::<Transform>(ReplicateDirection::Incoming)
+			.interpolate::<Transform>();
+
+		let entity = server
+			.world_mut()
+			.spawn((Replicate::default(), Transform::default()))
+			.id();
+
+		let mut step = |x: Option<f32>, millis: u64| -> f32 {
+			if let Some(x) = x {
+				server
+					.world_mut()
+					.get_mut::<Transform>(entity)
+					.unwrap()
+					.translation
+					.x = x;
+			}
+			server.update();
+			Message::loopback(server.world_mut(), client.world_mut());
+			client
+				.world_mut()
+				.resource_mut::<Time>()
+				.advance_by(Duration::from_millis(millis));
+			client.update();
+			client
+				.world_mut()
+				.query::<&Transform>()
+				.single(client.world())
+				.translation
+				.x
+		};
+
+		expect(step(None, 0)).to_be(0.);
+		// received but not yet rendered
+		expect(step(Some(10.), 100)).to_be(0.);
+		expect(step(None, 50)).to_be(5.);
+		expect(step(Some(20.), 50)).to_be(10.);
+		expect(step(None, 50)).to_be(15.);
+		expect(step(None, 100)).to_be(20.);
+		Ok(())
+	}
+}
@@ -0,0 +1,342 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::ecs::entity::EntityMapper;
+use bevy::ecs::entity::MapEntities;
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::Any;
+
+/// Serialize a component after mapping its entity references
+/// with an [`OutgoingEntityMapper`].
+pub type MapOutgoingFn = fn(&EntityMap, &dyn Any) -> Result<MessagePayload>;
+
+/// The payload of a component registered with
+/// [`App::replicate_map_entities`]. Each entity in the value is an
+/// index into `refs`, the id and origin peers know the entity by.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MappedPayload<T> {
+	pub value: T,
+	pub refs: Vec<(Entity, EntityOrigin)>,
+}
+
+/// Maps local entities to indices into a table of the ids and
+/// origins peers know them by, see [`EntityMap::outgoing`].
+pub struct OutgoingEntityMapper<'a> {
+	pub entities: &'a EntityMap,
+	pub refs: Vec<(Entity, EntityOrigin)>,
+}
+
+impl<'a> OutgoingEntityMapper<'a> {
+	pub fn new(entities: &'a EntityMap) -> Self {
+		Self {
+			entities,
+			refs: Vec::new(),
+		}
+	}
+}
+
+impl EntityMapper for OutgoingEntityMapper<'_> {
+	fn map_entity(&mut self, entity: Entity) -> Entity {
+		let outgoing = self.entities.outgoing(entity);
+		let index = match self.refs.iter().position(|other| *other == outgoing)
+		{
+			Some(index) => index,
+			None => {
+				self.refs.push(outgoing);
+				self.refs.len() - 1
+			}
+		};
+		Entity::from_raw(index as u32)
+	}
+}
+
+/// Maps the indices of a [`MappedPayload`] to the resolved local entities.
+pub struct IncomingEntityMapper(pub Vec<Entity>);
+
+impl EntityMapper for IncomingEntityMapper {
+	fn map_entity(&mut self, entity: Entity) -> Entity {
+		self.0
+			.get(entity.index() as usize)
+			.copied()
+			.unwrap_or_else(|| {
+				log::error!("entity reference {entity} is not in the payload");
+				Entity::PLACEHOLDER
+			})
+	}
+}
+
+/// A received value whose entity references are not all known yet,
+/// ie the referenced entity is spawned later in another batch.
+/// The value is applied once every reference is known.
+#[derive(Component)]
+pub struct PendingEntityRefs<T> {
+	value: T,
+	/// The spawner of each reference, `None` for entities of this app.
+	refs: Vec<(Option<ClientId>, Entity)>,
+	/// Whether the value was received as a [`Message::Change`],
+	/// otherwise it is only inserted if the component does not exist.
+	is_change: bool,
+	/// Called after the value is applied, ie to update the hierarchy.
+	on_apply: fn(&mut EntityWorldMut),
+}
+
+impl<T: Component + MapEntities> PendingEntityRefs<T> {
+	/// Resolve each reference, like incoming messages these are either
+	/// entities spawned by a peer or our own [`Replicate`] entities.
+	fn resolve(&self, world: &World) -> Option<Vec<Entity>> {
+		let registry = world.get_resource::<ReplicateRegistry>()?;
+		self.refs
+			.iter()
+			.map(|(spawner, entity)| match spawner {
+				Some(client_id) => registry
+					.entities
+					.local(RemoteEntity::new(*client_id, *entity)),
+				None => (world.get::<Replicate>(*entity).is_some()
+					&& world.get::<RemoteEntity>(*entity).is_none())
+				.then_some(*entity),
+			})
+			.collect()
+	}
+
+	/// Apply the value if every reference is known,
+	/// otherwise keep it on the entity until they are.
+	fn apply(self, entity: &mut EntityWorldMut) {
+		let Some(resolved) = self.resolve(entity.world()) else {
+			entity.insert(self);
+			return;
+		};
+		let Self {
+			mut value,
+			is_change,
+			on_apply,
+			..
+		} = self;
+		value.map_entities(&mut IncomingEntityMapper(resolved));
+		entity.remove::<Self>();
+		if is_change {
+			set_component(entity, value);
+		} else if !entity.contains::<T>() {
+			entity.insert(value);
+		} else {
+			return;
+		}
+		on_apply(entity);
+	}
+}
+
+/// Insert or change a received value of `T` with entity references,
+/// see [`PendingEntityRefs`].
+pub fn receive_mapped<T: Component + MapEntities + DeserializeOwned>(
+	commands: &mut EntityCommands,
+	sender: ClientId,
+	payload: &MessagePayload,
+	is_change: bool,
+	on_apply: fn(&mut EntityWorldMut),
+) -> Result<()> {
+	let MappedPayload { value, refs } =
+		payload.deserialize::<MappedPayload<T>>()?;
+	queue_if_exists(commands, move |entity| {
+		let local = entity
+			.world()
+			.get_resource::<LocalClientId>()
+			.copied()
+			.unwrap_or_default();
+		let refs = refs
+			.into_iter()
+			.map(|(entity, origin)| (origin.spawner(sender, local), entity))
+			.collect();
+		PendingEntityRefs {
+			value,
+			refs,
+			is_change,
+			on_apply,
+		}
+		.apply(entity);
+	});
+	Ok(())
+}
+
+/// Apply [`PendingEntityRefs`] whose references are now known.
+fn resolve_pending<T: Component + MapEntities>(world: &mut World) {
+	let entities = world
+		.query_filtered::<Entity, With<PendingEntityRefs<T>>>()
+		.iter(world)
+		.collect::<Vec<_>>();
+	for entity in entities {
+		let mut entity = world.entity_mut(entity);
+		if let Some(pending) = entity.take::<PendingEntityRefs<T>>() {
+			pending.apply(&mut entity);
+		}
+	}
+}
+
+/// Register entity mapping of `T`, see [`App::replicate_map_entities`].
+pub(crate) fn register_map_entities<
+	T: Component + MapEntities + Clone + Serialize + DeserializeOwned,
+>(
+	app: &mut App,
+) {
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<T>();
+	if registry.delta_types.contains(&id) {
+		// deltas are made against the unmapped value
+		log::error!(
+			"{} is replicated with deltas, its entities cannot be mapped",
+			std::any::type_name::<T>()
+		);
+		return;
+	}
+	registry.outgoing_map_fns.insert(id, |entities, value| {
+		let Some(value) = value.downcast_ref::<T>() else {
+			anyhow::bail!("expected {}", std::any::type_name::<T>());
+		};
+		let mut value = value.clone();
+		let mut mapper = OutgoingEntityMapper::new(entities);
+		value.map_entities(&mut mapper);
+		MessagePayload::new(&MappedPayload {
+			value,
+			refs: mapper.refs,
+		})
+	});
+	if let Some(fns) = registry.incoming_component_fns.get_mut(&id) {
+		fns.insert = |commands, _, sender, payload| {
+			receive_mapped::<T>(commands, sender, payload, false, |_| {})
+		};
+		fns.change = |commands, _, sender, payload| {
+			receive_mapped::<T>(commands, sender, payload, true, |_| {})
+		};
+	}
+	app.add_systems(
+		Update,
+		resolve_pending::<T>
+			.run_if(any_with_component::<PendingEntityRefs<T>>)
+			.after(MessageIncomingSet)
+			.before(MessageOutgoingSet),
+	);
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::ecs::entity::EntityMapper;
+	use bevy::ecs::entity::MapEntities;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct Target(Entity);
+
+	impl MapEntities for Target {
+		fn map_entities<M: EntityMapper>(&mut self, mapper: &mut M) {
+			self.0 = mapper.map_entity(self.0);
+		}
+	}
+
+	fn app() -> App {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin)
+			.replicate::<Target>()
+			.replicate_map_entities::<Target>();
+		app
+	}
+
+	#[test]
+	fn works() -> Result<()> {
+		let mut app1 = app();
+		let mut app2 = app();
+		// different entity layouts
+		app2.world_mut().spawn_empty();
+		app2.world_mut().spawn_empty();
+
+		let target1 = app1.world_mut().spawn(Replicate::default()).id();
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), Target(target1)))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let entities = &app2.world().resource::<ReplicateRegistry>().entities;
+		let target2 = entities.local(RemoteEntity::new(0, target1)).unwrap();
+		let entity2 = entities.local(RemoteEntity::new(0, entity1)).unwrap();
+		expect(target2).not().to_be(target1);
+		expect(app2.world().get::<Target>(entity2))
+			.to_be(Some(&Target(target2)));
+		// not sent back
+		expect(app2.world().resource::<MessageOutgoing>().len()).to_be(0);
+
+		// references to remote entities are mapped back to the sender's ids
+		app2.world_mut()
+			.spawn((Replicate::default(), Target(target2)));
+		app2.update();
+		Message::loopback(app2.world_mut(), app1.world_mut());
+		app1.update();
+		let targets = app1
+			.world_mut()
+			.query::<&Target>()
+			.iter(app1.world())
+			.cloned()
+			.collect::<Vec<_>>();
+		expect(targets).to_be(vec![Target(target1), Target(target1)]);
+		Ok(())
+	}
+	#[test]
+	fn pending() -> Result<()> {
+		let mut app1 = app();
+		let mut app2 = app();
+
+		// the target is not replicated yet
+		let target1 = app1.world_mut().spawn_empty().id();
+		let entity1 = app1
+			.world_mut()
+			.spawn((Replicate::default(), Target(target1)))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let entity2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, entity1))
+			.unwrap();
+		expect(app2.world().get::<Target>(entity2)).to_be_none();
+		expect(
+			app2.world()
+				.get::<PendingEntityRefs<Target>>(entity2)
+				.is_some(),
+		)
+		.to_be_true();
+
+		app1.world_mut()
+			.entity_mut(target1)
+			.insert(Replicate::default());
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+
+		let target2 = app2
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, target1))
+			.unwrap();
+		expect(app2.world().get::<Target>(entity2))
+			.to_be(Some(&Target(target2)));
+		expect(
+			app2.world()
+				.get::<PendingEntityRefs<Target>>(entity2)
+				.is_none(),
+		)
+		.to_be_true();
+		Ok(())
+	}
+}
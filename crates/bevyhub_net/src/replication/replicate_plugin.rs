@@ -10,6 +10,10 @@ pub struct MessageIncomingSet;
 #[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
 /// The set in which [`MessageOutgoing`] messages are written.
 pub struct MessageOutgoingSet;
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
+/// The set in which [`PeerOutgoing`] messages are written,
+/// after [`MessageOutgoingSet`].
+pub struct PeerOutgoingSet;
 
 /// Mark an entity for outgoing replication
 #[derive(Default, Component)]
@@ -20,10 +24,12 @@ Base replication plugin, excluding [`Transport`] and any registered [`Component`
 
 A typical replication system order would look something like this:
 - [`transport_incoming`]: [`MessageIncoming`] is appended by the transport
-- [`MessageIncomingSet`]: [`MessageIncoming`] is read by registered systems
+- [`MessageIncomingSet`]: [`MessageIncoming`] is read by registered systems, after [`remap_incoming`] maps registration ids to local ids
 - [`MessageOutgoingSet`]: [`MessageOutgoing`] is appended by registered systems
 - [`clear_incoming`]: [`MessageIncoming`] is cleared
-- [`transport_outgoing`]: [`MessageOutgoing`] is cleared and sent by the transport
+- [`clear_incoming_changes`]: [`IncomingChanges`] is cleared, after the [`MessageOutgoingSet`] used it to skip echoes
+- [`PeerOutgoingSet`]: [`PeerOutgoing`] is appended, ie by [`send_snapshots`] for peers that just connected
+- [`transport_outgoing`]: [`MessageOutgoing`] and [`PeerOutgoing`] are cleared and sent by the transport, within its [`TransportBudget`]
 **/
 pub struct ReplicatePlugin;
 
@@ -32,17 +38,37 @@ impl Plugin for ReplicatePlugin {
 		app /*-*/
 			.configure_sets(
 				Update,
-				MessageIncomingSet.before(MessageOutgoingSet),
+				(MessageIncomingSet, MessageOutgoingSet, PeerOutgoingSet)
+					.chain(),
 			)
 			.init_resource::<ReplicateRegistry>()
 			.init_resource::<MessageIncoming>()
 			.init_resource::<MessageOutgoing>()
+			.init_resource::<PeerOutgoing>()
+			.init_resource::<PendingSnapshots>()
+			.init_resource::<IncomingChanges>()
+			.init_resource::<TransportStats>()
+			.init_resource::<LocalClientId>()
+			.add_event::<PeerEvent>()
 			.add_systems(
 				Update,
 				(
+					remap_incoming
+						.in_set(MessageIncomingSet)
+						.before(handle_incoming_commands)
+						.before(handle_incoming_world),
 					handle_incoming_commands.in_set(MessageIncomingSet),
 					handle_incoming_world.in_set(MessageIncomingSet),
+					(handle_peer_messages, queue_snapshots)
+						.chain()
+						.in_set(MessageIncomingSet),
+					despawn_disconnected
+						.after(handle_peer_messages)
+						.after(handle_incoming_commands)
+						.in_set(MessageIncomingSet),
+					send_snapshots.in_set(PeerOutgoingSet),
 					clear_incoming.after(MessageIncomingSet),
+					clear_incoming_changes.after(MessageOutgoingSet),
 				),
 			);
 
@@ -53,3 +79,7 @@ impl Plugin for ReplicatePlugin {
 
 
 fn clear_incoming(mut incoming: ResMut<MessageIncoming>) { incoming.clear(); }
+
+fn clear_incoming_changes(mut changes: ResMut<IncomingChanges>) {
+	changes.clear();
+}
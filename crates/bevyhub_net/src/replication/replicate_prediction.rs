@@ -0,0 +1,492 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+/// Apply an input to a predicted component.
+/// This should be deterministic as it is run by both the predicting
+/// and authoritative apps, and again for each rollback.
+pub type SimulateFn<T, I> = fn(&mut T, &I);
+
+/// The [`SimulateFn`] shared by the prediction systems of `T` and `I`.
+#[derive(Resource)]
+pub struct Simulate<T, I>(pub SimulateFn<T, I>);
+
+/// An input stamped with its sequence number, sent by the predicting app.
+#[derive(Debug, Clone, PartialEq, Event, Serialize, Deserialize)]
+pub struct PredictedInput<I> {
+	/// The entity as sent, see [`EntityMap::outgoing`].
+	pub entity: Entity,
+	pub origin: EntityOrigin,
+	pub sequence: u32,
+	pub input: I,
+	/// The client that sent the input, set by the authoritative app.
+	#[serde(skip)]
+	pub sender: ClientId,
+}
+
+/// The sequence of the last input applied by the authoritative app.
+#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+pub struct InputAck<I> {
+	pub sequence: u32,
+	#[serde(skip)]
+	phantom: PhantomData<I>,
+}
+
+impl<I> InputAck<I> {
+	pub fn new(sequence: u32) -> Self {
+		Self {
+			sequence,
+			phantom: PhantomData,
+		}
+	}
+}
+
+/// The last value of a predicted component received
+/// from the authoritative app.
+#[derive(Debug, Clone, PartialEq, Deref, Component)]
+pub struct Confirmed<T>(pub T);
+
+/// Inputs for a locally owned entity, add this component to
+/// entities that should be predicted.
+#[derive(Debug, Clone, Component)]
+pub struct InputBuffer<I> {
+	/// Sequence numbers start at 1, so an [`InputAck`] of 0
+	/// acknowledges nothing.
+	next_sequence: u32,
+	pending: Vec<I>,
+	unacked: VecDeque<(u32, I)>,
+}
+
+impl<I> Default for InputBuffer<I> {
+	fn default() -> Self {
+		Self {
+			next_sequence: 1,
+			pending: Vec::new(),
+			unacked: VecDeque::new(),
+		}
+	}
+}
+
+impl<I> InputBuffer<I> {
+	/// Queue an input, it will be simulated and sent
+	/// before [`MessageOutgoingSet`].
+	pub fn push(&mut self, input: I) { self.pending.push(input); }
+
+	/// Inputs that have been simulated but not acknowledged.
+	pub fn unacked(&self) -> impl Iterator<Item = &(u32, I)> {
+		self.unacked.iter()
+	}
+
+	/// Discard inputs up to and including this sequence.
+	pub fn acknowledge(&mut self, sequence: u32) {
+		while self
+			.unacked
+			.front()
+			.is_some_and(|(other, _)| *other <= sequence)
+		{
+			self.unacked.pop_front();
+		}
+	}
+}
+
+/// Register prediction of `T` by inputs `I` in the predicting app, see
+/// [`App::predict`]. `T` should already be registered as incoming.
+pub(crate) fn register_prediction<
+	T: Component + Clone + Serialize + DeserializeOwned,
+	I: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+>(
+	app: &mut App,
+	simulate: SimulateFn<T, I>,
+) {
+	app.add_event::<PredictedInput<I>>()
+		.replicate_with::<InputAck<I>>(ReplicateDirection::Incoming)
+		.replicate_event_outgoing::<PredictedInput<I>>();
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<T>();
+	if let Some(fns) = registry.incoming_component_fns.get_mut(&id) {
+		// keep a copy of each authoritative value to roll back to
+		fns.insert = |commands, _, _, payload| {
+			let value = payload.deserialize::<T>()?;
+			commands.try_insert_if_new((Confirmed(value.clone()), value));
+			Ok(())
+		};
+		fns.change = |commands, _, _, payload| {
+			let value = payload.deserialize::<T>()?;
+			queue_if_exists(commands, move |entity| {
+				set_component(entity, Confirmed(value.clone()));
+				set_component(entity, value);
+			});
+			Ok(())
+		};
+	} else {
+		log::error!(
+			"{} must be registered as incoming before it can be predicted",
+			std::any::type_name::<T>()
+		);
+	}
+
+	app.insert_resource(Simulate(simulate)).add_systems(
+		Update,
+		(reconcile::<T, I>, predict_inputs::<T, I>)
+			.chain()
+			.after(MessageIncomingSet)
+			.before(MessageOutgoingSet),
+	);
+}
+
+/// Register the authoritative side of [`register_prediction`],
+/// see [`App::predict_authority`]. `T` should already be registered
+/// as outgoing.
+pub(crate) fn register_prediction_authority<
+	T: Component,
+	I: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+>(
+	app: &mut App,
+	simulate: SimulateFn<T, I>,
+) {
+	app.add_event::<PredictedInput<I>>()
+		.replicate_with::<InputAck<I>>(ReplicateDirection::Outgoing)
+		.replicate_event_incoming::<PredictedInput<I>>();
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<PredictedInput<I>>();
+	if let Some(fns) = registry.incoming_event_fns.get_mut(&id) {
+		// inputs are only applied if the sender owns the entity
+		fns.send = |world, sender, payload| {
+			let mut input = payload.deserialize::<PredictedInput<I>>()?;
+			input.sender = sender;
+			world.send_event(input);
+			Ok(())
+		};
+	}
+	app.insert_resource(Simulate(simulate))
+		.add_systems(
+			Update,
+			apply_inputs::<T, I>
+				.after(MessageIncomingSet)
+				.before(MessageOutgoingSet),
+		);
+}
+
+type Reconcile<'a, T, I> = (
+	&'a mut T,
+	Ref<'a, Confirmed<T>>,
+	Ref<'a, InputAck<I>>,
+	&'a mut InputBuffer<I>,
+);
+
+/// Reset components to their [`Confirmed`] value when it or the
+/// [`InputAck`] is received, and simulate the unacknowledged inputs again.
+fn reconcile<T: Component + Clone, I: 'static + Send + Sync>(
+	simulate: Res<Simulate<T, I>>,
+	mut query: Query<Reconcile<T, I>>,
+) {
+	for (mut value, confirmed, ack, mut buffer) in query.iter_mut() {
+		if !confirmed.is_changed() && !ack.is_changed() {
+			continue;
+		}
+		buffer.acknowledge(ack.sequence);
+		let mut next = confirmed.0.clone();
+		for (_, input) in buffer.unacked() {
+			(simulate.0)(&mut next, input);
+		}
+		*value = next;
+	}
+}
+
+/// Simulate pending inputs immediately and send them to the authoritative app.
+fn predict_inputs<T: Component, I: 'static + Send + Sync + Clone>(
+	simulate: Res<Simulate<T, I>>,
+	registrations: Res<ReplicateRegistry>,
+	mut query: Query<(Entity, &mut T, &mut InputBuffer<I>)>,
+	mut events: EventWriter<PredictedInput<I>>,
+) {
+	for (entity, mut value, mut buffer) in query.iter_mut() {
+		for input in std::mem::take(&mut buffer.pending) {
+			let sequence = buffer.next_sequence;
+			buffer.next_sequence += 1;
+			(simulate.0)(&mut value, &input);
+			let (entity, origin) = registrations.entities.outgoing(entity);
+			events.send(PredictedInput {
+				entity,
+				origin,
+				sequence,
+				input: input.clone(),
+				sender: DIRECT_CLIENT_ID,
+			});
+			buffer.unacked.push_back((sequence, input));
+		}
+	}
+}
+
+type ApplyInputs<'a, T, I> =
+	(&'a mut T, Option<&'a mut InputAck<I>>, Option<&'a Owner>);
+
+/// Apply received inputs in order, ignoring any that are
+/// older than the [`InputAck`] or not sent by the [`Owner`].
+/// Inputs for entities without an [`Owner`] are ignored.
+fn apply_inputs<T: Component, I: 'static + Send + Sync + Clone>(
+	simulate: Res<Simulate<T, I>>,
+	registrations: Res<ReplicateRegistry>,
+	local_client: Res<LocalClientId>,
+	mut commands: Commands,
+	mut events: EventReader<PredictedInput<I>>,
+	own: Query<(), (With<Replicate>, Without<RemoteEntity>)>,
+	mut query: Query<ApplyInputs<T, I>>,
+) {
+	// acks of entities without one yet, inserted after all inputs
+	let mut new_acks = HashMap::<Entity, u32>::default();
+	for event in events.read() {
+		let Some(entity) = registrations.entities.incoming(
+			event.sender,
+			*local_client,
+			event.entity,
+			event.origin,
+			|entity| own.contains(entity),
+		) else {
+			log::warn!("received input for unknown entity {}", event.entity);
+			continue;
+		};
+		let Ok((mut value, ack, owner)) = query.get_mut(entity) else {
+			log::warn!("received input for unknown entity {entity}");
+			continue;
+		};
+		if owner.is_none_or(|owner| owner.0 != event.sender) {
+			log::warn!(
+				"rejected input for {entity} from client {}, which is not the owner",
+				event.sender
+			);
+			continue;
+		}
+		let acked = match &ack {
+			Some(ack) => Some(ack.sequence),
+			None => new_acks.get(&entity).copied(),
+		};
+		if acked.is_some_and(|acked| acked >= event.sequence) {
+			continue;
+		}
+		match ack {
+			Some(mut ack) => ack.sequence = event.sequence,
+			None => {
+				new_acks.insert(entity, event.sequence);
+			}
+		}
+		(simulate.0)(&mut value, &event.input);
+	}
+	for (entity, sequence) in new_acks {
+		commands.entity(entity).insert(InputAck::<I>::new(sequence));
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use std::time::Duration;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct Position(i32);
+	#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+	struct Move(i32);
+
+	fn simulate(position: &mut Position, input: &Move) {
+		position.0 += input.0;
+	}
+
+	type Simulated = SimulatedTransport<ChannelsTransport>;
+
+	fn setup() -> (App, App) {
+		let (a, b) = ChannelsTransport::pair();
+		let a = SimulatedTransport::new(
+			a,
+			NetworkConditions::default()
+				.with_latency(Duration::from_millis(100)),
+		)
+		.with_manual_clock();
+
+		let mut client = App::new();
+		client
+			.add_plugins(ReplicatePlugin)
+			.init_resource::<Time>()
+			.replicate_with::<Position>(ReplicateDirection::Incoming)
+			.predict::<Position, Move>(simulate)
+			.add_transport_with_duration(a, Duration::ZERO);
+		let mut server = App::new();
+		server
+			.add_plugins(ReplicatePlugin)
+			.init_resource::<Time>()
+			.replicate_with::<Position>(ReplicateDirection::Outgoing)
+			.predict_authority::<Position, Move>(simulate)
+			.add_transport_with_duration(b, Duration::ZERO);
+		(client, server)
+	}
+
+	fn step(client: &mut App, server: &mut App) {
+		client
+			.world_mut()
+			.non_send_resource_mut::<Simulated>()
+			.advance(Duration::from_millis(10));
+		server.update();
+		client.update();
+	}
+
+	fn position(app: &mut App) -> i32 {
+		app.world_mut().query::<&Position>().single(app.world()).0
+	}
+
+	#[test]
+	fn predicts() -> Result<()> {
+		let (mut client, mut server) = setup();
+		let server_entity = server
+			.world_mut()
+			.spawn((
+				Replicate::default(),
+				Position(0),
+				Owner(DIRECT_CLIENT_ID),
+				// keep sending the authoritative value
+				Authority,
+			))
+			.id();
+		step(&mut client, &mut server);
+		step(&mut client, &mut server);
+		let entity = client
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, server_entity))
+			.unwrap();
+		client
+			.world_mut()
+			.entity_mut(entity)
+			.insert(InputBuffer::<Move>::default());
+
+		for i in 1..=20 {
+			client
+				.world_mut()
+				.get_mut::<InputBuffer<Move>>(entity)
+				.unwrap()
+				.push(Move(1));
+			step(&mut client, &mut server);
+			// applied immediately and never rolled back to an older value
+			expect(position(&mut client)).to_be(i);
+		}
+		expect(position(&mut server)).to_be_less_than(20);
+		for _ in 0..20 {
+			step(&mut client, &mut server);
+		}
+		expect(position(&mut server)).to_be(20);
+		expect(position(&mut client)).to_be(20);
+		let buffer = client.world().get::<InputBuffer<Move>>(entity).unwrap();
+		expect(buffer.unacked().count()).to_be(0);
+
+		// the authoritative value wins over the prediction
+		server
+			.world_mut()
+			.get_mut::<Position>(server_entity)
+			.unwrap()
+			.0 = 100;
+		client
+			.world_mut()
+			.get_mut::<InputBuffer<Move>>(entity)
+			.unwrap()
+			.push(Move(1));
+		step(&mut client, &mut server);
+		// the input is reapplied on top of the authoritative value
+		expect(position(&mut client)).to_be(101);
+		for _ in 0..20 {
+			step(&mut client, &mut server);
+		}
+		expect(position(&mut server)).to_be(101);
+		expect(position(&mut client)).to_be(101);
+		Ok(())
+	}
+
+	#[test]
+	fn owner() -> Result<()> {
+		let (mut client, mut server) = setup();
+		let server_entity = server
+			.world_mut()
+			.spawn((Replicate::default(), Position(0)))
+			.id();
+		step(&mut client, &mut server);
+		step(&mut client, &mut server);
+		let entity = client
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(0, server_entity))
+			.unwrap();
+		client
+			.world_mut()
+			.entity_mut(entity)
+			.insert(InputBuffer::<Move>::default());
+		let push = |client: &mut App| {
+			client
+				.world_mut()
+				.get_mut::<InputBuffer<Move>>(entity)
+				.unwrap()
+				.push(Move(1));
+		};
+
+		// inputs from a client that is not the owner are ignored
+		server
+			.world_mut()
+			.entity_mut(server_entity)
+			.insert(Owner(5));
+		push(&mut client);
+		for _ in 0..20 {
+			step(&mut client, &mut server);
+		}
+		expect(position(&mut server)).to_be(0);
+
+		server
+			.world_mut()
+			.entity_mut(server_entity)
+			.insert(Owner(DIRECT_CLIENT_ID));
+		push(&mut client);
+		for _ in 0..20 {
+			step(&mut client, &mut server);
+		}
+		expect(position(&mut server)).to_be(1);
+		Ok(())
+	}
+
+	#[test]
+	fn duplicate_inputs() -> Result<()> {
+		let (_, mut server) = setup();
+		let entity = server
+			.world_mut()
+			.spawn((Replicate::default(), Position(0), Owner(DIRECT_CLIENT_ID)))
+			.id();
+		let input = |sequence| PredictedInput {
+			entity,
+			origin: EntityOrigin::Receiver,
+			sequence,
+			input: Move(1),
+			sender: DIRECT_CLIENT_ID,
+		};
+		// duplicated and reordered before the entity has an ack
+		server.world_mut().send_event(input(2));
+		server.world_mut().send_event(input(1));
+		server.world_mut().send_event(input(2));
+		server.update();
+		expect(position(&mut server)).to_be(1);
+		expect(server.world().get::<InputAck<Move>>(entity))
+			.to_be(Some(&InputAck::new(2)));
+
+		// inputs for entities without an owner are ignored
+		server.world_mut().entity_mut(entity).remove::<Owner>();
+		server.world_mut().send_event(input(3));
+		server.update();
+		expect(position(&mut server)).to_be(1);
+		Ok(())
+	}
+}
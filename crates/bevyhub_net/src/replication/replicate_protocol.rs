@@ -0,0 +1,544 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use bevy::reflect::TypeInfo;
+use bevy::reflect::TypeRegistry;
+use bevy::reflect::VariantInfo;
+use bevy::utils::HashMap;
+use bevy::utils::HashSet;
+use std::any::TypeId;
+
+/// Version of the replication wire protocol,
+/// incremented on breaking changes to [`Message`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The protocol of this app, sent to peers by the [`ReplicateHandshakePlugin`].
+#[derive(Debug, Clone, PartialEq, Resource)]
+pub struct ReplicateProtocol {
+	pub version: u32,
+	/// The type name and schema hash of each registered type.
+	/// Types not registered in the [`AppTypeRegistry`] cannot be checked
+	/// and are hashed by name only.
+	pub schema: Vec<(String, u64)>,
+	/// Types hashed by name only, register them with
+	/// [`App::register_type`] to check their schema.
+	pub unchecked: Vec<String>,
+}
+
+impl ReplicateProtocol {
+	pub fn new(registry: &ReplicateRegistry, types: &TypeRegistry) -> Self {
+		let mut unchecked = Vec::new();
+		let mut schema = registry
+			.type_ids()
+			.map(|(type_id, name)| {
+				let hash = match types.get_type_info(type_id) {
+					Some(info) => schema_hash(types, info),
+					None => {
+						unchecked.push(name.to_string());
+						name_hash(name)
+					}
+				};
+				(name.to_string(), hash)
+			})
+			.collect::<Vec<_>>();
+		schema.sort();
+		unchecked.sort();
+		Self {
+			version: PROTOCOL_VERSION,
+			schema,
+			unchecked,
+		}
+	}
+
+	/// Warn about unchecked types that are not unchecked in `prev`,
+	/// so each is only logged once.
+	fn warn_unchecked(&self, prev: &[String]) {
+		for name in self.unchecked.iter().filter(|name| !prev.contains(name)) {
+			log::warn!(
+				"{name} is not registered in the AppTypeRegistry, peers with a different layout of it will not be rejected"
+			);
+		}
+	}
+
+	pub fn message(&self) -> Message {
+		Message::Protocol {
+			version: self.version,
+			schema: self.schema.clone(),
+		}
+	}
+
+	/// Check a peer's protocol is compatible with this one.
+	/// Only types registered by both apps are compared.
+	/// # Errors
+	/// With a description of every incompatibility.
+	pub fn check(
+		&self,
+		version: u32,
+		schema: &[(String, u64)],
+	) -> Result<(), String> {
+		let mut reasons = Vec::new();
+		if version != self.version {
+			reasons.push(format!(
+				"protocol version {version} does not match {}",
+				self.version
+			));
+		}
+		let schema = schema.iter().cloned().collect::<HashMap<_, _>>();
+		for (name, hash) in self.schema.iter() {
+			if schema.get(name).is_some_and(|other| other != hash) {
+				reasons.push(format!("type {name} has a different schema"));
+			}
+		}
+		if reasons.is_empty() {
+			Ok(())
+		} else {
+			Err(reasons.join("\n"))
+		}
+	}
+}
+
+/// Hash the reflected structure of a type, ie field names and types.
+/// The hash is stable across builds and platforms.
+pub fn schema_hash(types: &TypeRegistry, info: &TypeInfo) -> u64 {
+	let mut hasher = SchemaHasher::default();
+	hash_type_info(types, info, &mut hasher, &mut Vec::new());
+	hasher.0
+}
+
+/// Hash of a type that is not reflected, used in place
+/// of its [`schema_hash`].
+fn name_hash(name: &str) -> u64 {
+	let mut hasher = SchemaHasher::default();
+	hasher.write_str("unreflected");
+	hasher.write_str(name);
+	hasher.0
+}
+
+fn hash_type_info(
+	types: &TypeRegistry,
+	info: &TypeInfo,
+	hasher: &mut SchemaHasher,
+	visited: &mut Vec<TypeId>,
+) {
+	if visited.contains(&info.type_id()) {
+		return;
+	}
+	visited.push(info.type_id());
+
+	let mut field = |hasher: &mut SchemaHasher, type_id, type_path| {
+		hasher.write_str(type_path);
+		if let Some(info) = types.get_type_info(type_id) {
+			hash_type_info(types, info, hasher, visited);
+		}
+	};
+
+	match info {
+		TypeInfo::Struct(info) => {
+			hasher.write_str("struct");
+			for named in info.iter() {
+				hasher.write_str(named.name());
+				field(hasher, named.type_id(), named.type_path());
+			}
+		}
+		TypeInfo::TupleStruct(info) => {
+			hasher.write_str("tuple_struct");
+			for unnamed in info.iter() {
+				field(hasher, unnamed.type_id(), unnamed.type_path());
+			}
+		}
+		TypeInfo::Tuple(info) => {
+			hasher.write_str("tuple");
+			for unnamed in info.iter() {
+				field(hasher, unnamed.type_id(), unnamed.type_path());
+			}
+		}
+		TypeInfo::List(info) => {
+			hasher.write_str("list");
+			field(hasher, info.item_ty().id(), info.item_ty().path());
+		}
+		TypeInfo::Array(info) => {
+			hasher.write_str("array");
+			hasher.write(&(info.capacity() as u64).to_le_bytes());
+			field(hasher, info.item_ty().id(), info.item_ty().path());
+		}
+		TypeInfo::Map(info) => {
+			hasher.write_str("map");
+			field(hasher, info.key_ty().id(), info.key_ty().path());
+			field(hasher, info.value_ty().id(), info.value_ty().path());
+		}
+		TypeInfo::Set(info) => {
+			hasher.write_str("set");
+			field(hasher, info.value_ty().id(), info.value_ty().path());
+		}
+		TypeInfo::Enum(info) => {
+			hasher.write_str("enum");
+			for variant in info.iter() {
+				hasher.write_str(variant.name());
+				match variant {
+					VariantInfo::Struct(variant) => {
+						for named in variant.iter() {
+							hasher.write_str(named.name());
+							field(hasher, named.type_id(), named.type_path());
+						}
+					}
+					VariantInfo::Tuple(variant) => {
+						for unnamed in variant.iter() {
+							field(
+								hasher,
+								unnamed.type_id(),
+								unnamed.type_path(),
+							);
+						}
+					}
+					VariantInfo::Unit(_) => {}
+				}
+			}
+		}
+		TypeInfo::Opaque(info) => {
+			hasher.write_str("opaque");
+			hasher.write_str(info.type_path());
+		}
+	}
+}
+
+/// FNV-1a, unlike [`std::hash::DefaultHasher`] the output
+/// is the same for every build.
+struct SchemaHasher(u64);
+
+impl Default for SchemaHasher {
+	fn default() -> Self { Self(0xcbf29ce484222325) }
+}
+
+impl SchemaHasher {
+	fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.0 ^= *byte as u64;
+			self.0 = self.0.wrapping_mul(0x100000001b3);
+		}
+	}
+	fn write_str(&mut self, value: &str) {
+		self.write(value.as_bytes());
+		self.write(&[0xff]);
+	}
+}
+
+/// Sent when a peer's [`Message::Protocol`] is received.
+#[derive(Debug, Clone, PartialEq, Event)]
+pub enum ProtocolEvent {
+	Accepted {
+		client_id: ClientId,
+	},
+	/// Further messages from the peer will be ignored.
+	Rejected {
+		client_id: ClientId,
+		reason: String,
+	},
+}
+
+/// Peers that have sent their [`Message::Protocol`].
+#[derive(Debug, Default, Clone, Resource)]
+pub struct PeerProtocols {
+	pub accepted: HashSet<ClientId>,
+	/// Rejected peers and the reason.
+	pub rejected: HashMap<ClientId, String>,
+	/// Messages from peers that have not sent their protocol yet.
+	pub pending: HashMap<ClientId, Vec<Message>>,
+}
+
+impl PeerProtocols {
+	pub fn contains(&self, client_id: &ClientId) -> bool {
+		self.accepted.contains(client_id)
+			|| self.rejected.contains_key(client_id)
+	}
+
+	fn reject(&mut self, client_id: ClientId, reason: String) -> ProtocolEvent {
+		log::error!("rejected client {client_id}:\n{reason}");
+		self.accepted.remove(&client_id);
+		self.pending.remove(&client_id);
+		self.rejected.insert(client_id, reason.clone());
+		ProtocolEvent::Rejected { client_id, reason }
+	}
+}
+
+pub(crate) fn insert_protocol(
+	mut commands: Commands,
+	registry: Res<ReplicateRegistry>,
+	types: Res<AppTypeRegistry>,
+) {
+	let protocol = ReplicateProtocol::new(&registry, &types.read());
+	protocol.warn_unchecked(&[]);
+	commands.insert_resource(protocol);
+}
+
+/// Rebuilds the [`ReplicateProtocol`] when types are registered after
+/// startup, sending it and the registrations to peers again.
+pub fn update_protocol(
+	mut protocol: ResMut<ReplicateProtocol>,
+	mut registry: ResMut<ReplicateRegistry>,
+	types: Res<AppTypeRegistry>,
+	mut outgoing: ResMut<MessageOutgoing>,
+) {
+	if protocol.schema.len() == registry.type_ids().count() {
+		return;
+	}
+	let next = ReplicateProtocol::new(&registry, &types.read());
+	next.warn_unchecked(&protocol.unchecked);
+	*protocol = next;
+	registry.remap_peers();
+	outgoing.push(protocol.message());
+	outgoing.push(registry.registrations_message());
+}
+
+/// Checks received [`Message::Protocol`], replying with this app's
+/// protocol the first time a peer sends theirs.
+/// Messages from peers are held until their protocol is accepted,
+/// and discarded if it is rejected.
+pub fn check_protocol(
+	protocol: Res<ReplicateProtocol>,
+	mut peers: ResMut<PeerProtocols>,
+	mut incoming: ResMut<MessageIncoming>,
+	mut outgoing: ResMut<MessageOutgoing>,
+	mut peer_outgoing: ResMut<PeerOutgoing>,
+	mut events: EventWriter<ProtocolEvent>,
+) {
+	let mut sender = DIRECT_CLIENT_ID;
+	let mut messages = Vec::with_capacity(incoming.len());
+	for message in incoming.drain(..) {
+		match &message {
+			Message::Sender { client_id } => {
+				sender = *client_id;
+				messages.push(message);
+				continue;
+			}
+			Message::Protocol { version, schema } => {
+				if !peers.contains(&sender) {
+					// other peers already have it
+					if sender == DIRECT_CLIENT_ID {
+						outgoing.push(protocol.message());
+					} else {
+						peer_outgoing.push(sender, protocol.message());
+					}
+				}
+				match protocol.check(*version, schema) {
+					Ok(()) => {
+						peers.rejected.remove(&sender);
+						peers.accepted.insert(sender);
+						events.send(ProtocolEvent::Accepted {
+							client_id: sender,
+						});
+						messages.push(message);
+						if let Some(held) = peers.pending.remove(&sender) {
+							messages.extend(held);
+						}
+					}
+					Err(reason) => {
+						events.send(peers.reject(sender, reason));
+					}
+				}
+				continue;
+			}
+			Message::PeerDisconnected { client_id } => {
+				peers.accepted.remove(client_id);
+				peers.rejected.remove(client_id);
+				peers.pending.remove(client_id);
+			}
+			_ => {}
+		}
+		if peers.rejected.contains_key(&sender) {
+			continue;
+		}
+		if !peers.accepted.contains(&sender) && awaits_handshake(&message) {
+			let held = peers.pending.entry(sender).or_default();
+			if held.len() < MAX_PENDING_MESSAGES {
+				held.push(message);
+			} else {
+				let reason = "sent messages without a protocol".to_string();
+				events.send(peers.reject(sender, reason));
+			}
+			continue;
+		}
+		messages.push(message);
+	}
+	incoming.0 = messages;
+}
+
+#[cfg(test)]
+mod test {
+	use super::schema_hash;
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use bevy::reflect::TypeRegistry;
+	use bevy::reflect::Typed;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	mod v1 {
+		use super::*;
+		#[derive(Component, Reflect, Serialize, Deserialize)]
+		pub struct Health {
+			pub value: u32,
+		}
+	}
+	mod v1_copy {
+		use super::*;
+		#[derive(Component, Reflect, Serialize, Deserialize)]
+		pub struct Health {
+			pub value: u32,
+		}
+	}
+	mod v2 {
+		use super::*;
+		#[derive(Component, Reflect, Serialize, Deserialize)]
+		pub struct Health {
+			pub value: u64,
+		}
+	}
+
+	#[test]
+	fn hash() {
+		let types = TypeRegistry::new();
+		let hash = |info| schema_hash(&types, info);
+		expect(hash(v1::Health::type_info()))
+			.to_be(hash(v1_copy::Health::type_info()));
+		expect(hash(v1::Health::type_info()))
+			.not()
+			.to_be(hash(v2::Health::type_info()));
+	}
+
+	#[test]
+	fn rejects() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins((ReplicatePlugin, ReplicateHandshakePlugin))
+			.register_type::<v1::Health>()
+			.replicate::<v1::Health>();
+		app.update();
+		let protocol = app.world().resource::<ReplicateProtocol>().clone();
+		expect(protocol.schema.len()).to_be(1);
+		expect(protocol.unchecked.len()).to_be(0);
+		expect(&app.world().resource::<MessageOutgoing>()[0])
+			.to_be(&protocol.message());
+		app.world_mut().resource_mut::<MessageOutgoing>().clear();
+
+		let mut schema = protocol.schema.clone();
+		schema[0].1 += 1;
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			protocol.message(),
+			Message::Sender { client_id: 2 },
+			Message::Protocol {
+				version: PROTOCOL_VERSION + 1,
+				schema,
+			},
+			Message::Spawn {
+				entity: Entity::from_raw(0),
+			},
+		];
+		app.update();
+
+		let events = app
+			.world()
+			.resource::<Events<ProtocolEvent>>()
+			.iter_current_update_events()
+			.cloned()
+			.collect::<Vec<_>>();
+		expect(events).to_be(vec![
+			ProtocolEvent::Accepted { client_id: 1 },
+			ProtocolEvent::Rejected {
+				client_id: 2,
+				reason: format!(
+					"protocol version {} does not match {PROTOCOL_VERSION}\ntype {} has a different schema",
+					PROTOCOL_VERSION + 1,
+					std::any::type_name::<v1::Health>()
+				),
+			},
+		]);
+		// reply only to each new peer
+		expect(app.world().resource::<MessageOutgoing>().len()).to_be(0);
+		let peer_outgoing = app.world().resource::<PeerOutgoing>();
+		expect(&peer_outgoing[&1]).to_be(&vec![protocol.message()]);
+		expect(&peer_outgoing[&2]).to_be(&vec![protocol.message()]);
+		// the spawn from the rejected peer was ignored
+		expect(
+			app.world_mut()
+				.query::<&RemoteEntity>()
+				.iter(app.world())
+				.count(),
+		)
+		.to_be(0);
+		Ok(())
+	}
+
+	#[test]
+	fn holds_until_protocol() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins((ReplicatePlugin, ReplicateHandshakePlugin))
+			.replicate::<v1::Health>();
+		app.update();
+		let protocol = app.world().resource::<ReplicateProtocol>().clone();
+		// not in the type registry so only the name is checked
+		expect(&protocol.unchecked)
+			.to_be(&vec![std::any::type_name::<v1::Health>().to_string()]);
+		let registrations = app
+			.world()
+			.resource::<ReplicateRegistry>()
+			.registrations_message();
+
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			Message::Spawn {
+				entity: Entity::from_raw(0),
+			},
+		];
+		app.update();
+		expect(app.world().resource::<PeerProtocols>().pending[&1].len())
+			.to_be(1);
+
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::Sender { client_id: 1 },
+			protocol.message(),
+			registrations,
+		];
+		app.update();
+		expect(app.world().resource::<PeerProtocols>().pending.len())
+			.to_be(0);
+		expect(
+			app.world_mut()
+				.query::<&RemoteEntity>()
+				.iter(app.world())
+				.count(),
+		)
+		.to_be(1);
+		Ok(())
+	}
+
+	#[test]
+	fn late_registration() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins((ReplicatePlugin, ReplicateHandshakePlugin))
+			.replicate::<v1::Health>();
+		app.update();
+		app.world_mut().resource_mut::<MessageOutgoing>().clear();
+		// a peer registered a type this app does not know yet
+		let name = std::any::type_name::<v2::Health>().to_string();
+		app.world_mut()
+			.resource_mut::<ReplicateRegistry>()
+			.insert_peer(1, &[(name, RegistrationId::new_with(4))]);
+
+		app.replicate::<v2::Health>();
+		app.update();
+		let protocol = app.world().resource::<ReplicateProtocol>().clone();
+		expect(protocol.schema.len()).to_be(2);
+		let registry = app.world().resource::<ReplicateRegistry>();
+		expect(app.world().resource::<MessageOutgoing>().0.clone()).to_be(
+			vec![protocol.message(), registry.registrations_message()],
+		);
+		expect(registry.peers[&1].to_local[&RegistrationId::new_with(4)])
+			.to_be(registry.registration_id::<v2::Health>());
+		// no further updates
+		app.world_mut().resource_mut::<MessageOutgoing>().clear();
+		app.update();
+		expect(app.world().resource::<MessageOutgoing>().len()).to_be(0);
+		Ok(())
+	}
+}
@@ -0,0 +1,430 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use bevy::reflect::serde::TypedReflectDeserializer;
+use bevy::reflect::serde::TypedReflectSerializer;
+use bevy::reflect::TypeRegistration;
+use bevy::reflect::TypeRegistry;
+use forky::prelude::ResultTEExt;
+use std::any::TypeId;
+
+impl ComponentFns {
+	/// Functions for components registered with [`App::replicate_reflect`],
+	/// deserialized with the [`ReplicateRegistry::type_registry`].
+	pub fn reflect() -> Self {
+		Self {
+			insert: |commands, reg_id, _, payload| {
+				let payload = payload.clone();
+				queue_reflect(
+					commands,
+					reg_id,
+					move |entity, registration, registry| {
+						// unreliable changes may arrive before the add,
+						// in which case the change is newer
+						if entity.contains_type_id(registration.type_id()) {
+							return Ok(());
+						}
+						let value = payload.deserialize_seed(
+							TypedReflectDeserializer::new(
+								registration,
+								registry,
+							),
+						)?;
+						reflect_component(registration)?.insert(
+							entity,
+							value.as_ref(),
+							registry,
+						);
+						Ok(())
+					},
+				);
+				Ok(())
+			},
+			change: |commands, reg_id, _, payload| {
+				let payload = payload.clone();
+				queue_reflect(
+					commands,
+					reg_id,
+					move |entity, registration, registry| {
+						let value = payload.deserialize_seed(
+							TypedReflectDeserializer::new(
+								registration,
+								registry,
+							),
+						)?;
+						// applied in place if it exists, so only
+						// change detection is triggered
+						reflect_component(registration)?.apply_or_insert(
+							entity,
+							value.as_ref(),
+							registry,
+						);
+						let type_id = registration.type_id();
+						let ticks = entity
+							.world()
+							.components()
+							.get_id(type_id)
+							.and_then(|id| entity.get_change_ticks_by_id(id));
+						if let Some(ticks) = ticks {
+							IncomingChanges::record(
+								entity,
+								type_id,
+								ticks.changed,
+							);
+						}
+						Ok(())
+					},
+				);
+				Ok(())
+			},
+			apply_delta: |_, _, _| {
+				log::error!(
+					"deltas are not supported for reflected components"
+				);
+			},
+			remove: |commands, reg_id| {
+				queue_reflect(commands, reg_id, |entity, registration, _| {
+					reflect_component(registration)?.remove(entity);
+					Ok(())
+				});
+			},
+		}
+	}
+}
+
+/// Run `func` with the [`TypeRegistration`] of a reflected component,
+/// does nothing if the entity was despawned earlier in the same batch.
+fn queue_reflect(
+	commands: &mut EntityCommands,
+	reg_id: RegistrationId,
+	func: impl 'static
+		+ Send
+		+ FnOnce(
+			&mut EntityWorldMut,
+			&TypeRegistration,
+			&TypeRegistry,
+		) -> Result<()>,
+) {
+	let entity = commands.id();
+	commands.commands().queue(move |world: &mut World| {
+		let registrations = world.resource::<ReplicateRegistry>();
+		let Some(type_id) = registrations.reflect_types.get(&reg_id).copied()
+		else {
+			return;
+		};
+		let type_registry = registrations.type_registry.clone();
+		let type_registry = type_registry.read();
+		let Some(registration) = type_registry.get(type_id) else {
+			log::error!("reflected type is not in the AppTypeRegistry");
+			return;
+		};
+		if let Ok(mut entity) = world.get_entity_mut(entity) {
+			func(&mut entity, registration, &type_registry)
+				.ok_or(|e| log::error!("{e}"));
+		}
+	});
+}
+
+fn reflect_component(
+	registration: &TypeRegistration,
+) -> Result<&ReflectComponent> {
+	registration.data::<ReflectComponent>().ok_or_else(|| {
+		anyhow::anyhow!(
+			"{} does not reflect Component, add #[reflect(Component)]",
+			registration.type_info().type_path()
+		)
+	})
+}
+
+/// Serialize a reflected component if the entity has it,
+/// this is the [`SnapshotComponentFn`] of reflected components.
+pub fn reflect_payload(
+	registrations: &ReplicateRegistry,
+	reg_id: RegistrationId,
+	entity: &EntityRef,
+) -> Option<Result<MessagePayload>> {
+	let type_id = registrations.reflect_types.get(&reg_id)?;
+	let type_registry = registrations.type_registry.read();
+	let registration = type_registry.get(*type_id)?;
+	let value = match reflect_component(registration) {
+		Ok(component) => component.reflect(entity)?,
+		Err(err) => return Some(Err(err)),
+	};
+	Some(MessagePayload::new(TypedReflectSerializer::new(
+		value.as_partial_reflect(),
+		&type_registry,
+	)))
+}
+
+/// Register a reflected component by its [`TypeId`],
+/// see [`App::replicate_by_type_path`].
+/// # Errors
+/// If the type is not in the [`AppTypeRegistry`] or does not reflect [`Component`].
+pub(crate) fn register_reflect(
+	app: &mut App,
+	type_id: TypeId,
+	direction: ReplicateDirection,
+) -> Result<()> {
+	let type_registry = app.world().resource::<AppTypeRegistry>().clone();
+	let registry = type_registry.read();
+	let Some(registration) = registry.get(type_id) else {
+		anyhow::bail!("type is not registered in the AppTypeRegistry");
+	};
+	let component_id =
+		reflect_component(registration)?.register_component(app.world_mut());
+	let mut registrations = app
+		.init_resource::<ReplicateRegistry>()
+		.world_mut()
+		.resource_mut::<ReplicateRegistry>();
+	registrations.type_registry = type_registry.clone();
+	let reg_id = registrations.register_component_reflect(
+		type_id,
+		registration.type_info().type_path(),
+		direction,
+	);
+	if direction.is_outgoing() {
+		registrations
+			.snapshot_component_fns
+			.insert(reg_id, reflect_payload);
+		register_reflect_outgoing(app, reg_id, component_id);
+	}
+	Ok(())
+}
+
+fn register_reflect_outgoing(
+	app: &mut App,
+	reg_id: RegistrationId,
+	component_id: ComponentId,
+) {
+	app.world_mut().spawn(
+		Observer::new(
+			move |trigger: Trigger<OnAdd>, mut commands: Commands| {
+				let entity = trigger.entity();
+				commands.queue(move |world: &mut World| {
+					outgoing_reflect(
+						world,
+						reg_id,
+						entity,
+						|(entity, origin), payload| Message::Add {
+							entity,
+							origin,
+							reg_id,
+							payload,
+						},
+					);
+				});
+			},
+		)
+		.with_component(component_id),
+	);
+	app.world_mut().spawn(
+		Observer::new(
+			move |trigger: Trigger<OnRemove>,
+			      registrations: Res<ReplicateRegistry>,
+			      mut outgoing: ResMut<MessageOutgoing>,
+			      query: Query<(), (With<Replicate>, HasAuthority)>| {
+				if query.contains(trigger.entity()) {
+					let (entity, origin) =
+						registrations.entities.outgoing(trigger.entity());
+					outgoing.push(Message::Remove {
+						entity,
+						origin,
+						reg_id,
+					});
+				}
+			},
+		)
+		.with_component(component_id),
+	);
+	app.add_systems(
+		Update,
+		(move |world: &mut World| {
+			outgoing_reflect_change(world, reg_id, component_id);
+		})
+		.in_set(MessageOutgoingSet),
+	);
+}
+
+/// Push a message with the reflected value of a component
+/// if the entity is replicated by this app.
+fn outgoing_reflect(
+	world: &mut World,
+	reg_id: RegistrationId,
+	entity: Entity,
+	message: impl FnOnce((Entity, EntityOrigin), MessagePayload) -> Message,
+) {
+	let mut query =
+		world.query_filtered::<EntityRef, (With<Replicate>, HasAuthority)>();
+	let Ok(entity) = query.get(world, entity) else {
+		return;
+	};
+	let registrations = world.resource::<ReplicateRegistry>();
+	let Some(payload) = reflect_payload(registrations, reg_id, &entity)
+		.and_then(|payload| payload.ok_or(|e| log::error!("{e}")))
+	else {
+		return;
+	};
+	let message =
+		message(registrations.entities.outgoing(entity.id()), payload);
+	world.resource_mut::<MessageOutgoing>().push(message);
+}
+
+/// Like the outgoing change system of [`App::replicate`],
+/// using the change ticks of the component.
+fn outgoing_reflect_change(
+	world: &mut World,
+	reg_id: RegistrationId,
+	component_id: ComponentId,
+) {
+	let last_run = world.last_change_tick();
+	let this_run = world.change_tick();
+	let type_id = world
+		.components()
+		.get_info(component_id)
+		.and_then(|info| info.type_id());
+	let mut query =
+		world.query_filtered::<EntityRef, (With<Replicate>, HasAuthority)>();
+	let registrations = world.resource::<ReplicateRegistry>();
+	let incoming = world.resource::<IncomingChanges>();
+	let messages = query
+		.iter(world)
+		.filter(|entity| {
+			entity
+				.get_change_ticks_by_id(component_id)
+				.is_some_and(|ticks| {
+					ticks.is_changed(last_run, this_run)
+						&& !ticks.is_added(last_run, this_run)
+						&& !type_id.is_some_and(|id| {
+							incoming.is_echo(entity.id(), id, ticks.changed)
+						})
+				})
+		})
+		.filter_map(|entity| {
+			let payload = reflect_payload(registrations, reg_id, &entity)?
+				.ok_or(|e| log::error!("{e}"))?;
+			let (entity, origin) = registrations.entities.outgoing(entity.id());
+			Some(Message::Change {
+				entity,
+				origin,
+				reg_id,
+				payload,
+			})
+		})
+		.collect::<Vec<_>>();
+	world.resource_mut::<MessageOutgoing>().extend(messages);
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Default, Clone, PartialEq, Component, Reflect)]
+	#[reflect(Component)]
+	struct MyComponent {
+		value: u32,
+		name: String,
+	}
+
+	fn app() -> App {
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin)
+			.replicate_reflect::<MyComponent>();
+		app
+	}
+
+	#[test]
+	fn works() -> Result<()> {
+		let mut app1 = app();
+		let mut app2 = app();
+		let entity1 = app1
+			.world_mut()
+			.spawn((
+				Replicate::default(),
+				MyComponent {
+					value: 1,
+					name: "foo".into(),
+				},
+			))
+			.id();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		let value = |app: &mut App| {
+			app.world_mut()
+				.query::<&MyComponent>()
+				.get_single(app.world())
+				.ok()
+				.cloned()
+		};
+		expect(value(&mut app2)).to_be(Some(MyComponent {
+			value: 1,
+			name: "foo".into(),
+		}));
+
+		app1.world_mut()
+			.get_mut::<MyComponent>(entity1)
+			.unwrap()
+			.value = 2;
+		app1.update();
+		let bytes = Message::vec_into_bytes(
+			app1.world().resource::<MessageOutgoing>(),
+		)?;
+		app1.world_mut().resource_mut::<MessageOutgoing>().0 =
+			Message::vec_from_bytes(&bytes)?;
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(value(&mut app2).map(|v| v.value)).to_be(Some(2));
+
+		app1.world_mut().entity_mut(entity1).remove::<MyComponent>();
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(value(&mut app2)).to_be_none();
+		Ok(())
+	}
+
+	#[test]
+	fn type_path() -> Result<()> {
+		let app = || {
+			let mut app = App::new();
+			app.add_plugins(ReplicatePlugin)
+				.register_type::<Transform>()
+				.replicate_by_type_path(
+					"bevy_transform::components::transform::Transform",
+				);
+			app
+		};
+		let mut app1 = app();
+		let mut app2 = app();
+		app1.world_mut()
+			.spawn((Replicate::default(), Transform::from_xyz(1., 2., 3.)));
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		let transform =
+			*app2.world_mut().query::<&Transform>().single(app2.world());
+		expect(transform).to_be(Transform::from_xyz(1., 2., 3.));
+		Ok(())
+	}
+
+	#[test]
+	fn snapshot() -> Result<()> {
+		let mut app1 = app();
+		app1.world_mut()
+			.spawn((Replicate::default(), MyComponent::default()));
+		app1.update();
+		let messages = crate::prelude::snapshot(app1.world_mut());
+		expect(
+			messages
+				.iter()
+				.any(|message| matches!(message, Message::Change { .. })),
+		)
+		.to_be_true();
+		Ok(())
+	}
+}
@@ -1,6 +1,9 @@
 use crate::prelude::*;
+use anyhow::Result;
 use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
 use bevy::utils::HashMap;
+use bevy::utils::HashSet;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
@@ -36,13 +39,35 @@ pub struct ReplicateRegistry {
 
 	type_names: HashMap<RegistrationId, String>,
 
-	/// Map of remote to local entity ids
-	pub entities: HashMap<Entity, Entity>,
+	/// Bidirectional map of remote and local entity ids
+	pub entities: EntityMap,
 	pub incoming_component_fns: HashMap<RegistrationId, ComponentFns>,
 	pub incoming_resource_fns: HashMap<RegistrationId, ResourceFns>,
 	pub incoming_event_fns: HashMap<RegistrationId, EventFns>,
 	pub incoming_observer_fns: HashMap<RegistrationId, ObserverFns>,
+	pub snapshot_component_fns: HashMap<RegistrationId, SnapshotComponentFn>,
+	pub snapshot_resource_fns: HashMap<RegistrationId, SnapshotResourceFn>,
+	/// Components with entity references, see [`App::replicate_map_entities`]
+	pub outgoing_map_fns: HashMap<RegistrationId, MapOutgoingFn>,
+	/// Types registered with [`App::replicate_reflect`] or
+	/// [`App::replicate_by_type_path`]
+	pub reflect_types: HashMap<RegistrationId, TypeId>,
+	/// The [`AppTypeRegistry`] of the app, used by [`Self::reflect_types`]
+	pub type_registry: AppTypeRegistry,
 	pub directions: HashMap<RegistrationId, ReplicateDirection>,
+	/// Channels that changes are sent on, defaults to [`Channel::RELIABLE`]
+	pub channels: HashMap<RegistrationId, Channel>,
+	/// Types registered with [`App::replicate_delta`], their changes
+	/// are always sent on [`Channel::RELIABLE`]
+	pub delta_types: HashSet<RegistrationId>,
+	/// Priorities of changes sent over a [`TransportBudget`], defaults to 1
+	pub priorities: HashMap<RegistrationId, f32>,
+	/// Registrations of each peer that has sent [`Message::Registrations`]
+	pub peers: HashMap<ClientId, PeerRegistrations>,
+	/// Components removed by a [`Message::Remove`], changes to them are
+	/// dropped until they are added again, as an unreliable change sent
+	/// before the remove may arrive after it.
+	pub removed: HashSet<(Entity, RegistrationId)>,
 }
 
 impl ReplicateRegistry {
@@ -55,6 +80,188 @@ impl ReplicateRegistry {
 		}
 	}
 
+	/// Find a registration by its type path, using the [`TypeRegistry`]
+	/// if the type is registered there, otherwise by [`std::any::type_name`].
+	pub fn registration_id_by_path(
+		&self,
+		type_registry: &TypeRegistry,
+		type_path: &str,
+	) -> Option<RegistrationId> {
+		if let Some(id) = type_registry
+			.get_with_type_path(type_path)
+			.and_then(|registration| self.types.get(&registration.type_id()))
+		{
+			return Some(*id);
+		}
+		self.type_names
+			.iter()
+			.find(|(_, name)| *name == type_path)
+			.map(|(id, _)| *id)
+	}
+
+	/// The channel a message should be sent on. Only [`Message::Change`]
+	/// and [`Message::ChangeResource`] use the registered channel,
+	/// deltas depend on the previous value so are always reliable.
+	pub fn channel(&self, message: &Message) -> Channel {
+		self.channel_or(message, Channel::RELIABLE)
+	}
+
+	/// As [`Self::channel`], with the channel of changes
+	/// without a registered channel, see [`Transport::change_channel`].
+	pub fn channel_or(&self, message: &Message, default: Channel) -> Channel {
+		match message {
+			Message::Change { reg_id, .. }
+				if self.delta_types.contains(reg_id) =>
+			{
+				Channel::RELIABLE
+			}
+			Message::Change { reg_id, .. }
+			| Message::ChangeResource { reg_id, .. } => {
+				self.channels.get(reg_id).copied().unwrap_or(default)
+			}
+			_ => Channel::RELIABLE,
+		}
+	}
+
+	/// Serialize an outgoing component, mapping its entity references
+	/// to the ids used by peers if registered with [`App::replicate_map_entities`].
+	pub fn outgoing_payload<T: Component + Serialize>(
+		&self,
+		value: &T,
+	) -> Result<MessagePayload> {
+		match self
+			.types
+			.get(&TypeId::of::<T>())
+			.and_then(|id| self.outgoing_map_fns.get(id))
+		{
+			Some(map) => map(&self.entities, value),
+			None => MessagePayload::new(value),
+		}
+	}
+
+	/// Set the channel of changes to `T`, types registered with
+	/// [`App::replicate_delta`] can only be sent reliably.
+	pub fn set_channel<T: 'static>(&mut self, channel: Channel) {
+		let id = self.registration_id::<T>();
+		if self.delta_types.contains(&id) && !channel.kind.is_reliable() {
+			log::error!(
+				"{} is replicated with deltas, its changes must be reliable",
+				std::any::type_name::<T>()
+			);
+			return;
+		}
+		self.channels.insert(id, channel);
+	}
+
+	/// The priority of changes to a registration, see [`TransportBudget`].
+	pub fn priority(&self, reg_id: RegistrationId) -> f32 {
+		self.priorities.get(&reg_id).copied().unwrap_or(1.)
+	}
+
+	pub fn set_priority<T: 'static>(&mut self, priority: f32) {
+		let id = self.registration_id::<T>();
+		self.priorities.insert(id, priority);
+	}
+
+	/// The [`TypeId`] and [`std::any::type_name`] of each registered type.
+	pub fn type_ids(&self) -> impl Iterator<Item = (TypeId, &str)> {
+		self.types.iter().filter_map(|(type_id, id)| {
+			self.type_names
+				.get(id)
+				.map(|name| (*type_id, name.as_str()))
+		})
+	}
+
+	/// Types registered with [`std::any::type_name`], used when ids
+	/// are negotiated with [`Message::Registrations`] and [`ReplicateHandshakePlugin`].
+	/// Peers that do not send their registrations must register types in
+	/// the same order, [`Self::types_to_json`] can be used to compare them.
+	pub fn registrations_message(&self) -> Message {
+		let mut types = self
+			.type_names
+			.iter()
+			.map(|(id, name)| (name.clone(), *id))
+			.collect::<Vec<_>>();
+		types.sort_by_key(|(_, id)| *id);
+		Message::Registrations { types }
+	}
+
+	/// Store the registrations of a peer, returning the names of
+	/// types registered by the peer but not by this app.
+	pub fn insert_peer(
+		&mut self,
+		client_id: ClientId,
+		types: &[(String, RegistrationId)],
+	) -> Vec<String> {
+		let mut peer = PeerRegistrations {
+			type_names: types
+				.iter()
+				.map(|(name, remote_id)| (*remote_id, name.clone()))
+				.collect(),
+			..default()
+		};
+		let missing = self.map_peer(&mut peer);
+		self.peers.insert(client_id, peer);
+		missing
+	}
+
+	/// Map the registrations of every peer to local ids again,
+	/// used when types are registered after peers sent theirs.
+	pub fn remap_peers(&mut self) {
+		let mut peers = std::mem::take(&mut self.peers);
+		for peer in peers.values_mut() {
+			self.map_peer(peer);
+		}
+		self.peers = peers;
+	}
+
+	fn map_peer(&self, peer: &mut PeerRegistrations) -> Vec<String> {
+		peer.to_local.clear();
+		let mut missing = Vec::new();
+		for (remote_id, name) in peer.type_names.iter() {
+			match self.type_names.iter().find(|(_, local)| *local == name) {
+				Some((local_id, _)) => {
+					peer.to_local.insert(*remote_id, *local_id);
+				}
+				None => missing.push(name.clone()),
+			}
+		}
+		missing.sort();
+		missing
+	}
+
+	/// Map the registration id of a message from a peer to the local id.
+	/// Messages from peers that have not sent their registrations
+	/// are assumed to use the same ids, the [`ReplicateHandshakePlugin`]
+	/// holds them in [`PendingRegistrations`] instead.
+	/// # Errors
+	/// If the type is unknown or not registered by this app.
+	pub fn remap(
+		&self,
+		client_id: ClientId,
+		mut message: Message,
+	) -> Result<Message> {
+		let Some(peer) = self.peers.get(&client_id) else {
+			return Ok(message);
+		};
+		if let Some(reg_id) = message.reg_id_mut() {
+			let remote_id = *reg_id;
+			let Some(local_id) = peer.to_local.get(&remote_id) else {
+				match peer.type_names.get(&remote_id) {
+					Some(name) => anyhow::bail!(
+						"client {client_id} sent type {name} which is not registered in this app"
+					),
+					None => anyhow::bail!(
+						"client {client_id} sent unknown registration id {}",
+						remote_id.inner()
+					),
+				}
+			};
+			*reg_id = *local_id;
+		}
+		Ok(message)
+	}
+
 	pub fn types_to_json(&self) -> String {
 		let mut types = self.types.values().collect::<Vec<_>>();
 		types.sort();
@@ -71,12 +278,12 @@ impl ReplicateRegistry {
 
 	pub fn entity_fns(
 		&self,
-		remote: Entity,
+		remote: RemoteEntity,
 		id: RegistrationId,
 	) -> Option<(Entity, &ComponentFns)> {
-		if let Some(entity) = self.entities.get(&remote) {
+		if let Some(entity) = self.entities.local(remote) {
 			if let Some(fns) = self.incoming_component_fns.get(&id) {
-				return Some((*entity, fns));
+				return Some((entity, fns));
 			}
 		}
 		None
@@ -85,18 +292,29 @@ impl ReplicateRegistry {
 	fn next_id<T: 'static>(
 		&mut self,
 		direction: ReplicateDirection,
+	) -> RegistrationId {
+		self.next_id_with(
+			TypeId::of::<T>(),
+			std::any::type_name::<T>(),
+			direction,
+		)
+	}
+
+	fn next_id_with(
+		&mut self,
+		type_id: TypeId,
+		type_name: &str,
+		direction: ReplicateDirection,
 	) -> RegistrationId {
 		let id = RegistrationId(self.id_incr);
 		self.id_incr += 1;
 		self.directions.insert(id, direction);
-		self.types.insert(std::any::TypeId::of::<T>(), id);
-		#[cfg(debug_assertions)]
-		self.type_names
-			.insert(id, std::any::type_name::<T>().to_string());
+		self.types.insert(type_id, id);
+		self.type_names.insert(id, type_name.to_string());
 		id
 	}
 
-	pub fn register_component<T: Component + DeserializeOwned>(
+	pub fn register_component<T: Component + Serialize + DeserializeOwned>(
 		&mut self,
 		direction: ReplicateDirection,
 	) -> RegistrationId {
@@ -107,6 +325,22 @@ impl ReplicateRegistry {
 		}
 		id
 	}
+	/// Register a component by its [`TypeId`], serialized with reflection.
+	/// The type must be registered in the [`Self::type_registry`].
+	pub fn register_component_reflect(
+		&mut self,
+		type_id: TypeId,
+		type_path: &str,
+		direction: ReplicateDirection,
+	) -> RegistrationId {
+		let id = self.next_id_with(type_id, type_path, direction);
+		self.reflect_types.insert(id, type_id);
+		if direction.is_incoming() {
+			self.incoming_component_fns
+				.insert(id, ComponentFns::reflect());
+		}
+		id
+	}
 	pub fn register_resource<T: Resource + DeserializeOwned>(
 		&mut self,
 		direction: ReplicateDirection,
@@ -7,6 +7,7 @@ use std::path::PathBuf;
 
 /// Replicated components and resources have unique ids that
 /// must be consistent among apps. Use this exporter to share them
+/// with peers that do not use the [`ReplicateHandshakePlugin`].
 pub struct ReplicateRegistryExporter<P, M> {
 	pub plugin: P,
 	pub path: PathBuf,
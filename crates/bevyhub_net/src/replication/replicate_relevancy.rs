@@ -0,0 +1,432 @@
+use crate::prelude::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy::utils::HashSet;
+
+/// Which peers an entity is replicated to, entities without this
+/// component are visible to every peer.
+#[derive(Debug, Default, Clone, PartialEq, Component)]
+pub enum ReplicateVisibility {
+	#[default]
+	All,
+	/// Only visible to these peers.
+	Only(HashSet<ClientId>),
+	/// Visible to every peer except these.
+	Except(HashSet<ClientId>),
+}
+
+impl ReplicateVisibility {
+	pub fn only(clients: impl IntoIterator<Item = ClientId>) -> Self {
+		Self::Only(clients.into_iter().collect())
+	}
+	pub fn except(clients: impl IntoIterator<Item = ClientId>) -> Self {
+		Self::Except(clients.into_iter().collect())
+	}
+	pub fn is_visible(&self, client_id: ClientId) -> bool {
+		match self {
+			Self::All => true,
+			Self::Only(clients) => clients.contains(&client_id),
+			Self::Except(clients) => !clients.contains(&client_id),
+		}
+	}
+}
+
+/// The [`Transform`] a peer views the world from, used by [`InterestRadius`].
+#[derive(Debug, Copy, Clone, PartialEq, Component)]
+pub struct Viewpoint(pub ClientId);
+
+/// Only replicate this entity to peers with a [`Viewpoint`]
+/// within this distance of its [`Transform`].
+#[derive(Debug, Copy, Clone, PartialEq, Component)]
+pub struct InterestRadius(pub f32);
+
+/// A custom check that an entity is relevant to a peer.
+pub type RelevancyFn = fn(&World, Entity, ClientId) -> bool;
+
+/// The entities relevant to each connected peer.
+#[derive(Default, Clone, Resource)]
+pub struct Relevancy {
+	/// Custom checks, an entity is only relevant if every one returns true.
+	pub predicates: Vec<RelevancyFn>,
+	/// The relevancy set of each peer as of the last update.
+	pub peers: HashMap<ClientId, HashSet<Entity>>,
+}
+
+impl Relevancy {
+	pub fn with_predicate(mut self, predicate: RelevancyFn) -> Self {
+		self.predicates.push(predicate);
+		self
+	}
+
+	pub fn is_relevant(&self, client_id: ClientId, entity: Entity) -> bool {
+		self.peers
+			.get(&client_id)
+			.is_some_and(|entities| entities.contains(&entity))
+	}
+}
+
+/**
+Only replicate entities to the peers they are relevant to, see [`Relevancy`].
+
+An entity is relevant to a peer if:
+- its [`ReplicateVisibility`] includes the peer
+- it has no [`InterestRadius`], or the peer's [`Viewpoint`] is within the radius
+- every [`Relevancy::predicates`] returns true
+
+Peers are tracked with [`PeerEvent`]. Each peer only receives messages for
+entities in its relevancy set, which are sent reliably with [`PeerOutgoing`]
+unless the entity is relevant to every peer.
+When an entity enters a peer's set it is sent a [`Message::Spawn`] and the
+current value of each component, and when it leaves a [`Message::Despawn`].
+
+Only entities spawned by this app are filtered, entities with a
+[`RemoteEntity`] are always sent to every peer.
+**/
+#[derive(Default)]
+pub struct ReplicateRelevancyPlugin {
+	pub predicates: Vec<RelevancyFn>,
+}
+
+impl ReplicateRelevancyPlugin {
+	pub fn with_predicate(mut self, predicate: RelevancyFn) -> Self {
+		self.predicates.push(predicate);
+		self
+	}
+}
+
+impl Plugin for ReplicateRelevancyPlugin {
+	fn build(&self, app: &mut App) {
+		app.insert_resource(Relevancy {
+			predicates: self.predicates.clone(),
+			..default()
+		})
+		.add_systems(
+			Update,
+			(
+				track_relevancy_peers
+					.in_set(MessageIncomingSet)
+					.after(handle_peer_messages),
+				update_relevancy
+					.in_set(PeerOutgoingSet)
+					.after(send_snapshots),
+			),
+		);
+	}
+}
+
+fn track_relevancy_peers(
+	mut events: EventReader<PeerEvent>,
+	mut relevancy: ResMut<Relevancy>,
+) {
+	for event in events.read() {
+		match event {
+			PeerEvent::Connected(client_id) => {
+				relevancy.peers.entry(*client_id).or_default();
+			}
+			PeerEvent::Disconnected(client_id) => {
+				relevancy.peers.remove(client_id);
+			}
+		}
+	}
+}
+
+/// Evaluate the relevancy set of each peer and route entity messages in
+/// [`MessageOutgoing`] and [`PeerOutgoing`] accordingly.
+pub fn update_relevancy(world: &mut World) {
+	let prev = std::mem::take(&mut world.resource_mut::<Relevancy>().peers);
+	if prev.is_empty() {
+		return;
+	}
+	let (managed, next) = relevant_entities(world, prev.keys().copied());
+
+	// only entities spawned by this app are managed
+	let managed_entity = |message: &Message| {
+		message
+			.entity()
+			.filter(|_| message.origin() == Some(EntityOrigin::Sender))
+			.filter(|entity| {
+				managed.contains(entity)
+					|| prev.values().any(|entities| entities.contains(entity))
+			})
+	};
+	let entered = |client_id: &ClientId, entity: &Entity| {
+		next[client_id].contains(entity) && !prev[client_id].contains(entity)
+	};
+
+	let mut direct = HashMap::<ClientId, Vec<Message>>::default();
+	let mut broadcast = Vec::new();
+	for message in world.resource_mut::<MessageOutgoing>().drain(..) {
+		let Some(entity) = managed_entity(&message) else {
+			broadcast.push(message);
+			continue;
+		};
+		// despawned entities are only in the previous set
+		let receivers = if matches!(message, Message::Despawn { .. }) {
+			&prev
+		} else {
+			&next
+		};
+		if receivers
+			.values()
+			.all(|entities| entities.contains(&entity))
+		{
+			broadcast.push(message);
+			continue;
+		}
+		for (client_id, entities) in receivers.iter() {
+			// entering peers are sent a snapshot instead
+			if entities.contains(&entity) && !entered(client_id, &entity) {
+				direct.entry(*client_id).or_default().push(message.clone());
+			}
+		}
+	}
+	world.resource_mut::<MessageOutgoing>().0 = broadcast;
+
+	let mut peer_outgoing =
+		std::mem::take(&mut world.resource_mut::<PeerOutgoing>().0);
+	let registry = world.resource::<ReplicateRegistry>();
+	for (client_id, entities) in next.iter() {
+		let outgoing = peer_outgoing.entry(*client_id).or_default();
+		// ie a late joiner snapshot
+		outgoing.retain(|message| {
+			managed_entity(message).is_none_or(|entity| {
+				entities.contains(&entity) && !entered(client_id, &entity)
+			})
+		});
+		outgoing.extend(direct.remove(client_id).unwrap_or_default());
+		for entity in prev[client_id].difference(entities) {
+			if managed.contains(entity) {
+				outgoing.push(Message::Despawn {
+					entity: *entity,
+					origin: EntityOrigin::Sender,
+				});
+			}
+		}
+		for entity in entities.difference(&prev[client_id]) {
+			snapshot_entity(registry, &world.entity(*entity), outgoing);
+		}
+	}
+	peer_outgoing.retain(|_, messages| !messages.is_empty());
+
+	world.resource_mut::<PeerOutgoing>().0 = peer_outgoing;
+	world.resource_mut::<Relevancy>().peers = next;
+}
+
+/// Returns every entity that relevancy applies to,
+/// and the relevancy set of each peer.
+fn relevant_entities(
+	world: &mut World,
+	peers: impl Iterator<Item = ClientId>,
+) -> (HashSet<Entity>, HashMap<ClientId, HashSet<Entity>>) {
+	let viewpoints = world
+		.query::<(&Viewpoint, &Transform)>()
+		.iter(world)
+		.map(|(viewpoint, transform)| (viewpoint.0, transform.translation))
+		.collect::<HashMap<_, _>>();
+	let mut query = world.query_filtered::<(
+		Entity,
+		Option<&ReplicateVisibility>,
+		Option<&InterestRadius>,
+		Option<&Transform>,
+	), (With<Replicate>, Without<RemoteEntity>)>();
+	let predicates = &world.resource::<Relevancy>().predicates;
+
+	let mut managed = HashSet::default();
+	let mut next = peers
+		.map(|client_id| (client_id, HashSet::default()))
+		.collect::<HashMap<_, _>>();
+	for (entity, visibility, radius, transform) in query.iter(world) {
+		managed.insert(entity);
+		for (client_id, entities) in next.iter_mut() {
+			let visible = visibility
+				.is_none_or(|visibility| visibility.is_visible(*client_id));
+			let in_range = match (radius, transform) {
+				(Some(radius), Some(transform)) => {
+					viewpoints.get(client_id).is_some_and(|viewpoint| {
+						viewpoint.distance(transform.translation) <= radius.0
+					})
+				}
+				_ => true,
+			};
+			let passes = |predicate: &RelevancyFn| {
+				predicate(world, entity, *client_id)
+			};
+			if visible && in_range && predicates.iter().all(passes) {
+				entities.insert(entity);
+			}
+		}
+	}
+	(managed, next)
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct MyComponent(u32);
+
+	fn setup(plugin: ReplicateRelevancyPlugin) -> App {
+		let mut app = App::new();
+		app.add_plugins((ReplicatePlugin, plugin))
+			.replicate::<MyComponent>();
+		app.world_mut().resource_mut::<MessageIncoming>().0 = vec![
+			Message::PeerConnected { client_id: 1 },
+			Message::PeerConnected { client_id: 2 },
+		];
+		app
+	}
+
+	/// Take the entity messages sent to a peer, or to all peers.
+	fn take(app: &mut App, client_id: Option<ClientId>) -> Vec<Message> {
+		let messages = match client_id {
+			Some(client_id) => app
+				.world_mut()
+				.resource_mut::<PeerOutgoing>()
+				.remove(&client_id)
+				.unwrap_or_default(),
+			None => app
+				.world_mut()
+				.resource_mut::<MessageOutgoing>()
+				.0
+				.drain(..)
+				.collect(),
+		};
+		messages
+			.into_iter()
+			.filter(|message| message.entity().is_some())
+			.collect()
+	}
+
+	#[test]
+	fn visibility() -> Result<()> {
+		let mut app = setup(default());
+		let public = app
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(1)))
+			.id();
+		let private = app
+			.world_mut()
+			.spawn((
+				Replicate::default(),
+				MyComponent(2),
+				ReplicateVisibility::only([1]),
+			))
+			.id();
+		app.update();
+		expect(take(&mut app, None).len()).to_be(2);
+		// late joiner snapshots only include relevant entities
+		expect(take(&mut app, Some(1)).len()).to_be(4);
+		expect(take(&mut app, Some(2)).len()).to_be(2);
+
+		app.world_mut().get_mut::<MyComponent>(private).unwrap().0 = 3;
+		app.world_mut().get_mut::<MyComponent>(public).unwrap().0 = 4;
+		app.update();
+		expect(take(&mut app, None).len()).to_be(1);
+		expect(take(&mut app, Some(1)).len()).to_be(1);
+		expect(take(&mut app, Some(2)).len()).to_be(0);
+
+		app.world_mut()
+			.entity_mut(private)
+			.insert(ReplicateVisibility::only([2]));
+		app.update();
+		expect(take(&mut app, Some(1)))
+			.to_be(vec![Message::Despawn {
+				entity: private,
+				origin: EntityOrigin::Sender,
+			}]);
+		expect(take(&mut app, Some(2))).to_be(vec![
+			Message::Spawn { entity: private },
+			Message::Change {
+				reg_id: RegistrationId::new_with(0),
+				entity: private,
+				origin: EntityOrigin::Sender,
+				payload: MessagePayload::new(MyComponent(3))?,
+			},
+		]);
+
+		app.world_mut().despawn(private);
+		app.update();
+		expect(take(&mut app, None).len()).to_be(0);
+		expect(take(&mut app, Some(1)).len()).to_be(0);
+		expect(take(&mut app, Some(2)))
+			.to_be(vec![Message::Despawn {
+				entity: private,
+				origin: EntityOrigin::Sender,
+			}]);
+		Ok(())
+	}
+
+	#[test]
+	fn interest_radius() -> Result<()> {
+		let mut app = setup(default());
+		app.world_mut().spawn((Viewpoint(1), Transform::default()));
+		let viewpoint = app
+			.world_mut()
+			.spawn((Viewpoint(2), Transform::from_xyz(100., 0., 0.)))
+			.id();
+		let entity = app
+			.world_mut()
+			.spawn((
+				Replicate::default(),
+				InterestRadius(10.),
+				Transform::default(),
+			))
+			.id();
+		app.update();
+		expect(take(&mut app, Some(1)).len()).to_be(1);
+		expect(take(&mut app, Some(2)).len()).to_be(0);
+
+		app.world_mut()
+			.entity_mut(viewpoint)
+			.insert(Transform::from_xyz(5., 0., 0.));
+		app.update();
+		expect(take(&mut app, Some(2))).to_be(vec![Message::Spawn { entity }]);
+		expect(app.world().resource::<Relevancy>().is_relevant(2, entity))
+			.to_be_true();
+
+		app.world_mut()
+			.entity_mut(viewpoint)
+			.insert(Transform::from_xyz(50., 0., 0.));
+		app.update();
+		expect(take(&mut app, Some(2)))
+			.to_be(vec![Message::Despawn {
+				entity,
+				origin: EntityOrigin::Sender,
+			}]);
+		expect(take(&mut app, Some(1)).len()).to_be(0);
+		Ok(())
+	}
+
+	#[test]
+	fn predicate() -> Result<()> {
+		let mut app =
+			setup(ReplicateRelevancyPlugin::default().with_predicate(
+				|world, entity, client_id| {
+					world
+						.get::<MyComponent>(entity)
+						.is_some_and(|value| value.0 == client_id)
+				},
+			));
+		let entity = app
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(2)))
+			.id();
+		app.update();
+		expect(take(&mut app, Some(1)).len()).to_be(0);
+		expect(take(&mut app, Some(2)).len()).to_be(2);
+
+		// peers that disconnect are no longer tracked
+		app.world_mut().resource_mut::<MessageIncoming>().0 =
+			vec![Message::PeerDisconnected { client_id: 1 }];
+		app.world_mut().get_mut::<MyComponent>(entity).unwrap().0 = 2;
+		app.update();
+		expect(take(&mut app, None).len()).to_be(1);
+		Ok(())
+	}
+}
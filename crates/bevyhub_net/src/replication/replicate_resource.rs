@@ -34,6 +34,7 @@ impl ResourceFns {
 
 pub fn register_resource_outgoing<T: Resource + Serialize>(app: &mut App) {
 	app.add_systems(Update, handle_outgoing::<T>.in_set(MessageOutgoingSet));
+	register_resource_snapshot::<T>(app);
 }
 
 fn handle_outgoing<T: Resource + Serialize>(
@@ -57,7 +58,7 @@ fn handle_outgoing<T: Resource + Serialize>(
 				}
 				.into(),
 			);
-		} else {
+		} else if !*exists {
 			// ADDED
 			*exists = true;
 			let Some(payload) =
@@ -0,0 +1,191 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::prelude::*;
+use serde::Serialize;
+
+/// Serialize the current value of a component if the entity has it.
+pub type SnapshotComponentFn = fn(
+	&ReplicateRegistry,
+	RegistrationId,
+	&EntityRef,
+) -> Option<Result<MessagePayload>>;
+/// Serialize the current value of a resource if it exists.
+pub type SnapshotResourceFn = fn(&World) -> Option<Result<MessagePayload>>;
+
+/// Peers that connected this frame and will be sent a snapshot.
+#[derive(Debug, Default, Clone, Deref, DerefMut, Resource)]
+pub struct PendingSnapshots(pub Vec<ClientId>);
+
+pub(crate) fn register_component_snapshot<T: Component + Serialize>(
+	app: &mut App,
+) {
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<T>();
+	registry.snapshot_component_fns.insert(id, |registry, _, entity| {
+		entity
+			.get::<T>()
+			.map(|value| registry.outgoing_payload(value))
+	});
+}
+
+pub(crate) fn register_resource_snapshot<T: Resource + Serialize>(
+	app: &mut App,
+) {
+	let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+	let id = registry.registration_id::<T>();
+	registry.snapshot_resource_fns.insert(id, |world| {
+		world.get_resource::<T>().map(MessagePayload::new)
+	});
+}
+
+pub(crate) fn queue_snapshots(
+	mut events: EventReader<PeerEvent>,
+	mut pending: ResMut<PendingSnapshots>,
+) {
+	for event in events.read() {
+		if let PeerEvent::Connected(client_id) = event {
+			pending.push(*client_id);
+		}
+	}
+}
+
+/// Sends every [`Replicate`] entity with its outgoing components,
+/// and every outgoing resource, to peers that just connected.
+/// This runs in [`PeerOutgoingSet`] so the snapshot is at least
+/// as new as the messages broadcast this frame.
+pub fn send_snapshots(world: &mut World) {
+	let pending =
+		std::mem::take(&mut world.resource_mut::<PendingSnapshots>().0);
+	if pending.is_empty() {
+		return;
+	}
+	let messages = snapshot(world);
+	let mut peer_outgoing = world.resource_mut::<PeerOutgoing>();
+	for client_id in pending {
+		peer_outgoing
+			.entry(client_id)
+			.or_default()
+			.extend(messages.iter().cloned());
+	}
+}
+
+/// Messages that recreate the replicated state of this app.
+/// Components are sent as [`Message::Change`] so that they
+/// overwrite any older value received before the snapshot.
+pub fn snapshot(world: &mut World) -> Vec<Message> {
+	let mut query = world.query_filtered::<EntityRef, With<Replicate>>();
+	let registry = world.resource::<ReplicateRegistry>();
+	let mut messages = vec![registry.registrations_message()];
+	if let Some(protocol) = world.get_resource::<ReplicateProtocol>() {
+		messages.insert(0, protocol.message());
+	}
+
+	for entity in query.iter(world) {
+		if registry.entities.remote(entity.id()).is_some() {
+			// owned by another peer
+			continue;
+		}
+		snapshot_entity(registry, &entity, &mut messages);
+	}
+	for (reg_id, func) in registry.snapshot_resource_fns.iter() {
+		match func(world) {
+			Some(Ok(payload)) => messages.push(Message::InsertResource {
+				reg_id: *reg_id,
+				payload,
+			}),
+			Some(Err(err)) => log::error!("{err}"),
+			None => {}
+		}
+	}
+	messages
+}
+
+/// A [`Message::Spawn`] followed by its [`Owner`] and a
+/// [`Message::Change`] for each outgoing component of the entity.
+pub fn snapshot_entity(
+	registry: &ReplicateRegistry,
+	entity: &EntityRef,
+	messages: &mut Vec<Message>,
+) {
+	messages.push(Message::Spawn {
+		entity: entity.id(),
+	});
+	if let Some(owner) = entity.get::<Owner>() {
+		messages.push(Message::OwnerChanged {
+			entity: entity.id(),
+			client_id: Some(owner.0),
+		});
+	}
+	for (reg_id, func) in registry.snapshot_component_fns.iter() {
+		match func(registry, *reg_id, entity) {
+			Some(Ok(payload)) => messages.push(Message::Change {
+				reg_id: *reg_id,
+				entity: entity.id(),
+				origin: EntityOrigin::Sender,
+				payload,
+			}),
+			Some(Err(err)) => log::error!("{err}"),
+			None => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct MyComponent(u32);
+	#[derive(Debug, Clone, PartialEq, Resource, Serialize, Deserialize)]
+	struct MyResource(u32);
+
+	#[test]
+	fn late_joiner() -> Result<()> {
+		let mut app1 = App::new();
+		app1.add_plugins(ReplicatePlugin)
+			.replicate::<MyComponent>()
+			.replicate_resource_outgoing::<MyResource>()
+			.insert_resource(MyResource(3));
+		let entity = app1
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)))
+			.id();
+		app1.update();
+		app1.world_mut().get_mut::<MyComponent>(entity).unwrap().0 = 8;
+		app1.update();
+		// sent before the peer connected
+		app1.world_mut().resource_mut::<MessageOutgoing>().clear();
+
+		app1.world_mut().resource_mut::<MessageIncoming>().0 =
+			vec![Message::PeerConnected { client_id: 2 }];
+		app1.update();
+		expect(app1.world().resource::<MessageOutgoing>().len()).to_be(0);
+		let snapshot = app1
+			.world_mut()
+			.resource_mut::<PeerOutgoing>()
+			.remove(&2)
+			.unwrap();
+		expect(snapshot.len()).to_be(4);
+
+		let mut app2 = App::new();
+		app2.add_plugins(ReplicatePlugin)
+			.replicate::<MyComponent>()
+			.replicate_resource_incoming::<MyResource>();
+		app2.world_mut().resource_mut::<MessageIncoming>().0 = snapshot;
+		app2.update();
+
+		expect(
+			app2.world_mut()
+				.query::<&MyComponent>()
+				.single(app2.world()),
+		)
+		.to_be(&MyComponent(8));
+		expect(app2.world().resource::<MyResource>()).to_be(&MyResource(3));
+		Ok(())
+	}
+}
@@ -1,7 +1,11 @@
 use crate::prelude::*;
+use bevy::ecs::entity::MapEntities;
 use bevy::prelude::*;
+use bevy::reflect::GetTypeRegistration;
+use forky::prelude::ResultTEExt;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::any::TypeId;
 
 
 #[extend::ext(name=AppExtReplicate)]
@@ -24,6 +28,171 @@ pub impl App {
 		}
 		self
 	}
+	/// Like [`App::replicate`] but changes are sent as a [`ByteDelta`]
+	/// against the previous value where possible. Deltas and the full
+	/// changes between them are always sent on [`Channel::RELIABLE`].
+	fn replicate_delta<T: Component + Serialize + DeserializeOwned>(
+		&mut self,
+	) -> &mut Self {
+		self.replicate_delta_with::<T>(ReplicateDirection::Both)
+	}
+	fn replicate_delta_with<T: Component + Serialize + DeserializeOwned>(
+		&mut self,
+		direction: ReplicateDirection,
+	) -> &mut Self {
+		let mut registry = self
+			.init_resource::<ReplicateRegistry>()
+			.world_mut()
+			.resource_mut::<ReplicateRegistry>();
+		registry.register_component::<T>(direction);
+		let id = registry.registration_id::<T>();
+		registry.delta_types.insert(id);
+		if direction.is_outgoing() {
+			register_component_outgoing_delta::<T>(self);
+		}
+		self
+	}
+	/// Map the [`Entity`] references of `T` through [`ReplicateRegistry::entities`]
+	/// so they refer to the same entities in each app.
+	/// `T` must already be registered, ie with [`App::replicate`].
+	/// Types registered with [`App::replicate_delta`] are not mapped.
+	fn replicate_map_entities<
+		T: Component + MapEntities + Clone + Serialize + DeserializeOwned,
+	>(
+		&mut self,
+	) -> &mut Self {
+		register_map_entities::<T>(self);
+		self
+	}
+	/// Replicate the [`Parent`] of [`Replicate`] entities,
+	/// see [`ReplicateParent`].
+	fn replicate_hierarchy(&mut self) -> &mut Self {
+		register_hierarchy(self);
+		self
+	}
+	/// Like [`App::replicate`] but serialized with reflection,
+	/// for types that implement [`Reflect`] but not [`Serialize`].
+	fn replicate_reflect<
+		T: Component + Reflect + FromReflect + TypePath + GetTypeRegistration,
+	>(
+		&mut self,
+	) -> &mut Self {
+		self.replicate_reflect_with::<T>(ReplicateDirection::Both)
+	}
+	fn replicate_reflect_with<
+		T: Component + Reflect + FromReflect + TypePath + GetTypeRegistration,
+	>(
+		&mut self,
+		direction: ReplicateDirection,
+	) -> &mut Self {
+		self.register_type::<T>()
+			.register_type_data::<T, ReflectComponent>();
+		register_reflect(self, TypeId::of::<T>(), direction)
+			.ok_or(|e| log::error!("{e}"));
+		self
+	}
+	/// Like [`App::replicate_reflect`] for a type registered in the
+	/// [`AppTypeRegistry`], ie `bevy_transform::components::transform::Transform`.
+	/// This allows replication to be configured from data.
+	fn replicate_by_type_path(&mut self, type_path: &str) -> &mut Self {
+		self.replicate_by_type_path_with(type_path, ReplicateDirection::Both)
+	}
+	fn replicate_by_type_path_with(
+		&mut self,
+		type_path: &str,
+		direction: ReplicateDirection,
+	) -> &mut Self {
+		let type_id = self
+			.world()
+			.resource::<AppTypeRegistry>()
+			.read()
+			.get_with_type_path(type_path)
+			.map(|registration| registration.type_id());
+		if let Some(type_id) = type_id {
+			register_reflect(self, type_id, direction)
+				.ok_or(|e| log::error!("{type_path}: {e}"));
+		} else {
+			log::error!("{type_path} is not registered in the AppTypeRegistry");
+		}
+		self
+	}
+	/// Send changes to a registered type on the given channel,
+	/// for example [`Channel::SEQUENCED`] for latest-wins changes.
+	fn replicate_channel<T: 'static>(&mut self, channel: Channel) -> &mut Self {
+		self.world_mut()
+			.resource_mut::<ReplicateRegistry>()
+			.set_channel::<T>(channel);
+		self
+	}
+	/// Apply received changes to `T` with `patch` instead of replacing
+	/// the current value, ie to keep fields that are only used locally.
+	fn replicate_patch<T: Component>(
+		&mut self,
+		patch: fn(&mut T, T),
+	) -> &mut Self {
+		self.insert_resource(ComponentPatch(patch))
+	}
+	/// Apply received changes to `T` with [`PartialReflect::apply`],
+	/// so fields with `#[reflect(ignore)]` keep their current value.
+	fn replicate_patch_reflect<T: Component + Reflect>(&mut self) -> &mut Self {
+		self.replicate_patch::<T>(|current, value| current.apply(&value))
+	}
+	/// Send changes to a registered type before those of lower priority
+	/// when a transport is over its [`TransportBudget`], the default is 1.
+	fn replicate_priority<T: 'static>(&mut self, priority: f32) -> &mut Self {
+		self.world_mut()
+			.resource_mut::<ReplicateRegistry>()
+			.set_priority::<T>(priority);
+		self
+	}
+	/// Predict `T` immediately when inputs are pushed to an [`InputBuffer`],
+	/// rolling back to the authoritative value when it is received and
+	/// simulating unacknowledged inputs again.
+	/// `T` must already be registered as incoming, ie with [`App::replicate_with`],
+	/// and the authoritative app should call [`App::predict_authority`]
+	/// in the same registration order.
+	fn predict<
+		T: Component + Clone + Serialize + DeserializeOwned,
+		I: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+	>(
+		&mut self,
+		simulate: SimulateFn<T, I>,
+	) -> &mut Self {
+		register_prediction::<T, I>(self, simulate);
+		self
+	}
+	/// Apply inputs sent by [`App::predict`] and acknowledge them
+	/// with an [`InputAck`]. Inputs are only applied to entities whose
+	/// [`Owner`] is the sender, insert [`Authority`] with the owner so
+	/// this app keeps sending the authoritative value.
+	/// `T` must already be registered as outgoing.
+	fn predict_authority<
+		T: Component,
+		I: 'static + Send + Sync + Clone + Serialize + DeserializeOwned,
+	>(
+		&mut self,
+		simulate: SimulateFn<T, I>,
+	) -> &mut Self {
+		register_prediction_authority::<T, I>(self, simulate);
+		self
+	}
+	/// Buffer received values of `T` and render them [`InterpolationDelay`]
+	/// behind, blending between values with [`Interpolate`] instead of
+	/// snapping to each change.
+	/// `T` must already be registered as incoming, ie with [`App::replicate_with`].
+	fn interpolate<
+		T: Component
+			+ Clone
+			+ PartialEq
+			+ Interpolate
+			+ Serialize
+			+ DeserializeOwned,
+	>(
+		&mut self,
+	) -> &mut Self {
+		register_interpolation::<T>(self);
+		self
+	}
 	fn replicate_resource_incoming<
 		T: Resource + Serialize + DeserializeOwned,
 	>(
@@ -0,0 +1,387 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy::utils::HashSet;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Which replicated events are sent and received, in the same format
+/// as the `ReplicationConfig` of a scene so that scenes can declare
+/// their own network surface.
+/// Keys are type paths, ie `my_crate::MyEvent`. A value of `Some(false)`
+/// disables replication of the event, `Some(true)` or `None` enables it.
+/// Events that are not listed are unaffected.
+///
+/// This is also an [`Event`] so that it can be received from a peer
+/// allowed by the [`RemoteConfigPolicy`], see [`ReplicationConfigPlugin`].
+#[derive(
+	Debug, Default, Clone, PartialEq, Resource, Event, Serialize, Deserialize,
+)]
+pub struct ReplicationConfig {
+	#[serde(default)]
+	pub send_events: HashMap<String, Option<bool>>,
+	#[serde(default)]
+	pub recv_events: HashMap<String, Option<bool>>,
+}
+
+impl ReplicationConfig {
+	#[cfg(feature = "serde_json")]
+	pub fn from_json(json: &str) -> Result<Self> {
+		Ok(serde_json::from_str(json)?)
+	}
+
+	#[cfg(feature = "serde_json")]
+	pub fn from_json_file(path: impl AsRef<std::path::Path>) -> Result<Self> {
+		let json = std::fs::read_to_string(path)?;
+		Self::from_json(&json)
+	}
+
+	pub fn with_send(mut self, type_path: &str, enabled: bool) -> Self {
+		self.send_events
+			.insert(type_path.to_string(), Some(enabled));
+		self
+	}
+
+	pub fn with_recv(mut self, type_path: &str, enabled: bool) -> Self {
+		self.recv_events
+			.insert(type_path.to_string(), Some(enabled));
+		self
+	}
+
+	/// Overwrite the entries of `self` with those of `other`.
+	pub fn extend(&mut self, other: &Self) {
+		self.send_events.extend(other.send_events.clone());
+		self.recv_events.extend(other.recv_events.clone());
+	}
+}
+
+/// The registrations disabled by the [`ReplicationConfig`],
+/// updated whenever it changes.
+#[derive(Debug, Default, Clone, PartialEq, Resource)]
+pub struct ReplicationFilter {
+	pub send: HashSet<RegistrationId>,
+	pub recv: HashSet<RegistrationId>,
+}
+
+impl ReplicationFilter {
+	fn allows(disabled: &HashSet<RegistrationId>, message: &Message) -> bool {
+		match message {
+			Message::SendEvent { reg_id, .. }
+			| Message::SendObserver { reg_id, .. } => !disabled.contains(reg_id),
+			_ => true,
+		}
+	}
+}
+
+/// Which peers may change the [`ReplicationConfig`] by sending one,
+/// by default none.
+#[derive(Debug, Default, Clone, PartialEq, Resource)]
+pub enum RemoteConfigPolicy {
+	/// Received configs are ignored.
+	#[default]
+	Deny,
+	/// Configs are accepted from the listed clients,
+	/// ie the server or the owner of the scene.
+	Clients(HashSet<ClientId>),
+	/// Configs are accepted from any peer.
+	Any,
+}
+
+impl RemoteConfigPolicy {
+	pub fn allows(&self, sender: ClientId) -> bool {
+		match self {
+			Self::Deny => false,
+			Self::Clients(clients) => clients.contains(&sender),
+			Self::Any => true,
+		}
+	}
+}
+
+/**
+Enables or disables replication of registered events at runtime with a [`ReplicationConfig`].
+
+The config can be loaded from a json file, or received from a peer as
+a [`ReplicationConfig`] event which is merged into the current config.
+Received configs are ignored unless the sender is allowed by the
+[`RemoteConfigPolicy`], see [`ReplicationConfigPlugin::with_remote`].
+Events must still be registered, ie with [`App::replicate_event_outgoing`].
+**/
+#[derive(Default)]
+pub struct ReplicationConfigPlugin {
+	pub config: ReplicationConfig,
+	pub remote: RemoteConfigPolicy,
+}
+
+impl ReplicationConfigPlugin {
+	pub fn new(config: ReplicationConfig) -> Self {
+		Self {
+			config,
+			remote: default(),
+		}
+	}
+
+	/// Accept configs sent by the peers allowed by `remote`.
+	pub fn with_remote(mut self, remote: RemoteConfigPolicy) -> Self {
+		self.remote = remote;
+		self
+	}
+
+	/// Load the config from a json file, ie one exported from a scene.
+	#[cfg(feature = "serde_json")]
+	pub fn from_json_file(path: impl AsRef<std::path::Path>) -> Result<Self> {
+		Ok(Self::new(ReplicationConfig::from_json_file(path)?))
+	}
+}
+
+impl Plugin for ReplicationConfigPlugin {
+	fn build(&self, app: &mut App) {
+		app.insert_resource(self.config.clone())
+			.insert_resource(self.remote.clone())
+			.init_resource::<ReplicationFilter>()
+			.add_event::<ReplicationConfig>()
+			.replicate_event_incoming::<ReplicationConfig>();
+		let mut registry = app.world_mut().resource_mut::<ReplicateRegistry>();
+		let id = registry.registration_id::<ReplicationConfig>();
+		if let Some(fns) = registry.incoming_event_fns.get_mut(&id) {
+			fns.send = |world, sender, payload| {
+				let allowed = world
+					.get_resource::<RemoteConfigPolicy>()
+					.map(|policy| policy.allows(sender))
+					.unwrap_or_default();
+				if !allowed {
+					log::warn!(
+						"ignored replication config from client {sender}, which is not allowed by the RemoteConfigPolicy"
+					);
+					return Ok(());
+				}
+				world.send_event(payload.deserialize::<ReplicationConfig>()?);
+				Ok(())
+			};
+		}
+		app.add_systems(
+				Update,
+				(
+					(receive_replication_config, update_replication_filter)
+						.chain()
+						.before(MessageIncomingSet),
+					filter_incoming
+						.in_set(MessageIncomingSet)
+						.after(remap_incoming)
+						.before(handle_incoming_commands)
+						.before(handle_incoming_world),
+					filter_outgoing
+						.after(MessageOutgoingSet)
+						.before(PeerOutgoingSet),
+				),
+			);
+	}
+}
+
+fn receive_replication_config(
+	mut events: EventReader<ReplicationConfig>,
+	mut config: ResMut<ReplicationConfig>,
+) {
+	for event in events.read() {
+		config.extend(event);
+	}
+}
+
+/// Resolve the type paths of the [`ReplicationConfig`] to registrations.
+fn update_replication_filter(
+	type_registry: Res<AppTypeRegistry>,
+	registrations: Res<ReplicateRegistry>,
+	config: Res<ReplicationConfig>,
+	mut filter: ResMut<ReplicationFilter>,
+) {
+	if !config.is_changed() && !registrations.is_changed() {
+		return;
+	}
+	let type_registry = type_registry.read();
+	let disabled = |events: &HashMap<String, Option<bool>>| {
+		events
+			.iter()
+			.filter(|(_, enabled)| **enabled == Some(false))
+			.filter_map(|(type_path, _)| {
+				let id = registrations
+					.registration_id_by_path(&type_registry, type_path);
+				if id.is_none() {
+					log::warn!(
+						"replication config: {type_path} is not registered"
+					);
+				}
+				id
+			})
+			.collect::<HashSet<_>>()
+	};
+	filter.set_if_neq(ReplicationFilter {
+		send: disabled(&config.send_events),
+		recv: disabled(&config.recv_events),
+	});
+}
+
+fn filter_incoming(
+	filter: Res<ReplicationFilter>,
+	mut incoming: ResMut<MessageIncoming>,
+) {
+	if !filter.recv.is_empty() {
+		incoming
+			.retain(|message| ReplicationFilter::allows(&filter.recv, message));
+	}
+}
+
+fn filter_outgoing(
+	filter: Res<ReplicationFilter>,
+	mut outgoing: ResMut<MessageOutgoing>,
+) {
+	if !filter.send.is_empty() {
+		outgoing
+			.retain(|message| ReplicationFilter::allows(&filter.send, message));
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, PartialEq, Event, Serialize, Deserialize)]
+	struct EventA;
+	#[derive(Debug, Clone, PartialEq, Event, Serialize, Deserialize)]
+	struct EventB;
+
+	const EVENT_A: &str =
+		"bevyhub_net::replication::replication_config::test::EventA";
+
+	fn app(config: ReplicationConfig) -> App {
+		app_with_remote(config, default())
+	}
+
+	fn app_with_remote(
+		config: ReplicationConfig,
+		remote: RemoteConfigPolicy,
+	) -> App {
+		let mut app = App::new();
+		app.add_plugins((
+			ReplicatePlugin,
+			ReplicationConfigPlugin::new(config).with_remote(remote),
+		))
+		.add_event::<EventA>()
+		.add_event::<EventB>()
+		.replicate_event_outgoing::<EventA>()
+		.replicate_event_outgoing::<EventB>();
+		app
+	}
+
+	fn send(app: &mut App) -> usize {
+		app.world_mut().send_event(EventA);
+		app.world_mut().send_event(EventB);
+		app.update();
+		app.world_mut()
+			.resource_mut::<MessageOutgoing>()
+			.drain(..)
+			.count()
+	}
+
+	#[test]
+	fn send_events() -> Result<()> {
+		let mut app =
+			app(ReplicationConfig::default().with_send(EVENT_A, false));
+		expect(send(&mut app)).to_be(1);
+		// enabled at runtime
+		app.world_mut()
+			.resource_mut::<ReplicationConfig>()
+			.send_events
+			.insert(EVENT_A.to_string(), Some(true));
+		expect(send(&mut app)).to_be(2);
+		Ok(())
+	}
+
+	fn received(app: &App) -> (usize, usize) {
+		(
+			app.world().resource::<Events<EventA>>().len(),
+			app.world().resource::<Events<EventB>>().len(),
+		)
+	}
+
+	fn send_config(app: &mut App, config: &ReplicationConfig) -> Result<()> {
+		let reg_id = app
+			.world()
+			.resource::<ReplicateRegistry>()
+			.registration_id::<ReplicationConfig>();
+		app.world_mut().resource_mut::<MessageIncoming>().0 =
+			vec![Message::SendEvent {
+				reg_id,
+				payload: MessagePayload::new(config)?,
+			}];
+		app.update();
+		app.update();
+		Ok(())
+	}
+
+	#[test]
+	fn recv_events() -> Result<()> {
+		let mut app1 = app(default());
+		let remote = RemoteConfigPolicy::Clients(
+			[DIRECT_CLIENT_ID].into_iter().collect(),
+		);
+		let mut app2 = app_with_remote(default(), remote);
+		app1.world_mut().send_event(EventA);
+		app1.world_mut().send_event(EventB);
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(received(&app2)).to_be((1, 1));
+
+		// the config is received from a peer
+		let config = ReplicationConfig::default().with_recv(EVENT_A, false);
+		send_config(&mut app2, &config)?;
+		expect(app2.world().resource::<ReplicationConfig>()).to_be(&config);
+
+		app1.world_mut().send_event(EventA);
+		app1.world_mut().send_event(EventB);
+		app1.update();
+		Message::loopback(app1.world_mut(), app2.world_mut());
+		app2.update();
+		expect(received(&app2)).to_be((0, 1));
+		Ok(())
+	}
+
+	#[test]
+	fn remote_policy() -> Result<()> {
+		let config = ReplicationConfig::default().with_recv(EVENT_A, false);
+		// denied by default
+		let mut app1 = app(default());
+		send_config(&mut app1, &config)?;
+		expect(app1.world().resource::<ReplicationConfig>())
+			.to_be(&ReplicationConfig::default());
+		// not one of the allowed clients
+		let mut app2 = app_with_remote(
+			default(),
+			RemoteConfigPolicy::Clients([1].into_iter().collect()),
+		);
+		send_config(&mut app2, &config)?;
+		expect(app2.world().resource::<ReplicationConfig>())
+			.to_be(&ReplicationConfig::default());
+
+		let mut app3 = app_with_remote(default(), RemoteConfigPolicy::Any);
+		send_config(&mut app3, &config)?;
+		expect(app3.world().resource::<ReplicationConfig>()).to_be(&config);
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "serde_json")]
+	fn json() -> Result<()> {
+		let config = ReplicationConfig::from_json(&format!(
+			r#"{{"send_events":{{"{EVENT_A}":false}}}}"#
+		))?;
+		expect(config.send_events.get(EVENT_A)).to_be(Some(&Some(false)));
+		expect(config.recv_events.len()).to_be(0);
+		Ok(())
+	}
+}
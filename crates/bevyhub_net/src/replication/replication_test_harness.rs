@@ -0,0 +1,291 @@
+use crate::prelude::*;
+use anyhow::Result;
+use bevy::prelude::*;
+use std::fmt::Debug;
+
+/**
+Runs several apps in process, relaying their messages like the relay
+server, for testing replication without a transport.
+
+Each app is a client of a virtual lobby with ids starting at 1 like the
+relay server. Adding an app sends it a [`Message::Welcome`] and sends a
+[`Message::PeerConnected`] to the apps already in the lobby. Broadcast
+messages are sent to every other app, and messages following a
+[`Message::Recipient`] or in [`PeerOutgoing`] only to that app,
+stamped with a [`Message::Sender`].
+**/
+#[derive(Default)]
+pub struct ReplicationTestHarness {
+	pub apps: Vec<App>,
+}
+
+impl ReplicationTestHarness {
+	/// Create a harness with `num_apps` apps, each set up by `setup`,
+	/// ie to add the [`ReplicatePlugin`] and register replicated types.
+	pub fn new(num_apps: usize, setup: impl Fn(&mut App)) -> Self {
+		let mut harness = Self::default();
+		for _ in 0..num_apps {
+			let mut app = App::new();
+			setup(&mut app);
+			harness.add_app(app);
+		}
+		harness
+	}
+
+	/// Add an app to the lobby, returning its [`ClientId`].
+	pub fn add_app(&mut self, mut app: App) -> ClientId {
+		let client_id = self.apps.len() as ClientId + 1;
+		app.world_mut()
+			.resource_mut::<MessageIncoming>()
+			.push(Message::Welcome { client_id });
+		self.apps.push(app);
+		self.relay(client_id, vec![Message::PeerConnected { client_id }]);
+		client_id
+	}
+
+	/// The ids of every app in the lobby.
+	pub fn client_ids(&self) -> impl Iterator<Item = ClientId> {
+		1..=self.apps.len() as ClientId
+	}
+
+	pub fn app(&self, client_id: ClientId) -> &App {
+		&self.apps[client_id as usize - 1]
+	}
+
+	pub fn app_mut(&mut self, client_id: ClientId) -> &mut App {
+		&mut self.apps[client_id as usize - 1]
+	}
+
+	/// Update every app once, then relay their outgoing messages,
+	/// received on the next step.
+	pub fn step(&mut self) {
+		for app in self.apps.iter_mut() {
+			app.update();
+		}
+		for client_id in self.client_ids() {
+			let world = self.app_mut(client_id).world_mut();
+			let broadcast = world
+				.resource_mut::<MessageOutgoing>()
+				.drain(..)
+				.collect::<Vec<_>>();
+			let direct = world
+				.resource_mut::<PeerOutgoing>()
+				.drain()
+				.collect::<Vec<_>>();
+			self.relay(client_id, broadcast);
+			for (recipient, messages) in direct {
+				let mut batch = vec![Message::Recipient {
+					client_id: recipient,
+				}];
+				batch.extend(messages);
+				self.relay(client_id, batch);
+			}
+		}
+	}
+
+	pub fn step_n(&mut self, steps: usize) {
+		for _ in 0..steps {
+			self.step();
+		}
+	}
+
+	/// Step until `func` returns true, returning the number of steps.
+	pub fn step_until(
+		&mut self,
+		max_steps: usize,
+		mut func: impl FnMut(&mut Self) -> bool,
+	) -> Result<usize> {
+		for steps in 0..=max_steps {
+			if func(self) {
+				return Ok(steps);
+			}
+			if steps < max_steps {
+				self.step();
+			}
+		}
+		anyhow::bail!("condition not met after {max_steps} steps")
+	}
+
+	/// Send a batch from a client to the others, like
+	/// `LobbyInner::handle_message` in the relay server.
+	fn relay(&mut self, sender: ClientId, messages: Vec<Message>) {
+		let mut recipient = None;
+		let mut broadcast = Vec::new();
+		let mut direct = Vec::new();
+		for message in messages {
+			match (message, recipient) {
+				(Message::Recipient { client_id }, _) => {
+					recipient = Some(client_id);
+				}
+				(Message::Sender { .. }, _) => {}
+				(message, Some(client_id)) => {
+					direct.push((client_id, message))
+				}
+				(message, None) => broadcast.push(message),
+			}
+		}
+		for (client_id, app) in self.client_ids().zip(self.apps.iter_mut()) {
+			if client_id == sender {
+				continue;
+			}
+			let mut batch = broadcast.clone();
+			batch.extend(
+				direct
+					.iter()
+					.filter(|(recipient, _)| *recipient == client_id)
+					.map(|(_, message)| message.clone()),
+			);
+			if batch.is_empty() {
+				continue;
+			}
+			let mut incoming =
+				app.world_mut().resource_mut::<MessageIncoming>();
+			incoming.push(Message::Sender { client_id: sender });
+			incoming.extend(batch);
+		}
+	}
+
+	/// The local entity of an entity spawned by another app.
+	pub fn entity_on(
+		&self,
+		client_id: ClientId,
+		spawned_by: ClientId,
+		entity: Entity,
+	) -> Option<Entity> {
+		if client_id == spawned_by {
+			return Some(entity);
+		}
+		self.app(client_id)
+			.world()
+			.resource::<ReplicateRegistry>()
+			.entities
+			.local(RemoteEntity::new(spawned_by, entity))
+	}
+
+	/// Number of entities with the component on each app.
+	pub fn count<T: Component>(&mut self) -> Vec<usize> {
+		self.apps
+			.iter_mut()
+			.map(|app| {
+				let world = app.world_mut();
+				world.query_filtered::<(), With<T>>().iter(world).count()
+			})
+			.collect()
+	}
+
+	/// Whether every app has an entity with the component value.
+	pub fn exists_on_all<T: Component + PartialEq>(
+		&mut self,
+		value: &T,
+	) -> bool {
+		self.apps_without(value).is_empty()
+	}
+
+	pub fn assert_on_all<T: Component + PartialEq + Debug>(
+		&mut self,
+		value: &T,
+	) -> Result<()> {
+		let missing = self.apps_without(value);
+		if !missing.is_empty() {
+			anyhow::bail!("apps {missing:?} have no entity with {value:?}");
+		}
+		Ok(())
+	}
+
+	pub fn assert_count_on_all<T: Component>(
+		&mut self,
+		count: usize,
+	) -> Result<()> {
+		let counts = self.count::<T>();
+		if counts.iter().any(|other| *other != count) {
+			let name = std::any::type_name::<T>();
+			anyhow::bail!(
+				"expected {count} entities with {name} on each app, found {counts:?}"
+			);
+		}
+		Ok(())
+	}
+
+	fn apps_without<T: Component + PartialEq>(
+		&mut self,
+		value: &T,
+	) -> Vec<ClientId> {
+		self.client_ids()
+			.zip(self.apps.iter_mut())
+			.filter_map(|(client_id, app)| {
+				let world = app.world_mut();
+				let found =
+					world.query::<&T>().iter(world).any(|other| other == value);
+				(!found).then_some(client_id)
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::prelude::*;
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use sweet::prelude::*;
+
+	#[derive(Debug, Clone, Component, Serialize, Deserialize, PartialEq)]
+	struct MyComponent(i32);
+
+	fn harness(num_apps: usize) -> ReplicationTestHarness {
+		ReplicationTestHarness::new(num_apps, |app| {
+			app.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		})
+	}
+
+	#[test]
+	fn replicates_to_all() -> Result<()> {
+		let mut harness = harness(3);
+		let entity = harness
+			.app_mut(2)
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)))
+			.id();
+		harness.step_n(2);
+		harness.assert_on_all(&MyComponent(7))?;
+		harness.assert_count_on_all::<MyComponent>(1)?;
+
+		let on_3 = harness.entity_on(3, 2, entity).unwrap();
+		expect(harness.app(3).world().get::<MyComponent>(on_3))
+			.to_be(Some(&MyComponent(7)));
+
+		harness
+			.app_mut(2)
+			.world_mut()
+			.entity_mut(entity)
+			.insert(MyComponent(8));
+		let steps = harness
+			.step_until(4, |harness| harness.exists_on_all(&MyComponent(8)))?;
+		expect(steps).to_be(2);
+		expect(harness.assert_on_all(&MyComponent(7)).is_err()).to_be_true();
+		Ok(())
+	}
+
+	#[test]
+	fn late_joiner() -> Result<()> {
+		let mut harness = harness(2);
+		harness
+			.app_mut(1)
+			.world_mut()
+			.spawn((Replicate::default(), MyComponent(7)));
+		harness.step_n(2);
+
+		let mut app = App::new();
+		app.add_plugins(ReplicatePlugin).replicate::<MyComponent>();
+		let client_id = harness.add_app(app);
+		expect(client_id).to_be(3);
+		// the others send a snapshot directly to the new app
+		harness.step_n(3);
+		expect(harness.app(3).world().resource::<LocalClientId>().0)
+			.to_be(Some(3));
+		harness.assert_count_on_all::<MyComponent>(1)?;
+		Ok(())
+	}
+}
@@ -4,3 +4,11 @@ pub use self::native_client::*;
 pub mod native_client_plugin;
 #[allow(unused_imports)]
 pub use self::native_client_plugin::*;
+#[cfg(feature = "webrtc")]
+pub mod native_rtc_client;
+#[cfg(feature = "webrtc")]
+#[allow(unused_imports)]
+pub use self::native_rtc_client::*;
+pub mod native_udp_client;
+#[allow(unused_imports)]
+pub use self::native_udp_client::*;
@@ -2,108 +2,265 @@ use crate::prelude::*;
 use anyhow::Result;
 use flume::Receiver;
 use flume::Sender;
+use forky::prelude::ResultTEExt;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::net::TcpStream;
 use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Bytes;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio_tungstenite::WebSocketStream;
 
 type TungMessage = tokio_tungstenite::tungstenite::protocol::Message;
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
+/// How a [`NativeWsClient`] reconnects after the connection is lost.
+#[derive(Debug, Clone)]
+pub struct ReconnectSettings {
+	/// Delay before the first reconnect attempt, doubled for every
+	/// failed attempt.
+	pub initial_delay: Duration,
+	pub max_delay: Duration,
+	/// Stop reconnecting after this many failed attempts in a row,
+	/// or `None` to keep trying.
+	pub max_attempts: Option<u32>,
+	/// Batches of messages buffered while disconnected,
+	/// the oldest batch is dropped when full.
+	pub outbox_capacity: usize,
+}
 
-pub struct NativeWsClient {
-	send: Sender<Bytes>,
-	recv: Receiver<Bytes>,
-	send_task: tokio::task::JoinHandle<Result<()>>,
-	recv_task: tokio::task::JoinHandle<Result<()>>,
+impl Default for ReconnectSettings {
+	fn default() -> Self {
+		Self {
+			initial_delay: Duration::from_millis(250),
+			max_delay: Duration::from_secs(10),
+			max_attempts: None,
+			outbox_capacity: 1024,
+		}
+	}
 }
 
-// impl Default for NativeWsClient {
-// 	fn default() -> Self {
-// 		Self {
-// 			user_id: 7,
-// 			channel_id: 16,
-// 			url: "ws://127.0.0.1:3000/ws".into(),
-// 		}
-// 	}
-// }
+impl ReconnectSettings {
+	fn delay(&self, attempt: u32) -> Duration {
+		self.initial_delay
+			.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+			.min(self.max_delay)
+	}
+}
+
+/// A websocket client for the relay server. If the connection is lost it
+/// reconnects with exponential backoff, see [`ReconnectSettings`].
+/// Messages sent while disconnected are buffered and sent once reconnected.
+/// Must be created inside a tokio runtime.
+///
+/// Batches are sent as [`RelayFrame`]s with its [`WireFormat`],
+/// [`WireFormat::Bincode`] by default, see [`Self::with_format`].
+/// Received batches are decoded with the format they were sent with.
+pub struct NativeWsClient {
+	format: WireFormat,
+	send: Sender<TungMessage>,
+	/// Used to drop the oldest batch when the outbox is full.
+	outbox: Receiver<TungMessage>,
+	recv: Receiver<TungMessage>,
+	events: Receiver<ConnectionEvent>,
+	task: tokio::task::JoinHandle<()>,
+}
 
 
 impl NativeWsClient {
+	/// Connect to the server, returning an error if the first attempt
+	/// fails. Reconnects with the default [`ReconnectSettings`].
 	pub async fn new(url: &str) -> Result<Self> {
 		let (ws_stream, _response) = connect_async(url).await?;
-		let (mut send, mut recv_stream) = ws_stream.split();
+		Ok(Self::spawn(url, ReconnectSettings::default(), Some(ws_stream)))
+	}
+
+	/// Start connecting in the background, messages are buffered until
+	/// the connection is established.
+	pub fn connect(url: &str, settings: ReconnectSettings) -> Self {
+		Self::spawn(url, settings, None)
+	}
+
+	/// Set the format messages are sent with, text formats like
+	/// [`WireFormat::Json`] are sent as text frames.
+	pub fn with_format(mut self, format: WireFormat) -> Self {
+		self.format = format;
+		self
+	}
+
+	/// Connection state changes, in the order they occurred.
+	pub fn connection_events(&self) -> &Receiver<ConnectionEvent> {
+		&self.events
+	}
+
+	fn spawn(
+		url: &str,
+		settings: ReconnectSettings,
+		ws_stream: Option<WsStream>,
+	) -> Self {
+		let (send, outbox) = flume::bounded(settings.outbox_capacity.max(1));
+		let (recv_send, recv) = flume::unbounded();
+		let (event_send, events) = flume::unbounded();
+		let task = tokio::spawn(supervise(
+			url.to_string(),
+			settings,
+			ws_stream,
+			outbox.clone(),
+			recv_send,
+			event_send,
+		));
+		Self {
+			format: WireFormat::default(),
+			send,
+			outbox,
+			recv,
+			events,
+			task,
+		}
+	}
+}
 
-		let (recv_send, recv_recv) = flume::unbounded::<Bytes>();
-		let (send_send, send_recv) = flume::unbounded::<Bytes>();
-		let send_task = tokio::spawn(async move {
-			while let Ok(msg) = send_recv.recv_async().await {
-				send.send(TungMessage::Binary(msg.into())).await?;
+/// Connect and reconnect until the client is dropped
+/// or [`ReconnectSettings::max_attempts`] is reached.
+async fn supervise(
+	url: String,
+	settings: ReconnectSettings,
+	mut ws_stream: Option<WsStream>,
+	outbox: Receiver<TungMessage>,
+	recv_send: Sender<TungMessage>,
+	event_send: Sender<ConnectionEvent>,
+) {
+	let mut attempts = 0;
+	// a batch taken from the outbox that has not been sent yet
+	let mut pending = None;
+	loop {
+		let ws_stream = match ws_stream.take() {
+			Some(ws_stream) => Ok(ws_stream),
+			None => {
+				event_send.send(ConnectionEvent::Connecting).ok();
+				connect_async(&url).await.map(|(ws_stream, _)| ws_stream)
 			}
-			Ok(())
-		});
-
-
-		let recv_task = tokio::spawn(async move {
-			while let Some(Ok(msg)) = recv_stream.next().await {
-				match msg {
-					// #[allow(unused_variables)]
-					TungMessage::Text(_txt) => {
-						// #[cfg(feature = "json")]
-						// recv_send.recv(Message::vec_from_json(&txt)?).await?;
-						// 	#[cfg(not(feature = "json"))]
-						// 	anyhow::bail!("received text but feature coora_core/json disabled");
-					}
-					TungMessage::Binary(bytes) => {
-						recv_send.send(bytes)?;
-					}
-					_ => {}
-				}
+		};
+		let reason = match ws_stream {
+			Ok(ws_stream) => {
+				attempts = 0;
+				event_send.send(ConnectionEvent::Connected).ok();
+				run(ws_stream, &outbox, &recv_send, &mut pending).await
 			}
-			Ok(())
-		});
-
-		Ok(Self {
-			send_task,
-			recv_task,
-			send: send_send,
-			recv: recv_recv,
-		})
+			Err(err) => err.to_string(),
+		};
+		log::warn!("disconnected from {url}: {reason}");
+		event_send
+			.send(ConnectionEvent::Disconnected { reason })
+			.ok();
+		attempts += 1;
+		if settings.max_attempts.is_some_and(|max| attempts > max) {
+			return;
+		}
+		tokio::time::sleep(settings.delay(attempts)).await;
+	}
+}
+
+/// Send and receive until the connection is lost, returning the reason.
+async fn run(
+	ws_stream: WsStream,
+	outbox: &Receiver<TungMessage>,
+	recv_send: &Sender<TungMessage>,
+	pending: &mut Option<TungMessage>,
+) -> String {
+	let (mut send, mut recv_stream) = ws_stream.split();
+	loop {
+		if let Some(msg) = pending.clone() {
+			if let Err(err) = send.send(msg).await {
+				return err.to_string();
+			}
+			*pending = None;
+		}
+		tokio::select! {
+			msg = outbox.recv_async() => match msg {
+				Ok(msg) => *pending = Some(msg),
+				Err(err) => return err.to_string(),
+			},
+			msg = recv_stream.next() => match msg {
+				Some(Ok(
+					msg @ (TungMessage::Binary(_) | TungMessage::Text(_)),
+				)) => {
+					recv_send.send(msg).ok();
+				}
+				Some(Ok(TungMessage::Close(frame))) => {
+					return frame
+						.map(|frame| frame.reason.to_string())
+						.unwrap_or_else(|| "connection closed".into());
+				}
+				Some(Ok(_)) => {}
+				Some(Err(err)) => return err.to_string(),
+				None => return "connection closed".into(),
+			},
+		}
 	}
 }
 
 impl Drop for NativeWsClient {
-	fn drop(&mut self) {
-		self.send_task.abort();
-		self.recv_task.abort();
+	fn drop(&mut self) { self.task.abort(); }
+}
+
+impl NativeWsClient {
+	fn send_frame(
+		&mut self,
+		header: RelayHeader,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		if self.task.is_finished() {
+			anyhow::bail!("client stopped reconnecting");
+		}
+		let mut msg = match RelayFrame::encode(header, self.format, messages)?
+		{
+			RelayFrame::Binary(bytes) => TungMessage::Binary(bytes.into()),
+			RelayFrame::Text(text) => TungMessage::Text(text.into()),
+		};
+		// the outbox is never disconnected, the client holds a receiver
+		while let Err(err) = self.send.try_send(msg) {
+			log::warn!("outbox full, dropping oldest messages");
+			self.outbox.try_recv().ok();
+			msg = err.into_inner();
+		}
+		Ok(())
 	}
 }
 
 impl Transport for NativeWsClient {
 	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
-		let bytes = Message::vec_into_bytes(messages)?;
-		self.send.send(bytes.into())?;
-		Ok(())
+		self.send_frame(RelayHeader::Broadcast, messages)
 	}
+	fn send_to(
+		&mut self,
+		client_id: ClientId,
+		messages: &[Message],
+	) -> Result<()> {
+		self.send_frame(RelayHeader::Recipient(client_id), &messages.to_vec())
+	}
+	/// Frames that cannot be decoded are logged and skipped,
+	/// so one bad frame does not discard the others.
 	fn recv(&mut self) -> Result<Vec<Message>> {
 		let messages = self
 			.recv
-			.try_recv_all()?
-			.into_iter()
-			.map(|bytes| Message::vec_from_bytes(&bytes))
-			.collect::<Result<Vec<_>, _>>()?
-			.into_iter()
+			.try_iter()
+			.filter_map(tung_message_to_frame)
+			.filter_map(|frame| {
+				frame
+					.decode()
+					.ok_or(|e| log::error!("skipped relay frame: {e}"))
+			})
 			.flatten()
 			.collect::<Vec<_>>();
 		Ok(messages)
 	}
-	// async fn send(&mut self, messa: Vec<u8>) -> Result<()> {
-	// 	let bytes = Message::vec_
-
-	// 	self.send.send(TungMessage::Binary(bytes)).await?;
-	// 	Ok(())
-	// }
+}
 
-	// fn recv(&mut self) -> Result<Vec<Vec<u8>>> {
-	// 	Ok(bytes)
-	// }
+fn tung_message_to_frame(msg: TungMessage) -> Option<RelayFrame> {
+	match msg {
+		TungMessage::Binary(bytes) => Some(RelayFrame::Binary(bytes.into())),
+		TungMessage::Text(text) => Some(RelayFrame::Text(text.to_string())),
+		_ => None,
+	}
 }
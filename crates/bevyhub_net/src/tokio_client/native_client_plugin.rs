@@ -1,33 +1,173 @@
 use super::native_client::NativeWsClient;
 use crate::prelude::*;
 use bevy::prelude::*;
-use bevy::tasks::block_on;
-use forky::prelude::ResultTEExt;
 
+pub const DEFAULT_CLIENT_ADDRESS: &str = "ws://127.0.0.1:3000/ws";
+/// Env var for the server address, ie `BEVYHUB_ADDRESS=ws://127.0.0.1:3000/ws`.
+pub const ADDRESS_ENV_VAR: &str = "BEVYHUB_ADDRESS";
+/// CLI arg for the server address, ie `--address ws://127.0.0.1:3000/ws`,
+/// takes precedence over [`ADDRESS_ENV_VAR`].
+pub const ADDRESS_ARG: &str = "--address";
+
+/// Connects a [`NativeWsClient`] without blocking startup. The client
+/// connects in a background task, polled by [`poll_native_client`],
+/// and is inserted as the transport once connected. Until then outgoing
+/// messages are queued in [`MessageOutgoing`], so the handshake is never
+/// dropped by the bounded outbox of the client.
+/// Must be added inside a tokio runtime.
 pub struct NativeClientPlugin {
 	pub address: String,
+	pub reconnect: ReconnectSettings,
+	/// The format messages are sent with, see [`NativeWsClient::with_format`].
+	pub format: WireFormat,
 }
 
 impl Default for NativeClientPlugin {
-	fn default() -> Self {
+	fn default() -> Self { Self::new(Self::address_from_env()) }
+}
+
+impl NativeClientPlugin {
+	pub fn new(address: impl Into<String>) -> Self {
 		Self {
-			address: "ws://127.0.0.1:3000/ws".into(),
+			address: address.into(),
+			reconnect: default(),
+			format: default(),
+		}
+	}
+
+	/// The address from the [`ADDRESS_ARG`], then the [`ADDRESS_ENV_VAR`],
+	/// falling back to the [`DEFAULT_CLIENT_ADDRESS`].
+	pub fn address_from_env() -> String {
+		address_from_args(std::env::args())
+			.or_else(|| std::env::var(ADDRESS_ENV_VAR).ok())
+			.unwrap_or_else(|| DEFAULT_CLIENT_ADDRESS.to_string())
+	}
+}
+
+fn address_from_args(
+	args: impl IntoIterator<Item = String>,
+) -> Option<String> {
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		if arg == ADDRESS_ARG {
+			return args.next();
+		}
+		if let Some(address) = arg
+			.strip_prefix(ADDRESS_ARG)
+			.and_then(|arg| arg.strip_prefix('='))
+		{
+			return Some(address.to_string());
 		}
 	}
+	None
 }
 
 impl Plugin for NativeClientPlugin {
 	fn build(&self, app: &mut App) {
-		// TODO async tasks
-		if let Some(client) =
-			block_on(async { NativeWsClient::new(&self.address).await })
-				.ok_or(|e| log::error!("{e}"))
-		{
-			log::info!("client connected");
+		let client =
+			NativeWsClient::connect(&self.address, self.reconnect.clone())
+				.with_format(self.format);
+		app.add_event::<ConnectionEvent>()
+			.insert_non_send_resource(PendingNativeWsClient(client))
+			.add_transport_systems::<NativeWsClient>(
+				DEFAULT_TRANSPORT_INTERVAL,
+			)
+			.add_systems(Update, poll_native_client.before(MessageIncomingSet));
+	}
+}
 
-			app.add_transport(client);
+/// A [`NativeWsClient`] that has not connected yet, inserted as the
+/// transport by [`poll_native_client`] once connected.
+pub struct PendingNativeWsClient(pub NativeWsClient);
 
-			// app.add_plugins(TransportPlugin::arc(client));
+/// Forward the [`ConnectionEvent`]s of the client.
+/// - When first connected the [`PendingNativeWsClient`] is inserted as
+///   the transport, sending the queued messages.
+/// - When disconnected every peer is treated as disconnected,
+///   see [`disconnect_peers`].
+/// - When reconnected the relay server assigns a new id, so the
+///   handshake and a [`snapshot`] are sent as if the app just started.
+pub fn poll_native_client(world: &mut World, mut was_connected: Local<bool>) {
+	let client = match world.get_non_send_resource::<NativeWsClient>() {
+		Some(client) => client,
+		None => match world.get_non_send_resource::<PendingNativeWsClient>() {
+			Some(pending) => &pending.0,
+			None => return,
+		},
+	};
+	let events = client.connection_events().try_iter().collect::<Vec<_>>();
+	let connected = events.contains(&ConnectionEvent::Connected);
+	for event in events {
+		match &event {
+			ConnectionEvent::Connected => log::info!("client connected"),
+			ConnectionEvent::Disconnected { reason } => {
+				log::info!("client disconnected: {reason}");
+				disconnect_peers(world);
+			}
+			ConnectionEvent::Connecting => {}
 		}
+		world.send_event(event);
+	}
+	if !connected {
+		return;
+	}
+	if let Some(pending) =
+		world.remove_non_send_resource::<PendingNativeWsClient>()
+	{
+		world.insert_non_send_resource(pending.0);
+	}
+	if *was_connected && world.contains_resource::<ReplicateRegistry>() {
+		let messages = snapshot(world);
+		world.resource_mut::<MessageOutgoing>().extend(messages);
+	}
+	*was_connected = true;
+}
+
+/// The relay server cannot tell a disconnected client which peers left,
+/// so a [`Message::PeerDisconnected`] is received for every known peer,
+/// despawning their entities, see [`despawn_disconnected`].
+pub fn disconnect_peers(world: &mut World) {
+	let Some(registry) = world.get_resource::<ReplicateRegistry>() else {
+		return;
+	};
+	let mut peers = registry.peers.keys().copied().collect::<Vec<_>>();
+	peers.extend(
+		world
+			.query::<&RemoteEntity>()
+			.iter(world)
+			.map(|remote| remote.client_id),
+	);
+	peers.sort();
+	peers.dedup();
+	if let Some(mut local) = world.get_resource_mut::<LocalClientId>() {
+		local.0 = None;
+	}
+	let mut incoming = world.resource_mut::<MessageIncoming>();
+	for client_id in peers {
+		if client_id != DIRECT_CLIENT_ID {
+			incoming.push(Message::PeerDisconnected { client_id });
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::address_from_args;
+	use crate::prelude::*;
+	use anyhow::Result;
+	use sweet::prelude::*;
+
+	fn args(args: &[&str]) -> Option<String> {
+		address_from_args(args.iter().map(|arg| arg.to_string()))
+	}
+
+	#[test]
+	fn address_args() -> Result<()> {
+		expect(args(&["app"])).to_be(None);
+		expect(args(&["app", ADDRESS_ARG, "ws://a/ws"]))
+			.to_be(Some("ws://a/ws".into()));
+		expect(args(&["app", "--address=ws://b/ws"]))
+			.to_be(Some("ws://b/ws".into()));
+		Ok(())
 	}
 }
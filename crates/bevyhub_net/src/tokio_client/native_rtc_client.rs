@@ -0,0 +1,283 @@
+use crate::prelude::*;
+use anyhow::Result;
+use flume::Receiver;
+use flume::Sender;
+use forky::prelude::ResultTEExt;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio_tungstenite::tungstenite::Bytes;
+use webrtc::api::APIBuilder;
+use webrtc::api::API;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// An [`RtcTransport`] signaling through a [`NativeWsClient`],
+/// for headless peers and tests.
+pub type NativeRtcClient = RtcTransport<NativeWsClient, NativeRtcBackend>;
+
+/// Creates peer connections with the `webrtc` crate,
+/// must be used inside a tokio runtime.
+pub struct NativeRtcBackend {
+	api: Arc<API>,
+	config: RTCConfiguration,
+}
+
+impl Default for NativeRtcBackend {
+	fn default() -> Self {
+		Self {
+			api: Arc::new(APIBuilder::new().build()),
+			config: RTCConfiguration::default(),
+		}
+	}
+}
+
+impl NativeRtcBackend {
+	/// Add STUN or TURN servers, without any only host
+	/// candidates are gathered, ie for a local network.
+	pub fn with_ice_servers(mut self, urls: Vec<String>) -> Self {
+		self.config.ice_servers.push(RTCIceServer {
+			urls,
+			..Default::default()
+		});
+		self
+	}
+}
+
+impl RtcBackend for NativeRtcBackend {
+	type Peer = NativeRtcPeer;
+	fn connect(
+		&mut self,
+		client_id: ClientId,
+		offer: bool,
+		signals: RtcSignalSender,
+		data: RtcDataSender,
+	) -> Result<Self::Peer> {
+		let (signal_send, signal_recv) = flume::unbounded();
+		let (outgoing_send, outgoing_recv) = flume::unbounded();
+		let open = Arc::new(AtomicBool::new(false));
+		let peer = PeerTask {
+			client_id,
+			signals,
+			data,
+			open: open.clone(),
+			channels: Default::default(),
+		};
+		let api = self.api.clone();
+		let config = self.config.clone();
+		tokio::spawn(async move {
+			peer.run(&api, config, offer, signal_recv, outgoing_recv)
+				.await
+				.ok_or(|e| log::error!("peer {client_id}: {e}"));
+		});
+		Ok(NativeRtcPeer {
+			signals: signal_send,
+			outgoing: outgoing_send,
+			open,
+		})
+	}
+}
+
+/// A connection to a peer, run by a tokio task that
+/// closes the connection when this is dropped.
+pub struct NativeRtcPeer {
+	signals: Sender<RtcSignal>,
+	outgoing: Sender<(bool, Bytes)>,
+	open: Arc<AtomicBool>,
+}
+
+impl RtcPeer for NativeRtcPeer {
+	fn signal(&mut self, signal: RtcSignal) -> Result<()> {
+		self.signals.send(signal)?;
+		Ok(())
+	}
+	fn is_open(&self) -> bool {
+		self.open.load(Ordering::SeqCst)
+	}
+	fn send(&mut self, reliable: bool, bytes: Vec<u8>) -> Result<()> {
+		self.outgoing.send((reliable, bytes.into()))?;
+		Ok(())
+	}
+}
+
+/// The reliable and unreliable data channels once open.
+type DataChannels = Arc<Mutex<[Option<Arc<RTCDataChannel>>; 2]>>;
+
+#[derive(Clone)]
+struct PeerTask {
+	client_id: ClientId,
+	signals: RtcSignalSender,
+	data: RtcDataSender,
+	open: Arc<AtomicBool>,
+	channels: DataChannels,
+}
+
+impl PeerTask {
+	async fn run(
+		self,
+		api: &API,
+		config: RTCConfiguration,
+		offer: bool,
+		signal_recv: Receiver<RtcSignal>,
+		outgoing_recv: Receiver<(bool, Bytes)>,
+	) -> Result<()> {
+		let connection = api.new_peer_connection(config).await?;
+		let client_id = self.client_id;
+		let signals = self.signals.clone();
+		connection.on_ice_candidate(Box::new(move |candidate| {
+			if let Some(candidate) =
+				candidate.and_then(|candidate| candidate.to_json().ok())
+			{
+				let signal = RtcSignal::IceCandidate {
+					candidate: candidate.candidate,
+					sdp_mid: candidate.sdp_mid,
+					sdp_m_line_index: candidate.sdp_mline_index,
+				};
+				signals.send((client_id, signal)).ok();
+			}
+			Box::pin(async {})
+		}));
+
+		if offer {
+			let reliable = connection
+				.create_data_channel(RTC_RELIABLE_LABEL, None)
+				.await?;
+			self.on_channel(reliable);
+			let unreliable = connection
+				.create_data_channel(
+					RTC_UNRELIABLE_LABEL,
+					Some(RTCDataChannelInit {
+						ordered: Some(false),
+						max_retransmits: Some(0),
+						..Default::default()
+					}),
+				)
+				.await?;
+			self.on_channel(unreliable);
+			let offer = connection.create_offer(None).await?;
+			connection.set_local_description(offer.clone()).await?;
+			self.signals
+				.send((client_id, RtcSignal::Offer { sdp: offer.sdp }))?;
+		} else {
+			let task = self.clone();
+			connection.on_data_channel(Box::new(move |channel| {
+				task.on_channel(channel);
+				Box::pin(async {})
+			}));
+		}
+
+		// candidates may arrive before the remote description
+		let mut candidates = Vec::new();
+		loop {
+			tokio::select! {
+				signal = signal_recv.recv_async() => match signal {
+					Ok(signal) => {
+						self.handle_signal(&connection, signal, &mut candidates)
+							.await
+							.ok_or(|e| log::error!("peer {client_id}: {e}"));
+					}
+					Err(_) => break,
+				},
+				outgoing = outgoing_recv.recv_async() => match outgoing {
+					Ok((reliable, bytes)) => {
+						// unreliable messages are sent reliably until
+						// the unreliable channel is open
+						let channels = self.channels.lock().unwrap().clone();
+						let channel = channels[!reliable as usize]
+							.clone()
+							.or_else(|| channels[0].clone());
+						if let Some(channel) = channel {
+							channel
+								.send(&bytes)
+								.await
+								.ok_or(|e| log::error!("peer {client_id}: {e}"));
+						}
+					}
+					Err(_) => break,
+				},
+			};
+		}
+		connection.close().await?;
+		Ok(())
+	}
+
+	async fn handle_signal(
+		&self,
+		connection: &RTCPeerConnection,
+		signal: RtcSignal,
+		candidates: &mut Vec<RTCIceCandidateInit>,
+	) -> Result<()> {
+		match signal {
+			RtcSignal::Offer { sdp } => {
+				connection
+					.set_remote_description(RTCSessionDescription::offer(sdp)?)
+					.await?;
+				let answer = connection.create_answer(None).await?;
+				connection.set_local_description(answer.clone()).await?;
+				self.signals.send((
+					self.client_id,
+					RtcSignal::Answer { sdp: answer.sdp },
+				))?;
+			}
+			RtcSignal::Answer { sdp } => {
+				connection
+					.set_remote_description(RTCSessionDescription::answer(sdp)?)
+					.await?;
+			}
+			RtcSignal::IceCandidate {
+				candidate,
+				sdp_mid,
+				sdp_m_line_index,
+			} => {
+				candidates.push(RTCIceCandidateInit {
+					candidate,
+					sdp_mid,
+					sdp_mline_index: sdp_m_line_index,
+					username_fragment: None,
+				});
+			}
+			// handled by the RtcTransport
+			RtcSignal::ChannelOpen => {}
+		}
+		if connection.remote_description().await.is_some() {
+			for candidate in candidates.drain(..) {
+				connection.add_ice_candidate(candidate).await?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Store the channel once open and forward its messages.
+	fn on_channel(&self, channel: Arc<RTCDataChannel>) {
+		let reliable = channel.label() == RTC_RELIABLE_LABEL;
+		let client_id = self.client_id;
+		let data = self.data.clone();
+		channel.on_message(Box::new(move |msg| {
+			data.send((client_id, msg.data.to_vec())).ok();
+			Box::pin(async {})
+		}));
+		let task = self.clone();
+		let open_channel = channel.clone();
+		channel.on_open(Box::new(move || {
+			task.channels.lock().unwrap()[!reliable as usize] =
+				Some(open_channel);
+			if reliable {
+				task.open.store(true, Ordering::SeqCst);
+			}
+			Box::pin(async {})
+		}));
+		let open = self.open.clone();
+		channel.on_close(Box::new(move || {
+			if reliable {
+				open.store(false, Ordering::SeqCst);
+			}
+			Box::pin(async {})
+		}));
+	}
+}
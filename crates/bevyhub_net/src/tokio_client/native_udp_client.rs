@@ -0,0 +1,196 @@
+use crate::prelude::*;
+use anyhow::Result;
+use flume::Receiver;
+use flume::Sender;
+use forky::prelude::ResultTEExt;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::ToSocketAddrs;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+/// A UDP client for the relay server, sending changes on unreliable
+/// channels without the head-of-line blocking of a websocket.
+/// See [`UdpConnection`] for the protocol, each batch is a [`RelayFrame`].
+/// Must be created inside a tokio runtime, the connection is closed when
+/// the client is dropped.
+pub struct NativeUdpClient {
+	send: Sender<(Channel, Vec<u8>)>,
+	recv: Receiver<(Channel, Vec<u8>)>,
+	task: tokio::task::JoinHandle<()>,
+}
+
+impl NativeUdpClient {
+	/// Connect to the server with the default [`UdpSettings`].
+	pub async fn new(address: impl ToSocketAddrs) -> Result<Self> {
+		Self::with_settings(address, UdpSettings::default()).await
+	}
+
+	/// Connect to the server, returning an error if it does not accept
+	/// within [`UdpSettings::timeout`].
+	pub async fn with_settings(
+		address: impl ToSocketAddrs,
+		settings: UdpSettings,
+	) -> Result<Self> {
+		let Some(address) = tokio::net::lookup_host(address).await?.next()
+		else {
+			anyhow::bail!("no address to connect to");
+		};
+		let local: SocketAddr = if address.is_ipv4() {
+			"0.0.0.0:0".parse()?
+		} else {
+			"[::]:0".parse()?
+		};
+		let socket = UdpSocket::bind(local).await?;
+		socket.connect(address).await?;
+
+		let token = timeout(settings.timeout, handshake(&socket, &settings))
+			.await
+			.map_err(|_| anyhow::anyhow!("{address} did not accept"))??;
+		let connection = UdpConnection::new(token, settings, Instant::now());
+
+		let (send, outgoing) = flume::unbounded();
+		let (incoming, recv) = flume::unbounded();
+		let task = tokio::spawn(async move {
+			run(socket, connection, outgoing, incoming)
+				.await
+				.ok_or(|e| log::error!("udp client: {e}"));
+		});
+		Ok(Self { send, recv, task })
+	}
+
+	/// Whether the connection is open, it closes if the server
+	/// disconnects or times out.
+	pub fn is_connected(&self) -> bool { !self.task.is_finished() }
+
+	fn send_frame(
+		&mut self,
+		channel: Channel,
+		header: RelayHeader,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		if !self.is_connected() {
+			anyhow::bail!("udp connection closed");
+		}
+		let frame = RelayFrame::encode(header, WireFormat::Bincode, messages)?;
+		self.send.send((channel, frame.into_bytes()?))?;
+		Ok(())
+	}
+}
+
+/// Send [`UdpBody::Connect`] until accepted, returning the token.
+async fn handshake(socket: &UdpSocket, settings: &UdpSettings) -> Result<u64> {
+	let nonce = rand::random::<u64>();
+	let connect =
+		UdpPacket::handshake(0, UdpBody::Connect { nonce }).to_bytes()?;
+	let mut buf = vec![0; UDP_MAX_DATAGRAM];
+	loop {
+		socket.send(&connect).await?;
+		let accept = async {
+			loop {
+				match socket.recv(&mut buf).await {
+					Ok(len) => {
+						if let Ok(UdpPacket {
+							token,
+							body: UdpBody::Accept { nonce: accepted },
+							..
+						}) = UdpPacket::from_bytes(&buf[..len])
+						{
+							if accepted == nonce {
+								return token;
+							}
+						}
+					}
+					// refused until the server is listening
+					Err(_) => std::future::pending::<()>().await,
+				}
+			}
+		};
+		if let Ok(token) = timeout(settings.resend_after, accept).await {
+			return Ok(token);
+		}
+	}
+}
+
+/// Send and receive until the client is dropped or the connection closes.
+async fn run(
+	socket: UdpSocket,
+	mut connection: UdpConnection,
+	outgoing: Receiver<(Channel, Vec<u8>)>,
+	incoming: Sender<(Channel, Vec<u8>)>,
+) -> Result<()> {
+	let mut buf = vec![0; UDP_MAX_DATAGRAM];
+	let mut interval = tokio::time::interval(UDP_TICK);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {
+				let now = Instant::now();
+				for datagram in connection.poll_transmit(now)? {
+					socket.send(&datagram).await.ok();
+				}
+				if connection.is_closed(now) {
+					anyhow::bail!("connection closed");
+				}
+			}
+			batch = outgoing.recv_async() => match batch {
+				Ok((channel, bytes)) => connection.send(channel, &bytes)?,
+				Err(_) => {
+					socket.send(&connection.disconnect()?).await?;
+					return Ok(());
+				}
+			},
+			len = socket.recv(&mut buf) => {
+				let Some(packet) = len
+					.ok()
+					.and_then(|len| UdpPacket::from_bytes(&buf[..len]).ok())
+					.filter(|packet| packet.token == connection.token)
+				else {
+					continue;
+				};
+				for batch in connection.receive(Instant::now(), packet) {
+					incoming.send(batch).ok();
+				}
+			}
+		}
+	}
+}
+
+impl Transport for NativeUdpClient {
+	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
+		self.send_channel(Channel::RELIABLE, messages)
+	}
+
+	fn send_channel(
+		&mut self,
+		channel: Channel,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		self.send_frame(channel, RelayHeader::Broadcast, messages)
+	}
+
+	fn send_to(
+		&mut self,
+		client_id: ClientId,
+		messages: &[Message],
+	) -> Result<()> {
+		self.send_frame(
+			Channel::RELIABLE,
+			RelayHeader::Recipient(client_id),
+			&messages.to_vec(),
+		)
+	}
+
+	fn recv(&mut self) -> Result<Vec<Message>> {
+		let mut messages = Vec::new();
+		for (_, bytes) in self.recv.try_iter() {
+			// one bad datagram does not discard the others
+			if let Some(batch) = RelayFrame::Binary(bytes)
+				.decode()
+				.ok_or(|e| log::error!("skipped relay frame: {e}"))
+			{
+				messages.extend(batch);
+			}
+		}
+		Ok(messages)
+	}
+}
@@ -4,6 +4,9 @@ pub use self::web_event_client::*;
 pub mod web_postmessage_client;
 #[allow(unused_imports)]
 pub use self::web_postmessage_client::*;
+pub mod web_rtc_client;
+#[allow(unused_imports)]
+pub use self::web_rtc_client::*;
 pub mod web_ws_client;
 #[allow(unused_imports)]
 pub use self::web_ws_client::*;
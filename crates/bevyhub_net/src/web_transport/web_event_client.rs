@@ -4,19 +4,22 @@ use flume::Receiver;
 use forky::prelude::ResultTEExt;
 use forky::web::HtmlEventListener;
 use forky::web::ResultTJsValueExt;
+use std::cell::Cell;
+use std::rc::Rc;
 use wasm_bindgen::JsCast;
-use wasm_bindgen::JsValue;
 use web_sys::CustomEvent;
 use web_sys::CustomEventInit;
 use web_sys::EventTarget;
 
 /// The [`WebEventClient`] can be used on any [`EventTarget`].
 /// Can receive binary or json messages.
-/// Sends json messages.
+/// Sends json messages, or a `Uint8Array` for binary formats,
+/// see [`Self::with_format`].
 /// - listens for `"js-message"`
 /// - emits `"wasm-message"`
 pub struct WebEventClient {
 	target: EventTarget,
+	format: Rc<Cell<WireFormat>>,
 	recv: Receiver<Vec<Message>>,
 	#[allow(unused)] // dropping this deregisters the listener
 	listener: HtmlEventListener<CustomEvent>,
@@ -28,12 +31,15 @@ impl WebEventClient {
 
 	pub fn new(target: EventTarget) -> Self {
 		let (send, recv) = flume::unbounded();
+		let format = Rc::new(Cell::new(WireFormat::Json));
 
+		let listener_format = format.clone();
 		let listener = HtmlEventListener::new_with_target(
 			"js-message",
 			move |e: CustomEvent| {
-				if let Some(messags) = js_value_to_messages(&e.detail())
-					.ok_or(|e| log::error!("{e}"))
+				if let Some(messags) =
+					js_value_to_messages(&e.detail(), listener_format.get())
+						.ok_or(|e| log::error!("{e}"))
 				{
 					send.send(messags).ok_or(|e| log::error!("{e}"));
 				}
@@ -42,17 +48,24 @@ impl WebEventClient {
 		);
 		Self {
 			target,
+			format,
 			recv,
 			listener,
 		}
 	}
+
+	/// Set the format messages are sent with, ie [`WireFormat::MsgPack`]
+	/// for a compact format that js consumers can still decode.
+	pub fn with_format(self, format: WireFormat) -> Self {
+		self.format.set(format);
+		self
+	}
 }
 
 impl Transport for WebEventClient {
 	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
-		let json = Message::vec_into_json(messages)?;
 		let init = CustomEventInit::new();
-		init.set_detail(&JsValue::from_str(&json));
+		init.set_detail(&format_js_value(self.format.get(), messages)?);
 		let event =
 			CustomEvent::new_with_event_init_dict("wasm-message", &init)
 				.anyhow()?;
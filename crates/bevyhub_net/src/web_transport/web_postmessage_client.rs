@@ -4,12 +4,16 @@ use flume::Receiver;
 use forky::prelude::ResultTEExt;
 use forky::web::HtmlEventListener;
 use forky::web::ResultTJsValueExt;
-use wasm_bindgen::JsValue;
+use std::cell::Cell;
+use std::rc::Rc;
 use web_sys::MessageEvent;
 use web_sys::Window;
 
+/// Sends json messages, or a `Uint8Array` for binary formats,
+/// see [`Self::with_format`].
 pub struct WebPostmessageClient {
 	target: Window,
+	format: Rc<Cell<WireFormat>>,
 	recv: Receiver<Vec<Message>>,
 	#[allow(unused)] // dropping this deregisters the listener
 	listener: HtmlEventListener<MessageEvent>,
@@ -18,12 +22,15 @@ impl WebPostmessageClient {
 	pub fn new() -> Self { Self::new_with(web_sys::window().unwrap()) }
 	pub fn new_with(target: Window) -> Self {
 		let (send, recv) = flume::unbounded();
+		let format = Rc::new(Cell::new(WireFormat::Json));
 
+		let listener_format = format.clone();
 		let listener = HtmlEventListener::new_with_target(
 			"message",
 			move |e: MessageEvent| {
-				if let Some(msg) = js_value_to_messages(&e.data())
-					.ok_or(|e| log::error!("{e}"))
+				if let Some(msg) =
+					js_value_to_messages(&e.data(), listener_format.get())
+						.ok_or(|e| log::error!("{e}"))
 				{
 					send.send(msg).ok_or(|e| log::error!("{e}"));
 				}
@@ -32,17 +39,23 @@ impl WebPostmessageClient {
 		);
 		Self {
 			target,
+			format,
 			recv,
 			listener,
 		}
 	}
+
+	/// Set the format messages are sent with, see [`WebEventClient::with_format`].
+	pub fn with_format(self, format: WireFormat) -> Self {
+		self.format.set(format);
+		self
+	}
 }
 
 impl Transport for WebPostmessageClient {
 	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
-		let json = Message::vec_into_json(messages)?;
 		self.target
-			.post_message(&JsValue::from_str(&json), "*")
+			.post_message(&format_js_value(self.format.get(), messages)?, "*")
 			.anyhow()?;
 		Ok(())
 	}
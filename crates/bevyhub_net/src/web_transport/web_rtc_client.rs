@@ -0,0 +1,344 @@
+use crate::prelude::*;
+use anyhow::Result;
+use flume::Receiver;
+use flume::Sender;
+use forky::prelude::ResultTEExt;
+use forky::web::ResultTJsValueExt;
+use js_sys::ArrayBuffer;
+use js_sys::Reflect;
+use js_sys::Uint8Array;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::MessageEvent;
+use web_sys::RtcConfiguration;
+use web_sys::RtcDataChannel;
+use web_sys::RtcDataChannelEvent;
+use web_sys::RtcDataChannelInit;
+use web_sys::RtcDataChannelType;
+use web_sys::RtcIceCandidateInit;
+use web_sys::RtcIceServer;
+use web_sys::RtcPeerConnection;
+use web_sys::RtcPeerConnectionIceEvent;
+use web_sys::RtcSdpType;
+use web_sys::RtcSessionDescriptionInit;
+
+/// An [`RtcTransport`] signaling through a [`WebWsClient`].
+pub type WebRtcClient = RtcTransport<WebWsClient, WebRtcBackend>;
+
+/// Creates peer connections with the browser's `RTCPeerConnection`.
+#[derive(Debug, Default, Clone)]
+pub struct WebRtcBackend {
+	ice_servers: Vec<String>,
+}
+
+impl WebRtcBackend {
+	/// Add STUN or TURN servers, without any only host
+	/// candidates are gathered, ie for a local network.
+	pub fn with_ice_servers(mut self, urls: Vec<String>) -> Self {
+		self.ice_servers.extend(urls);
+		self
+	}
+
+	fn config(&self) -> RtcConfiguration {
+		let config = RtcConfiguration::new();
+		if !self.ice_servers.is_empty() {
+			let server = RtcIceServer::new();
+			let urls = self
+				.ice_servers
+				.iter()
+				.map(|url| JsValue::from_str(url))
+				.collect::<js_sys::Array>();
+			server.set_urls(&urls);
+			config.set_ice_servers(&js_sys::Array::of1(&server));
+		}
+		config
+	}
+}
+
+type JsClosure = Closure<dyn FnMut(JsValue)>;
+/// The reliable and unreliable data channels once open.
+type DataChannels = Rc<RefCell<[Option<RtcDataChannel>; 2]>>;
+
+impl RtcBackend for WebRtcBackend {
+	type Peer = WebRtcPeer;
+	fn connect(
+		&mut self,
+		client_id: ClientId,
+		offer: bool,
+		signals: RtcSignalSender,
+		data: RtcDataSender,
+	) -> Result<Self::Peer> {
+		let connection =
+			RtcPeerConnection::new_with_configuration(&self.config())
+				.anyhow()?;
+		let (signal_send, signal_recv) = flume::unbounded();
+		let peer = WebRtcPeer {
+			connection,
+			signals: signal_send,
+			handles: PeerHandles {
+				client_id,
+				data,
+				channels: Default::default(),
+				open: Default::default(),
+				closures: Default::default(),
+			},
+		};
+
+		let ice_signals = signals.clone();
+		peer.set_closure(
+			move |e: JsValue| {
+				let e: RtcPeerConnectionIceEvent = e.unchecked_into();
+				if let Some(candidate) = e.candidate() {
+					let signal = RtcSignal::IceCandidate {
+						candidate: candidate.candidate(),
+						sdp_mid: candidate.sdp_mid(),
+						sdp_m_line_index: candidate.sdp_m_line_index(),
+					};
+					ice_signals.send((client_id, signal)).ok();
+				}
+			},
+			|connection, func| connection.set_onicecandidate(Some(func)),
+		);
+
+		if offer {
+			peer.handles.on_channel(
+				peer.connection.create_data_channel(RTC_RELIABLE_LABEL),
+			);
+			let init = RtcDataChannelInit::new();
+			init.set_ordered(false);
+			init.set_max_retransmits(0);
+			peer.handles.on_channel(
+				peer.connection.create_data_channel_with_data_channel_dict(
+					RTC_UNRELIABLE_LABEL,
+					&init,
+				),
+			);
+		} else {
+			let handles = peer.handles.clone();
+			peer.set_closure(
+				move |e: JsValue| {
+					let e: RtcDataChannelEvent = e.unchecked_into();
+					handles.on_channel(e.channel());
+				},
+				|connection, func| connection.set_ondatachannel(Some(func)),
+			);
+		}
+
+		let connection = peer.connection.clone();
+		wasm_bindgen_futures::spawn_local(async move {
+			handle_signals(client_id, connection, offer, signals, signal_recv)
+				.await
+				.ok_or(|e| log::error!("peer {client_id}: {e}"));
+		});
+		Ok(peer)
+	}
+}
+
+/// A connection to a peer, closed when dropped.
+pub struct WebRtcPeer {
+	connection: RtcPeerConnection,
+	/// Signals from the remote peer, handled in order by a local task.
+	signals: Sender<RtcSignal>,
+	handles: PeerHandles,
+}
+
+impl WebRtcPeer {
+	fn set_closure(
+		&self,
+		func: impl FnMut(JsValue) + 'static,
+		set: impl FnOnce(&RtcPeerConnection, &js_sys::Function),
+	) {
+		let closure = Closure::<dyn FnMut(JsValue)>::new(func);
+		set(&self.connection, closure.as_ref().unchecked_ref());
+		self.handles.closures.borrow_mut().push(closure);
+	}
+}
+
+/// State shared with the event listeners.
+#[derive(Clone)]
+struct PeerHandles {
+	client_id: ClientId,
+	data: RtcDataSender,
+	channels: DataChannels,
+	open: Rc<Cell<bool>>,
+	/// Event listeners, dropping these deregisters them.
+	closures: Rc<RefCell<Vec<JsClosure>>>,
+}
+
+impl PeerHandles {
+	/// Store the channel once open and forward its messages.
+	fn on_channel(&self, channel: RtcDataChannel) {
+		channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+		let reliable = channel.label() == RTC_RELIABLE_LABEL;
+		let index = !reliable as usize;
+		let client_id = self.client_id;
+
+		let data = self.data.clone();
+		let on_message =
+			Closure::<dyn FnMut(JsValue)>::new(move |e: JsValue| {
+				let e: MessageEvent = e.unchecked_into();
+				if let Some(buffer) = e.data().dyn_ref::<ArrayBuffer>() {
+					let bytes = Uint8Array::new(buffer).to_vec();
+					data.send((client_id, bytes)).ok();
+				}
+			});
+		channel.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+		let channels = self.channels.clone();
+		let open = self.open.clone();
+		let open_channel = channel.clone();
+		let on_open = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+			channels.borrow_mut()[index] = Some(open_channel.clone());
+			if reliable {
+				open.set(true);
+			}
+		});
+		channel.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+
+		let channels = self.channels.clone();
+		let open = self.open.clone();
+		let on_close = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+			channels.borrow_mut()[index] = None;
+			if reliable {
+				open.set(false);
+			}
+		});
+		channel.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+		self.closures
+			.borrow_mut()
+			.extend([on_message, on_open, on_close]);
+	}
+}
+
+impl RtcPeer for WebRtcPeer {
+	fn signal(&mut self, signal: RtcSignal) -> Result<()> {
+		self.signals.send(signal)?;
+		Ok(())
+	}
+	fn is_open(&self) -> bool { self.handles.open.get() }
+	fn send(&mut self, reliable: bool, bytes: Vec<u8>) -> Result<()> {
+		let channels = self.handles.channels.borrow();
+		// unreliable messages are sent reliably until
+		// the unreliable channel is open
+		if let Some(channel) = channels[!reliable as usize]
+			.as_ref()
+			.or(channels[0].as_ref())
+		{
+			channel.send_with_u8_array(&bytes).anyhow()?;
+		}
+		Ok(())
+	}
+}
+
+impl Drop for WebRtcPeer {
+	fn drop(&mut self) {
+		self.connection.close();
+		// the listeners hold handles to the peer
+		self.handles.closures.borrow_mut().clear();
+	}
+}
+
+/// Send the offer if offering, then handle signals from
+/// the remote peer until it is dropped.
+async fn handle_signals(
+	client_id: ClientId,
+	connection: RtcPeerConnection,
+	offer: bool,
+	signals: RtcSignalSender,
+	signal_recv: Receiver<RtcSignal>,
+) -> Result<()> {
+	if offer {
+		let sdp = set_local_description(
+			&connection,
+			RtcSdpType::Offer,
+			connection.create_offer(),
+		)
+		.await?;
+		signals.send((client_id, RtcSignal::Offer { sdp }))?;
+	}
+	// candidates may arrive before the remote description
+	let mut candidates = Vec::new();
+	while let Ok(signal) = signal_recv.recv_async().await {
+		match signal {
+			RtcSignal::Offer { sdp } => {
+				set_remote_description(&connection, RtcSdpType::Offer, &sdp)
+					.await?;
+				let sdp = set_local_description(
+					&connection,
+					RtcSdpType::Answer,
+					connection.create_answer(),
+				)
+				.await?;
+				signals.send((client_id, RtcSignal::Answer { sdp }))?;
+			}
+			RtcSignal::Answer { sdp } => {
+				set_remote_description(&connection, RtcSdpType::Answer, &sdp)
+					.await?;
+			}
+			RtcSignal::IceCandidate {
+				candidate,
+				sdp_mid,
+				sdp_m_line_index,
+			} => {
+				let init = RtcIceCandidateInit::new(&candidate);
+				init.set_sdp_mid(sdp_mid.as_deref());
+				init.set_sdp_m_line_index(sdp_m_line_index);
+				candidates.push(init);
+			}
+			// handled by the RtcTransport
+			RtcSignal::ChannelOpen => {}
+		}
+		if connection.remote_description().is_some() {
+			for candidate in candidates.drain(..) {
+				JsFuture::from(
+					connection
+						.add_ice_candidate_with_opt_rtc_ice_candidate_init(
+							Some(&candidate),
+						),
+				)
+				.await
+				.anyhow()?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Await a created offer or answer and set it as the local
+/// description, returning its sdp.
+async fn set_local_description(
+	connection: &RtcPeerConnection,
+	sdp_type: RtcSdpType,
+	created: js_sys::Promise,
+) -> Result<String> {
+	let created = JsFuture::from(created).await.anyhow()?;
+	let sdp = Reflect::get(&created, &JsValue::from_str("sdp"))
+		.anyhow()?
+		.as_string()
+		.ok_or_else(|| anyhow::anyhow!("session description has no sdp"))?;
+	let description = RtcSessionDescriptionInit::new(sdp_type);
+	description.set_sdp(&sdp);
+	JsFuture::from(connection.set_local_description(&description))
+		.await
+		.anyhow()?;
+	Ok(sdp)
+}
+
+async fn set_remote_description(
+	connection: &RtcPeerConnection,
+	sdp_type: RtcSdpType,
+	sdp: &str,
+) -> Result<()> {
+	let description = RtcSessionDescriptionInit::new(sdp_type);
+	description.set_sdp(sdp);
+	JsFuture::from(connection.set_remote_description(&description))
+		.await
+		.anyhow()?;
+	Ok(())
+}
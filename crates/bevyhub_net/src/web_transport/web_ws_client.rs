@@ -14,9 +14,13 @@ use web_sys::MessageEvent;
 use web_sys::WebSocket;
 
 
-/// Can receive binary or json messages, sends as binary.
+/// A websocket client for the relay server, batches are sent as
+/// [`RelayFrame`]s with its [`WireFormat`], [`WireFormat::Bincode`]
+/// by default, see [`Self::with_format`].
+/// Received batches are decoded with the format they were sent with.
 pub struct WebWsClient {
 	ws: WebSocket,
+	format: WireFormat,
 	recv: Receiver<Vec<Message>>,
 	#[allow(unused)] // dropping this deregisters the listener
 	listener: HtmlEventListener<MessageEvent>,
@@ -31,7 +35,8 @@ impl WebWsClient {
 		let listener = HtmlEventListener::new_with_target(
 			"message",
 			move |e: MessageEvent| {
-				if let Some(messages) = js_value_to_messages(&e.data())
+				if let Some(messages) = js_value_to_frame(&e.data())
+					.and_then(|frame| frame.decode())
 					.ok_or(|e| log::error!("{e}"))
 				{
 					send.send(messages).ok_or(|e| log::error!("{e}"));
@@ -39,14 +44,46 @@ impl WebWsClient {
 			},
 			ws.clone(),
 		);
-		Self { ws, recv, listener }
+		Self {
+			ws,
+			format: WireFormat::Bincode,
+			recv,
+			listener,
+		}
+	}
+
+	/// Set the format messages are sent with, text formats like
+	/// [`WireFormat::Json`] are sent as text frames.
+	pub fn with_format(mut self, format: WireFormat) -> Self {
+		self.format = format;
+		self
+	}
+
+	fn send_frame(
+		&mut self,
+		header: RelayHeader,
+		messages: &Vec<Message>,
+	) -> Result<()> {
+		match RelayFrame::encode(header, self.format, messages)? {
+			RelayFrame::Binary(bytes) => {
+				self.ws.send_with_u8_array(&bytes).anyhow()
+			}
+			RelayFrame::Text(text) => self.ws.send_with_str(&text).anyhow(),
+		}
 	}
 }
 
 impl Transport for WebWsClient {
 	fn send(&mut self, messages: &Vec<Message>) -> Result<()> {
-		let bytes = Message::vec_into_bytes(messages)?;
-		self.ws.send_with_u8_array(&bytes).anyhow()
+		self.send_frame(RelayHeader::Broadcast, messages)
+	}
+
+	fn send_to(
+		&mut self,
+		client_id: ClientId,
+		messages: &[Message],
+	) -> Result<()> {
+		self.send_frame(RelayHeader::Recipient(client_id), &messages.to_vec())
 	}
 
 	fn recv(&mut self) -> Result<Vec<Message>> { self.recv.try_recv_all_flat() }
@@ -61,14 +98,48 @@ impl Drop for WebWsClient {
 	}
 }
 
-/// Converts the [`MessageEvent::data`] field into a vec of bytes.
-/// If the data is a string, it will be converted to bytes using `serde_json`.
-pub fn js_value_to_messages(data: &JsValue) -> Result<Vec<Message>> {
+/// Encode messages for js, text formats as a string
+/// and binary formats as a `Uint8Array`.
+pub fn format_js_value(
+	format: WireFormat,
+	messages: &Vec<Message>,
+) -> Result<JsValue> {
+	let bytes = format.encode(messages)?;
+	if format.is_text() {
+		Ok(JsValue::from_str(std::str::from_utf8(&bytes)?))
+	} else {
+		Ok(Uint8Array::from(bytes.as_slice()).into())
+	}
+}
+
+/// Converts the [`MessageEvent::data`] of a websocket into a [`RelayFrame`].
+fn js_value_to_frame(data: &JsValue) -> Result<RelayFrame> {
+	if let Some(array_buffer) = data.dyn_ref::<ArrayBuffer>() {
+		Ok(RelayFrame::Binary(Uint8Array::new(&array_buffer).to_vec()))
+	} else if let Some(str) = data.dyn_ref::<JsString>() {
+		Ok(RelayFrame::Text(str.into()))
+	} else {
+		anyhow::bail!(
+			"received unknown message type: {}",
+			data.js_typeof()
+				.as_string()
+				.unwrap_or("no type".to_string())
+		)
+	}
+}
+
+/// Converts the [`MessageEvent::data`] field into a vec of messages.
+/// Binary data is decoded with [`WireFormat::binary`] of the transport format.
+/// If the data is a string, it will be converted using `serde_json`.
+pub fn js_value_to_messages(
+	data: &JsValue,
+	format: WireFormat,
+) -> Result<Vec<Message>> {
 	if let Some(array_buffer) = data.dyn_ref::<ArrayBuffer>() {
 		let array = Uint8Array::new(&array_buffer);
-		let bytes = array.to_vec();
-		let messages = Message::vec_from_bytes(&bytes)?;
-		Ok(messages)
+		format.binary().decode(&array.to_vec())
+	} else if let Some(array) = data.dyn_ref::<Uint8Array>() {
+		format.binary().decode(&array.to_vec())
 	} else if let Some(str) = data.dyn_ref::<JsString>() {
 		// #[allow(unused_variables)]
 		#[cfg(feature = "serde_json")]
@@ -0,0 +1,3 @@
+pub mod replication_ordering;
+#[allow(unused_imports)]
+pub use self::replication_ordering::*;
@@ -0,0 +1,205 @@
+#[cfg(test)]
+mod test {
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use bevyhub_net::prelude::*;
+	use serde::Deserialize;
+	use serde::Serialize;
+	use std::time::Duration;
+	use sweet::prelude::*;
+
+	type Simulated = SimulatedTransport<ChannelsTransport>;
+
+	const FRAME: Duration = Duration::from_millis(16);
+
+	#[derive(Debug, Clone, PartialEq, Component, Serialize, Deserialize)]
+	struct Health(u32);
+
+	/// A sending app with simulated conditions and a perfect receiving app,
+	/// transports are run every update.
+	fn setup(
+		conditions: NetworkConditions,
+		seed: u64,
+		channel: Channel,
+	) -> (App, App) {
+		let (a, b) = ChannelsTransport::pair();
+		let mut app1 = App::new();
+		app1.init_resource::<Time>()
+			.add_plugins(ReplicatePlugin)
+			.replicate::<Health>()
+			.replicate_channel::<Health>(channel)
+			.add_transport_with_duration(
+				SimulatedTransport::new(a, conditions)
+					.with_seed(seed)
+					.with_manual_clock(),
+				Duration::ZERO,
+			);
+		let mut app2 = App::new();
+		app2.init_resource::<Time>()
+			.add_plugins(ReplicatePlugin)
+			.replicate::<Health>()
+			.add_transport_with_duration(b, Duration::ZERO);
+		(app1, app2)
+	}
+
+	fn step(app1: &mut App, app2: &mut App) {
+		app1.update();
+		app1.world_mut()
+			.non_send_resource_mut::<Simulated>()
+			.advance(FRAME);
+		app2.update();
+	}
+
+	/// Step until every packet has been received.
+	fn settle(app1: &mut App, app2: &mut App) {
+		for _ in 0..100 {
+			step(app1, app2);
+			let transport = app1.world().non_send_resource::<Simulated>();
+			if transport.num_in_flight() == 0 {
+				break;
+			}
+		}
+		// apply the last received messages
+		step(app1, app2);
+	}
+
+	fn received(app: &mut App) -> Vec<Health> {
+		app.world_mut()
+			.query::<&Health>()
+			.iter(app.world())
+			.cloned()
+			.collect()
+	}
+
+	fn num_remote(app: &mut App) -> usize {
+		app.world_mut()
+			.query::<&RemoteEntity>()
+			.iter(app.world())
+			.count()
+	}
+
+	fn jitter() -> NetworkConditions {
+		NetworkConditions::default()
+			.with_latency(Duration::from_millis(50))
+			.with_jitter(Duration::from_millis(50))
+	}
+
+	#[test]
+	fn change_before_add() -> Result<()> {
+		for seed in 0..20 {
+			let (mut app1, mut app2) =
+				setup(jitter(), seed, Channel::UNRELIABLE);
+			let entity = app1.world_mut().spawn(Replicate::default()).id();
+			settle(&mut app1, &mut app2);
+			expect(num_remote(&mut app2)).to_be(1);
+
+			app1.world_mut().entity_mut(entity).insert(Health(1));
+			step(&mut app1, &mut app2);
+			app1.world_mut().get_mut::<Health>(entity).unwrap().0 = 2;
+			settle(&mut app1, &mut app2);
+			// a late add does not overwrite a newer change
+			expect(received(&mut app2)).to_be(vec![Health(2)]);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn change_after_despawn() -> Result<()> {
+		for seed in 0..20 {
+			let (mut app1, mut app2) =
+				setup(jitter(), seed, Channel::UNRELIABLE);
+			let entity = app1
+				.world_mut()
+				.spawn((Replicate::default(), Health(1)))
+				.id();
+			settle(&mut app1, &mut app2);
+			expect(received(&mut app2)).to_be(vec![Health(1)]);
+
+			app1.world_mut().get_mut::<Health>(entity).unwrap().0 = 2;
+			step(&mut app1, &mut app2);
+			app1.world_mut().despawn(entity);
+			settle(&mut app1, &mut app2);
+			// a late change does not resurrect the entity
+			expect(received(&mut app2)).to_be(vec![]);
+			expect(num_remote(&mut app2)).to_be(0);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn change_after_remove() -> Result<()> {
+		for channel in [Channel::UNRELIABLE, Channel::SEQUENCED] {
+			for seed in 0..20 {
+				let (mut app1, mut app2) = setup(jitter(), seed, channel);
+				let entity = app1
+					.world_mut()
+					.spawn((Replicate::default(), Health(1)))
+					.id();
+				settle(&mut app1, &mut app2);
+				expect(received(&mut app2)).to_be(vec![Health(1)]);
+
+				app1.world_mut().get_mut::<Health>(entity).unwrap().0 = 2;
+				step(&mut app1, &mut app2);
+				app1.world_mut().entity_mut(entity).remove::<Health>();
+				settle(&mut app1, &mut app2);
+				// a late change does not add the removed component again
+				expect(received(&mut app2)).to_be(vec![]);
+				expect(num_remote(&mut app2)).to_be(1);
+			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn spawn_add_despawn_same_frame() -> Result<()> {
+		let (mut app1, mut app2) = setup(jitter(), 0, Channel::UNRELIABLE);
+		let entity = app1
+			.world_mut()
+			.spawn((Replicate::default(), Health(1)))
+			.id();
+		app1.world_mut().get_mut::<Health>(entity).unwrap().0 = 2;
+		app1.update();
+		app1.world_mut().despawn(entity);
+		settle(&mut app1, &mut app2);
+		expect(received(&mut app2)).to_be(vec![]);
+		expect(num_remote(&mut app2)).to_be(0);
+		Ok(())
+	}
+
+	#[test]
+	fn sequenced_latest_wins() -> Result<()> {
+		let conditions = jitter().with_duplication(0.5).with_reorder(0.5);
+		for seed in 0..20 {
+			let (mut app1, mut app2) =
+				setup(conditions.clone(), seed, Channel::SEQUENCED);
+			let entity = app1
+				.world_mut()
+				.spawn((Replicate::default(), Health(0)))
+				.id();
+			settle(&mut app1, &mut app2);
+			for i in 1..=20 {
+				app1.world_mut().get_mut::<Health>(entity).unwrap().0 = i;
+				step(&mut app1, &mut app2);
+			}
+			settle(&mut app1, &mut app2);
+			expect(received(&mut app2)).to_be(vec![Health(20)]);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn unreliable_loss() -> Result<()> {
+		let conditions = jitter().with_packet_loss(1.);
+		let (mut app1, mut app2) = setup(conditions, 0, Channel::UNRELIABLE);
+		let entity = app1
+			.world_mut()
+			.spawn((Replicate::default(), Health(1)))
+			.id();
+		settle(&mut app1, &mut app2);
+		app1.world_mut().get_mut::<Health>(entity).unwrap().0 = 2;
+		settle(&mut app1, &mut app2);
+		// spawn and add are reliable, the change is lost
+		expect(received(&mut app2)).to_be(vec![Health(1)]);
+		Ok(())
+	}
+}
@@ -1,12 +1,17 @@
 use super::*;
 use anyhow::Result;
+use bevyhub_net::prelude::Channel;
+use bevyhub_net::prelude::RelayFrame;
+use bevyhub_net::prelude::RelayHeader;
 use futures::future::try_join_all;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use tokio::sync::RwLock;
 
-pub type LobbyId = usize;
-pub type ClientId = usize;
+pub use bevyhub_net::prelude::ClientId;
+pub use bevyhub_net::prelude::LobbyId;
 
 pub type Lobby = Arc<RwLock<LobbyInner>>;
 
@@ -18,35 +23,109 @@ pub struct LobbyInner {
 
 
 impl LobbyInner {
+	/// Ids start at 1, 0 is the sender of messages that were not relayed,
+	/// see [`bevyhub_net::prelude::DIRECT_CLIENT_ID`].
 	fn next_id(&mut self) -> ClientId {
-		let id = self.client_id_incr;
 		self.client_id_incr += 1;
-		id
+		self.client_id_incr
 	}
 
-	pub fn push_client(&mut self, self_arc: Lobby, client: Client) {
+	/// Add a client and notify the others with a
+	/// [`RelayHeader::PeerConnected`].
+	pub async fn push_client(
+		&mut self,
+		self_arc: Lobby,
+		client: Client,
+	) -> Result<()> {
 		let id = self.next_id();
-		let lobby_client = LobbyClient::new(self_arc, client, id);
+		self.insert_client(id, LobbyClient::new(self_arc, client, id))
+			.await
+	}
+
+	/// Add a client connected over UDP, see [`LobbyClient::new_udp`].
+	pub async fn push_udp_client(
+		&mut self,
+		self_arc: Lobby,
+		address: SocketAddr,
+		incoming: mpsc::UnboundedReceiver<(Channel, Vec<u8>)>,
+		outgoing: UdpOutgoing,
+	) -> Result<()> {
+		let id = self.next_id();
+		let lobby_client =
+			LobbyClient::new_udp(self_arc, address, incoming, outgoing, id);
+		self.insert_client(id, lobby_client).await
+	}
+
+	/// Tell the client its id with a [`RelayHeader::Welcome`] before
+	/// notifying the others.
+	async fn insert_client(
+		&mut self,
+		id: ClientId,
+		mut lobby_client: LobbyClient,
+	) -> Result<()> {
+		lobby_client
+			.send(
+				Channel::RELIABLE,
+				RelayFrame::header_only(RelayHeader::Welcome(id)),
+			)
+			.await?;
 		self.clients.insert(id, lobby_client);
+		self.broadcast(id, RelayHeader::PeerConnected(id)).await
 	}
 
+	/// Send a client's batch to every other client, or only the
+	/// [`RelayHeader::Recipient`], on the channel it was received on.
+	/// The batch is never decoded, only its header is replaced with a
+	/// [`RelayHeader::Sender`].
 	pub async fn handle_message(
 		&mut self,
 		client_id: ClientId,
-		msg: Vec<u8>,
+		channel: Channel,
+		frame: RelayFrame,
 	) -> Result<()> {
+		let recipient = match frame.header()? {
+			RelayHeader::Broadcast => None,
+			RelayHeader::Recipient(recipient) => Some(recipient),
+			header => anyhow::bail!("clients cannot send {header:?}"),
+		};
+		let frame = frame.with_header(RelayHeader::Sender(client_id))?;
 		let futs = self
 			.clients
 			.iter_mut()
 			.filter(|(id, _)| **id != client_id)
-			.map(|(_, client)| client.send(msg.clone()));
+			.filter(|(id, _)| recipient.is_none_or(|r| r == **id))
+			.map(|(_, client)| client.send(channel, frame.clone()));
 
 		try_join_all(futs).await?;
 		Ok(())
 	}
 
-	pub fn remove_client(&mut self, client_id: ClientId) -> Result<()> {
-		self.clients.remove(&client_id);
+	async fn broadcast(
+		&mut self,
+		client_id: ClientId,
+		header: RelayHeader,
+	) -> Result<()> {
+		let frame = RelayFrame::header_only(header);
+		let futs = self
+			.clients
+			.iter_mut()
+			.filter(|(id, _)| **id != client_id)
+			.map(|(_, client)| client.send(Channel::RELIABLE, frame.clone()));
+
+		try_join_all(futs).await?;
 		Ok(())
 	}
+
+	/// Remove a client and notify the others with a
+	/// [`RelayHeader::PeerDisconnected`].
+	pub async fn remove_client(&mut self, client_id: ClientId) -> Result<()> {
+		// dropping the client aborts its recv task, which may be the
+		// one running this, so hold it until the broadcast is sent
+		let removed = self.clients.remove(&client_id);
+		let result = self
+			.broadcast(client_id, RelayHeader::PeerDisconnected(client_id))
+			.await;
+		drop(removed);
+		result
+	}
 }
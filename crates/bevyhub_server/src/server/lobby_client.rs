@@ -1,61 +1,128 @@
 use super::*;
 use anyhow::Result;
 use axum::extract::ws;
+use bevyhub_net::prelude::Channel;
+use bevyhub_net::prelude::RelayFrame;
 use forky::prelude::*;
 use futures::SinkExt;
 use futures_util::stream::SplitSink;
+use futures_util::Stream;
 use futures_util::StreamExt;
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
 
 pub type AxumWsEvent = axum::extract::ws::Message;
 
 
 pub struct LobbyClient {
-	send: SplitSink<ws::WebSocket, ws::Message>,
+	send: ClientSink,
 	#[allow(dead_code)]
 	recv_task: tokio::task::JoinHandle<()>,
 }
 
+enum ClientSink {
+	Ws(SplitSink<ws::WebSocket, ws::Message>),
+	Udp {
+		address: SocketAddr,
+		outgoing: UdpOutgoing,
+	},
+}
+
 impl LobbyClient {
 	pub fn new(lobby: Lobby, client: super::Client, client_id: ClientId) -> Self {
-		let (send, mut recv) = client.socket.split();
-		let recv_task = tokio::spawn(async move {
-			while let Some(Ok(msg)) = recv.next().await {
-				if let Some(msg) =
-					filter_payload(msg).ok_or(|e| log::error!("{e}")).flatten()
-				{
-					lobby
-						.write()
-						.await
-						.handle_message(client_id, msg)
-						.await
-						.ok_or(|e| log::error!("{e}"));
-				}
-			}
-			lobby
-				.write()
-				.await
-				.remove_client(client_id)
-				.ok_or(|e| log::error!("{e}"));
-			log::info!("<<< {}: Disconnected", client_id);
-		});
+		let (send, recv) = client.socket.split();
+		let recv = recv
+			.take_while(|msg| std::future::ready(msg.is_ok()))
+			.filter_map(|msg| async move {
+				filter_payload(msg.ok()?)
+					.ok_or(|e| log::error!("{e}"))
+					.flatten()
+					.map(|msg| (Channel::RELIABLE, msg))
+			});
+		Self {
+			send: ClientSink::Ws(send),
+			recv_task: spawn_recv(lobby, client_id, recv),
+		}
+	}
 
-		Self { send, recv_task }
+	/// A client connected to the [`LobbyMap::listen_udp`] socket,
+	/// receiving its batches from `incoming`.
+	pub fn new_udp(
+		lobby: Lobby,
+		address: SocketAddr,
+		mut incoming: mpsc::UnboundedReceiver<(Channel, Vec<u8>)>,
+		outgoing: UdpOutgoing,
+		client_id: ClientId,
+	) -> Self {
+		let recv = futures_util::stream::poll_fn(move |cx| {
+			incoming.poll_recv(cx).map(|batch| {
+				batch.map(|(channel, bytes)| {
+					(channel, RelayFrame::Binary(bytes))
+				})
+			})
+		});
+		Self {
+			send: ClientSink::Udp { address, outgoing },
+			recv_task: spawn_recv(lobby, client_id, recv),
+		}
 	}
 
-	pub async fn send(&mut self, msg: Vec<u8>) -> Result<()> {
-		self.send.send(AxumWsEvent::Binary(msg)).await?;
+	/// Send a batch, websocket clients receive every channel reliably.
+	pub async fn send(
+		&mut self,
+		channel: Channel,
+		frame: RelayFrame,
+	) -> Result<()> {
+		match &mut self.send {
+			ClientSink::Ws(send) => {
+				let msg = match frame {
+					RelayFrame::Binary(bytes) => AxumWsEvent::Binary(bytes),
+					RelayFrame::Text(text) => AxumWsEvent::Text(text),
+				};
+				send.send(msg).await?
+			}
+			ClientSink::Udp { address, outgoing } => {
+				outgoing.send((*address, channel, frame.into_bytes()?))?
+			}
+		}
 		Ok(())
 	}
 }
 
+/// Handle a client's batches until the stream ends, then remove it.
+fn spawn_recv(
+	lobby: Lobby,
+	client_id: ClientId,
+	recv: impl Stream<Item = (Channel, RelayFrame)> + Send + 'static,
+) -> tokio::task::JoinHandle<()> {
+	tokio::spawn(async move {
+		let mut recv = std::pin::pin!(recv);
+		while let Some((channel, frame)) = recv.next().await {
+			lobby
+				.write()
+				.await
+				.handle_message(client_id, channel, frame)
+				.await
+				.ok_or(|e| log::error!("{e}"));
+		}
+		lobby
+			.write()
+			.await
+			.remove_client(client_id)
+			.await
+			.ok_or(|e| log::error!("{e}"));
+		log::info!("<<< {}: Disconnected", client_id);
+	})
+}
+
 impl Drop for LobbyClient {
 	fn drop(&mut self) { self.recv_task.abort(); }
 }
 
-fn filter_payload(msg: AxumWsEvent) -> Result<Option<Vec<u8>>> {
+fn filter_payload(msg: AxumWsEvent) -> Result<Option<RelayFrame>> {
 	match msg {
-		// AxumWsEvent::Text(txt) => Ok(Some(Message::from_string(&txt)?)),
-		AxumWsEvent::Binary(bytes) => Ok(Some(bytes)),
+		AxumWsEvent::Text(text) => Ok(Some(RelayFrame::Text(text))),
+		AxumWsEvent::Binary(bytes) => Ok(Some(RelayFrame::Binary(bytes))),
 		_ => Ok(None),
 	}
 }
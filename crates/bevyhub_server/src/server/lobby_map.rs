@@ -3,9 +3,12 @@ use axum::extract::ConnectInfo;
 use axum::extract::WebSocketUpgrade;
 use axum::response::IntoResponse;
 use axum_extra::TypedHeader;
+use bevyhub_net::prelude::Channel;
+use forky::prelude::*;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use tokio::sync::RwLock;
 
 
@@ -50,12 +53,37 @@ pub struct LobbyMapInner {
 }
 
 impl LobbyMapInner {
-	pub async fn push_client(&mut self, client: Client) {
-		let lobby_id = LobbyId::default();
+	fn default_lobby(&mut self) -> Lobby {
+		self.lobbies
+			.entry(LobbyId::default())
+			.or_insert_with(Lobby::default)
+			.clone()
+	}
 
-		let lobby = self.lobbies.entry(lobby_id).or_insert_with(Lobby::default);
+	pub async fn push_client(&mut self, client: Client) {
+		let lobby = self.default_lobby();
+		lobby
+			.write()
+			.await
+			.push_client(lobby.clone(), client)
+			.await
+			.ok_or(|e| log::error!("{e}"));
+	}
 
-		let lobby_arc = lobby.clone();
-		lobby.write().await.push_client(lobby_arc, client);
+	/// Add a client connected over UDP to the same lobby as
+	/// websocket clients.
+	pub async fn push_udp_client(
+		&mut self,
+		address: SocketAddr,
+		incoming: mpsc::UnboundedReceiver<(Channel, Vec<u8>)>,
+		outgoing: UdpOutgoing,
+	) {
+		let lobby = self.default_lobby();
+		lobby
+			.write()
+			.await
+			.push_udp_client(lobby.clone(), address, incoming, outgoing)
+			.await
+			.ok_or(|e| log::error!("{e}"));
 	}
 }
@@ -16,3 +16,6 @@ pub use self::server::*;
 pub mod tracing_utils;
 #[allow(unused_imports)]
 pub use self::tracing_utils::*;
+pub mod udp_listener;
+#[allow(unused_imports)]
+pub use self::udp_listener::*;
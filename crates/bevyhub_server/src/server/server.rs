@@ -2,6 +2,7 @@ use super::*;
 use axum::response::Html;
 use axum::routing::get;
 use axum::Router;
+use forky::prelude::*;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use tower_http::services::ServeDir;
@@ -12,12 +13,20 @@ pub const DEFAULT_ADDRESS: &str = "0.0.0.0:3000";
 
 pub struct Server {
 	pub address: String,
+	/// Also accept clients over UDP on this address, see
+	/// [`LobbyMap::listen_udp`].
+	pub udp_address: Option<String>,
+	/// Clients connecting over UDP once this many are connected
+	/// are refused.
+	pub max_udp_connections: usize,
 }
 
 impl Default for Server {
 	fn default() -> Self {
 		Self {
 			address: DEFAULT_ADDRESS.to_string(),
+			udp_address: None,
+			max_udp_connections: DEFAULT_MAX_UDP_CONNECTIONS,
 		}
 	}
 }
@@ -29,6 +38,17 @@ impl Server {
 			..Default::default()
 		}
 	}
+
+	pub fn with_udp_address(mut self, address: String) -> Self {
+		self.udp_address = Some(address);
+		self
+	}
+
+	pub fn with_max_udp_connections(mut self, max: usize) -> Self {
+		self.max_udp_connections = max;
+		self
+	}
+
 	pub async fn run(self) -> anyhow::Result<()> {
 		init_tracing();
 		::tracing::debug!("listenin");
@@ -37,6 +57,16 @@ impl Server {
 			PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
 
 		let lobby_map = LobbyMap::default();
+		if let Some(udp_address) = self.udp_address {
+			let lobby_map = lobby_map.clone();
+			let max_connections = self.max_udp_connections;
+			tokio::spawn(async move {
+				lobby_map
+					.listen_udp(udp_address, max_connections)
+					.await
+					.ok_or(|e| log::error!("udp listener: {e}"));
+			});
+		}
 
 		let app = Router::new()
 			.fallback_service(
@@ -14,7 +14,8 @@ pub fn init_tracing() {
 				}),
 		)
 		.with(tracing_subscriber::fmt::layer())
-		.init();
+		.try_init()
+		.ok();
 }
 
 
@@ -0,0 +1,180 @@
+use super::*;
+use anyhow::Result;
+use bevyhub_net::prelude::*;
+use forky::prelude::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Batches for the UDP listener to send, by client address.
+pub type UdpOutgoing = mpsc::UnboundedSender<(SocketAddr, Channel, Vec<u8>)>;
+
+/// Batches received from a client of the UDP listener.
+type UdpIncoming = mpsc::UnboundedReceiver<(Channel, Vec<u8>)>;
+
+/// A client connected to the UDP listener.
+struct UdpSession {
+	nonce: u64,
+	connection: UdpConnection,
+	/// Dropped when the connection closes, removing the client.
+	incoming: mpsc::UnboundedSender<(Channel, Vec<u8>)>,
+}
+
+/// The default number of clients the UDP listener accepts,
+/// see [`Server::with_max_udp_connections`].
+pub const DEFAULT_MAX_UDP_CONNECTIONS: usize = 1024;
+
+impl LobbyMap {
+	/// Accept up to `max_connections` clients over UDP, ie the
+	/// `NativeUdpClient`, into the same lobbies as websocket clients.
+	pub async fn listen_udp(
+		self,
+		address: String,
+		max_connections: usize,
+	) -> Result<()> {
+		let socket = UdpSocket::bind(address).await?;
+		println!("listening for udp on {}", socket.local_addr()?);
+		self.serve_udp(socket, max_connections).await
+	}
+
+	/// Run the UDP listener on a bound socket.
+	pub async fn serve_udp(
+		self,
+		socket: UdpSocket,
+		max_connections: usize,
+	) -> Result<()> {
+		let (outgoing, mut outgoing_recv): (UdpOutgoing, _) =
+			mpsc::unbounded_channel();
+		// new clients are added to a lobby outside of the select loop
+		// so waiting for the lobby lock does not block other clients
+		let (accepted, mut accepted_recv) = mpsc::unbounded_channel();
+		let lobby_outgoing = outgoing.clone();
+		tokio::spawn(async move {
+			while let Some((address, incoming)) = accepted_recv.recv().await {
+				self.0
+					.write()
+					.await
+					.push_udp_client(address, incoming, lobby_outgoing.clone())
+					.await;
+			}
+		});
+		let mut sessions = HashMap::<SocketAddr, UdpSession>::new();
+		let mut buf = vec![0; UDP_MAX_DATAGRAM];
+		let mut interval = tokio::time::interval(UDP_TICK);
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {
+					let now = Instant::now();
+					sessions.retain(|address, session| {
+						let closed = session.connection.is_closed(now);
+						if closed {
+							log::info!("<<< {address}: UDP connection closed");
+						}
+						!closed
+					});
+					let mut failed = Vec::new();
+					for (address, session) in sessions.iter_mut() {
+						match session.connection.poll_transmit(now) {
+							Ok(datagrams) => {
+								for datagram in datagrams {
+									socket
+										.send_to(&datagram, address)
+										.await
+										.ok();
+								}
+							}
+							Err(e) => {
+								log::error!("<<< {address}: {e}");
+								failed.push(*address);
+							}
+						}
+					}
+					for address in failed {
+						sessions.remove(&address);
+					}
+				}
+				Some((address, channel, msg)) = outgoing_recv.recv() => {
+					if let Some(session) = sessions.get_mut(&address) {
+						session
+							.connection
+							.send(channel, &msg)
+							.ok_or(|e| log::error!("{e}"));
+					}
+				}
+				received = socket.recv_from(&mut buf) => {
+					// errors are reported for unreachable clients
+					let Ok((len, address)) = received else {
+						continue;
+					};
+					handle_datagram(
+						&socket,
+						&mut sessions,
+						&accepted,
+						max_connections,
+						address,
+						&buf[..len],
+					)
+					.await
+					.ok_or(|e| log::debug!("{address}: {e}"));
+				}
+			}
+		}
+	}
+}
+
+async fn handle_datagram(
+	socket: &UdpSocket,
+	sessions: &mut HashMap<SocketAddr, UdpSession>,
+	accepted: &mpsc::UnboundedSender<(SocketAddr, UdpIncoming)>,
+	max_connections: usize,
+	address: SocketAddr,
+	bytes: &[u8],
+) -> Result<()> {
+	let packet = UdpPacket::from_bytes(bytes)?;
+	let now = Instant::now();
+	let full = sessions.len() >= max_connections;
+	match (&packet.body, sessions.get_mut(&address)) {
+		// the accept was lost
+		(UdpBody::Connect { nonce }, Some(session))
+			if session.nonce == *nonce =>
+		{
+			let accept = UdpPacket::handshake(
+				session.connection.token,
+				UdpBody::Accept { nonce: *nonce },
+			);
+			socket.send_to(&accept.to_bytes()?, address).await?;
+		}
+		// no accept is sent, the client times out
+		(UdpBody::Connect { .. }, None) if full => {
+			anyhow::bail!("refused, {max_connections} connections open");
+		}
+		(UdpBody::Connect { nonce }, _) => {
+			log::info!(">>> {address}: New UDP Connection");
+			let token = rand::random::<u64>();
+			let (incoming, incoming_recv) = mpsc::unbounded_channel();
+			// replaces any previous session from the same address
+			sessions.insert(address, UdpSession {
+				nonce: *nonce,
+				connection: UdpConnection::new(
+					token,
+					UdpSettings::default(),
+					now,
+				),
+				incoming,
+			});
+			accepted.send((address, incoming_recv))?;
+			let accept =
+				UdpPacket::handshake(token, UdpBody::Accept { nonce: *nonce });
+			socket.send_to(&accept.to_bytes()?, address).await?;
+		}
+		(_, Some(session)) if packet.token == session.connection.token => {
+			for batch in session.connection.receive(now, packet) {
+				session.incoming.send(batch).ok();
+			}
+		}
+		_ => anyhow::bail!("unknown connection"),
+	}
+	Ok(())
+}
@@ -0,0 +1,294 @@
+#[cfg(test)]
+mod test {
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use bevyhub_net::prelude::*;
+	use bevyhub_server::prelude::*;
+	use std::time::Duration;
+	use sweet::prelude::*;
+	use tokio::time::timeout;
+
+	const ADDRESS: &str = "127.0.0.1:3417";
+	const URL: &str = "ws://127.0.0.1:3417/ws";
+	const PLUGIN_ADDRESS: &str = "127.0.0.1:3418";
+	const PLUGIN_URL: &str = "ws://127.0.0.1:3418/ws";
+	const JSON_ADDRESS: &str = "127.0.0.1:3419";
+	const COMPACT_ADDRESS: &str = "127.0.0.1:3423";
+	const MSGPACK_ADDRESS: &str = "127.0.0.1:3424";
+	const TIMEOUT: Duration = Duration::from_secs(10);
+
+	/// Receive until a message matches `func`.
+	async fn recv_until(
+		client: &mut NativeWsClient,
+		func: impl Fn(&Message) -> bool,
+	) -> Result<Message> {
+		timeout(TIMEOUT, async {
+			loop {
+				if let Some(message) =
+					client.recv()?.into_iter().find(|message| func(message))
+				{
+					return Ok(message);
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await?
+	}
+
+	async fn connect_until_ok(url: &str) -> Result<NativeWsClient> {
+		Ok(timeout(TIMEOUT, async {
+			loop {
+				if let Ok(client) = NativeWsClient::new(url).await {
+					return client;
+				}
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		})
+		.await?)
+	}
+
+	/// A server on its own runtime, dropping it closes
+	/// every connection like a crash.
+	struct CrashableServer(Option<tokio::runtime::Runtime>);
+
+	impl Drop for CrashableServer {
+		fn drop(&mut self) {
+			if let Some(runtime) = self.0.take() {
+				runtime.shutdown_background();
+			}
+		}
+	}
+
+	fn run_server(address: &str) -> Result<CrashableServer> {
+		let runtime = tokio::runtime::Builder::new_multi_thread()
+			.enable_all()
+			.build()?;
+		let address = address.to_string();
+		runtime.spawn(async move {
+			// the previous server may still hold the address
+			while Server::new(address.clone()).run().await.is_err() {
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		});
+		Ok(CrashableServer(Some(runtime)))
+	}
+
+	/// Update the app until `func` returns true.
+	async fn update_until(
+		app: &mut App,
+		func: impl Fn(&App) -> bool,
+	) -> Result<()> {
+		Ok(timeout(TIMEOUT, async {
+			while {
+				app.update();
+				!func(app)
+			} {
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}
+		})
+		.await?)
+	}
+
+	fn num_mirrors(app: &App) -> usize {
+		app.world().resource::<ReplicateRegistry>().entities.len()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn reconnects() -> Result<()> {
+		let server = run_server(ADDRESS)?;
+		let mut app = App::new();
+		app.add_plugins((
+			bevy::time::TimePlugin,
+			ReplicatePlugin,
+			NativeClientPlugin {
+				reconnect: ReconnectSettings {
+					initial_delay: Duration::from_millis(100),
+					max_delay: Duration::from_millis(100),
+					..default()
+				},
+				..NativeClientPlugin::new(URL)
+			},
+		));
+		let entity = app.world_mut().spawn(Replicate::default()).id();
+		// welcomed once the server added it to the lobby
+		update_until(&mut app, |app| {
+			app.world().resource::<LocalClientId>().is_some()
+		})
+		.await?;
+		let mut client_b = connect_until_ok(URL).await?;
+		client_b.send(&vec![Message::Spawn {
+			entity: Entity::from_raw(9),
+		}])?;
+		update_until(&mut app, |app| num_mirrors(app) == 1).await?;
+
+		// the entities of peers are despawned when disconnected
+		drop(server);
+		update_until(&mut app, |app| {
+			num_mirrors(app) == 0
+				&& app.world().resource::<LocalClientId>().is_none()
+		})
+		.await?;
+
+		// peers receive a snapshot whichever connects first
+		let _server = run_server(ADDRESS)?;
+		let mut client_b = connect_until_ok(URL).await?;
+		let message = timeout(TIMEOUT, async {
+			loop {
+				app.update();
+				if let Some(message) = client_b
+					.recv()?
+					.into_iter()
+					.find(|message| matches!(message, Message::Spawn { .. }))
+				{
+					return anyhow::Ok(message);
+				}
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}
+		})
+		.await??;
+		expect(message).to_be(Message::Spawn { entity });
+		Ok(())
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn plugin_queues_until_connected() -> Result<()> {
+		let mut app = App::new();
+		app.add_plugins((
+			bevy::time::TimePlugin,
+			ReplicatePlugin,
+			NativeClientPlugin {
+				reconnect: ReconnectSettings {
+					initial_delay: Duration::from_millis(100),
+					max_delay: Duration::from_millis(100),
+					..default()
+				},
+				..NativeClientPlugin::new(PLUGIN_URL)
+			},
+		));
+		let entity = Entity::from_raw(7);
+		app.world_mut()
+			.resource_mut::<MessageOutgoing>()
+			.push(Message::Spawn { entity });
+		// queued until connected, the transport is not inserted yet
+		for _ in 0..5 {
+			app.update();
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+		expect(app.world().resource::<MessageOutgoing>().is_empty())
+			.to_be_false();
+		expect(app.world().contains_non_send::<NativeWsClient>())
+			.to_be_false();
+		expect(app.world().resource::<LocalClientId>().is_none()).to_be_true();
+
+		let batch = tokio::spawn(recv_first_batch(PLUGIN_ADDRESS));
+		let batch = timeout(TIMEOUT, async {
+			while !batch.is_finished() {
+				app.update();
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}
+			batch.await?
+		})
+		.await??;
+		expect(batch.contains(&Message::Spawn { entity })).to_be_true();
+		Ok(())
+	}
+
+	/// Accept a single client, returning the first batch it sends.
+	async fn recv_first_batch(address: &str) -> Result<Vec<Message>> {
+		use futures_util::StreamExt;
+		let listener = tokio::net::TcpListener::bind(address).await?;
+		let (stream, _) = listener.accept().await?;
+		let mut ws = tokio_tungstenite::accept_async(stream).await?;
+		let Some(msg) = ws.next().await else {
+			anyhow::bail!("connection closed");
+		};
+		RelayFrame::Binary(msg?.into_data().to_vec())
+			.with_header(RelayHeader::Sender(1))?
+			.decode()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn json() -> Result<()> {
+		relay_formats(JSON_ADDRESS, WireFormat::Json, WireFormat::Bincode)
+			.await
+	}
+
+	/// Relay a batch each way between two clients sending with
+	/// different formats through a [`Server`].
+	async fn relay_formats(
+		address: &str,
+		format_a: WireFormat,
+		format_b: WireFormat,
+	) -> Result<()> {
+		tokio::spawn(Server::new(address.to_string()).run());
+		let url = format!("ws://{address}/ws");
+		let mut client_a = connect_until_ok(&url).await?.with_format(format_a);
+		recv_until(&mut client_a, |message| {
+			matches!(message, Message::Welcome { .. })
+		})
+		.await?;
+		let mut client_b = connect_until_ok(&url).await?.with_format(format_b);
+		recv_until(&mut client_a, |message| {
+			matches!(message, Message::PeerConnected { .. })
+		})
+		.await?;
+
+		let entity = Entity::from_raw(7);
+		client_a.send(&vec![
+			Message::Spawn { entity },
+			Message::SendEvent {
+				reg_id: RegistrationId::new_with(3),
+				payload: MessagePayload::new(7u32)?,
+			},
+		])?;
+		let messages = timeout(TIMEOUT, async {
+			let mut messages = Vec::new();
+			while messages.len() < 2 {
+				messages.extend(client_b.recv()?.into_iter().filter(|message| {
+					matches!(
+						message,
+						Message::Spawn { .. } | Message::SendEvent { .. }
+					)
+				}));
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+			anyhow::Ok(messages)
+		})
+		.await??;
+		expect(&messages[0]).to_be(&Message::Spawn { entity });
+		// payloads are decoded regardless of the format of the batch
+		let Message::SendEvent { payload, .. } = &messages[1] else {
+			anyhow::bail!("expected an event");
+		};
+		expect(payload.deserialize::<u32>()?).to_be(7);
+
+		let despawn = Message::Despawn {
+			entity,
+			origin: EntityOrigin::Receiver,
+		};
+		client_b.send(&vec![despawn.clone()])?;
+		let message = recv_until(&mut client_a, |message| {
+			matches!(message, Message::Despawn { .. })
+		})
+		.await?;
+		expect(message).to_be(despawn);
+		Ok(())
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn compact() -> Result<()> {
+		relay_formats(
+			COMPACT_ADDRESS,
+			WireFormat::Compact(
+				CompactCodec::default().with_compress_threshold(0),
+			),
+			WireFormat::Bincode,
+		)
+		.await
+	}
+	#[tokio::test(flavor = "multi_thread")]
+	async fn msgpack() -> Result<()> {
+		relay_formats(MSGPACK_ADDRESS, WireFormat::MsgPack, WireFormat::Bincode)
+			.await
+	}
+}
@@ -0,0 +1,91 @@
+#[cfg(test)]
+mod test {
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use bevyhub_net::prelude::*;
+	use bevyhub_server::prelude::*;
+	use std::time::Duration;
+	use sweet::prelude::*;
+	use tokio::time::timeout;
+
+	const ADDRESS: &str = "127.0.0.1:3420";
+	const URL: &str = "ws://127.0.0.1:3420/ws";
+	const TIMEOUT: Duration = Duration::from_secs(20);
+
+	async fn client() -> Result<NativeRtcClient> {
+		let signaling = timeout(TIMEOUT, async {
+			loop {
+				if let Ok(client) = NativeWsClient::new(URL).await {
+					return client;
+				}
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		})
+		.await?;
+		Ok(RtcTransport::new(signaling, NativeRtcBackend::default()))
+	}
+
+	/// Receive on both clients until `func` returns a value.
+	async fn poll_until<T>(
+		a: &mut NativeRtcClient,
+		b: &mut NativeRtcClient,
+		mut func: impl FnMut(
+			&NativeRtcClient,
+			&NativeRtcClient,
+			Vec<Message>,
+		) -> Option<T>,
+	) -> Result<T> {
+		timeout(TIMEOUT, async {
+			loop {
+				a.recv()?;
+				let received = b.recv()?;
+				if let Some(value) = func(a, b, received) {
+					return Ok(value);
+				}
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}
+		})
+		.await?
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn data_channels() -> Result<()> {
+		tokio::spawn(Server::new(ADDRESS.to_string()).run());
+		// the server assigns ids in order of connection
+		let mut a = client().await?;
+		let mut b = client().await?;
+
+		poll_until(&mut a, &mut b, |a, b, _| {
+			(a.is_open(2) && b.is_open(1)).then_some(())
+		})
+		.await?;
+
+		let entity = Entity::from_raw(7);
+		a.send(&vec![Message::Spawn { entity }])?;
+		let change = Message::Change {
+			reg_id: RegistrationId::new_with(0),
+			entity,
+			origin: EntityOrigin::Sender,
+			payload: MessagePayload::Bytes(vec![1, 2, 3]),
+		};
+		a.send_channel(Channel::UNRELIABLE, &vec![change.clone()])?;
+
+		// the channels are not ordered relative to each other
+		let spawn = Message::Spawn { entity };
+		let mut received = Vec::new();
+		poll_until(&mut a, &mut b, |_, _, messages| {
+			received.extend(messages);
+			(received.contains(&spawn) && received.contains(&change))
+				.then_some(())
+		})
+		.await?;
+		// the channel open signal is relayed with its own sender
+		expect(&received[0]).to_be(&Message::Sender { client_id: 1 });
+		let num_messages = received
+			.iter()
+			.filter(|message| !matches!(message, Message::Sender { .. }))
+			.count();
+		expect(num_messages).to_be(2);
+		Ok(())
+	}
+}
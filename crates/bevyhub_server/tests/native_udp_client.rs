@@ -0,0 +1,110 @@
+#[cfg(test)]
+mod test {
+	use anyhow::Result;
+	use bevy::prelude::*;
+	use bevyhub_net::prelude::*;
+	use bevyhub_server::prelude::*;
+	use std::time::Duration;
+	use sweet::prelude::*;
+	use tokio::time::timeout;
+
+	const ADDRESS: &str = "127.0.0.1:3421";
+	const URL: &str = "ws://127.0.0.1:3421/ws";
+	const UDP_ADDRESS: &str = "127.0.0.1:3422";
+	const LIMITED_ADDRESS: &str = "127.0.0.1:3425";
+	const LIMITED_UDP_ADDRESS: &str = "127.0.0.1:3426";
+	const TIMEOUT: Duration = Duration::from_secs(10);
+
+	/// Receive until every expected message has arrived.
+	async fn recv_all(
+		client: &mut impl Transport,
+		expected: &[Message],
+	) -> Result<Vec<Message>> {
+		let mut received = Vec::new();
+		timeout(TIMEOUT, async {
+			loop {
+				received.extend(client.recv()?);
+				if expected.iter().all(|msg| received.contains(msg)) {
+					return anyhow::Ok(());
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await??;
+		Ok(received)
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn relays_over_udp() -> Result<()> {
+		tokio::spawn(
+			Server::new(ADDRESS.to_string())
+				.with_udp_address(UDP_ADDRESS.to_string())
+				.run(),
+		);
+		// the server assigns ids in order of connection
+		let mut a = NativeUdpClient::new(UDP_ADDRESS).await?;
+		let mut b = NativeUdpClient::new(UDP_ADDRESS).await?;
+		let mut c = timeout(TIMEOUT, async {
+			loop {
+				if let Ok(client) = NativeWsClient::new(URL).await {
+					return client;
+				}
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		})
+		.await?;
+		recv_all(&mut a, &[
+			Message::Welcome { client_id: 1 },
+			Message::PeerConnected { client_id: 2 },
+			Message::PeerConnected { client_id: 3 },
+		])
+		.await?;
+
+		let entity = Entity::from_raw(7);
+		let spawn = Message::Spawn { entity };
+		// larger than a single datagram
+		let change = Message::Change {
+			reg_id: RegistrationId::new_with(0),
+			entity,
+			origin: EntityOrigin::Sender,
+			payload: MessagePayload::Bytes(vec![7; 5000]),
+		};
+		a.send(&vec![spawn.clone()])?;
+		a.send_channel(Channel::UNRELIABLE, &vec![change.clone()])?;
+
+		let expected = [Message::Sender { client_id: 1 }, spawn, change];
+		recv_all(&mut b, &expected).await?;
+		// websocket clients share the lobby
+		recv_all(&mut c, &expected).await?;
+
+		drop(a);
+		let received =
+			recv_all(&mut b, &[Message::PeerDisconnected { client_id: 1 }])
+				.await?;
+		// the spawn is not received again
+		expect(received.contains(&Message::Spawn { entity })).to_be_false();
+		Ok(())
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn max_connections() -> Result<()> {
+		tokio::spawn(
+			Server::new(LIMITED_ADDRESS.to_string())
+				.with_udp_address(LIMITED_UDP_ADDRESS.to_string())
+				.with_max_udp_connections(1)
+				.run(),
+		);
+		let mut a = NativeUdpClient::new(LIMITED_UDP_ADDRESS).await?;
+		recv_all(&mut a, &[Message::Welcome { client_id: 1 }]).await?;
+		let refused = NativeUdpClient::with_settings(
+			LIMITED_UDP_ADDRESS,
+			UdpSettings {
+				timeout: Duration::from_millis(500),
+				..default()
+			},
+		)
+		.await;
+		expect(refused.is_err()).to_be_true();
+		Ok(())
+	}
+}